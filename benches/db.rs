@@ -0,0 +1,134 @@
+use std::hint::black_box;
+use std::time::Duration;
+use cendb::{Db, DbOptions, SyncPolicy, TempDir};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+// Database sizes every benchmark runs at
+const SIZES: [u64; 2] = [10_000, 1_000_000];
+const VALUE: [u8; 100] = [7; 100];
+// Writes made before each flush is measured
+const FLUSHED_WRITES: u64 = 1_000;
+const SCAN_LENGTH: usize = 100;
+
+fn key(i: u64) -> [u8; 8] {
+    i.to_be_bytes()
+}
+
+// Writes are not synced one by one, that would measure the disk rather than the database
+fn open(dir: &TempDir) -> Db {
+    DbOptions::new().path(dir).sync(SyncPolicy::Never).open().unwrap()
+}
+
+// A database holding keys 0..size, flushed unless the keys should stay in the WAL
+fn populated(size: u64, flush: bool) -> (TempDir, Db) {
+    let dir = TempDir::new().unwrap();
+    let db = open(&dir);
+    for i in 0..size {
+        db.put(&key(i), &VALUE).unwrap();
+    }
+    if flush {
+        db.flush().unwrap();
+    }
+    (dir, db)
+}
+
+// xorshift64, so every run reads the same keys
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn sequential_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sequential_insert");
+    group.sample_size(10);
+    for size in SIZES {
+        group.throughput(Throughput::Elements(size));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter_batched(
+                || TempDir::new().unwrap(),
+                |dir| {
+                    let db = open(&dir);
+                    for i in 0..size {
+                        db.put(&key(i), &VALUE).unwrap();
+                    }
+                    (dir, db)
+                },
+                BatchSize::PerIteration,
+            );
+        });
+    }
+    group.finish();
+}
+
+fn random_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("random_get");
+    group.throughput(Throughput::Elements(1));
+    for size in SIZES {
+        let (_dir, db) = populated(size, true);
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| db.get(&key(next_random(&mut state) % size)).unwrap());
+        });
+    }
+    group.finish();
+}
+
+fn range_scan(c: &mut Criterion) {
+    let mut group = c.benchmark_group("range_scan");
+    group.throughput(Throughput::Elements(SCAN_LENGTH as u64));
+    for size in SIZES {
+        let (_dir, db) = populated(size, true);
+        let mut state = 0x9e37_79b9_7f4a_7c15;
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                let start = key(next_random(&mut state) % size);
+                for entry in db.range(start..).take(SCAN_LENGTH) {
+                    black_box(entry.unwrap());
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+fn flush_latency(c: &mut Criterion) {
+    let mut group = c.benchmark_group("flush_latency");
+    group.sample_size(10);
+    for size in SIZES {
+        let (_dir, db) = populated(size, true);
+        let mut next = size;
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter_batched(
+                || {
+                    for i in next..next + FLUSHED_WRITES {
+                        db.put(&key(i % size), &VALUE).unwrap();
+                    }
+                    next += FLUSHED_WRITES;
+                },
+                |()| db.flush().unwrap(),
+                BatchSize::PerIteration,
+            );
+        });
+    }
+    group.finish();
+}
+
+// Opening replays the WAL, none of the keys were flushed to the map
+fn recovery_time(c: &mut Criterion) {
+    let mut group = c.benchmark_group("recovery_time");
+    group.sample_size(10).measurement_time(Duration::from_secs(20));
+    for size in SIZES {
+        let (dir, db) = populated(size, false);
+        drop(db);
+        group.throughput(Throughput::Elements(size));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| open(&dir));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sequential_insert, random_get, range_scan, flush_latency, recovery_time);
+criterion_main!(benches);
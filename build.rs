@@ -0,0 +1,61 @@
+fn main() {
+    #[cfg(feature = "cendb-grpc")]
+    grpc::compile();
+    #[cfg(feature = "ffi")]
+    ffi::generate_header();
+}
+
+// Writes include/cendb.h from the extern "C" functions of src/db/ffi.rs
+#[cfg(feature = "ffi")]
+mod ffi {
+    pub fn generate_header() {
+        println!("cargo:rerun-if-changed=src/db/ffi.rs");
+        let dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR is set by cargo");
+        let config = cbindgen::Config { usize_is_size_t: true, ..Default::default() };
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(format!("{dir}/src/db/ffi.rs"))
+            .with_language(cbindgen::Language::C)
+            .with_include_guard("CENDB_H")
+            .with_sys_include("stddef.h")
+            .with_sys_include("stdint.h")
+            .with_no_includes()
+            .with_documentation(true)
+            .generate()
+            .expect("src/db/ffi.rs cannot be parsed by cbindgen")
+            .write_to_file(format!("{dir}/include/cendb.h"));
+    }
+}
+
+// Generates the service and client of proto/cendb.proto around the hand written messages
+// of src/db/grpc.rs, so building needs no protoc
+#[cfg(feature = "cendb-grpc")]
+mod grpc {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    // Method names and their routes, which also name the request and response messages
+    const METHODS: [(&str, &str); 5] = [
+        ("get", "Get"),
+        ("put", "Put"),
+        ("delete", "Delete"),
+        ("scan", "Scan"),
+        ("batch_write", "BatchWrite"),
+    ];
+
+    pub fn compile() {
+        println!("cargo:rerun-if-changed=build.rs");
+        let mut service = Service::builder().name("Cendb").package("cendb");
+        for (name, route_name) in METHODS {
+            service = service.method(
+                Method::builder()
+                    .name(name)
+                    .route_name(route_name)
+                    .input_type(format!("crate::db::grpc::{route_name}Request"))
+                    .output_type(format!("crate::db::grpc::{route_name}Response"))
+                    .codec_path("tonic_prost::ProstCodec")
+                    .build(),
+            );
+        }
+        Builder::new().compile(&[service.build()]);
+    }
+}
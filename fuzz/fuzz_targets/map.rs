@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Run with `cargo +nightly fuzz run map`
+fuzz_target!(|data: &[u8]| {
+    let _ = cendb::db::fuzz::parse_map(data);
+});
@@ -0,0 +1,77 @@
+#ifndef CENDB_H
+#define CENDB_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define CENDB_OK 0
+
+#define CENDB_NOT_FOUND 1
+
+#define CENDB_ERROR -1
+
+/**
+ * Database handle returned by [`cendb_open`], opaque to C.
+ */
+typedef struct CendbDb CendbDb;
+
+/**
+ * Opens or creates the database in the folder `path`, a NUL terminated UTF-8 string.
+ * Returns null on failure. The handle is released with [`cendb_close`].
+ *
+ * # Safety
+ * `path` must be null or point to a NUL terminated string.
+ */
+struct CendbDb *cendb_open(const char *path);
+
+/**
+ * Looks up `key`. If found, stores a copy of the value in `*value` and its length in `*value_len`
+ * and returns [`CENDB_OK`], the copy is released with [`cendb_free_value`].
+ * Returns [`CENDB_NOT_FOUND`] without touching them otherwise.
+ *
+ * # Safety
+ * `db` must come from [`cendb_open`] and not be closed, `key` must point to `key_len` readable
+ * bytes, `value` and `value_len` must be valid for writes.
+ */
+int cendb_get(const struct CendbDb *db,
+              const uint8_t *key,
+              size_t key_len,
+              uint8_t **value,
+              size_t *value_len);
+
+/**
+ * Stores `value` under `key`, returning [`CENDB_OK`] once it is in the WAL.
+ *
+ * # Safety
+ * `db` must come from [`cendb_open`] and not be closed, `key` and `value` must point to
+ * `key_len` and `value_len` readable bytes.
+ */
+int cendb_put(const struct CendbDb *db,
+              const uint8_t *key,
+              size_t key_len,
+              const uint8_t *value,
+              size_t value_len);
+
+/**
+ * Releases a value returned by [`cendb_get`].
+ *
+ * # Safety
+ * `value` and `value_len` must be a pair written by [`cendb_get`], released once, or `value` null.
+ */
+void cendb_free_value(uint8_t *value, size_t value_len);
+
+/**
+ * Closes a database opened by [`cendb_open`]. Null is ignored.
+ *
+ * # Safety
+ * `db` must come from [`cendb_open`] and must not be used afterwards.
+ */
+void cendb_close(struct CendbDb *db);
+
+/**
+ * Message of the last failed call on this thread, or null. The string stays valid until the
+ * next failing call on the same thread.
+ */
+const char *cendb_last_error(void);
+
+#endif  /* CENDB_H */
@@ -1,4 +1,105 @@
+#[cfg(feature = "tokio")]
+pub mod async_db;
+pub mod backend;
+pub mod batch;
+pub mod bloom;
+pub mod btree;
+pub mod cache;
+pub mod changefeed;
+pub mod check;
+pub mod clock;
+pub mod column_family;
+pub mod compression;
+pub mod cursor;
+pub mod database;
+#[cfg(target_os = "linux")]
+mod direct;
+pub mod engine;
+pub mod export;
+pub mod fault;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod files;
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub mod fuzz;
+#[cfg(feature = "cendb-grpc")]
+pub mod grpc;
+pub mod hash;
+mod hash_index;
+pub mod header;
 pub mod index;
+pub mod iter;
+pub mod keys;
 pub mod lookup;
+mod lsm;
+pub mod memory;
+pub mod merge;
+pub mod multimap;
+pub mod options;
+mod pin;
+pub mod queue;
+pub mod recovery;
+pub mod replication;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod series;
+pub mod shard;
+#[cfg(test)]
+mod simulation;
+pub mod snapshot;
+pub mod stats;
+pub mod storage;
+pub mod sync;
+pub mod temp;
+mod trace;
+pub mod transaction;
+#[cfg(feature = "serde")]
+pub mod typed;
+pub mod typed_db;
+#[cfg(feature = "io-uring")]
+pub mod uring;
+mod varint;
+pub mod wal;
 
+#[cfg(feature = "tokio")]
+pub use async_db::AsyncDb;
+pub use backend::{FileSystem, OpenMode, StorageBackend, StorageFile};
+pub use batch::WriteBatch;
+pub use cache::CacheStats;
+pub use changefeed::ChangeEvent;
+pub use check::CheckReport;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use column_family::ColumnFamily;
+pub use compression::Compression;
+pub use cursor::Cursor;
+pub use database::Db;
+pub use engine::{EngineIter, StorageEngine};
+pub use export::Format;
+pub use fault::{FaultyBackend, FaultyFile};
+pub use hash::KeyHasher;
+pub use index::IndexError;
+pub use iter::{DbIter, SnapshotIter};
+pub use keys::{Element, Tuple};
+pub use lookup::{MapError, WalError};
+pub use memory::MemoryBackend;
+pub use multimap::MultiMap;
+pub use options::{Backpressure, DbOptions, Engine, LogLevel};
+pub use queue::Queue;
+pub use recovery::RecoveryReport;
+pub use replication::{ReplicationHandle, ReplicationServer};
+#[cfg(feature = "server")]
+pub use server::Server;
+pub use snapshot::Snapshot;
+pub use stats::{DiskUsage, KeyspaceUsage, Latency, MemoryUsage, Stats};
+pub use storage::ValueRef;
+pub use sync::{SyncPolicy, WriteOptions};
+pub use temp::TempDir;
+pub use transaction::{ReadTransaction, Transaction};
+#[cfg(feature = "serde")]
+pub use typed::{Bincode, Codec, Json, Postcard};
+pub use typed_db::{BigEndian, KeyCodec, Raw, TypedDb, TypedIter, Utf8, ValueCodec};
+#[cfg(feature = "io-uring")]
+pub use uring::IoUringBackend;
+pub use wal::{WalOpType, WalRecordInfo};
 // pub use lookup::{LookupTable, EntryLocation};
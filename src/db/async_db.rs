@@ -0,0 +1,101 @@
+use std::path::Path;
+use crate::db::database::Db;
+use crate::db::options::DbOptions;
+use crate::error::{Error, Result};
+
+/// Handle for async code that runs every call of the blocking [`Db`] API on the tokio
+/// blocking thread pool, so file I/O never stalls the runtime.
+///
+/// Needs a tokio runtime. Like `Db` it is a cheap, cloneable handle, other operations
+/// are reachable through [`AsyncDb::db`] and [`AsyncDb::run`].
+#[derive(Clone)]
+pub struct AsyncDb {
+    db: Db,
+}
+
+impl AsyncDb {
+    /// Opens the database stored in `path`, see [`Db::open`].
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        AsyncDb::spawn(move || Db::open(&path)).await.map(AsyncDb::from)
+    }
+
+    /// Opens the database with `options`, see [`DbOptions::open`].
+    pub async fn open_with(options: DbOptions) -> Result<Self> {
+        AsyncDb::spawn(move || options.open()).await.map(AsyncDb::from)
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = key.to_vec();
+        self.run(move |db| db.get(&key)).await
+    }
+
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let (key, value) = (key.to_vec(), value.to_vec());
+        self.run(move |db| db.put(&key, &value)).await
+    }
+
+    pub async fn delete(&self, key: &[u8]) -> Result<()> {
+        let key = key.to_vec();
+        self.run(move |db| db.delete(&key)).await
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.run(|db| db.flush()).await
+    }
+
+    /// Runs `f` with the blocking handle on the blocking thread pool.
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Db) -> Result<T> + Send + 'static,
+    {
+        let db = self.db.clone();
+        AsyncDb::spawn(move || f(&db)).await
+    }
+
+    /// The blocking handle, its calls block the current thread.
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    pub fn into_inner(self) -> Db {
+        self.db
+    }
+
+    async fn spawn<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+        tokio::task::spawn_blocking(f)
+            .await
+            .map_err(|e| Error::Custom(format!("blocking database task failed: {e}")))?
+    }
+}
+
+impl From<Db> for AsyncDb {
+    fn from(db: Db) -> Self {
+        Self { db }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::temp::TempDir;
+
+    #[test]
+    fn test_async_db() -> Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        runtime.block_on(async {
+            let dir = TempDir::new()?;
+            let db = AsyncDb::open(dir.path()).await?;
+            db.put(b"a", b"1").await?;
+            db.put(b"b", b"2").await?;
+            db.delete(b"b").await?;
+            db.flush().await?;
+            assert_eq!(db.get(b"a").await?, Some(b"1".to_vec()));
+            assert_eq!(db.get(b"b").await?, None);
+            assert_eq!(db.run(|db| Ok(db.iter().count())).await?, 1);
+            db.into_inner().destroy()
+        })
+    }
+}
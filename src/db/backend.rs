@@ -0,0 +1,354 @@
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use crate::db::direct::DirectFile;
+
+/// How [`StorageBackend::open`] opens a file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OpenMode {
+    /// An existing file, for reading only.
+    Read,
+    /// An existing file, for reading and writing.
+    Write,
+    /// For reading and writing, created empty if it does not exist.
+    Create,
+    /// A new file for reading and writing, fails if it exists.
+    CreateNew,
+    /// For reading and writing, created if it does not exist and emptied if it does.
+    Truncate,
+}
+
+/// Storage that holds the files of a database, see [`DbOptions::backend`](crate::DbOptions::backend).
+///
+/// Paths are those the database was opened with, joined with the names of its files.
+/// [`FileSystem`] is the default, other backends keep the files elsewhere, such as in memory
+/// or in a browser's IndexedDB on wasm32.
+pub trait StorageBackend: Debug + Send + Sync {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn StorageFile>>;
+
+    /// Opens a file bypassing caches of the backend where it can, see
+    /// [`DbOptions::direct_io`](crate::DbOptions::direct_io). Opens it as [`open`](Self::open)
+    /// does by default.
+    fn open_direct(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn StorageFile>> {
+        self.open(path, mode)
+    }
+
+    fn exists(&self, path: &Path) -> bool;
+
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Files and folders directly inside `folder`, in no particular order.
+    fn list(&self, folder: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Atomically replaces `to` with `from`. The rename must survive a crash once this returns.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Makes the files created in or removed from `folder` so far survive a crash.
+    fn sync_dir(&self, folder: &Path) -> io::Result<()>;
+
+    /// Contents of the file at `path`.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let file = self.open(path, OpenMode::Read)?;
+        let mut buffer = vec![0; file.len()? as usize];
+        file.read_at(&mut buffer, 0)?;
+        Ok(buffer)
+    }
+
+    /// Length in bytes of the file at `path`.
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        self.open(path, OpenMode::Read)?.len()
+    }
+}
+
+/// An open file of a [`StorageBackend`].
+///
+/// Reads and writes are positional, so a file can be shared between threads.
+pub trait StorageFile: Send + Sync {
+    /// Fills `buffer` from `offset`, failing with `UnexpectedEof` if the file ends before.
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Writes all of `data` at `offset`, extending the file if needed.
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Forces the written data to stable storage.
+    fn sync(&self) -> io::Result<()>;
+
+    /// Writes all of `data` at `offset` and then syncs. Backends that can submit both at once
+    /// override it.
+    fn write_and_sync_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        self.write_at(data, offset)?;
+        self.sync()
+    }
+
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Truncates or zero extends the file to `len` bytes.
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    /// Takes an advisory lock held until the file is dropped, shared or exclusive.
+    /// Returns `false` if it is held elsewhere. Backends that have a single user may always succeed.
+    fn try_lock(&self, _shared: bool) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// The local file system through `std::fs`, the default backend.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct FileSystem;
+
+impl FileSystem {
+    pub(crate) fn open_file(path: &Path, mode: OpenMode) -> io::Result<File> {
+        FileSystem::open_options(mode).open(path)
+    }
+
+    fn open_options(mode: OpenMode) -> OpenOptions {
+        let mut options = OpenOptions::new();
+        options.read(true).write(mode != OpenMode::Read);
+        match mode {
+            OpenMode::Read | OpenMode::Write => options.create(false),
+            OpenMode::Create => options.create(true).truncate(false),
+            OpenMode::CreateNew => options.create_new(true),
+            OpenMode::Truncate => options.create(true).truncate(true),
+        };
+        options
+    }
+}
+
+impl StorageBackend for FileSystem {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn StorageFile>> {
+        Ok(Arc::new(FileSystem::open_file(path, mode)?))
+    }
+
+    // O_DIRECT, unless the file system rejects it as tmpfs does
+    #[cfg(target_os = "linux")]
+    fn open_direct(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn StorageFile>> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let mut options = FileSystem::open_options(mode);
+        options.custom_flags(libc::O_DIRECT);
+        match options.open(path) {
+            Ok(file) => Ok(Arc::new(DirectFile::new(file))),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => self.open(path, mode),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn list(&self, folder: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(folder)?.map(|entry| Ok(entry?.path())).collect()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)?;
+        match to.parent() {
+            Some(parent) => self.sync_dir(parent),
+            None => Ok(()),
+        }
+    }
+
+    // Directories can only be synced this way on unix
+    #[cfg(unix)]
+    fn sync_dir(&self, folder: &Path) -> io::Result<()> {
+        let folder = if folder.as_os_str().is_empty() { Path::new(".") } else { folder };
+        File::open(folder)?.sync_all()
+    }
+
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _folder: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+}
+
+// Positional reads and writes don't move the shared cursor, so concurrent users don't race
+impl StorageFile for File {
+    #[cfg(unix)]
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buffer, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, mut buffer: &mut [u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !buffer.is_empty() {
+            match self.seek_read(buffer, offset)? {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                n => {
+                    buffer = &mut buffer[n..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn read_at(&self, _buffer: &mut [u8], _offset: u64) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    #[cfg(unix)]
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(self, data, offset)
+    }
+
+    #[cfg(windows)]
+    fn write_at(&self, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !data.is_empty() {
+            match self.seek_write(data, offset)? {
+                0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                n => {
+                    data = &data[n..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn write_at(&self, _data: &[u8], _offset: u64) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn try_lock(&self, shared: bool) -> io::Result<bool> {
+        let locked = match shared {
+            true => File::try_lock_shared(self),
+            false => File::try_lock(self),
+        };
+        match locked {
+            Ok(()) => Ok(true),
+            Err(TryLockError::WouldBlock) => Ok(false),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::options::DbOptions;
+    use crate::error::Result;
+    use crate::db::temp::TempDir;
+    use std::sync::Mutex;
+
+    // File system that records the name of every file it opens
+    #[derive(Debug, Default, Clone)]
+    struct Recording {
+        opened: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl StorageBackend for Recording {
+        fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn StorageFile>> {
+            let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            self.opened.lock().unwrap().push(name);
+            FileSystem.open(path, mode)
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            FileSystem.exists(path)
+        }
+
+        fn is_dir(&self, path: &Path) -> bool {
+            FileSystem.is_dir(path)
+        }
+
+        fn list(&self, folder: &Path) -> io::Result<Vec<PathBuf>> {
+            FileSystem.list(folder)
+        }
+
+        fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+            FileSystem.create_dir_all(path)
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            FileSystem.remove_file(path)
+        }
+
+        fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+            FileSystem.remove_dir_all(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            FileSystem.rename(from, to)
+        }
+
+        fn sync_dir(&self, folder: &Path) -> io::Result<()> {
+            FileSystem.sync_dir(folder)
+        }
+    }
+
+    #[test]
+    fn test_custom_backend() -> Result<()> {
+        let backend = Recording::default();
+        let dir = TempDir::new()?;
+        let options = DbOptions::new().path(&dir).bloom_filter(0.01).backend(backend.clone());
+        let db = options.open()?;
+        db.put(b"a", b"1")?;
+        db.cf("users")?.put(b"b", b"2")?;
+        db.flush()?;
+        db.compact()?;
+        drop(db);
+
+        let db = options.clone().reset(false).open()?;
+        assert_eq!(db.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(db.cf("users")?.get(b"b")?, Some(b"2".to_vec()));
+        let opened = backend.opened.lock().unwrap().clone();
+        for name in ["LOCK", "map.db", "wal-000001.db", "data.db", "data.db.compact", "bloom.db.tmp", "map.db.compact"] {
+            assert!(opened.iter().any(|opened| opened == name), "{name} was not opened through the backend");
+        }
+        db.destroy()
+    }
+}
@@ -0,0 +1,81 @@
+/// A group of puts and deletes logged as a single WAL record and applied atomically.
+///
+/// Operations are applied in the order they were added.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) operations: Vec<BatchOperation>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum BatchOperation {
+    Put{key: Vec<u8>, value: Vec<u8>},
+    Delete{key: Vec<u8>},
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.operations.push(BatchOperation::Put{key: key.to_vec(), value: value.to_vec()});
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.operations.push(BatchOperation::Delete{key: key.to_vec()});
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.operations.clear();
+    }
+
+    // What the last operation on key left behind, Some(None) if it deleted the key and None if
+    // the batch does not touch it
+    pub(crate) fn lookup(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        self.operations.iter().rev().find_map(|operation| match operation {
+            BatchOperation::Put{key: put, value} if put == key => Some(Some(value.as_slice())),
+            BatchOperation::Delete{key: deleted} if deleted == key => Some(None),
+            _ => None,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::database::Db;
+    use crate::error::Result;
+
+    #[test]
+    fn test_get_with_batch() -> Result<()> {
+        let db = Db::open_temp()?;
+        db.put(b"a", b"1")?;
+        db.put(b"b", b"2")?;
+        let mut batch = WriteBatch::new();
+        batch.delete(b"a").put(b"b", b"3").put(b"c", b"4").delete(b"c");
+        assert_eq!(db.get_with_batch(&batch, b"a")?, None);
+        assert_eq!(db.get_with_batch(&batch, b"b")?, Some(b"3".to_vec()));
+        assert_eq!(db.get_with_batch(&batch, b"c")?, None);
+        batch.put(b"a", b"5");
+        assert_eq!(db.get_with_batch(&batch, b"a")?, Some(b"5".to_vec()));
+        // Nothing is written until the batch is
+        assert_eq!(db.get(b"a")?, Some(b"1".to_vec()));
+        let cf = db.cf("other")?;
+        cf.put(b"a", b"6")?;
+        assert_eq!(cf.get_with_batch(&WriteBatch::new().delete(b"a").clone(), b"a")?, None);
+        db.write(batch.clone())?;
+        assert_eq!(db.get(b"a")?, db.get_with_batch(&batch, b"a")?);
+        Ok(())
+    }
+}
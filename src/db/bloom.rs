@@ -0,0 +1,141 @@
+use std::path::Path;
+use crate::db::backend::{OpenMode, StorageBackend};
+use crate::db::header::{FileHeader, BLOOM_MAGIC, HEADER_SIZE};
+use crate::error::{Error, Result};
+
+// Fewest keys a filter is sized for, so small tables don't rebuild on every flush
+const MIN_CAPACITY: usize = 1024;
+// Hash count and bit count after the file header
+const PARAMETERS_SIZE: usize = 12;
+
+// Bloom filter over the keys of the lookup table. It never forgets a key, so a negative
+// answer is definite and a positive one is wrong at roughly the configured rate.
+// Persisted as [file header][hash count: u32][bit count: u64][bits][crc32 of everything before: u32]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BloomFilter {
+    hash_count: u32,
+    bit_count: u64,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    // Sized to hold `capacity` keys at the given false positive rate
+    pub fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let capacity = capacity.max(MIN_CAPACITY) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bit_count = (-capacity * false_positive_rate.ln() / (ln2 * ln2)).ceil().max(8.0) as u64;
+        let hash_count = ((bit_count as f64 / capacity) * ln2).round().max(1.0) as u32;
+        Self { hash_count, bit_count, bits: vec![0; bit_count.div_ceil(8) as usize] }
+    }
+
+    pub fn from_keys<'a>(keys: impl ExactSizeIterator<Item = &'a Vec<u8>>, false_positive_rate: f64) -> Self {
+        // Leaves room for the keys written until the next flush rebuilds the filter
+        let mut filter = BloomFilter::new(keys.len() * 2, false_positive_rate);
+        for key in keys {
+            filter.insert(key);
+        }
+        filter
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in BloomFilter::bit_positions(key, self.hash_count, self.bit_count) {
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        BloomFilter::bit_positions(key, self.hash_count, self.bit_count)
+            .all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+
+    // Double hashing over two FNV-1a hashes. The filter is persisted, so the hash must not
+    // change between builds the way std's DefaultHasher may.
+    fn bit_positions(key: &[u8], hash_count: u32, bit_count: u64) -> impl Iterator<Item = u64> {
+        let first = fnv1a(key, 0xcbf2_9ce4_8422_2325);
+        let second = fnv1a(key, 0x8422_2325_cbf2_9ce4) | 1;
+        (0..hash_count as u64).map(move |i| first.wrapping_add(i.wrapping_mul(second)) % bit_count)
+    }
+
+    // Bytes of the bit array
+    pub fn memory_size(&self) -> usize {
+        self.bits.len()
+    }
+
+    // Whether the filter still meets the false positive rate for this many keys
+    pub fn fits(&self, key_count: usize, false_positive_rate: f64) -> bool {
+        let expected = BloomFilter::new(key_count, false_positive_rate);
+        self.bit_count >= expected.bit_count && self.hash_count == expected.hash_count
+    }
+
+    pub fn encode(&self, block_size: usize) -> Vec<u8> {
+        let mut buffer = FileHeader::new(BLOOM_MAGIC, block_size).encode().to_vec();
+        buffer.extend_from_slice(&self.hash_count.to_le_bytes());
+        buffer.extend_from_slice(&self.bit_count.to_le_bytes());
+        buffer.extend_from_slice(&self.bits);
+        let checksum = crc32fast::hash(&buffer);
+        buffer.extend_from_slice(&checksum.to_le_bytes());
+        buffer
+    }
+
+    pub fn decode(buffer: &[u8]) -> Result<Self> {
+        FileHeader::decode(buffer, BLOOM_MAGIC)?;
+        let invalid = || Error::InvalidFormat("corrupt bloom filter".to_string());
+        let (body, checksum) = buffer.split_at_checked(buffer.len().checked_sub(4).ok_or_else(invalid)?)
+            .ok_or_else(invalid)?;
+        if crc32fast::hash(body) != u32::from_le_bytes(checksum.try_into()?) {
+            return Err(invalid());
+        }
+        let parameters = body.get(HEADER_SIZE..HEADER_SIZE + PARAMETERS_SIZE).ok_or_else(invalid)?;
+        let hash_count = u32::from_le_bytes(parameters[0..4].try_into()?);
+        let bit_count = u64::from_le_bytes(parameters[4..12].try_into()?);
+        let bits = body[HEADER_SIZE + PARAMETERS_SIZE..].to_vec();
+        if hash_count == 0 || bit_count == 0 || bits.len() as u64 != bit_count.div_ceil(8) {
+            return Err(invalid());
+        }
+        Ok(Self { hash_count, bit_count, bits })
+    }
+
+    // None if the file is missing or unreadable, the filter is then rebuilt from the map
+    pub fn load(backend: &dyn StorageBackend, path: &Path) -> Option<Self> {
+        BloomFilter::decode(&backend.read(path).ok()?).ok()
+    }
+
+    // Written to a temporary file and renamed into place, like the map
+    pub fn save(&self, backend: &dyn StorageBackend, path: &Path, block_size: usize) -> Result<()> {
+        let tmp_path = path.with_extension("db.tmp");
+        let file = backend.open(&tmp_path, OpenMode::Truncate)?;
+        file.write_at(&self.encode(block_size), 0)?;
+        file.sync()?;
+        backend.rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+fn fnv1a(bytes: &[u8], basis: u64) -> u64 {
+    bytes.iter().fold(basis, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::btree::DEFAULT_BLOCK_SIZE;
+
+    #[test]
+    fn test_bloom_filter() -> Result<()> {
+        let keys: Vec<Vec<u8>> = (0..2000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let filter = BloomFilter::from_keys(keys.iter(), 0.01);
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+        let false_positives = (2000..12000u32).filter(|i| filter.may_contain(&i.to_be_bytes())).count();
+        assert!(false_positives < 200, "{false_positives} false positives");
+
+        let decoded = BloomFilter::decode(&filter.encode(DEFAULT_BLOCK_SIZE))?;
+        assert_eq!(decoded, filter);
+        let mut corrupt = filter.encode(DEFAULT_BLOCK_SIZE);
+        corrupt[HEADER_SIZE + PARAMETERS_SIZE] ^= 1;
+        assert!(BloomFilter::decode(&corrupt).is_err());
+        assert!(filter.fits(2000, 0.01));
+        assert!(!filter.fits(100_000, 0.01));
+        Ok(())
+    }
+}
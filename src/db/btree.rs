@@ -0,0 +1,290 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::db::backend::{OpenMode, StorageBackend, StorageFile};
+use crate::db::cache::BlockCache;
+use crate::error::{Error, Result};
+
+pub(crate) const DEFAULT_BLOCK_SIZE: usize = 4096;
+// Bounds of DbOptions::block_size, the used bytes of a node are counted in a u16
+pub(crate) const MIN_BLOCK_SIZE: usize = 512;
+pub(crate) const MAX_BLOCK_SIZE: usize = 32 * 1024;
+pub(crate) const NODE_HEADER_SIZE: usize = 4;
+pub(crate) const ENTRY_LENGTH_SIZE: usize = 4;
+const ENTRY_FLAG_SHIFT: u32 = 24;
+
+// A node is one block on disk laid out as
+// [entry count: u16][used bytes: u16][len: u32][bytes]...[len: u32][bytes][zero padding]
+// Entries are addressed by their byte offset within the block. The high byte of an entry length
+// holds a flag, the codec the value log compressed the entry with.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Node {
+    block: u64,
+    data: Vec<u8>,
+}
+
+impl Node {
+    pub fn new(block: u64, block_size: usize) -> Self {
+        let mut data = vec![0; block_size];
+        data[2..4].copy_from_slice(&(NODE_HEADER_SIZE as u16).to_le_bytes());
+        Self { block, data }
+    }
+
+    pub fn from_bytes(block: u64, data: Vec<u8>) -> Result<Self> {
+        if !valid_block_size(data.len()) {
+            return Err(Error::Custom(format!("block {block} has invalid size {}", data.len())));
+        }
+        let node = Self { block, data };
+        if node.used() < NODE_HEADER_SIZE || node.used() > node.data.len() {
+            return Err(Error::Custom(format!("block {block} has corrupt header")));
+        }
+        Ok(node)
+    }
+
+    pub fn block(&self) -> u64 {
+        self.block
+    }
+
+    pub fn entry_count(&self) -> usize {
+        u16::from_le_bytes([self.data[0], self.data[1]]) as usize
+    }
+
+    fn used(&self) -> usize {
+        u16::from_le_bytes([self.data[2], self.data[3]]) as usize
+    }
+
+    pub fn free_space(&self) -> usize {
+        self.data.len() - self.used()
+    }
+
+    // Largest entry that fits into an empty node of block_size bytes
+    pub fn max_entry_size(block_size: usize) -> usize {
+        block_size - NODE_HEADER_SIZE - ENTRY_LENGTH_SIZE
+    }
+
+    pub fn fits(&self, entry_len: usize) -> bool {
+        ENTRY_LENGTH_SIZE + entry_len <= self.free_space()
+    }
+
+    // Appends an entry with its flag and returns its pointer, or None if the node is full
+    pub fn push(&mut self, entry: &[u8], flag: u8) -> Option<u64> {
+        if !self.fits(entry.len()) {
+            return None;
+        }
+        let pointer = self.used();
+        let start = pointer + ENTRY_LENGTH_SIZE;
+        let end = start + entry.len();
+        let length = (flag as u32) << ENTRY_FLAG_SHIFT | entry.len() as u32;
+        self.data[pointer..start].copy_from_slice(&length.to_le_bytes());
+        self.data[start..end].copy_from_slice(entry);
+        let count = self.entry_count() as u16 + 1;
+        self.data[0..2].copy_from_slice(&count.to_le_bytes());
+        self.data[2..4].copy_from_slice(&(end as u16).to_le_bytes());
+        Some(pointer as u64)
+    }
+
+    // Flag and bytes of the entry at pointer
+    pub fn read(&self, pointer: u64) -> Result<(u8, &[u8])> {
+        let (flag, range) = self.entry_range(pointer)?;
+        Ok((flag, &self.data[range]))
+    }
+
+    // Flag of the entry at pointer and where its bytes are within the block
+    pub fn entry_range(&self, pointer: u64) -> Result<(u8, Range<usize>)> {
+        let pointer = pointer as usize;
+        if pointer < NODE_HEADER_SIZE || pointer + ENTRY_LENGTH_SIZE > self.used() {
+            return Err(Error::Custom(format!("invalid pointer {pointer} in block {}", self.block)));
+        }
+        let start = pointer + ENTRY_LENGTH_SIZE;
+        let length = u32::from_le_bytes(self.data[pointer..start].try_into()?);
+        let flag = (length >> ENTRY_FLAG_SHIFT) as u8;
+        let length = (length & ((1 << ENTRY_FLAG_SHIFT) - 1)) as usize;
+        if start + length > self.used() {
+            return Err(Error::Custom(format!("entry at {pointer} overruns block {}", self.block)));
+        }
+        Ok((flag, start..start + length))
+    }
+
+    // Flag and bytes of the entry the node starts with
+    pub fn first_entry(&self) -> Result<(u8, &[u8])> {
+        self.read(NODE_HEADER_SIZE as u64)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+pub(crate) fn valid_block_size(block_size: usize) -> bool {
+    block_size.is_power_of_two() && (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+}
+
+// Allocates, reads and writes fixed size blocks of a single file
+pub(crate) struct Pager {
+    file: Arc<dyn StorageFile>,
+    path: PathBuf,
+    block_size: usize,
+    block_count: u64,
+    // Identifies this file's blocks in the shared cache
+    id: u64,
+    cache: Arc<BlockCache>,
+}
+
+impl Pager {
+    pub fn open(
+        backend: &dyn StorageBackend,
+        path: &Path,
+        block_size: usize,
+        read_only: bool,
+        direct_io: bool,
+        cache: Arc<BlockCache>,
+    ) -> Result<Self> {
+        let mode = if read_only { OpenMode::Read } else { OpenMode::Create };
+        let file = match direct_io {
+            true => backend.open_direct(path, mode)?,
+            false => backend.open(path, mode)?,
+        };
+        let block_count = file.len()? / block_size as u64;
+        Ok(Self { file, path: path.to_path_buf(), block_size, block_count, id: BlockCache::next_file_id(), cache })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    // Reserves a new block at the end of the file and returns an empty node for it
+    pub fn allocate(&mut self) -> Result<Node> {
+        let node = Node::new(self.block_count, self.block_size);
+        self.block_count += 1;
+        self.write_node(&node)?;
+        Ok(node)
+    }
+
+    pub fn read_node(&self, block: u64) -> Result<Node> {
+        Ok(Arc::unwrap_or_clone(self.read_shared_node(block)?))
+    }
+
+    // The node as the cache holds it, without copying the block
+    pub fn read_shared_node(&self, block: u64) -> Result<Arc<Node>> {
+        if block >= self.block_count {
+            return Err(Error::Custom(format!("block {block} is out of bounds")));
+        }
+        if let Some(node) = self.cache.get(self.id, block) {
+            return Ok(node);
+        }
+        let mut data = vec![0; self.block_size];
+        self.file.read_at(&mut data, block * self.block_size as u64)?;
+        let node = Arc::new(Node::from_bytes(block, data)?);
+        self.cache.insert(self.id, Arc::clone(&node));
+        Ok(node)
+    }
+
+    // Reads the given blocks, which must be sorted and unique. Blocks missing from the
+    // cache are read with one positional read per run of adjacent blocks.
+    pub fn read_nodes(&self, blocks: &[u64]) -> Result<Vec<Node>> {
+        if let Some(block) = blocks.iter().find(|block| **block >= self.block_count) {
+            return Err(Error::Custom(format!("block {block} is out of bounds")));
+        }
+        let mut nodes: Vec<Option<Arc<Node>>> = blocks.iter().map(|block| self.cache.get(self.id, *block)).collect();
+        let mut start = 0;
+        while start < blocks.len() {
+            if nodes[start].is_some() {
+                start += 1;
+                continue;
+            }
+            let mut end = start + 1;
+            while end < blocks.len() && nodes[end].is_none() && blocks[end] == blocks[end - 1] + 1 {
+                end += 1;
+            }
+            let mut data = vec![0; (end - start) * self.block_size];
+            self.file.read_at(&mut data, blocks[start] * self.block_size as u64)?;
+            for (i, bytes) in data.chunks_exact(self.block_size).enumerate() {
+                let node = Arc::new(Node::from_bytes(blocks[start + i], bytes.to_vec())?);
+                self.cache.insert(self.id, Arc::clone(&node));
+                nodes[start + i] = Some(node);
+            }
+            start = end;
+        }
+        nodes.into_iter()
+            .map(|node| node.map(Arc::unwrap_or_clone).ok_or_else(|| "block was not read".into()))
+            .collect()
+    }
+
+    pub fn write_node(&mut self, node: &Node) -> Result<()> {
+        self.file.write_at(node.as_bytes(), node.block() * self.block_size as u64)?;
+        // Write-through, so the cache never holds an outdated copy of a block
+        self.cache.insert(self.id, Arc::new(node.clone()));
+        Ok(())
+    }
+
+    // Shared handle to the file for syncing it from another thread
+    pub fn file_handle(&self) -> Arc<dyn StorageFile> {
+        Arc::clone(&self.file)
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::backend::FileSystem;
+    use crate::db::temp::TempDir;
+
+    #[test]
+    fn test_node_push_read() -> Result<()> {
+        let mut node = Node::new(0, DEFAULT_BLOCK_SIZE);
+        let p1 = node.push(b"abc", 0).unwrap();
+        let p2 = node.push(b"", 2).unwrap();
+        assert_eq!(node.read(p1)?, (0, &b"abc"[..]));
+        assert_eq!(node.read(p2)?, (2, &b""[..]));
+        assert_eq!(node.entry_count(), 2);
+
+        let decoded = Node::from_bytes(0, node.as_bytes().to_vec())?;
+        assert_eq!(decoded, node);
+        assert!(node.read(1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_node_full() {
+        let mut node = Node::new(0, DEFAULT_BLOCK_SIZE);
+        let entry = vec![7; Node::max_entry_size(DEFAULT_BLOCK_SIZE)];
+        assert!(node.push(&entry, 0).is_some());
+        assert_eq!(node.free_space(), 0);
+        assert!(node.push(b"x", 0).is_none());
+    }
+
+    #[test]
+    fn test_pager_roundtrip() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("pager.db");
+        let cache = Arc::new(BlockCache::new(16));
+        let mut pager = Pager::open(&FileSystem, &path, 1024, false, false, Arc::clone(&cache))?;
+        let mut first = pager.allocate()?;
+        let second = pager.allocate()?;
+        let pointer = first.push(b"value", 0).unwrap();
+        pager.write_node(&first)?;
+        pager.sync()?;
+
+        let pager = Pager::open(&FileSystem, &path, 1024, false, false, Arc::new(BlockCache::new(0)))?;
+        assert_eq!(std::fs::metadata(&path)?.len(), 2048);
+        assert_eq!(pager.block_count(), 2);
+        assert_eq!(pager.read_node(first.block())?.read(pointer)?.1, b"value");
+        assert_eq!(pager.read_node(second.block())?.entry_count(), 0);
+        assert!(pager.read_node(2).is_err());
+        Ok(())
+    }
+}
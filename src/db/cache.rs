@@ -0,0 +1,150 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::db::btree::Node;
+
+/// Hit and miss counts of the block cache since the database was opened.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Share of block reads served from the cache, 0 before the first read.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            reads => self.hits as f64 / reads as f64,
+        }
+    }
+}
+
+// Cached blocks are keyed by (file id, block number), so every pager can share one cache
+type BlockKey = (u64, u64);
+
+// Least recently used cache of blocks, shared by all pagers of a database
+pub(crate) struct BlockCache {
+    capacity: usize,
+    state: Mutex<LruState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct LruState {
+    // Block and the tick of its last use
+    entries: HashMap<BlockKey, (Arc<Node>, u64)>,
+    // Keys by the tick of their last use, the first one is evicted next
+    recency: BTreeMap<u64, BlockKey>,
+    tick: u64,
+    // Bytes of the cached blocks
+    size: usize,
+}
+
+impl LruState {
+    fn touch(&mut self, key: BlockKey) -> u64 {
+        self.tick += 1;
+        self.recency.insert(self.tick, key);
+        self.tick
+    }
+}
+
+static NEXT_FILE_ID: AtomicU64 = AtomicU64::new(0);
+
+impl BlockCache {
+    // Holds up to `capacity` blocks, a capacity of 0 disables caching
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(LruState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // Unique id for every opened file, ids are never reused so stale blocks of a
+    // replaced file are simply never read again and age out
+    pub fn next_file_id() -> u64 {
+        NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn get(&self, file: u64, block: u64) -> Option<Arc<Node>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let Some((_, last_used)) = state.entries.get(&(file, block)) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let last_used = *last_used;
+        state.recency.remove(&last_used);
+        let tick = state.touch((file, block));
+        let (node, last_used) = state.entries.get_mut(&(file, block))?;
+        *last_used = tick;
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(Arc::clone(node))
+    }
+
+    // Adds or replaces a block, evicting the least recently used one when full
+    pub fn insert(&self, file: u64, node: Arc<Node>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = (file, node.block());
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((replaced, last_used)) = state.entries.remove(&key) {
+            state.recency.remove(&last_used);
+            state.size -= replaced.as_bytes().len();
+        }
+        while state.entries.len() >= self.capacity {
+            let Some((_, evicted)) = state.recency.pop_first() else { break };
+            if let Some((evicted, _)) = state.entries.remove(&evicted) {
+                state.size -= evicted.as_bytes().len();
+            }
+        }
+        let tick = state.touch(key);
+        state.size += node.as_bytes().len();
+        state.entries.insert(key, (node, tick));
+    }
+
+    // Bytes of the blocks held by the cache
+    pub fn memory_size(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).size
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats { hits: self.hits.load(Ordering::Relaxed), misses: self.misses.load(Ordering::Relaxed) }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::btree::DEFAULT_BLOCK_SIZE;
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = BlockCache::new(2);
+        let node = |block| Arc::new(Node::new(block, DEFAULT_BLOCK_SIZE));
+        let (a, b, c) = (node(0), node(1), node(2));
+        cache.insert(7, Arc::clone(&a));
+        cache.insert(7, Arc::clone(&b));
+        assert_eq!(cache.get(7, 0), Some(a.clone()));
+        // Block 1 is now the least recently used one
+        cache.insert(7, Arc::clone(&c));
+        assert_eq!(cache.get(7, 1), None);
+        assert_eq!(cache.get(7, 0), Some(a));
+        assert_eq!(cache.get(7, 2), Some(c));
+        assert_eq!(cache.get(8, 2), None);
+        assert_eq!(cache.stats(), CacheStats { hits: 3, misses: 2 });
+        assert_eq!(cache.memory_size(), 2 * DEFAULT_BLOCK_SIZE);
+
+        let disabled = BlockCache::new(0);
+        disabled.insert(7, b);
+        assert_eq!(disabled.get(7, 1), None);
+        assert_eq!(disabled.stats(), CacheStats::default());
+    }
+}
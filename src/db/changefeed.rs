@@ -0,0 +1,126 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// A committed change of a key, received from [`Db::subscribe`](crate::Db::subscribe).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub key: Vec<u8>,
+    /// Value before the write, `None` if the key was absent or expired.
+    pub old_value: Option<Vec<u8>>,
+    /// Value after the write, `None` if the key was deleted.
+    pub new_value: Option<Vec<u8>>,
+    /// Sequence number of the write, see [`Db::last_sequence`](crate::Db::last_sequence).
+    /// The changes of a batch share one.
+    pub sequence: u64,
+}
+
+struct Subscriber {
+    column_family: Option<String>,
+    prefix: Vec<u8>,
+    sender: Sender<ChangeEvent>,
+}
+
+// Subscribers of every keyspace. One whose receiver was dropped is removed with the next change it would get.
+#[derive(Default)]
+pub(crate) struct Changefeed {
+    subscribers: Vec<Subscriber>,
+}
+
+impl Changefeed {
+    pub fn subscribe(&mut self, column_family: Option<&str>, prefix: &[u8]) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(Subscriber {
+            column_family: column_family.map(str::to_string),
+            prefix: prefix.to_vec(),
+            sender,
+        });
+        receiver
+    }
+
+    // Writes only read the values they replace for keys that someone subscribed to
+    pub fn is_watched(&self, column_family: Option<&str>, key: &[u8]) -> bool {
+        self.subscribers.iter().any(|subscriber| subscriber.wants(column_family, key))
+    }
+
+    pub fn publish(&mut self, column_family: Option<&str>, events: &[ChangeEvent]) {
+        self.subscribers.retain(|subscriber| {
+            events.iter()
+                .filter(|event| subscriber.wants(column_family, &event.key))
+                .all(|event| subscriber.sender.send(event.clone()).is_ok())
+        });
+    }
+}
+
+impl Subscriber {
+    fn wants(&self, column_family: Option<&str>, key: &[u8]) -> bool {
+        self.column_family.as_deref() == column_family && key.starts_with(&self.prefix)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::batch::WriteBatch;
+    use crate::db::options::DbOptions;
+    use crate::db::temp::TempDir;
+    use crate::error::Result;
+
+    fn change(key: &[u8], old_value: Option<&[u8]>, new_value: Option<&[u8]>, sequence: u64) -> ChangeEvent {
+        ChangeEvent { key: key.to_vec(), old_value: old_value.map(<[u8]>::to_vec), new_value: new_value.map(<[u8]>::to_vec), sequence }
+    }
+
+    #[test]
+    fn test_subscribe() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = DbOptions::new().path(&dir)
+            .merge_operator(|_: &[u8], current: Option<&[u8]>, operand: &[u8]| {
+                [current.unwrap_or_default(), operand].concat()
+            })
+            .open()?;
+        db.put(b"user:ada", b"before")?;
+        let users = db.subscribe(b"user:");
+        let everything = db.subscribe(b"");
+        db.put(b"user:ada", b"admin")?;
+        db.put(b"item:1", b"book")?;
+        db.write(WriteBatch::new().put(b"user:bob", b"guest").put(b"user:bob", b"owner").delete(b"user:ada").clone())?;
+        db.merge(b"user:bob", b"!")?;
+        db.delete(b"user:eve")?;
+        assert_eq!(users.try_iter().collect::<Vec<_>>(), vec![
+            change(b"user:ada", Some(b"before"), Some(b"admin"), 2),
+            change(b"user:bob", None, Some(b"guest"), 4),
+            change(b"user:bob", Some(b"guest"), Some(b"owner"), 4),
+            change(b"user:ada", Some(b"admin"), None, 4),
+            change(b"user:bob", Some(b"owner"), Some(b"owner!"), 5),
+            change(b"user:eve", None, None, 6),
+        ]);
+        let all: Vec<ChangeEvent> = everything.try_iter().collect();
+        assert_eq!(all.len(), 7);
+        assert_eq!(all[1], change(b"item:1", None, Some(b"book"), 3));
+
+        // Column families have subscribers of their own
+        let items = db.cf("items")?;
+        let counters = items.subscribe(b"count");
+        items.increment(b"count", 2)?;
+        db.put(b"user:ada", b"again")?;
+        assert_eq!(counters.try_iter().collect::<Vec<_>>(), vec![
+            change(b"count", None, Some(&2u64.to_le_bytes()), 7),
+        ]);
+        assert_eq!(everything.try_iter().count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dropped_receivers_unsubscribe() {
+        let mut changefeed = Changefeed::default();
+        let kept = changefeed.subscribe(None, b"a");
+        drop(changefeed.subscribe(None, b"a"));
+        let unchanged = changefeed.subscribe(None, b"b");
+        drop(unchanged);
+        changefeed.publish(None, &[change(b"a", None, Some(b"1"), 1)]);
+        // Only removed once a change for it comes along
+        assert_eq!(changefeed.subscribers.len(), 2);
+        assert!(changefeed.is_watched(None, b"b"));
+        assert!(!changefeed.is_watched(Some("cf"), b"a"));
+        assert_eq!(kept.try_recv().ok(), Some(change(b"a", None, Some(b"1"), 1)));
+    }
+}
@@ -0,0 +1,24 @@
+use std::path::PathBuf;
+
+/// What [`Db::check`](crate::Db::check) found, and whether it repaired it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// Keys whose value was read back, in every keyspace.
+    pub keys_checked: usize,
+    /// Keys pointing outside the value file or at a record that can't be read, with the name of
+    /// their column family, None for the default keyspace.
+    pub broken_keys: Vec<(Option<String>, Vec<u8>)>,
+    /// Maps, map deltas and WAL segments whose checksum or records no longer check out.
+    pub corrupt_files: Vec<PathBuf>,
+    /// Bytes of the value file that no key points at, reclaimed by a compaction.
+    pub orphaned_bytes: u64,
+    /// Whether the broken keys were deleted and the database rebuilt, see [`Db::vacuum`](crate::Db::vacuum).
+    pub repaired: bool,
+}
+
+impl CheckReport {
+    /// Whether no keys are broken and no files are corrupt. Orphaned data is left by every overwrite.
+    pub fn is_clean(&self) -> bool {
+        self.broken_keys.is_empty() && self.corrupt_files.is_empty()
+    }
+}
@@ -0,0 +1,46 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the time that keys written with a TTL expire by, see [`DbOptions::clock`](crate::DbOptions::clock).
+pub trait Clock: Debug + Send + Sync {
+    /// Milliseconds since the Unix epoch.
+    fn now_millis(&self) -> u64;
+}
+
+/// The system time, the default clock.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+    }
+}
+
+/// Clock that only moves when told to, for tests and simulations that need reproducible expiries.
+///
+/// Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    millis: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    /// A clock showing `millis` milliseconds since the Unix epoch.
+    pub fn new(millis: u64) -> Self {
+        ManualClock { millis: Arc::new(AtomicU64::new(millis)) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let by = by.as_millis().try_into().unwrap_or(u64::MAX);
+        let _ = self.millis.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |millis| Some(millis.saturating_add(by)));
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_millis(&self) -> u64 {
+        self.millis.load(Ordering::SeqCst)
+    }
+}
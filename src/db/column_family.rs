@@ -0,0 +1,180 @@
+use std::iter::Rev;
+use std::ops::RangeBounds;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+use crate::db::batch::WriteBatch;
+use crate::db::changefeed::ChangeEvent;
+use crate::db::cursor::Cursor;
+use crate::db::database::Db;
+use crate::db::iter::DbIter;
+use crate::db::sync::WriteOptions;
+use crate::error::Result;
+
+/// Handle to a named keyspace returned by [`Db::cf`].
+///
+/// Every column family has its own keys and write-ahead log, values share the data file
+/// of the database and are flushed and compacted with it.
+#[derive(Clone)]
+pub struct ColumnFamily {
+    db: Db,
+    name: String,
+}
+
+impl ColumnFamily {
+    pub(crate) fn new(db: Db, name: &str) -> Self {
+        Self { db, name: name.to_string() }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.db.index_mut().insert(Some(&self.name), key, value)
+    }
+
+    /// Stores `value` under `key` and returns the value it replaced, see [`Db::insert`].
+    pub fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.index_mut().replace(Some(&self.name), key, value)
+    }
+
+    /// Stores `value` under `key`, synced if `options` asks for it, see [`Db::put_with`].
+    pub fn put_with(&self, key: &[u8], value: &[u8], options: WriteOptions) -> Result<()> {
+        let name = Some(self.name.as_str());
+        self.db.index_mut().with_sync(name, options.sync, |index| index.insert(name, key, value))
+    }
+
+    /// Stores `value` under `key` until `ttl` has passed, see [`Db::put_with_ttl`].
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.db.index_mut().insert_with_ttl(Some(&self.name), key, value, ttl)
+    }
+
+    /// Merges `operand` into the value of `key`, see [`Db::merge`].
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.db.index_mut().merge(Some(&self.name), key, operand)
+    }
+
+    /// Atomically adds `delta` to the counter stored under `key`, see [`Db::increment`].
+    pub fn increment(&self, key: &[u8], delta: u64) -> Result<u64> {
+        self.db.index_mut().increment(Some(&self.name), key, delta)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.lookup_index().get(Some(&self.name), key)
+    }
+
+    /// Whether `key` exists, see [`Db::contains_key`].
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.db.lookup_index().contains_key(Some(&self.name), key)
+    }
+
+    /// Reads `key` as it will be once `batch` is written to this column family, see
+    /// [`Db::get_with_batch`].
+    pub fn get_with_batch(&self, batch: &WriteBatch, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match batch.lookup(key) {
+            Some(value) => Ok(value.map(<[u8]>::to_vec)),
+            None => self.get(key),
+        }
+    }
+
+    /// Reads the values of all `keys` at once, see [`Db::multi_get`].
+    pub fn multi_get<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.db.lookup_index().multi_get(Some(&self.name), keys)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.db.index_mut().remove(Some(&self.name), key)
+    }
+
+    /// Number of keys in the column family, see [`Db::len`].
+    pub fn len(&self) -> usize {
+        self.db.index().len(Some(&self.name))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Approximate number of keys within `range`, see [`Db::estimate_range_count`].
+    pub fn estimate_range_count<K, R>(&self, range: R) -> usize
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        self.db.index().estimate_range_count(Some(&self.name), range)
+    }
+
+    /// Approximate bytes the values within `range` take, see [`Db::approximate_size`].
+    pub fn approximate_size<K, R>(&self, range: R) -> Result<u64>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        self.db.index().approximate_size(Some(&self.name), range)
+    }
+
+    /// Applies all operations of the batch atomically, see [`Db::write`].
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.db.index_mut().write_batch(Some(&self.name), batch)
+    }
+
+    /// Subscribes to the changes of the keys starting with `prefix`, see [`Db::subscribe`].
+    pub fn subscribe(&self, prefix: &[u8]) -> Receiver<ChangeEvent> {
+        self.db.index_mut().subscribe(Some(&self.name), prefix)
+    }
+
+    /// Iterates over the key/value pairs whose key lies within `range`, in ascending key order.
+    pub fn range<K, R>(&self, range: R) -> DbIter
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let index = self.db.index();
+        DbIter::new(self.db.clone(), index.range_locations(Some(&self.name), range), index.pin())
+    }
+
+    /// Splits `range` into `shards` iterators over disjoint sub-ranges, see [`Db::par_scan`].
+    pub fn par_scan<K, R>(&self, range: R, shards: usize) -> Vec<DbIter>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let index = self.db.index();
+        match index.partition_locations(Some(&self.name), range, shards) {
+            Ok(partitions) => partitions.into_iter()
+                .map(|entries| DbIter::new(self.db.clone(), Ok(entries), index.pin()))
+                .collect(),
+            Err(error) => vec![DbIter::new(self.db.clone(), Err(error), index.pin())],
+        }
+    }
+
+    /// Iterates over the key/value pairs whose key starts with `prefix`, in ascending key order.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> DbIter {
+        let index = self.db.index();
+        DbIter::new(self.db.clone(), index.prefix_locations(Some(&self.name), prefix), index.pin())
+    }
+
+    /// Creates a cursor over the keys of this column family, see [`Db::cursor`].
+    pub fn cursor(&self) -> Cursor {
+        Cursor::new(self.db.clone(), Some(&self.name))
+    }
+
+    /// Iterates over all key/value pairs in ascending key order.
+    pub fn iter(&self) -> DbIter {
+        self.range::<&[u8], _>(..)
+    }
+
+    /// Iterates over the key/value pairs whose key lies within `range`, in descending key order.
+    pub fn range_rev<K, R>(&self, range: R) -> Rev<DbIter>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        self.range(range).rev()
+    }
+
+    /// Iterates over all key/value pairs in descending key order.
+    pub fn iter_rev(&self) -> Rev<DbIter> {
+        self.iter().rev()
+    }
+}
@@ -0,0 +1,102 @@
+use std::borrow::Cow;
+use crate::error::{Error, Result};
+
+/// Codec that values are compressed with in the data file, see
+/// [`DbOptions::compression`](crate::DbOptions::compression).
+///
+/// Every value records the codec it was written with, so values stay readable when the codec
+/// changes, as long as the build has the feature of the codec enabled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Values are stored as they are.
+    #[default]
+    None,
+    /// LZ4, fast with a moderate ratio. Needs the `lz4` feature.
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Zstandard at the given level, slower with a better ratio. Needs the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+// Codec of a stored value, kept in the flag byte of its entry in data.db.
+// Entries written before compression existed have 0 there.
+pub(crate) const RAW: u8 = 0;
+const LZ4: u8 = 1;
+const ZSTD: u8 = 2;
+
+impl Compression {
+    // Bytes to store for value with the flag of their codec. Values shorter than threshold and
+    // ones that compression doesn't make smaller are stored as they are.
+    pub(crate) fn compress<'a>(&self, value: &'a [u8], threshold: usize) -> Result<(u8, Cow<'a, [u8]>)> {
+        if value.len() < threshold {
+            return Ok((RAW, Cow::Borrowed(value)));
+        }
+        let compressed: Option<(u8, Vec<u8>)> = match self {
+            Compression::None => None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some((LZ4, lz4_flex::compress_prepend_size(value))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => Some((ZSTD, zstd::bulk::compress(value, *level)?)),
+        };
+        match compressed {
+            Some((flag, compressed)) if compressed.len() < value.len() => Ok((flag, Cow::Owned(compressed))),
+            _ => Ok((RAW, Cow::Borrowed(value))),
+        }
+    }
+
+    pub(crate) fn decompress(flag: u8, bytes: &[u8]) -> Result<Vec<u8>> {
+        match flag {
+            RAW => Ok(bytes.to_vec()),
+            #[cfg(feature = "lz4")]
+            LZ4 => lz4_flex::decompress_size_prepended(bytes)
+                .map_err(|e| Error::InvalidFormat(format!("corrupt lz4 value: {e}"))),
+            #[cfg(feature = "zstd")]
+            ZSTD => zstd::decode_all(bytes)
+                .map_err(|e| Error::InvalidFormat(format!("corrupt zstd value: {e}"))),
+            #[cfg(not(feature = "lz4"))]
+            LZ4 => Err(Error::Custom("value is compressed with lz4, which needs the lz4 feature".to_string())),
+            #[cfg(not(feature = "zstd"))]
+            ZSTD => Err(Error::Custom("value is compressed with zstd, which needs the zstd feature".to_string())),
+            flag => Err(Error::InvalidFormat(format!("value compressed with unknown codec {flag}"))),
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) if !zstd::compression_level_range().contains(level) => {
+                Err(Error::Custom(format!("zstd compression level {level} is out of range")))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let repetitive = b"abcd".repeat(100);
+        let codecs = [
+            Compression::None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(3),
+        ];
+        for codec in codecs {
+            let (flag, stored) = codec.compress(&repetitive, 64)?;
+            assert_eq!(flag == RAW, codec == Compression::None);
+            assert_eq!(Compression::decompress(flag, &stored)?, repetitive);
+            // Too short to bother, or no smaller compressed
+            assert_eq!(codec.compress(b"abcd", 64)?.0, RAW);
+            assert_eq!(codec.compress(&[7, 1, 9], 0)?.0, RAW);
+        }
+        assert!(Compression::decompress(9, b"").is_err());
+        Ok(())
+    }
+}
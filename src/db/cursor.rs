@@ -0,0 +1,110 @@
+use std::ops::Bound;
+use crate::db::database::Db;
+use crate::db::lookup::EntryLocation;
+use crate::db::pin::Pin;
+use crate::error::{Error, Result};
+
+/// Movable position in the key order, returned by [`Db::cursor`].
+///
+/// A new cursor is not positioned, call [`seek`](Cursor::seek) or
+/// [`seek_to_first`](Cursor::seek_to_first) first. Every move sees the latest writes.
+/// The value of the current entry stays readable across a [`Db::compact`] until the cursor moves.
+/// A move that fails to read the keys leaves the cursor unpositioned, see [`status`](Cursor::status).
+pub struct Cursor {
+    db: Db,
+    column_family: Option<String>,
+    current: Option<(Vec<u8>, EntryLocation)>,
+    // Keeps the data file that current points into readable
+    pin: Option<Pin>,
+    // Failure of the last move, until status takes it
+    error: Option<Error>,
+}
+
+impl Cursor {
+    pub(crate) fn new(db: Db, column_family: Option<&str>) -> Self {
+        Self { db, column_family: column_family.map(str::to_string), current: None, pin: None, error: None }
+    }
+
+    /// Moves to the first key that is greater than or equal to `key`.
+    /// Returns whether the cursor points at an entry.
+    pub fn seek(&mut self, key: &[u8]) -> bool {
+        self.move_forward(Bound::Included(key.to_vec()))
+    }
+
+    /// Moves to the last key that is less than or equal to `key`.
+    pub fn seek_for_prev(&mut self, key: &[u8]) -> bool {
+        self.move_backward(Bound::Included(key.to_vec()))
+    }
+
+    pub fn seek_to_first(&mut self) -> bool {
+        self.move_forward(Bound::Unbounded)
+    }
+
+    pub fn seek_to_last(&mut self) -> bool {
+        self.move_backward(Bound::Unbounded)
+    }
+
+    /// Moves to the next greater key, an unpositioned cursor stays unpositioned.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> bool {
+        match self.current.take() {
+            Some((key, _)) => self.move_forward(Bound::Excluded(key)),
+            None => false,
+        }
+    }
+
+    /// Moves to the next smaller key, an unpositioned cursor stays unpositioned.
+    pub fn prev(&mut self) -> bool {
+        match self.current.take() {
+            Some((key, _)) => self.move_backward(Bound::Excluded(key)),
+            None => false,
+        }
+    }
+
+    /// Whether the cursor points at an entry.
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Returns the error of the last move that failed, once.
+    pub fn status(&mut self) -> Result<()> {
+        self.error.take().map_or(Ok(()), Err)
+    }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(key, _)| key.as_slice())
+    }
+
+    /// Value of the current entry as it was when the cursor moved there.
+    pub fn value(&self) -> Result<Option<Vec<u8>>> {
+        let (Some((_, location)), Some(pin)) = (&self.current, &self.pin) else { return Ok(None) };
+        self.db.index().read_pinned(pin, *location).map(Some)
+    }
+
+    fn move_forward(&mut self, start: Bound<Vec<u8>>) -> bool {
+        let index = self.db.index();
+        self.pin = Some(index.pin());
+        let current = index.seek_location(self.column_family.as_deref(), (start, Bound::Unbounded), false);
+        drop(index);
+        self.position(current)
+    }
+
+    fn move_backward(&mut self, end: Bound<Vec<u8>>) -> bool {
+        let index = self.db.index();
+        self.pin = Some(index.pin());
+        let current = index.seek_location(self.column_family.as_deref(), (Bound::Unbounded, end), true);
+        drop(index);
+        self.position(current)
+    }
+
+    fn position(&mut self, current: Result<Option<(Vec<u8>, EntryLocation)>>) -> bool {
+        match current {
+            Ok(current) => self.current = current,
+            Err(error) => {
+                self.current = None;
+                self.error = Some(error);
+            }
+        }
+        self.current.is_some()
+    }
+}
@@ -0,0 +1,1699 @@
+use std::iter::Rev;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+use crate::db::backend::{FileSystem, OpenMode};
+use crate::db::batch::WriteBatch;
+use crate::db::cache::CacheStats;
+use crate::db::changefeed::ChangeEvent;
+use crate::db::check::CheckReport;
+use crate::db::column_family::ColumnFamily;
+use crate::db::cursor::Cursor;
+use crate::db::files::{copy_bytes, copy_folder};
+use crate::db::index::{Index, IndexError};
+use crate::db::lookup::LOCK_FILE_NAME;
+use crate::db::iter::DbIter;
+use crate::db::recovery::RecoveryReport;
+use crate::db::snapshot::Snapshot;
+use crate::db::stats::{DiskUsage, Stats};
+use crate::db::storage::{ValueRef, DATA_FILE_NAME};
+use crate::db::sync::WriteOptions;
+use crate::db::temp::TempDir;
+use crate::db::trace::warning;
+use crate::db::transaction::{ReadTransaction, Transaction};
+use crate::db::options::DbOptions;
+use crate::error::{Error, Result};
+
+/// Embedded key-value store tying the lookup table and value storage together.
+///
+/// `Db` is a cheap, cloneable handle that can be shared across threads. Reads run
+/// concurrently, writes are serialized behind an internal lock.
+#[derive(Clone)]
+pub struct Db {
+    inner: Arc<RwLock<Index>>,
+}
+
+impl Db {
+    /// Opens the database stored in `path`, creating it if it does not exist.
+    ///
+    /// Use [`DbOptions`] to open with other settings.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        DbOptions::new().path(path).open()
+    }
+
+    /// Opens an empty database in a new [`TempDir`], which is removed once the last handle
+    /// to the database is dropped. Meant for tests, which can then run in parallel.
+    pub fn open_temp() -> Result<Self> {
+        let dir = TempDir::new()?;
+        let db = Db::open(dir.path())?;
+        db.index_mut().remove_on_close(dir);
+        Ok(db)
+    }
+
+    pub(crate) fn open_with(options: &DbOptions) -> Result<Self> {
+        let index = Index::open(options)?;
+        Ok(Self { inner: Arc::new(RwLock::new(index)) })
+    }
+
+    // A panic while holding the lock leaves the index usable, every mutation is logged before it is applied
+    // Low-memory handles load their maps the first time anything but a point lookup reads them.
+    // A load that fails is retried by the next read, until then these reads see only what point
+    // lookups find.
+    pub(crate) fn index(&self) -> RwLockReadGuard<'_, Index> {
+        let index = self.lookup_index();
+        if !index.is_lazy() {
+            return index;
+        }
+        drop(index);
+        let loaded = self.index_mut().load_maps();
+        if let Err(e) = loaded {
+            warning!(self.lookup_index().log_level(), error = %e; "failed to load the map of a low-memory handle");
+        }
+        self.lookup_index()
+    }
+
+    // Read access for point lookups, which low-memory handles answer without loading their maps
+    pub(crate) fn lookup_index(&self) -> RwLockReadGuard<'_, Index> {
+        self.inner.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn index_mut(&self) -> RwLockWriteGuard<'_, Index> {
+        self.inner.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.index_mut().insert(None, key, value)
+    }
+
+    /// Stores `value` under `key` and returns the value it replaced, like
+    /// [`HashMap::insert`](std::collections::HashMap::insert).
+    ///
+    /// The previous value is read under the same lock as the write, so no other write to `key`
+    /// can come in between.
+    pub fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.index_mut().replace(None, key, value)
+    }
+
+    /// Stores `value` under `key`, synced before it returns if `options` asks for it even when
+    /// the [`SyncPolicy`](crate::SyncPolicy) of the database would not sync it.
+    pub fn put_with(&self, key: &[u8], value: &[u8], options: WriteOptions) -> Result<()> {
+        self.index_mut().with_sync(None, options.sync, |index| index.insert(None, key, value))
+    }
+
+    /// Stores `value` under `key` until `ttl` has passed, after that the key reads as absent.
+    ///
+    /// Expired keys are removed from disk by the next [`flush`](Db::flush) or [`compact`](Db::compact).
+    /// A later [`put`](Db::put) of the key clears the expiry.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.index_mut().insert_with_ttl(None, key, value, ttl)
+    }
+
+    /// Combines the current value of `key` with `operand` using the function set with
+    /// [`DbOptions::merge_operator`] and stores the result atomically.
+    ///
+    /// Keeps the expiry time of a key written with [`put_with_ttl`](Db::put_with_ttl).
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.index_mut().merge(None, key, operand)
+    }
+
+    /// Atomically adds `delta` to the counter stored under `key` and returns the new count.
+    ///
+    /// Counters are stored as 8 byte little endian integers and start at 0 when the key is absent.
+    /// Fails if the current value is not a counter or the count would overflow.
+    pub fn increment(&self, key: &[u8], delta: u64) -> Result<u64> {
+        self.index_mut().increment(None, key, delta)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.lookup_index().get(None, key)
+    }
+
+    /// Reads `key` as it will be once `batch` is written, seeing its puts and deletes of the key
+    /// over the database.
+    pub fn get_with_batch(&self, batch: &WriteBatch, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match batch.lookup(key) {
+            Some(value) => Ok(value.map(<[u8]>::to_vec)),
+            None => self.get(key),
+        }
+    }
+
+    /// Whether `key` exists, answered from the lookup table without reading its value.
+    /// Expired keys do not exist.
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.lookup_index().contains_key(None, key)
+    }
+
+    /// Reads `key` like [`get`](Db::get) without copying the value out of the block cache when
+    /// it is stored uncompressed, see [`ValueRef`].
+    pub fn get_ref(&self, key: &[u8]) -> Result<Option<ValueRef<'_>>> {
+        self.lookup_index().get_ref(None, key)
+    }
+
+    /// Reads the values of all `keys` at once, in the order of `keys` with `None` for absent ones.
+    ///
+    /// Faster than separate [`get`](Db::get) calls, every block of the value file is read once.
+    pub fn multi_get<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.lookup_index().multi_get(None, keys)
+    }
+
+    /// Takes a snapshot that keeps seeing the current state while later writes proceed.
+    pub fn snapshot(&self) -> Snapshot {
+        self.index().snapshot()
+    }
+
+    /// Reads `key` as it was when `snapshot` was taken.
+    pub fn get_at(&self, snapshot: &Snapshot, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.index().get_at(None, key, snapshot)
+    }
+
+    /// Iterates over the key/value pairs whose key lies within `range`, in ascending key order.
+    pub fn range<K, R>(&self, range: R) -> DbIter
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let index = self.index();
+        DbIter::new(self.clone(), index.range_locations(None, range), index.pin())
+    }
+
+    /// Iterates over the key/value pairs whose key starts with `prefix`, in ascending key order.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> DbIter {
+        let index = self.index();
+        DbIter::new(self.clone(), index.prefix_locations(None, prefix), index.pin())
+    }
+
+    /// Splits `range` into `shards` disjoint sub-ranges of about the same number of keys and
+    /// returns an iterator over each, in ascending key order, so a large scan can be spread
+    /// over threads.
+    ///
+    /// All iterators see the entries as they were when `par_scan` was called, like a single
+    /// [`range`](Db::range) would. Some are empty when there are fewer keys than `shards`, and a
+    /// `shards` of 0 is taken as 1.
+    ///
+    /// ```no_run
+    /// use cendb::Db;
+    ///
+    /// let db = Db::open("data/my_db")?;
+    /// let counts: Vec<usize> = std::thread::scope(|scope| {
+    ///     let workers: Vec<_> = db.par_scan::<[u8], _>(.., 4).into_iter()
+    ///         .map(|shard| scope.spawn(move || shard.count()))
+    ///         .collect();
+    ///     workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+    /// });
+    /// # Ok::<(), cendb::Error>(())
+    /// ```
+    pub fn par_scan<K, R>(&self, range: R, shards: usize) -> Vec<DbIter>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let index = self.index();
+        match index.partition_locations(None, range, shards) {
+            Ok(partitions) => partitions.into_iter()
+                .map(|entries| DbIter::new(self.clone(), Ok(entries), index.pin()))
+                .collect(),
+            // A single iterator that returns the error
+            Err(error) => vec![DbIter::new(self.clone(), Err(error), index.pin())],
+        }
+    }
+
+    /// Iterates over all key/value pairs in ascending key order.
+    pub fn iter(&self) -> DbIter {
+        self.range::<&[u8], _>(..)
+    }
+
+    /// Iterates over the key/value pairs whose key lies within `range`, in descending key order.
+    pub fn range_rev<K, R>(&self, range: R) -> Rev<DbIter>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        self.range(range).rev()
+    }
+
+    /// Iterates over all key/value pairs in descending key order.
+    pub fn iter_rev(&self) -> Rev<DbIter> {
+        self.iter().rev()
+    }
+
+    /// Creates a cursor for seeking and stepping through the keys in either direction.
+    pub fn cursor(&self) -> Cursor {
+        Cursor::new(self.clone(), None)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.index_mut().remove(None, key)
+    }
+
+    /// Applies all operations of the batch atomically with a single WAL write.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.index_mut().write_batch(None, batch)
+    }
+
+    /// Applies all operations of the batch atomically like [`write`](Db::write), synced if
+    /// `options` asks for it, see [`put_with`](Db::put_with).
+    pub fn write_with(&self, batch: WriteBatch, options: WriteOptions) -> Result<()> {
+        self.index_mut().with_sync(None, options.sync, |index| index.write_batch(None, batch))
+    }
+
+    /// Subscribes to the changes of the keys starting with `prefix`, which are sent to the returned
+    /// receiver in commit order once they are logged. Dropping the receiver ends the subscription.
+    ///
+    /// Keys that expire are not reported, and the changes are lost if the process crashes before
+    /// they are received.
+    pub fn subscribe(&self, prefix: &[u8]) -> Receiver<ChangeEvent> {
+        self.index_mut().subscribe(None, prefix)
+    }
+
+    /// Starts a transaction whose operations are committed atomically.
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// Starts a read-only transaction that sees every keyspace as of the last write, see [`ReadTransaction`].
+    pub fn read_txn(&self) -> ReadTransaction {
+        ReadTransaction::new(self.clone())
+    }
+
+    /// Persists the in-memory lookup table and checkpoints the write-ahead log, the next write truncates it.
+    pub fn flush(&self) -> Result<()> {
+        self.index_mut().flush()
+    }
+
+    /// Returns the column family `name`, creating it if it does not exist yet.
+    ///
+    /// Names consist of up to 64 ASCII letters, digits, `_` or `-`.
+    pub fn cf(&self, name: &str) -> Result<ColumnFamily> {
+        if !self.index().has_column_family(name) {
+            self.index_mut().create_column_family(name)?;
+        }
+        Ok(ColumnFamily::new(self.clone(), name))
+    }
+
+    /// Names of all column families in ascending order.
+    pub fn column_families(&self) -> Vec<String> {
+        self.index().column_families()
+    }
+
+    /// Number of keys in the default keyspace, an exact count the lookup tables keep in memory.
+    pub fn len(&self) -> usize {
+        self.index().len(None)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Approximate number of keys within `range`, cheap enough to plan pagination with.
+    ///
+    /// Exact for ranges of up to a thousand keys, beyond that estimated from a sample of the keys
+    /// taken when the lookup table was last loaded or written in full. Keys that expired but were
+    /// not purged yet are counted.
+    pub fn estimate_range_count<K, R>(&self, range: R) -> usize
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        self.index().estimate_range_count(None, range)
+    }
+
+    /// Approximate bytes the values of the keys within `range` take in the value file, cheap
+    /// enough to split a keyspace into ranges of similar size for parallel scans.
+    ///
+    /// The sizes the index records for up to 256 values spread over the range, taken from the key
+    /// samples [`estimate_range_count`](Db::estimate_range_count) uses, are scaled up to the
+    /// estimated number of keys. No values are read. Overwritten values and other keyspaces do not
+    /// count, nor do values written before the index recorded their size until a compaction.
+    pub fn approximate_size<K, R>(&self, range: R) -> Result<u64>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        self.index().approximate_size(None, range)
+    }
+
+    /// Sequence number of the last write logged to the database, 0 if there was none.
+    ///
+    /// Every write, batch or not, gets the next number across all column families.
+    pub fn last_sequence(&self) -> u64 {
+        self.index().last_sequence()
+    }
+
+    /// What opening the database recovered from, such as replayed or discarded WAL records.
+    pub fn recovery_report(&self) -> RecoveryReport {
+        self.index().recovery_report()
+    }
+
+    /// Operation counters and file sizes since the database was opened.
+    pub fn stats(&self) -> Result<Stats> {
+        self.index().stats()
+    }
+
+    /// Bytes the value file and the index files of every keyspace take on disk, and how much
+    /// of the value file a [`compact`](Db::compact) would reclaim.
+    ///
+    /// Reads every block of the value file that holds a reachable value.
+    pub fn size_on_disk(&self) -> Result<DiskUsage> {
+        self.index().size_on_disk()
+    }
+
+    /// Hit and miss counts of the block cache, see [`DbOptions::block_cache_size`].
+    pub fn cache_stats(&self) -> CacheStats {
+        self.index().cache_stats()
+    }
+
+    /// Rewrites the value file with only the values that are still reachable,
+    /// reclaiming the space of overwritten and deleted values.
+    ///
+    /// Flushes first and blocks all other access while it runs.
+    pub fn compact(&self) -> Result<()> {
+        self.index_mut().compact()
+    }
+
+    /// Rebuilds the database from its reachable keys and values into a folder next to it,
+    /// `<path>.vacuum`, and swaps that in, leaving behind the WAL, map deltas and any
+    /// fragmentation of the value file.
+    ///
+    /// Blocks all other access while it runs and fails while snapshots or read transactions
+    /// are open. A crash during the swap is completed by the next open.
+    pub fn vacuum(&self) -> Result<()> {
+        self.index_mut().vacuum()
+    }
+
+    /// Reads back the value of every key and the maps and WAL segments of every keyspace,
+    /// reporting keys with unreadable values, corrupt files and orphaned data.
+    ///
+    /// With `repair` set the broken keys are deleted and the database is rebuilt with
+    /// [`Db::vacuum`], which blocks all other access while the check runs.
+    pub fn check(&self, repair: bool) -> Result<CheckReport> {
+        match repair {
+            true => self.index_mut().check_and_repair(),
+            false => self.index().check(),
+        }
+    }
+
+    /// Copies a consistent state of the database into `dest`, which must be empty or missing,
+    /// while other handles keep reading and writing.
+    ///
+    /// Writes only wait while the lookup tables are copied, the value file is copied afterwards.
+    /// The backup is written to the storage backend of the database and opens like any other database.
+    pub fn backup(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        let backend = self.index().backend();
+        if backend.exists(dest) && !backend.list(dest)?.is_empty() {
+            return Err(Error::Custom(format!("backup directory {} is not empty", dest.display())));
+        }
+        backend.create_dir_all(dest)?;
+        let (data, len, tail) = self.index_mut().backup_tables(dest)?;
+        // Apart from its last block the value file is append only, so the copy taken
+        // under the lock plus the bytes before it are the state of the tables
+        let copy = backend.open(&dest.join(DATA_FILE_NAME), OpenMode::Truncate)?;
+        let unchanged = len - tail.len() as u64;
+        copy_bytes(data.as_ref(), copy.as_ref(), unchanged)?;
+        copy.write_at(&tail, unchanged)?;
+        copy.sync()?;
+        backend.sync_dir(dest)?;
+        Ok(())
+    }
+
+    /// Copies a backup made with [`Db::backup`] into `dest`, which must be empty or missing.
+    pub fn restore(backup: impl AsRef<Path>, dest: impl AsRef<Path>) -> Result<()> {
+        Db::restore_files(backup.as_ref(), dest.as_ref(), None)
+    }
+
+    /// Copies a backup like [`Db::restore`], rolling the database back to when the write with
+    /// sequence number `sequence` was made, see [`Db::last_sequence`].
+    ///
+    /// Only writes logged since the last flush before the backup can be rolled back.
+    /// For databases written before sequence numbers were logged, they count the writes since
+    /// that flush separately for the default keyspace and every column family.
+    pub fn restore_to(backup: impl AsRef<Path>, dest: impl AsRef<Path>, sequence: u64) -> Result<()> {
+        Db::restore_files(backup.as_ref(), dest.as_ref(), Some(sequence))
+    }
+
+    fn restore_files(backup: &Path, dest: &Path, sequence: Option<u64>) -> Result<()> {
+        if !backup.join("map.db").exists() || !backup.join(DATA_FILE_NAME).exists() {
+            return Err(Error::Custom(format!("{} is not a backup", backup.display())));
+        }
+        if dest.exists() && std::fs::read_dir(dest)?.next().is_some() {
+            return Err(Error::Custom(format!("restore directory {} is not empty", dest.display())));
+        }
+        copy_folder(&FileSystem, backup, dest, &[LOCK_FILE_NAME])?;
+        if let Some(sequence) = sequence {
+            Index::truncate_wals(&FileSystem, dest, sequence)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes everything written to the database, syncs its files and releases the lock on its folder.
+    /// The next open then skips reading the write-ahead log.
+    ///
+    /// Dropping the last handle does the same, but can only log a failure. Fails if other handles
+    /// to the database are still alive.
+    pub fn close(self) -> Result<()> {
+        let lock = Arc::try_unwrap(self.inner)
+            .map_err(|_| Error::Index(IndexError::InUse))?;
+        lock.into_inner().unwrap_or_else(|e| e.into_inner()).close()
+    }
+
+    /// Closes the database and deletes all of its files.
+    ///
+    /// Fails if other handles to the database are still alive.
+    pub fn destroy(self) -> Result<()> {
+        let lock = Arc::try_unwrap(self.inner)
+            .map_err(|_| Error::Index(IndexError::InUse))?;
+        lock.into_inner().unwrap_or_else(|e| e.into_inner()).cleanup()
+    }
+
+    // Drops the handle without flushing, the next open replays the WAL as after a crash
+    #[cfg(test)]
+    pub(crate) fn crash(self) {
+        self.index_mut().abandon();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::clock::ManualClock;
+    use crate::db::fault::FaultyBackend;
+    use crate::db::memory::MemoryBackend;
+    use crate::db::options::Backpressure;
+    use crate::db::sync::SyncPolicy;
+    use proptest::prelude::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_put_get() -> Result<()> {
+        let db = Db::open_temp()?;
+        db.put(b"1", b"hello")?;
+        db.put(b"2", b"world")?;
+
+        assert_eq!(db.get(b"1")?, Some(b"hello".to_vec()));
+        assert_eq!(db.get(b"2")?, Some(b"world".to_vec()));
+        assert_eq!(db.get(b"3")?, None);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_overwrite_and_delete() -> Result<()> {
+        let db = Db::open_temp()?;
+        db.put(b"1", b"first")?;
+        db.put(b"1", b"second")?;
+        assert_eq!(db.get(b"1")?, Some(b"second".to_vec()));
+
+        db.delete(b"1")?;
+        assert_eq!(db.get(b"1")?, None);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_reopen_after_flush() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        db.put(b"1", b"persisted")?;
+        db.put(b"2", b"")?;
+        db.flush()?;
+        drop(db);
+
+        let db = Db::open(&dir)?;
+        assert_eq!(db.get(b"1")?, Some(b"persisted".to_vec()));
+        assert_eq!(db.get(b"2")?, Some(Vec::new()));
+        db.destroy()
+    }
+
+    #[test]
+    fn test_reopen_without_flush() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        db.put(b"1", b"logged")?;
+        db.put(b"2", b"deleted")?;
+        db.delete(b"2")?;
+        db.crash();
+
+        let db = Db::open(&dir)?;
+        assert_eq!(db.get(b"1")?, Some(b"logged".to_vec()));
+        assert_eq!(db.get(b"2")?, None);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_close() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        db.put(b"1", b"closed")?;
+        db.cf("users")?.put(b"2", b"closed")?;
+        let other = db.clone();
+        assert!(matches!(db.close(), Err(Error::Index(IndexError::InUse))));
+        other.close()?;
+
+        // Nothing left to replay, and the lock is released
+        let db = Db::open(&dir)?;
+        assert_eq!(db.recovery_report().wal_records_replayed, 0);
+        assert_eq!(db.get(b"1")?, Some(b"closed".to_vec()));
+        assert_eq!(db.cf("users")?.get(b"2")?, Some(b"closed".to_vec()));
+        // Dropping the last handle closes too
+        db.put(b"3", b"dropped")?;
+        drop(db);
+        let db = Db::open(&dir)?;
+        assert_eq!(db.recovery_report().wal_records_replayed, 0);
+        assert_eq!(db.get(b"3")?, Some(b"dropped".to_vec()));
+        db.destroy()
+    }
+
+    #[test]
+    fn test_clean_shutdown() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        db.put(b"1", b"one")?;
+        db.close()?;
+        // Not read at all after a clean shutdown, not even its torn end
+        let wal_path = dir.path().join("wal-000001.db");
+        std::fs::OpenOptions::new().append(true).open(&wal_path)?.write_all(&[1, 2, 3])?;
+        let db = Db::open(&dir)?;
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.get(b"1")?, Some(b"one".to_vec()));
+        db.crash();
+
+        // Opening cleared the flag, after a crash the WAL is read again
+        let db = Db::open(&dir)?;
+        assert_eq!(db.recovery_report().wal_bytes_discarded, 3);
+        assert_eq!(db.get(b"1")?, Some(b"one".to_vec()));
+        db.destroy()
+    }
+
+    #[test]
+    fn test_recovery_report() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        assert!(db.recovery_report().is_clean());
+        db.put(b"1", b"one")?;
+        db.cf("users")?.put(b"2", b"two")?;
+        db.crash();
+
+        let db = Db::open(&dir)?;
+        let report = db.recovery_report();
+        assert_eq!(report.wal_records_replayed, 2);
+        assert!(report.is_clean());
+        db.crash();
+
+        // A torn record at the end of the WAL
+        let wal_path = dir.path().join("wal-000001.db");
+        std::fs::OpenOptions::new().append(true).open(&wal_path)?.write_all(&[1, 2, 3])?;
+        let db = Db::open(&dir)?;
+        let report = db.recovery_report();
+        assert_eq!((report.wal_records_replayed, report.wal_bytes_discarded), (2, 3));
+        assert_eq!(report.repaired_files, vec![wal_path]);
+        assert_eq!(db.get(b"1")?, Some(b"one".to_vec()));
+        db.destroy()
+    }
+
+    #[test]
+    fn test_open_options() -> Result<()> {
+        let dir = TempDir::new()?;
+        assert!(DbOptions::new().open().is_err());
+        let missing = DbOptions::new().path(&dir).create_if_missing(false).open();
+        assert!(matches!(missing, Err(Error::Index(IndexError::NotFound { .. }))));
+        assert!(DbOptions::new().path(&dir).read_only(true).open().is_err());
+        assert!(DbOptions::new().path(&dir).bloom_filter(1.5).open().is_err());
+
+        let db = DbOptions::new().path(&dir).open()?;
+        db.put(b"k", b"v")?;
+        drop(db);
+
+        let db = DbOptions::new().path(&dir).read_only(true).create_if_missing(false).open()?;
+        assert_eq!(db.get(b"k")?, Some(b"v".to_vec()));
+        assert!(matches!(db.put(b"k", b"other"), Err(Error::Index(IndexError::ReadOnly))));
+        assert!(db.delete(b"k").is_err());
+        assert!(db.flush().is_err());
+        drop(db);
+
+        let db = DbOptions::new().path(&dir).reset(true).open()?;
+        assert_eq!(db.get(b"k")?, None);
+        assert_eq!(db.iter().count(), 0);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_directory_is_locked() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        db.put(b"k", b"v")?;
+        assert!(matches!(Db::open(&dir), Err(Error::DatabaseLocked(_))));
+        assert!(matches!(DbOptions::new().path(&dir).reset(true).open(), Err(Error::DatabaseLocked(_))));
+        assert_eq!(db.get(b"k")?, Some(b"v".to_vec()));
+        drop(db);
+
+        let reader = DbOptions::new().path(&dir).read_only(true).open()?;
+        let other_reader = DbOptions::new().path(&dir).read_only(true).open()?;
+        assert!(matches!(Db::open(&dir), Err(Error::DatabaseLocked(_))));
+        assert_eq!(other_reader.get(b"k")?, Some(b"v".to_vec()));
+        drop(reader);
+        drop(other_reader);
+        Db::open(&dir)?.destroy()
+    }
+
+    #[test]
+    fn test_low_memory_open() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = DbOptions::new().path(&dir).bloom_filter(0.01).open()?;
+        for i in 0..1000u32 {
+            db.put(&i.to_be_bytes(), &i.to_le_bytes())?;
+        }
+        db.compact()?;
+        // Left in a delta over map.db
+        db.put(b"late", b"1")?;
+        db.put(&7u32.to_be_bytes(), b"updated")?;
+        db.delete(&8u32.to_be_bytes())?;
+        db.close()?;
+
+        let reader = DbOptions::new().path(&dir).read_only(true).low_memory(true).bloom_filter(0.01).open()?;
+        assert!(reader.lookup_index().is_lazy());
+        assert_eq!(reader.get(&500u32.to_be_bytes())?, Some(500u32.to_le_bytes().to_vec()));
+        assert_eq!(reader.get(&7u32.to_be_bytes())?, Some(b"updated".to_vec()));
+        assert_eq!(reader.get(&8u32.to_be_bytes())?, None);
+        assert_eq!(reader.get(b"late")?, Some(b"1".to_vec()));
+        assert_eq!(reader.get(&1000u32.to_be_bytes())?, None);
+        assert_eq!(reader.multi_get(&[0u32.to_be_bytes(), 999u32.to_be_bytes()])?.len(), 2);
+        assert!(reader.lookup_index().is_lazy());
+        // Anything else loads the map
+        assert_eq!(reader.len(), 1000);
+        assert!(!reader.lookup_index().is_lazy());
+        assert_eq!(reader.get(&8u32.to_be_bytes())?, None);
+        assert_eq!(reader.iter().count(), 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        for i in 0..200u32 {
+            db.put(&i.to_be_bytes(), &[i as u8; 100])?;
+        }
+        for i in 0..200u32 {
+            match i % 3 {
+                0 => db.delete(&i.to_be_bytes())?,
+                1 => db.put(&i.to_be_bytes(), b"updated")?,
+                _ => {}
+            }
+        }
+        let snapshot = db.snapshot();
+        db.put(&2u32.to_be_bytes(), b"after the snapshot")?;
+        let stale = db.iter();
+        let data_size = || std::fs::metadata(dir.path().join("data.db")).map(|metadata| metadata.len());
+        let before = data_size()?;
+
+        db.compact()?;
+        assert!(data_size()? < before);
+        // Still reads the replaced data file
+        let entries = stale.collect::<Result<Vec<_>>>()?;
+        assert_eq!(entries.len(), 133);
+        assert!(entries.contains(&(2u32.to_be_bytes().to_vec(), b"after the snapshot".to_vec())));
+        assert_eq!(db.get_at(&snapshot, &2u32.to_be_bytes())?, Some(vec![2; 100]));
+        drop(snapshot);
+        db.put(b"new", b"value")?;
+        drop(db);
+
+        let db = Db::open(&dir)?;
+        assert_eq!(db.iter().count(), 134);
+        assert_eq!(db.get(&0u32.to_be_bytes())?, None);
+        assert_eq!(db.get(&1u32.to_be_bytes())?, Some(b"updated".to_vec()));
+        assert_eq!(db.get(&2u32.to_be_bytes())?, Some(b"after the snapshot".to_vec()));
+        assert_eq!(db.get(&5u32.to_be_bytes())?, Some(vec![5; 100]));
+        assert_eq!(db.get(b"new")?, Some(b"value".to_vec()));
+        db.destroy()
+    }
+
+    #[test]
+    fn test_vacuum() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("db");
+        let open = || DbOptions::new().path(&path).shards(2).max_map_deltas(4).open();
+        let db = open()?;
+        let users = db.cf("users")?;
+        for i in 0..100u32 {
+            db.put(&i.to_be_bytes(), &[i as u8; 100])?;
+            db.put(&i.to_be_bytes(), &[i as u8; 200])?;
+            db.flush()?;
+        }
+        db.delete(&0u32.to_be_bytes())?;
+        db.put_with_ttl(b"expiring", b"1", Duration::from_secs(600))?;
+        db.put(b"big", &[9; 10_000])?;
+        users.put(b"ada", b"lovelace")?;
+        let sequence = db.last_sequence();
+        let snapshot = db.snapshot();
+        assert!(matches!(db.vacuum(), Err(Error::Index(IndexError::SnapshotsOpen))));
+        drop(snapshot);
+        let stale = db.iter();
+        let before = db.size_on_disk()?;
+
+        db.vacuum()?;
+        let after = db.size_on_disk()?;
+        assert!(after.data_size < before.data_size);
+        assert!(after.keyspaces[0].index_size < before.keyspaces[0].index_size);
+        assert_eq!(stale.collect::<Result<Vec<_>>>()?.len(), 101);
+        assert!(!dir.path().join("db.vacuum").exists());
+        assert!(!dir.path().join("db.old").exists());
+        assert_eq!(db.last_sequence(), sequence);
+        users.put(b"bob", b"1")?;
+        drop((users, db));
+
+        let db = open()?;
+        assert_eq!(db.iter().count(), 101);
+        assert_eq!(db.get(&0u32.to_be_bytes())?, None);
+        assert_eq!(db.get(&7u32.to_be_bytes())?, Some(vec![7; 200]));
+        assert_eq!(db.get(b"big")?, Some(vec![9; 10_000]));
+        assert_eq!(db.get(b"expiring")?, Some(b"1".to_vec()));
+        assert_eq!(db.cf("users")?.get(b"ada")?, Some(b"lovelace".to_vec()));
+        assert_eq!(db.last_sequence(), sequence + 1);
+        drop(db);
+
+        // Interrupted between renaming the database away and the new folder into its place
+        let vacuumed = dir.path().join("db.old");
+        std::fs::rename(&path, &vacuumed)?;
+        copy_folder(&FileSystem, &vacuumed, &dir.path().join("db.vacuum"), &[])?;
+        let db = open()?;
+        assert_eq!(db.get(&7u32.to_be_bytes())?, Some(vec![7; 200]));
+        assert!(!vacuumed.exists());
+        assert!(!db.recovery_report().is_clean());
+        db.destroy()
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        let dir = TempDir::new()?;
+        // Flushes rewrite map.db instead of writing deltas
+        let open = || DbOptions::new().path(&dir).max_map_deltas(0).open();
+        let db = open()?;
+        db.cf("users")?.put(b"ada", b"1")?;
+        for i in 0..3u8 {
+            db.put(&[i], &[i; 3000])?;
+        }
+        db.flush()?;
+        drop(db);
+        // Loses the blocks holding the values of keys 1 and 2
+        let data = std::fs::OpenOptions::new().write(true).open(dir.path().join("data.db"))?;
+        data.set_len(4096)?;
+        drop(data);
+
+        let db = open()?;
+        db.put(&[0], b"overwritten")?;
+        let clean = db.check(false)?;
+        assert_eq!(clean.keys_checked, 4);
+        assert_eq!(clean.broken_keys, vec![(None, vec![1]), (None, vec![2])]);
+        assert!(clean.corrupt_files.is_empty());
+        assert!(clean.orphaned_bytes >= 3004);
+        let map_path = dir.path().join("cf").join("users").join("map.db");
+        let mut map = std::fs::read(&map_path)?;
+        *map.last_mut().ok_or("empty map")? ^= 1;
+        std::fs::write(&map_path, map)?;
+
+        let report = db.check(true)?;
+        assert!(report.repaired && !report.is_clean());
+        assert_eq!(report.corrupt_files, vec![map_path]);
+        let repaired = db.check(false)?;
+        assert!(repaired.is_clean() && !repaired.repaired);
+        assert_eq!((repaired.keys_checked, repaired.orphaned_bytes), (2, 0));
+        assert_eq!(db.get(&[1])?, None);
+        drop(db);
+
+        let db = open()?;
+        assert_eq!(db.get(&[0])?, Some(b"overwritten".to_vec()));
+        assert_eq!(db.cf("users")?.get(b"ada")?, Some(b"1".to_vec()));
+        db.destroy()
+    }
+
+    #[test]
+    fn test_column_families() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        let users = db.cf("users")?;
+        db.put(b"1", b"default")?;
+        users.put(b"1", b"ada")?;
+        users.put(b"2", b"bob")?;
+        users.delete(b"2")?;
+        db.cf("items")?.put(b"1", &[7; 100])?;
+        assert!(db.cf("").is_err());
+        assert!(db.cf("../escape").is_err());
+        assert_eq!(db.get(b"1")?, Some(b"default".to_vec()));
+        assert_eq!(users.get(b"1")?, Some(b"ada".to_vec()));
+        assert_eq!(db.iter().count(), 1);
+        drop(users);
+        drop(db);
+
+        let db = Db::open(&dir)?;
+        assert_eq!(db.column_families(), vec!["items".to_string(), "users".to_string()]);
+        let users = db.cf("users")?;
+        assert_eq!(users.get(b"2")?, None);
+        db.put(b"1", b"overwritten")?;
+        db.compact()?;
+        assert_eq!(users.iter().collect::<Result<Vec<_>>>()?, vec![(b"1".to_vec(), b"ada".to_vec())]);
+        assert_eq!(db.cf("items")?.get(b"1")?, Some(vec![7; 100]));
+        drop(users);
+        drop(db);
+
+        let db = DbOptions::new().path(&dir).read_only(true).open()?;
+        assert_eq!(db.cf("users")?.get(b"1")?, Some(b"ada".to_vec()));
+        assert!(db.cf("missing").is_err());
+        drop(db);
+        Db::open(&dir)?.destroy()
+    }
+
+    #[test]
+    fn test_put_with_ttl() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        db.put_with_ttl(b"short", b"gone", Duration::from_millis(20))?;
+        db.put_with_ttl(b"long", b"kept", Duration::from_secs(3600))?;
+        db.put_with_ttl(b"cleared", b"first", Duration::from_millis(20))?;
+        db.put(b"cleared", b"second")?;
+        assert_eq!(db.get(b"short")?, Some(b"gone".to_vec()));
+        std::thread::sleep(Duration::from_millis(30));
+
+        assert_eq!(db.get(b"short")?, None);
+        assert_eq!(db.iter().count(), 2);
+        drop(db);
+        let db = Db::open(&dir)?;
+        assert_eq!(db.get(b"short")?, None);
+        db.flush()?;
+        drop(db);
+
+        let db = Db::open(&dir)?;
+        assert_eq!(db.get(b"long")?, Some(b"kept".to_vec()));
+        assert_eq!(db.get(b"cleared")?, Some(b"second".to_vec()));
+        db.compact()?;
+        assert_eq!(db.iter().count(), 2);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_merge() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        assert!(db.merge(b"list", b"a").is_err());
+        drop(db);
+
+        let append = |_: &[u8], current: Option<&[u8]>, operand: &[u8]| {
+            let mut value = current.map(<[u8]>::to_vec).unwrap_or_default();
+            value.extend_from_slice(operand);
+            value
+        };
+        let db = DbOptions::new().path(&dir).merge_operator(append).open()?;
+        let threads: Vec<_> = (0..4u8).map(|thread| {
+            let db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    db.merge(b"list", &[thread])?;
+                }
+                Ok(())
+            })
+        }).collect();
+        for handle in threads {
+            handle.join().map_err(|_| "thread panicked")??;
+        }
+        db.put_with_ttl(b"expiring", b"a", Duration::from_millis(20))?;
+        db.merge(b"expiring", b"b")?;
+        db.cf("users")?.merge(b"list", b"cf")?;
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(db.get(b"expiring")?, None);
+        drop(db);
+
+        let db = DbOptions::new().path(&dir).merge_operator(append).open()?;
+        assert_eq!(db.get(b"list")?.map(|value| value.len()), Some(100));
+        assert_eq!(db.cf("users")?.get(b"list")?, Some(b"cf".to_vec()));
+        db.merge(b"expiring", b"c")?;
+        assert_eq!(db.get(b"expiring")?, Some(b"c".to_vec()));
+        db.destroy()
+    }
+
+    #[test]
+    fn test_increment() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        let threads: Vec<_> = (0..4).map(|_| {
+            let db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    db.increment(b"hits", 2)?;
+                }
+                Ok(())
+            })
+        }).collect();
+        for handle in threads {
+            handle.join().map_err(|_| "thread panicked")??;
+        }
+        db.put(b"text", b"not a counter")?;
+        assert!(matches!(db.increment(b"text", 1), Err(Error::Index(IndexError::NotACounter { length: 13, .. }))));
+        db.put(b"max", &u64::MAX.to_le_bytes())?;
+        assert!(db.increment(b"max", 1).is_err());
+        assert_eq!(db.cf("stats")?.increment(b"hits", 5)?, 5);
+        drop(db);
+
+        let db = Db::open(&dir)?;
+        assert_eq!(db.get(b"hits")?, Some(400u64.to_le_bytes().to_vec()));
+        assert_eq!(db.increment(b"hits", 0)?, 400);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_backup() -> Result<()> {
+        let db = Db::open_temp()?;
+        let dir = TempDir::new()?;
+        let backup_path = dir.path().join("backup");
+        for i in 0..20u8 {
+            db.put(&[i], &[i; 500])?;
+        }
+        db.flush()?;
+        db.put(b"logged", b"before")?;
+        db.cf("users")?.put(b"ada", b"admin")?;
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..50u8 {
+                    db.put(b"logged", &[i])?;
+                }
+                Ok(())
+            })
+        };
+        db.backup(&backup_path)?;
+        writer.join().map_err(|_| "thread panicked")??;
+        assert!(db.backup(&backup_path).is_err());
+        db.put(b"after", b"backup")?;
+
+        let backup = Db::open(&backup_path)?;
+        assert_eq!(backup.get(&[19])?, Some(vec![19; 500]));
+        assert!(backup.get(b"logged")?.is_some());
+        assert_eq!(backup.get(b"after")?, None);
+        assert_eq!(backup.cf("users")?.get(b"ada")?, Some(b"admin".to_vec()));
+        backup.destroy()?;
+        db.destroy()
+    }
+
+    #[test]
+    fn test_restore() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db_path = dir.path().join("db");
+        let [backup_path, restore_path, rollback_path] = ["backup", "restore", "rollback"].map(|name| dir.path().join(name));
+        let db = Db::open(&db_path)?;
+        db.put(b"flushed", b"1")?;
+        db.flush()?;
+        for key in [b"a", b"b", b"c"] {
+            db.put(key, key)?;
+        }
+        let users = db.cf("users")?;
+        users.put(b"ada", b"admin")?;
+        let before_bob = db.last_sequence();
+        users.put(b"bob", b"guest")?;
+        db.put(b"d", b"d")?;
+        db.backup(&backup_path)?;
+        drop(users);
+        db.destroy()?;
+        assert!(Db::restore(&db_path, &restore_path).is_err());
+
+        Db::restore(&backup_path, &restore_path)?;
+        assert!(Db::restore(&backup_path, &restore_path).is_err());
+        let restored = Db::open(&restore_path)?;
+        assert_eq!(restored.get(b"c")?, Some(b"c".to_vec()));
+        assert_eq!(restored.cf("users")?.get(b"bob")?, Some(b"guest".to_vec()));
+        restored.destroy()?;
+
+        // Sequence numbers order the writes across keyspaces
+        Db::restore_to(&backup_path, &rollback_path, before_bob)?;
+        let rolled_back = Db::open(&rollback_path)?;
+        assert_eq!(rolled_back.get(b"flushed")?, Some(b"1".to_vec()));
+        assert_eq!(rolled_back.get(b"c")?, Some(b"c".to_vec()));
+        assert_eq!(rolled_back.get(b"d")?, None);
+        assert_eq!(rolled_back.cf("users")?.get(b"ada")?, Some(b"admin".to_vec()));
+        assert_eq!(rolled_back.cf("users")?.get(b"bob")?, None);
+        assert_eq!(rolled_back.last_sequence(), before_bob);
+        rolled_back.put(b"b", b"again")?;
+        assert_eq!(rolled_back.last_sequence(), before_bob + 1);
+        rolled_back.destroy()
+    }
+
+    #[test]
+    fn test_last_sequence() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        assert_eq!(db.last_sequence(), 0);
+        db.put(b"a", b"1")?;
+        db.write(WriteBatch::new().put(b"b", b"2").delete(b"a").clone())?;
+        db.cf("users")?.put(b"ada", b"admin")?;
+        assert_eq!(db.last_sequence(), 3);
+        db.flush()?;
+        drop(db);
+
+        let db = Db::open(&dir)?;
+        assert_eq!(db.last_sequence(), 3);
+        assert_eq!(db.recovery_report().wal_records_replayed, 0);
+        db.put(b"c", b"3")?;
+        drop(db);
+        assert_eq!(Db::open(&dir)?.last_sequence(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_len() -> Result<()> {
+        let db = Db::open_temp()?;
+        assert!(db.is_empty());
+        for i in 0..5000 {
+            db.put(format!("key{i:05}").as_bytes(), b"v")?;
+        }
+        db.cf("users")?.put(b"ada", b"1")?;
+        db.delete(b"key00000")?;
+        assert_eq!((db.len(), db.cf("users")?.len()), (4999, 1));
+        assert_eq!(db.estimate_range_count(&b"key00010"[..]..&b"key00020"[..]), 10);
+        assert_eq!(db.estimate_range_count(&b"key9"[..]..), 0);
+        db.flush()?;
+        let estimate = db.estimate_range_count(&b"key01000"[..]..&b"key04000"[..]);
+        assert!((2700..3300).contains(&estimate), "estimated {estimate} keys");
+        Ok(())
+    }
+
+    #[test]
+    fn test_approximate_size() -> Result<()> {
+        let db = Db::open_temp()?;
+        assert_eq!(db.approximate_size::<[u8], _>(..)?, 0);
+        for i in 0..4000u32 {
+            db.put(format!("key{i:05}").as_bytes(), &[i as u8; 100])?;
+        }
+        // Neither large values of another keyspace nor overwritten ones count
+        let images = db.cf("images")?;
+        for i in 0..50u32 {
+            images.put(&i.to_be_bytes(), &vec![i as u8; 20_000])?;
+        }
+        for i in 0..1000u32 {
+            db.put(b"key00000", &i.to_le_bytes().repeat(25))?;
+        }
+        db.flush()?;
+        let misses = db.cache_stats().misses;
+
+        let total = db.approximate_size::<[u8], _>(..)?;
+        assert!((360_000..440_000).contains(&total), "{total} bytes in total");
+        let half = db.approximate_size(&b"key00000"[..]..&b"key02000"[..])?;
+        assert!((total * 4 / 10..total * 6 / 10).contains(&half), "{half} of {total} bytes");
+        let few = db.approximate_size(&b"key00100"[..]..&b"key00110"[..])?;
+        assert!((1_000..1_200).contains(&few), "{few} bytes of 10 keys");
+        assert_eq!(db.approximate_size(&b"key9"[..]..)?, 0);
+        let size = images.approximate_size::<[u8], _>(..)?;
+        assert!((1_000_000..1_100_000).contains(&size), "{size} bytes of images");
+        // Sizes come from the index, not from the blocks holding the values
+        assert_eq!(db.cache_stats().misses, misses);
+        drop(images);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_stats() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        db.put(b"a", b"1")?;
+        db.put(b"b", b"2")?;
+        db.delete(b"b")?;
+        db.write(WriteBatch::new().put(b"c", b"3").delete(b"a").clone())?;
+        db.get(b"a")?;
+        db.multi_get(&[b"b", b"c"])?;
+        let stats = db.stats()?;
+        assert_eq!((stats.puts, stats.gets, stats.deletes, stats.flushes), (3, 3, 2, 0));
+        assert!(stats.wal_bytes_written > 0);
+        assert!(stats.index_size > stats.wal_bytes_written);
+        // A batch and a multi-get take one latency each
+        assert_eq!((stats.put_latency.count, stats.get_latency.count, stats.flush_latency.count), (3, 2, 0));
+        assert!(stats.put_latency.p50 > Duration::ZERO && stats.put_latency.p99 <= stats.put_latency.max);
+
+        let readers: Vec<_> = (0..4).map(|_| {
+            let db = db.clone();
+            std::thread::spawn(move || (0..100).try_for_each(|_| db.get(b"c").map(drop)))
+        }).collect();
+        for reader in readers {
+            reader.join().map_err(|_| "thread panicked")??;
+        }
+        db.compact()?;
+        let stats = db.stats()?;
+        assert_eq!((stats.gets, stats.flushes, stats.compactions), (403, 1, 1));
+        assert_eq!((stats.get_latency.count, stats.flush_latency.count, stats.compaction_latency.count), (402, 1, 1));
+        assert!(stats.compaction_latency.max >= stats.flush_latency.max);
+        assert_eq!(stats.data_size, std::fs::metadata(dir.path().join("data.db"))?.len());
+        db.destroy()
+    }
+
+    #[test]
+    fn test_backpressure() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = DbOptions::new().path(dir.path()).max_unflushed_writes(3).open()?;
+        for i in 0..10u8 {
+            db.put(&[i], b"v")?;
+        }
+        // The fourth, seventh and tenth write flushed first
+        assert_eq!(db.stats()?.flushes, 3);
+        db.close()?;
+
+        let db = DbOptions::new().path(dir.path()).max_unflushed_wal_bytes(1).backpressure(Backpressure::Fail).open()?;
+        db.put(b"a", b"1")?;
+        assert!(matches!(db.put(b"b", b"2"), Err(Error::Backpressure)));
+        assert!(matches!(db.write(WriteBatch::new().put(b"b", b"2").clone()), Err(Error::Backpressure)));
+        db.flush()?;
+        db.put(b"b", b"2")?;
+        db.close()?;
+
+        // Every key of a batch counts
+        let db = DbOptions::new().path(dir.path()).max_unflushed_writes(3).backpressure(Backpressure::Fail).open()?;
+        db.write(WriteBatch::new().put(b"c", b"3").put(b"d", b"4").delete(b"x").clone())?;
+        assert!(matches!(db.put(b"e", b"5"), Err(Error::Backpressure)));
+        assert_eq!((db.get(b"a")?, db.get(b"b")?), (Some(b"1".to_vec()), Some(b"2".to_vec())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_usage() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = DbOptions::new().path(dir.path()).bloom_filter(0.01).open()?;
+        for i in 0..1000u32 {
+            db.put(&i.to_be_bytes(), b"v")?;
+        }
+        let memory = db.stats()?.memory;
+        assert!(memory.index > 1000 * 8 && memory.wal > 1000 * 4 && memory.cache > 0);
+        db.flush()?;
+        let flushed = db.stats()?.memory;
+        assert_eq!((flushed.wal, flushed.total()), (0, flushed.index + flushed.cache));
+        db.close()?;
+
+        // The limit is exceeded by the keys alone, writes flush once the logged operations take 64 KiB
+        let db = DbOptions::new().path(dir.path()).memory_limit(1).open()?;
+        for i in 0..10_000u32 {
+            db.put(&i.to_be_bytes(), b"v")?;
+        }
+        let stats = db.stats()?;
+        assert!(stats.flushes >= 5 && stats.memory.wal <= 65 * 1024, "{stats:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_on_disk() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        let cf = db.cf("other")?;
+        for i in 0..3 {
+            db.put(b"a", &[i; 3000])?;
+        }
+        db.put(b"big", &[3; 10_000])?;
+        cf.put(b"b", &[4; 100])?;
+        let usage = db.size_on_disk()?;
+        assert_eq!(usage.data_size, std::fs::metadata(dir.path().join("data.db"))?.len());
+        assert_eq!(usage.keyspaces.len(), 2);
+        let (default, other) = (&usage.keyspaces[0], &usage.keyspaces[1]);
+        assert_eq!((default.name.as_deref(), other.name.as_deref()), (None, Some("other")));
+        assert_eq!(default.live_bytes, 3004 + 3 * 4092 + 20);
+        assert_eq!(other.live_bytes, 104);
+        assert_eq!(usage.live_bytes, default.live_bytes + other.live_bytes);
+        assert!(usage.dead_bytes < usage.data_size - usage.live_bytes);
+        assert!(default.wal_size > 0 && default.index_size > 0);
+
+        // The overwritten values are dead until a compaction
+        db.compact()?;
+        let compacted = db.size_on_disk()?;
+        assert_eq!(compacted.live_bytes, usage.live_bytes);
+        assert_eq!(compacted.data_size, usage.data_size - 2 * 4096);
+        drop(cf);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_block_cache() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        for i in 0..3u8 {
+            db.put(&[i], &[i; 3000])?;
+        }
+        drop(db);
+
+        let db = Db::open(&dir)?;
+        assert_eq!(db.get(&[0])?, Some(vec![0; 3000]));
+        assert_eq!(db.get(&[0])?, Some(vec![0; 3000]));
+        assert_eq!(db.get(&[1])?, Some(vec![1; 3000]));
+        // Opening read the tail block once
+        assert_eq!(db.cache_stats(), CacheStats { hits: 1, misses: 3 });
+        drop(db);
+
+        let db = DbOptions::new().path(&dir).block_cache_size(0).open()?;
+        assert_eq!(db.get(&[0])?, Some(vec![0; 3000]));
+        assert_eq!(db.cache_stats(), CacheStats::default());
+        db.destroy()
+    }
+
+    #[test]
+    fn test_multi_get() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        for i in 0..8u8 {
+            db.put(&[i], &[i; 1500])?;
+        }
+        drop(db);
+
+        let db = Db::open(&dir)?;
+        let values = db.multi_get(&[[6], [1], [9], [0], [4], [1], [2]])?;
+        let expected = [Some(6), Some(1), None, Some(0), Some(4), Some(1), Some(2)];
+        assert_eq!(values, expected.map(|i| i.map(|i| vec![i; 1500])));
+        // Two values fit into a block. Opening read the tail block 3, blocks 0 to 2 were missing
+        // from the cache once each and read together.
+        assert_eq!(db.cache_stats(), CacheStats { hits: 0, misses: 4 });
+        assert_eq!(db.multi_get::<&[u8]>(&[])?, Vec::<Option<Vec<u8>>>::new());
+        db.destroy()
+    }
+
+    #[test]
+    fn test_get_ref() -> Result<()> {
+        let db = Db::open_temp()?;
+        for i in 0..4u8 {
+            db.put(&[i], &[i; 1500])?;
+        }
+        let value = db.get_ref(&[0])?.ok_or("missing value")?;
+        assert_eq!(*value, [0; 1500]);
+        // Values in the cached block are not copied again
+        let second = db.get_ref(&[1])?.ok_or("missing value")?;
+        assert_eq!(db.cache_stats().misses, 0);
+        db.put(&[0], b"replaced")?;
+        assert_eq!((&*value, &*second), (&[0; 1500][..], &[1; 1500][..]));
+        assert_eq!(db.get_ref(&[0])?.map(ValueRef::into_vec), Some(b"replaced".to_vec()));
+        assert!(db.get_ref(b"missing")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_contains_key() -> Result<()> {
+        let dir = TempDir::new()?;
+        let clock = ManualClock::new(0);
+        let options = DbOptions::new().path(&dir).clock(clock.clone());
+        let db = options.open()?;
+        db.put(b"a", &[1; 1500])?;
+        db.put_with_ttl(b"expiring", b"2", Duration::from_secs(1))?;
+        db.cf("other")?.put(b"b", b"3")?;
+        db.delete(b"deleted")?;
+        db.close()?;
+
+        let db = options.open()?;
+        let opened = db.cache_stats();
+        assert!(db.contains_key(b"a")? && db.contains_key(b"expiring")?);
+        assert!(!db.contains_key(b"b")? && !db.contains_key(b"deleted")?);
+        assert!(db.cf("other")?.contains_key(b"b")?);
+        clock.advance(Duration::from_secs(2));
+        assert!(!db.contains_key(b"expiring")?);
+        // Not a single block of the value file was read
+        assert_eq!(db.cache_stats(), opened);
+        assert_eq!(db.stats()?.gets, 0);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_insert() -> Result<()> {
+        let db = Db::open_temp()?;
+        assert_eq!(db.insert(b"a", b"1")?, None);
+        assert_eq!(db.insert(b"a", b"2")?, Some(b"1".to_vec()));
+        assert_eq!(db.get(b"a")?, Some(b"2".to_vec()));
+        db.delete(b"a")?;
+        assert_eq!(db.insert(b"a", b"3")?, None);
+        assert_eq!(db.cf("other")?.insert(b"a", b"4")?, None);
+        assert_eq!(db.cf("other")?.insert(b"a", b"5")?, Some(b"4".to_vec()));
+        assert_eq!(db.get(b"a")?, Some(b"3".to_vec()));
+
+        // Concurrent inserts each see the value of the one before
+        let previous: Vec<Option<Vec<u8>>> = std::thread::scope(|scope| {
+            let writers: Vec<_> = (0..4u8)
+                .map(|i| {
+                    let db = db.clone();
+                    scope.spawn(move || db.insert(b"shared", &[i]))
+                })
+                .collect();
+            writers.into_iter().map(|writer| writer.join().unwrap()).collect::<Result<_>>()
+        })?;
+        assert_eq!(previous.iter().filter(|value| value.is_none()).count(), 1);
+        let mut replaced: Vec<u8> = previous.into_iter().flatten().flatten().collect();
+        replaced.extend(db.get(b"shared")?.unwrap());
+        replaced.sort_unstable();
+        assert_eq!(replaced, [0, 1, 2, 3]);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_sync_policies() -> Result<()> {
+        let dir = TempDir::new()?;
+        let policies = [
+            SyncPolicy::Never,
+            SyncPolicy::Interval(std::time::Duration::from_millis(5)),
+            SyncPolicy::Always,
+        ];
+        for (i, policy) in policies.into_iter().enumerate() {
+            let db = DbOptions::new().path(&dir).sync(policy).open()?;
+            db.put(&[i as u8], b"value")?;
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let db = Db::open(&dir)?;
+        for i in 0..3u8 {
+            assert_eq!(db.get(&[i])?, Some(b"value".to_vec()));
+        }
+        db.destroy()
+    }
+
+    #[test]
+    fn test_write_options_override_sync_policy() -> Result<()> {
+        let backend = FaultyBackend::new(MemoryBackend::new());
+        let options = DbOptions::new().path("db").backend(backend.clone()).sync(SyncPolicy::Never);
+        let db = options.open()?;
+        db.put_with(b"critical", b"1", WriteOptions { sync: true })?;
+        db.cf("orders")?.put_with(b"order", b"2", WriteOptions { sync: true })?;
+        db.put(b"bulk", b"3")?;
+        db.crash();
+        backend.crash()?;
+        let db = options.clone().sync(SyncPolicy::Always).open()?;
+        assert_eq!(db.get(b"critical")?, Some(b"1".to_vec()));
+        assert_eq!(db.cf("orders")?.get(b"order")?, Some(b"2".to_vec()));
+        assert_eq!(db.get(b"bulk")?, None);
+
+        // Writes without sync are still synced when the policy syncs every write
+        db.write_with(WriteBatch::new().put(b"buffered", b"4").delete(b"critical").clone(), WriteOptions::default())?;
+        db.crash();
+        backend.crash()?;
+        let db = options.open()?;
+        assert_eq!(db.get(b"buffered")?, Some(b"4".to_vec()));
+        assert_eq!(db.get(b"critical")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_batch() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        db.put(b"old", b"value")?;
+        let mut batch = WriteBatch::new();
+        for key in 0..500u64 {
+            batch.put(&key.to_be_bytes(), &[key as u8; 100]);
+        }
+        batch.delete(b"old").delete(&7u64.to_be_bytes());
+        assert_eq!(batch.len(), 502);
+        db.write(batch)?;
+        drop(db);
+
+        let db = Db::open(&dir)?;
+        assert_eq!(db.get(b"old")?, None);
+        assert_eq!(db.get(&7u64.to_be_bytes())?, None);
+        assert_eq!(db.get(&499u64.to_be_bytes())?, Some(vec![243; 100]));
+        db.destroy()
+    }
+
+    #[test]
+    fn test_range_and_iter() -> Result<()> {
+        let db = Db::open_temp()?;
+        for key in [b"user:3", b"user:1", b"item:9", b"user:2"] {
+            db.put(key, key)?;
+        }
+        db.delete(b"user:2")?;
+
+        let keys: Vec<Vec<u8>> = db.iter().map(|entry| entry.map(|(key, _)| key)).collect::<Result<_>>()?;
+        assert_eq!(keys, vec![b"item:9".to_vec(), b"user:1".to_vec(), b"user:3".to_vec()]);
+
+        let users = db.range(b"user:".as_slice()..b"user;".as_slice()).collect::<Result<Vec<_>>>()?;
+        assert_eq!(users, vec![
+            (b"user:1".to_vec(), b"user:1".to_vec()),
+            (b"user:3".to_vec(), b"user:3".to_vec()),
+        ]);
+        assert_eq!(db.scan_prefix(b"user:").collect::<Result<Vec<_>>>()?, users);
+
+        let latest = db.range_rev(b"user:".as_slice()..b"user;".as_slice()).next().transpose()?;
+        assert_eq!(latest, Some((b"user:3".to_vec(), b"user:3".to_vec())));
+        let keys: Vec<Vec<u8>> = db.iter_rev().map(|entry| entry.map(|(key, _)| key)).collect::<Result<_>>()?;
+        assert_eq!(keys, vec![b"user:3".to_vec(), b"user:1".to_vec(), b"item:9".to_vec()]);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_par_scan() -> Result<()> {
+        let db = Db::open_temp()?;
+        for i in 0..100u32 {
+            db.put(&i.to_be_bytes(), &i.to_le_bytes())?;
+        }
+        let shards = db.par_scan(&10u32.to_be_bytes()[..]..&90u32.to_be_bytes()[..], 3);
+        // Writes after the call are not seen
+        db.delete(&50u32.to_be_bytes())?;
+        db.compact()?;
+        let keys: Vec<Vec<u32>> = std::thread::scope(|scope| {
+            let workers: Vec<_> = shards.into_iter()
+                .map(|shard| scope.spawn(move || {
+                    shard.map(|pair| pair.map(|(key, _)| u32::from_be_bytes(key.try_into().unwrap())))
+                        .collect::<Result<Vec<_>>>()
+                }))
+                .collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect::<Result<_>>()
+        })?;
+        assert_eq!(keys.iter().map(Vec::len).collect::<Vec<_>>(), [26, 27, 27]);
+        assert_eq!(keys.concat(), (10..90).collect::<Vec<_>>());
+
+        let shards = db.par_scan(&0u32.to_be_bytes()[..]..&2u32.to_be_bytes()[..], 4);
+        assert_eq!(shards.into_iter().map(|shard| shard.count()).collect::<Vec<_>>(), [0, 1, 0, 1]);
+        assert_eq!(db.par_scan::<[u8], _>(.., 0).len(), 1);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_cursor() -> Result<()> {
+        let db = Db::open_temp()?;
+        for key in [b"b", b"d", b"f"] {
+            db.put(key, key)?;
+        }
+        let mut cursor = db.cursor();
+        assert!(!cursor.valid() && !cursor.next());
+        assert!(cursor.seek(b"c"));
+        assert_eq!((cursor.key(), cursor.value()?), (Some(b"d".as_slice()), Some(b"d".to_vec())));
+        db.put(b"e", b"e")?;
+        assert!(cursor.next());
+        assert_eq!(cursor.key(), Some(b"e".as_slice()));
+        assert!(cursor.prev() && cursor.prev());
+        assert_eq!(cursor.key(), Some(b"b".as_slice()));
+        assert!(!cursor.prev());
+        assert_eq!(cursor.value()?, None);
+
+        assert!(cursor.seek_for_prev(b"c"));
+        assert_eq!(cursor.key(), Some(b"b".as_slice()));
+        assert!(cursor.seek_to_last());
+        assert_eq!(cursor.key(), Some(b"f".as_slice()));
+        assert!(!cursor.seek(b"g"));
+        db.compact()?;
+        assert!(cursor.seek_to_first());
+        assert_eq!(cursor.value()?, Some(b"b".to_vec()));
+        // The value the cursor points at is moved, then overwritten
+        db.put(b"b", b"overwritten")?;
+        db.compact()?;
+        assert_eq!(cursor.value()?, Some(b"b".to_vec()));
+        drop(cursor);
+
+        let users = db.cf("users")?;
+        users.put(b"z", b"cf")?;
+        let mut cursor = users.cursor();
+        assert!(cursor.seek_to_first());
+        assert_eq!(cursor.key(), Some(b"z".as_slice()));
+        assert!(!cursor.next());
+        drop(cursor);
+        drop(users);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_transaction_commit_and_rollback() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        db.put(b"a", b"1")?;
+
+        let mut txn = db.begin();
+        txn.put(b"b", b"2");
+        txn.delete(b"a");
+        txn.commit()?;
+
+        let mut txn = db.begin();
+        txn.put(b"c", b"3");
+        txn.rollback();
+        {
+            let mut txn = db.begin();
+            txn.put(b"d", b"4");
+        }
+        drop(db);
+
+        let db = Db::open(&dir)?;
+        assert_eq!(db.get(b"a")?, None);
+        assert_eq!(db.get(b"b")?, Some(b"2".to_vec()));
+        assert_eq!(db.get(b"c")?, None);
+        assert_eq!(db.get(b"d")?, None);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_snapshot_reads() -> Result<()> {
+        let db = Db::open_temp()?;
+        db.put(b"k", b"old")?;
+        let snapshot = db.snapshot();
+        db.put(b"k", b"new")?;
+        db.put(b"added", b"later")?;
+        db.flush()?;
+
+        assert_eq!(db.get(b"k")?, Some(b"new".to_vec()));
+        assert_eq!(db.get_at(&snapshot, b"k")?, Some(b"old".to_vec()));
+        assert_eq!(db.get_at(&snapshot, b"added")?, None);
+        drop(snapshot);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_concurrent_readers_and_writers() -> Result<()> {
+        let db = Db::open_temp()?;
+        let writers: Vec<_> = (0..4u8).map(|thread| {
+            let db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..100u8 {
+                    db.put(&[thread, i], &[thread, i, 0xaa])?;
+                }
+                Ok(())
+            })
+        }).collect();
+        let readers: Vec<_> = (0..4u8).map(|thread| {
+            let db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..100u8 {
+                    // Either not written yet or fully written
+                    if let Some(value) = db.get(&[thread, i])? {
+                        assert_eq!(value, vec![thread, i, 0xaa]);
+                    }
+                    for entry in db.scan_prefix(&[thread]) {
+                        let (key, value) = entry?;
+                        assert_eq!(&value[..2], &key[..]);
+                    }
+                }
+                Ok(())
+            })
+        }).collect();
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().map_err(|_| "thread panicked")??;
+        }
+
+        assert_eq!(db.iter().count(), 400);
+        let other = db.clone();
+        assert!(db.clone().destroy().is_err());
+        drop(other);
+        db.destroy()
+    }
+
+    #[test]
+    fn test_values_span_blocks() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        let value = vec![42; 1000];
+        for key in 0..20u64 {
+            db.put(&key.to_be_bytes(), &value)?;
+        }
+        // Larger than a block
+        db.put(b"large", &vec![7; 5000])?;
+        drop(db);
+
+        let db = Db::open(&dir)?;
+        for key in 0..20u64 {
+            assert_eq!(db.get(&key.to_be_bytes())?, Some(value.clone()));
+        }
+        assert_eq!(db.get(b"large")?, Some(vec![7; 5000]));
+        db.compact()?;
+        assert_eq!(db.get(b"large")?, Some(vec![7; 5000]));
+        drop(db);
+
+        let db = DbOptions::new().path(&dir).max_value_size(4096).open()?;
+        assert!(db.put(b"too large", &vec![0; 5000]).is_err());
+        db.destroy()
+    }
+
+    #[test]
+    fn test_block_size() -> Result<()> {
+        let dir = TempDir::new()?;
+        let data_len = || std::fs::metadata(dir.path().join("data.db")).map(|metadata| metadata.len());
+        let db = DbOptions::new().path(&dir).block_size(16 * 1024).open()?;
+        db.put(b"a", &vec![1; 10_000])?;
+        db.put(b"b", &vec![2; 10_000])?;
+        db.flush()?;
+        assert_eq!(data_len()?, 2 * 16 * 1024);
+        drop(db);
+
+        // The size the database was created with wins over the options
+        let db = Db::open(&dir)?;
+        assert_eq!(db.get(b"a")?, Some(vec![1; 10_000]));
+        db.put(b"c", &vec![3; 10_000])?;
+        db.flush()?;
+        assert_eq!(data_len()?, 3 * 16 * 1024);
+        db.compact()?;
+        assert_eq!(db.get(b"c")?, Some(vec![3; 10_000]));
+        assert_eq!(data_len()? % (16 * 1024), 0);
+        drop(db);
+
+        for invalid in [0, 1000, 256, 64 * 1024] {
+            assert!(DbOptions::new().path(&dir).block_size(invalid).open().is_err());
+        }
+        Db::open(&dir)?.destroy()
+    }
+
+    // Operation of the model based test, keys are drawn from a small set so they collide
+    #[derive(Debug, Clone)]
+    enum ModelOp {
+        Put(u8, Vec<u8>),
+        Delete(u8),
+        Flush,
+        Compact,
+        Reopen,
+    }
+
+    fn model_op() -> impl Strategy<Value = ModelOp> {
+        prop_oneof![
+            6 => (0..16u8, prop::collection::vec(any::<u8>(), 0..1500)).prop_map(|(key, value)| ModelOp::Put(key, value)),
+            3 => (0..16u8).prop_map(ModelOp::Delete),
+            1 => Just(ModelOp::Flush),
+            1 => Just(ModelOp::Compact),
+            1 => Just(ModelOp::Reopen),
+        ]
+    }
+
+    // Applies ops to a database and to a BTreeMap, the database must read back the same entries throughout
+    fn check_against_model(ops: &[ModelOp]) -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut db = Db::open(&dir)?;
+        let mut model = std::collections::BTreeMap::new();
+        for op in ops {
+            match op {
+                ModelOp::Put(key, value) => {
+                    db.put(&[*key], value)?;
+                    model.insert(vec![*key], value.clone());
+                }
+                ModelOp::Delete(key) => {
+                    db.delete(&[*key])?;
+                    model.remove([*key].as_slice());
+                }
+                ModelOp::Flush => db.flush()?,
+                ModelOp::Compact => db.compact()?,
+                ModelOp::Reopen => {
+                    drop(db);
+                    db = Db::open(&dir)?;
+                }
+            }
+            for key in 0..16u8 {
+                assert_eq!(db.get(&[key])?.as_ref(), model.get([key].as_slice()), "key {key} after {op:?}");
+            }
+        }
+        let entries = db.iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(entries, model.into_iter().collect::<Vec<_>>());
+        db.destroy()
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_matches_model(ops in prop::collection::vec(model_op(), 1..40)) {
+            check_against_model(&ops).unwrap();
+        }
+    }
+}
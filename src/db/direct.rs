@@ -0,0 +1,172 @@
+use std::fs::File;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::FileExt;
+use crate::db::backend::StorageFile;
+
+// Alignment of the buffers, offsets and lengths of direct I/O. 4 KiB is the logical block size
+// of common devices, and a multiple of the smaller ones.
+pub(crate) const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+// File opened with O_DIRECT. Aligned reads and writes go to the device as they are, others are
+// widened to the aligned range around them through a buffer of its own. Widened writes read the
+// range first and cut off the zeros they padded the end of the file with.
+pub(crate) struct DirectFile {
+    file: File,
+}
+
+impl DirectFile {
+    pub fn new(file: File) -> Self {
+        Self { file }
+    }
+
+    fn is_aligned(bytes: &[u8], offset: u64) -> bool {
+        bytes.as_ptr().align_offset(DIRECT_IO_ALIGNMENT) == 0
+            && bytes.len().is_multiple_of(DIRECT_IO_ALIGNMENT)
+            && offset.is_multiple_of(DIRECT_IO_ALIGNMENT as u64)
+    }
+
+    // The aligned range around len bytes at offset, where it starts and how many bytes of it the
+    // file holds. Bytes past the end of the file are zero.
+    fn read_around(&self, offset: u64, len: usize) -> io::Result<(AlignedBuffer, u64, usize)> {
+        let start = offset - offset % DIRECT_IO_ALIGNMENT as u64;
+        let end = (offset + len as u64).next_multiple_of(DIRECT_IO_ALIGNMENT as u64);
+        let mut buffer = AlignedBuffer::new((end - start) as usize);
+        // Direct reads of a regular file only come up short at its end
+        let filled = loop {
+            match FileExt::read_at(&self.file, &mut buffer, start) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                read => break read?,
+            }
+        };
+        Ok((buffer, start, filled))
+    }
+}
+
+impl StorageFile for DirectFile {
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        if DirectFile::is_aligned(buffer, offset) {
+            return self.file.read_exact_at(buffer, offset);
+        }
+        let (aligned, start, filled) = self.read_around(offset, buffer.len())?;
+        let skip = (offset - start) as usize;
+        if filled < skip + buffer.len() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        buffer.copy_from_slice(&aligned[skip..skip + buffer.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        if DirectFile::is_aligned(data, offset) {
+            return self.file.write_all_at(data, offset);
+        }
+        let len = self.file.metadata()?.len();
+        let (mut aligned, start, _) = self.read_around(offset, data.len())?;
+        let skip = (offset - start) as usize;
+        aligned[skip..skip + data.len()].copy_from_slice(data);
+        self.file.write_all_at(&aligned, start)?;
+        let end = len.max(offset + data.len() as u64);
+        if start + aligned.len() as u64 > end {
+            self.file.set_len(end)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.file.len()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        StorageFile::set_len(&self.file, len)
+    }
+
+    fn try_lock(&self, shared: bool) -> io::Result<bool> {
+        StorageFile::try_lock(&self.file, shared)
+    }
+}
+
+// Zeroed bytes starting at an aligned address, carved out of a slightly larger vector
+struct AlignedBuffer {
+    bytes: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    fn new(len: usize) -> Self {
+        let bytes = vec![0; len + DIRECT_IO_ALIGNMENT];
+        let start = bytes.as_ptr().align_offset(DIRECT_IO_ALIGNMENT);
+        Self { bytes, start, len }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[self.start..self.start + self.len]
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes[self.start..self.start + self.len]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::backend::{FileSystem, OpenMode};
+    use crate::db::options::DbOptions;
+    use crate::db::temp::TempDir;
+    use crate::error::Result;
+
+    #[test]
+    fn test_unaligned_io() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("file");
+        // Buffered, the widening does not depend on O_DIRECT
+        let file = DirectFile::new(FileSystem::open_file(&path, OpenMode::CreateNew)?);
+        file.write_at(&[1; 512], 0)?;
+        file.write_at(&[2; 512], 512)?;
+        assert_eq!(file.len()?, 1024);
+        file.write_at(&[3; 100], 4000)?;
+        assert_eq!(file.len()?, 4100);
+        let mut aligned = AlignedBuffer::new(DIRECT_IO_ALIGNMENT);
+        file.read_at(&mut aligned, 0)?;
+        assert!(aligned[..512].iter().all(|&byte| byte == 1) && aligned[512..1024].iter().all(|&byte| byte == 2));
+        assert!(aligned[1024..4000].iter().all(|&byte| byte == 0) && aligned[4000..].iter().all(|&byte| byte == 3));
+        let mut buffer = [0; 150];
+        file.read_at(&mut buffer, 3950)?;
+        assert_eq!((buffer[49], buffer[50], buffer[149]), (0, 3, 3));
+        let error = file.read_at(&mut buffer, 4000).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(std::fs::read(&path)?.len(), 4100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_direct_io_database() -> Result<()> {
+        let dir = TempDir::new()?;
+        let options = DbOptions::new().path(dir.path()).direct_io(true).block_size(512);
+        let db = options.open()?;
+        for i in 0..200u32 {
+            db.put(&i.to_be_bytes(), &vec![i as u8; 100 + i as usize])?;
+        }
+        db.put(b"large", &vec![7; 10_000])?;
+        db.flush()?;
+        db.compact()?;
+        db.close()?;
+        let db = DbOptions::new().path(dir.path()).open()?;
+        assert_eq!(db.get(&150u32.to_be_bytes())?, Some(vec![150; 250]));
+        assert_eq!(db.get(b"large")?, Some(vec![7; 10_000]));
+        Ok(())
+    }
+}
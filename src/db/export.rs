@@ -0,0 +1,133 @@
+use std::io::{BufRead, Write};
+use crate::db::batch::WriteBatch;
+use crate::db::database::Db;
+use crate::error::{Error, Result};
+
+/// Text format of [`Db::export`] and [`Db::import`].
+///
+/// Keys and values are hex encoded, so any bytes survive the round trip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One `{"key":"…","value":"…"}` object per line.
+    Json,
+    /// A `key,value` header followed by one row per entry.
+    Csv,
+}
+
+// Entries written per batch during import
+const IMPORT_BATCH_SIZE: usize = 1024;
+const CSV_HEADER: &str = "key,value";
+
+impl Db {
+    /// Writes every key/value pair of the default keyspace to `writer` in ascending key order
+    /// and returns the number of entries written.
+    ///
+    /// Values are read one at a time, see [`Db::iter`].
+    pub fn export(&self, mut writer: impl Write, format: Format) -> Result<u64> {
+        if format == Format::Csv {
+            writeln!(writer, "{CSV_HEADER}")?;
+        }
+        let mut count = 0;
+        for entry in self.iter() {
+            let (key, value) = entry?;
+            let (key, value) = (encode_hex(&key), encode_hex(&value));
+            match format {
+                Format::Json => writeln!(writer, r#"{{"key":"{key}","value":"{value}"}}"#)?,
+                Format::Csv => writeln!(writer, "{key},{value}")?,
+            }
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Reads entries written by [`Db::export`] from `reader` and stores them, returning their number.
+    ///
+    /// Entries are written in batches as they are read. A malformed line fails the import with
+    /// the entries before its batch already stored.
+    pub fn import(&self, reader: impl BufRead, format: Format) -> Result<u64> {
+        let mut batch = WriteBatch::new();
+        let mut count = 0;
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || (format == Format::Csv && number == 0 && line == CSV_HEADER) {
+                continue;
+            }
+            let (key, value) = parse_line(line, format)
+                .ok_or_else(|| Error::InvalidFormat(format!("import line {}: {line}", number + 1)))?;
+            batch.put(&key, &value);
+            count += 1;
+            if batch.len() == IMPORT_BATCH_SIZE {
+                self.write(std::mem::take(&mut batch))?;
+            }
+        }
+        self.write(batch)?;
+        Ok(count)
+    }
+}
+
+fn parse_line(line: &str, format: Format) -> Option<(Vec<u8>, Vec<u8>)> {
+    let (key, value) = match format {
+        Format::Json => {
+            let fields = line.strip_prefix('{')?.strip_suffix('}')?;
+            let (key, value) = fields.split_once(',')?;
+            (json_field(key, "key")?, json_field(value, "value")?)
+        }
+        Format::Csv => line.split_once(',')?,
+    };
+    Some((decode_hex(key)?, decode_hex(value)?))
+}
+
+// Value of a `"name":"value"` member, hex strings never contain escapes
+fn json_field<'a>(member: &'a str, name: &str) -> Option<&'a str> {
+    let (field, value) = member.split_once(':')?;
+    if field.trim() != format!("\"{name}\"") {
+        return None;
+    }
+    value.trim().strip_prefix('"')?.strip_suffix('"')
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_import() -> Result<()> {
+        let db = Db::open_temp()?;
+        db.put(b"a", b"1")?;
+        db.put(b"b", &[0, 255, b','])?;
+        db.put(b"", b"empty key")?;
+
+        for format in [Format::Json, Format::Csv] {
+            let mut exported = Vec::new();
+            assert_eq!(db.export(&mut exported, format)?, 3);
+            let copy = Db::open_temp()?;
+            assert_eq!(copy.import(exported.as_slice(), format)?, 3);
+            assert_eq!(copy.iter().collect::<Result<Vec<_>>>()?, db.iter().collect::<Result<Vec<_>>>()?);
+            copy.destroy()?;
+        }
+        let mut json = Vec::new();
+        db.export(&mut json, Format::Json)?;
+        assert!(String::from_utf8(json).unwrap().starts_with(r#"{"key":"","value":"656d707479206b6579"}"#));
+
+        assert!(matches!(db.import(&b"key,value\n61,zz\n"[..], Format::Csv), Err(Error::InvalidFormat(_))));
+        assert!(db.import(&b"{\"value\":\"61\",\"key\":\"61\"}\n"[..], Format::Json).is_err());
+        db.destroy()
+    }
+}
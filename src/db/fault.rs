@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use crate::db::backend::{OpenMode, StorageBackend, StorageFile};
+
+/// Storage backend that wraps another one to inject the failures of a crash, for testing recovery.
+///
+/// Files opened through it are [`FaultyFile`]s. Writes can be torn and syncs made to fail, and
+/// [`crash`](FaultyBackend::crash) drops everything written since the last sync of each file.
+/// Clones share the same faults.
+#[derive(Debug, Clone)]
+pub struct FaultyBackend {
+    inner: Arc<dyn StorageBackend>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+#[derive(Debug, Default)]
+struct Faults {
+    fail_syncs: bool,
+    // Writes that succeed before one is torn
+    writes_before_tear: Option<usize>,
+    // Set by a torn write, the process is considered dead until the crash
+    dead: bool,
+    // Length of each file at its last sync, what survives a crash
+    synced: HashMap<PathBuf, u64>,
+}
+
+/// A file of a [`FaultyBackend`].
+pub struct FaultyFile {
+    inner: Arc<dyn StorageFile>,
+    path: PathBuf,
+    faults: Arc<Mutex<Faults>>,
+}
+
+fn injected(what: &str) -> io::Error {
+    io::Error::other(format!("injected {what} failure"))
+}
+
+impl FaultyBackend {
+    pub fn new(inner: impl StorageBackend + 'static) -> Self {
+        FaultyBackend { inner: Arc::new(inner), faults: Arc::default() }
+    }
+
+    fn faults(&self) -> MutexGuard<'_, Faults> {
+        self.faults.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Makes every sync fail until called again with `false`.
+    pub fn fail_syncs(&self, fail: bool) {
+        self.faults().fail_syncs = fail;
+    }
+
+    /// Tears the write that follows the next `writes` ones: only the first half of its bytes reach
+    /// the file and it fails. Every write and sync after it fails too, until [`crash`](Self::crash).
+    pub fn tear_write_after(&self, writes: usize) {
+        self.faults().writes_before_tear = Some(writes);
+    }
+
+    /// Simulates a power loss, every file is cut back to its length at its last sync.
+    /// Close the database before, and reopen it after to see what recovery makes of it.
+    pub fn crash(&self) -> io::Result<()> {
+        let mut faults = self.faults();
+        for (path, &synced) in &faults.synced {
+            if self.inner.exists(path) {
+                let file = self.inner.open(path, OpenMode::Write)?;
+                if file.len()? > synced {
+                    file.set_len(synced)?;
+                }
+            }
+        }
+        faults.writes_before_tear = None;
+        faults.dead = false;
+        Ok(())
+    }
+}
+
+impl StorageBackend for FaultyBackend {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn StorageFile>> {
+        let inner = self.inner.open(path, mode)?;
+        let mut faults = self.faults();
+        // Files that are not tracked yet were written before the backend could see them
+        let len = inner.len()?;
+        let synced = faults.synced.entry(path.to_path_buf()).or_insert(len);
+        *synced = (*synced).min(len);
+        Ok(Arc::new(FaultyFile { inner, path: path.to_path_buf(), faults: Arc::clone(&self.faults) }))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.is_dir(path)
+    }
+
+    fn list(&self, folder: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.list(folder)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_file(path)?;
+        self.faults().synced.remove(path);
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_dir_all(path)?;
+        self.faults().synced.retain(|file, _| !file.starts_with(path));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)?;
+        let mut faults = self.faults();
+        if let Some(synced) = faults.synced.remove(from) {
+            faults.synced.insert(to.to_path_buf(), synced);
+        }
+        Ok(())
+    }
+
+    fn sync_dir(&self, folder: &Path) -> io::Result<()> {
+        self.inner.sync_dir(folder)
+    }
+}
+
+impl FaultyFile {
+    fn faults(&self) -> MutexGuard<'_, Faults> {
+        self.faults.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl StorageFile for FaultyFile {
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        self.inner.read_at(buffer, offset)
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        let mut faults = self.faults();
+        if faults.dead {
+            return Err(injected("write"));
+        }
+        match faults.writes_before_tear {
+            Some(0) => {
+                faults.dead = true;
+                self.inner.write_at(&data[..data.len() / 2], offset)?;
+                Err(injected("write"))
+            }
+            Some(writes) => {
+                faults.writes_before_tear = Some(writes - 1);
+                self.inner.write_at(data, offset)
+            }
+            None => self.inner.write_at(data, offset),
+        }
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut faults = self.faults();
+        if faults.dead || faults.fail_syncs {
+            return Err(injected("sync"));
+        }
+        self.inner.sync()?;
+        faults.synced.insert(self.path.clone(), self.inner.len()?);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let mut faults = self.faults();
+        if faults.dead {
+            return Err(injected("write"));
+        }
+        self.inner.set_len(len)?;
+        // The bytes cut off do not come back with a crash
+        if let Some(synced) = faults.synced.get_mut(&self.path) {
+            *synced = (*synced).min(len);
+        }
+        Ok(())
+    }
+
+    fn try_lock(&self, shared: bool) -> io::Result<bool> {
+        self.inner.try_lock(shared)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::memory::MemoryBackend;
+    use crate::db::options::DbOptions;
+    use crate::db::sync::SyncPolicy;
+    use crate::error::Result;
+
+    #[test]
+    fn test_torn_writes_keep_acknowledged_writes() -> Result<()> {
+        for writes in 0..40 {
+            let backend = FaultyBackend::new(MemoryBackend::new());
+            let options = DbOptions::new().path("db").backend(backend.clone());
+            let db = options.open()?;
+            db.put(b"before", b"opened")?;
+            backend.tear_write_after(writes);
+            let mut acknowledged = Vec::new();
+            for i in 0..20u8 {
+                let written = match i {
+                    7 => db.flush(),
+                    13 => db.compact(),
+                    _ => db.put(&[i], &[i; 300]),
+                };
+                match written {
+                    Ok(()) => acknowledged.push(i),
+                    Err(_) => break,
+                }
+            }
+            db.crash();
+            backend.crash()?;
+
+            let db = options.open()?;
+            assert_eq!(db.get(b"before")?, Some(b"opened".to_vec()), "tear after {writes} writes");
+            for i in acknowledged.into_iter().filter(|i| ![7, 13].contains(i)) {
+                assert_eq!(db.get(&[i])?, Some(vec![i; 300]), "key {i} with a tear after {writes} writes");
+            }
+            for entry in db.iter() {
+                let (key, value) = entry?;
+                assert!(key == b"before" || value == vec![key[0]; 300]);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_syncs() -> Result<()> {
+        let backend = FaultyBackend::new(MemoryBackend::new());
+        let options = DbOptions::new().path("db").backend(backend.clone());
+        let db = options.open()?;
+        db.put(b"synced", b"1")?;
+        backend.fail_syncs(true);
+        assert!(db.put(b"unsynced", b"2").is_err());
+        assert!(db.flush().is_err());
+        backend.fail_syncs(false);
+        db.crash();
+        backend.crash()?;
+        let db = options.open()?;
+        assert_eq!(db.get(b"synced")?, Some(b"1".to_vec()));
+        assert_eq!(db.get(b"unsynced")?, None);
+        drop(db);
+
+        // Without syncing each write only what a flush synced is kept
+        let db = options.clone().sync(SyncPolicy::Never).open()?;
+        db.put(b"flushed", b"3")?;
+        db.flush()?;
+        db.put(b"lost", b"4")?;
+        db.crash();
+        backend.crash()?;
+        let db = options.open()?;
+        assert_eq!(db.get(b"flushed")?, Some(b"3".to_vec()));
+        assert_eq!(db.get(b"lost")?, None);
+        assert_eq!(db.get(b"synced")?, Some(b"1".to_vec()));
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::ptr;
+use std::slice;
+use crate::db::database::Db;
+use crate::error::{Error, Result};
+
+// C interface of the database, include/cendb.h is generated from this file by build.rs.
+// Calls return one of these status codes, the message of a failure is kept for cendb_last_error.
+pub const CENDB_OK: c_int = 0;
+pub const CENDB_NOT_FOUND: c_int = 1;
+pub const CENDB_ERROR: c_int = -1;
+
+/// Database handle returned by [`cendb_open`], opaque to C.
+pub struct CendbDb {
+    db: Db,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+// Keeps the message of a failed call for cendb_last_error
+fn set_last_error(error: Error) {
+    let message = error.to_string().replace('\0', " ");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).ok());
+}
+
+// Turns the result of a call into its status code
+fn status(result: Result<c_int>) -> c_int {
+    result.unwrap_or_else(|e| {
+        set_last_error(e);
+        CENDB_ERROR
+    })
+}
+
+// A pointer and length from C as a slice, null is allowed for an empty one
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Result<&'a [u8]> {
+    match data.is_null() {
+        true if len == 0 => Ok(&[]),
+        true => Err(Error::Custom("null pointer with a non-zero length".to_string())),
+        false => Ok(slice::from_raw_parts(data, len)),
+    }
+}
+
+unsafe fn handle<'a>(db: *const CendbDb) -> Result<&'a Db> {
+    db.as_ref().map(|handle| &handle.db).ok_or_else(|| Error::Custom("null database handle".to_string()))
+}
+
+/// Opens or creates the database in the folder `path`, a NUL terminated UTF-8 string.
+/// Returns null on failure. The handle is released with [`cendb_close`].
+///
+/// # Safety
+/// `path` must be null or point to a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cendb_open(path: *const c_char) -> *mut CendbDb {
+    let open = || -> Result<Db> {
+        if path.is_null() {
+            return Err("null path".into());
+        }
+        let path = CStr::from_ptr(path).to_str().map_err(|_| "path is not valid UTF-8")?;
+        Db::open(path)
+    };
+    match open() {
+        Ok(db) => Box::into_raw(Box::new(CendbDb { db })),
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Looks up `key`. If found, stores a copy of the value in `*value` and its length in `*value_len`
+/// and returns [`CENDB_OK`], the copy is released with [`cendb_free_value`].
+/// Returns [`CENDB_NOT_FOUND`] without touching them otherwise.
+///
+/// # Safety
+/// `db` must come from [`cendb_open`] and not be closed, `key` must point to `key_len` readable
+/// bytes, `value` and `value_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn cendb_get(
+    db: *const CendbDb,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> c_int {
+    status((|| {
+        if value.is_null() || value_len.is_null() {
+            return Err("null value pointer".into());
+        }
+        match handle(db)?.get(bytes(key, key_len)?)? {
+            Some(found) => {
+                let found = Box::into_raw(found.into_boxed_slice());
+                *value_len = found.len();
+                *value = found.cast();
+                Ok(CENDB_OK)
+            }
+            None => Ok(CENDB_NOT_FOUND),
+        }
+    })())
+}
+
+/// Stores `value` under `key`, returning [`CENDB_OK`] once it is in the WAL.
+///
+/// # Safety
+/// `db` must come from [`cendb_open`] and not be closed, `key` and `value` must point to
+/// `key_len` and `value_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cendb_put(
+    db: *const CendbDb,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> c_int {
+    status((|| {
+        handle(db)?.put(bytes(key, key_len)?, bytes(value, value_len)?)?;
+        Ok(CENDB_OK)
+    })())
+}
+
+/// Releases a value returned by [`cendb_get`].
+///
+/// # Safety
+/// `value` and `value_len` must be a pair written by [`cendb_get`], released once, or `value` null.
+#[no_mangle]
+pub unsafe extern "C" fn cendb_free_value(value: *mut u8, value_len: usize) {
+    if !value.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(value, value_len)));
+    }
+}
+
+/// Closes a database opened by [`cendb_open`]. Null is ignored.
+///
+/// # Safety
+/// `db` must come from [`cendb_open`] and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn cendb_close(db: *mut CendbDb) {
+    if !db.is_null() {
+        drop(Box::from_raw(db));
+    }
+}
+
+/// Message of the last failed call on this thread, or null. The string stays valid until the
+/// next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn cendb_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::temp::TempDir;
+
+    #[test]
+    fn test_ffi() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = CString::new(dir.path().to_string_lossy().into_owned()).map_err(|_| "temp path has a nul byte")?;
+        unsafe {
+            let db = cendb_open(path.as_ptr());
+            assert!(!db.is_null());
+            assert_eq!(cendb_put(db, b"key".as_ptr(), 3, b"value".as_ptr(), 5), CENDB_OK);
+            assert_eq!(cendb_put(db, ptr::null(), 0, b"empty key".as_ptr(), 9), CENDB_OK);
+
+            let (mut value, mut value_len) = (ptr::null_mut(), 0);
+            assert_eq!(cendb_get(db, b"key".as_ptr(), 3, &mut value, &mut value_len), CENDB_OK);
+            assert_eq!(slice::from_raw_parts(value, value_len), b"value");
+            cendb_free_value(value, value_len);
+            assert_eq!(cendb_get(db, b"nope".as_ptr(), 4, &mut value, &mut value_len), CENDB_NOT_FOUND);
+            assert_eq!(cendb_get(db, ptr::null(), 0, &mut value, &mut value_len), CENDB_OK);
+            assert_eq!(slice::from_raw_parts(value, value_len), b"empty key");
+            cendb_free_value(value, value_len);
+
+            assert_eq!(cendb_put(db, ptr::null(), 1, b"v".as_ptr(), 1), CENDB_ERROR);
+            assert!(!cendb_last_error().is_null());
+            // The folder is locked by the open handle
+            assert!(cendb_open(path.as_ptr()).is_null());
+            cendb_close(db);
+        }
+        Ok(())
+    }
+}
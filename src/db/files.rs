@@ -0,0 +1,46 @@
+use std::path::Path;
+use crate::db::backend::{OpenMode, StorageBackend, StorageFile};
+use crate::error::Result;
+
+// Bytes read and written at a time by copies
+const COPY_CHUNK_SIZE: u64 = 1024 * 1024;
+
+// Copies a file and syncs the copy
+pub(crate) fn copy_durably(backend: &dyn StorageBackend, from: &Path, to: &Path) -> Result<()> {
+    let source = backend.open(from, OpenMode::Read)?;
+    let copy = backend.open(to, OpenMode::Truncate)?;
+    copy_bytes(source.as_ref(), copy.as_ref(), source.len()?)?;
+    copy.sync()?;
+    Ok(())
+}
+
+// Copies the first len bytes of from to the start of to
+pub(crate) fn copy_bytes(from: &dyn StorageFile, to: &dyn StorageFile, len: u64) -> Result<()> {
+    let mut buffer = vec![0; COPY_CHUNK_SIZE.min(len) as usize];
+    let mut copied = 0;
+    while copied < len {
+        let chunk = &mut buffer[..(len - copied).min(COPY_CHUNK_SIZE) as usize];
+        from.read_at(chunk, copied)?;
+        to.write_at(chunk, copied)?;
+        copied += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+// Copies the files of a folder and its subfolders into to, except those named in skip
+pub(crate) fn copy_folder(backend: &dyn StorageBackend, from: &Path, to: &Path, skip: &[&str]) -> Result<()> {
+    backend.create_dir_all(to)?;
+    for path in backend.list(from)? {
+        let Some(name) = path.file_name() else { continue };
+        if skip.iter().any(|skipped| name == *skipped) {
+            continue;
+        }
+        if backend.is_dir(&path) {
+            copy_folder(backend, &path, &to.join(name), skip)?;
+        } else {
+            copy_durably(backend, &path, &to.join(name))?;
+        }
+    }
+    backend.sync_dir(to)?;
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use std::path::Path;
+use std::sync::Arc;
+use crate::db::backend::{OpenMode, StorageBackend, StorageFile};
+use crate::db::btree::DEFAULT_BLOCK_SIZE;
+use crate::db::hash::KeyHashState;
+use crate::db::header::{FileHeader, MAP_MAGIC, WAL_MAGIC};
+use crate::db::lookup::LookupTable;
+use crate::db::memory::MemoryBackend;
+use crate::error::Result;
+
+// Entry points of the targets in fuzz/, they parse bytes the way opening a database reads its files.
+// Malformed input must come back as an error, never as a panic.
+
+fn in_memory(path: &Path, bytes: &[u8]) -> Result<Arc<dyn StorageFile>> {
+    let file = MemoryBackend::new().open(path, OpenMode::Create)?;
+    file.write_at(bytes, 0)?;
+    Ok(file)
+}
+
+/// Replays `bytes` as a WAL segment, returning the number of operations read.
+pub fn parse_wal(bytes: &[u8]) -> Result<usize> {
+    let path = Path::new("wal-000001.db");
+    let file = in_memory(path, bytes)?;
+    FileHeader::init_or_validate(file.as_ref(), WAL_MAGIC, DEFAULT_BLOCK_SIZE, false)?;
+    let (operations, _) = LookupTable::get_wal_from_file(file.as_ref(), path, false, 1)?;
+    Ok(operations.len())
+}
+
+/// Loads `bytes` as a map file, then salvages it as a repairing open would, returning the number of keys.
+pub fn parse_map(bytes: &[u8]) -> Result<usize> {
+    let path = Path::new("map.db");
+    let file = in_memory(path, bytes)?;
+    FileHeader::init_or_validate(file.as_ref(), MAP_MAGIC, DEFAULT_BLOCK_SIZE, false)?;
+    let strict = LookupTable::get_map_from_file(file.as_ref(), path, &KeyHashState::default(), false);
+    let (map, _, _, _) = LookupTable::get_map_from_file(file.as_ref(), path, &KeyHashState::default(), true)?;
+    strict?;
+    Ok(map.len())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::batch::WriteBatch;
+    use crate::db::header::HEADER_SIZE;
+    use crate::db::options::DbOptions;
+    use crate::db::temp::TempDir;
+    use std::time::Duration;
+
+    // Truncations and bit flips of real files, a cheap stand-in for the fuzzer that runs with every test
+    #[test]
+    fn test_parsers_reject_mangled_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        // The flush rewrites map.db instead of writing a delta
+        let db = DbOptions::new().path(&dir).max_map_deltas(0).open()?;
+        db.put(b"flushed", b"1")?;
+        db.put_with_ttl(b"expiring", b"2", Duration::from_secs(60))?;
+        db.flush()?;
+        db.put(b"logged", b"3")?;
+        db.write(WriteBatch::new().put(b"a", b"4").delete(b"flushed").clone())?;
+        db.crash();
+        let wal = std::fs::read(dir.path().join("wal-000001.db"))?;
+        let map = std::fs::read(dir.path().join("map.db"))?;
+        assert_eq!(parse_wal(&wal)?, 2);
+        assert_eq!(parse_map(&map)?, 2);
+
+        for (bytes, parse) in [(&wal, parse_wal as fn(&[u8]) -> Result<usize>), (&map, parse_map)] {
+            for len in 0..bytes.len() {
+                let _ = parse(&bytes[..len]);
+            }
+            for bit in 0..bytes.len() * 8 {
+                let mut mangled = bytes.clone();
+                mangled[bit / 8] ^= 1 << (bit % 8);
+                let _ = parse(&mangled);
+            }
+        }
+        // Lengths that point far past the end of the file
+        let mut huge = wal[..HEADER_SIZE].to_vec();
+        huge.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f, 2, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(parse_wal(&huge)?, 0);
+        Ok(())
+    }
+}
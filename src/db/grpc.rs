@@ -0,0 +1,238 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use tonic::{Request, Response, Status};
+use crate::db::async_db::AsyncDb;
+use crate::db::batch::WriteBatch;
+use crate::db::database::Db;
+use crate::db::index::IndexError;
+use crate::error::{Error, Result};
+
+include!(concat!(env!("OUT_DIR"), "/cendb.Cendb.rs"));
+
+pub use cendb_client::CendbClient;
+pub use cendb_server::CendbServer;
+
+// Entries per Scan page when the request sets no limit, and the most it may ask for
+const DEFAULT_SCAN_LIMIT: u32 = 1000;
+const MAX_SCAN_LIMIT: u32 = 10_000;
+
+// Messages of proto/cendb.proto
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetResponse {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    pub value: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PutRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PutResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ScanRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub start: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub prefix: Vec<u8>,
+    #[prost(uint32, tag = "3")]
+    pub limit: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Entry {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ScanResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: Vec<Entry>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub next: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BatchOperation {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub value: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BatchWriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub operations: Vec<BatchOperation>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BatchWriteResponse {}
+
+/// Implementation of the `cendb.Cendb` gRPC service of `proto/cendb.proto` on a database.
+///
+/// Calls run on the tokio blocking thread pool like those of [`AsyncDb`].
+/// Add [`GrpcService::into_server`] to a tonic router, or use [`serve`].
+#[derive(Clone)]
+pub struct GrpcService {
+    db: AsyncDb,
+}
+
+impl GrpcService {
+    pub fn new(db: Db) -> Self {
+        Self { db: AsyncDb::from(db) }
+    }
+
+    pub fn into_server(self) -> CendbServer<Self> {
+        CendbServer::new(self)
+    }
+}
+
+/// Serves `db` over gRPC on `address` until `shutdown` completes.
+pub async fn serve(db: Db, address: SocketAddr, shutdown: impl Future<Output = ()>) -> Result<()> {
+    tonic::transport::Server::builder()
+        .add_service(GrpcService::new(db).into_server())
+        .serve_with_shutdown(address, shutdown)
+        .await
+        .map_err(|e| Error::Custom(format!("gRPC server failed: {e}")))
+}
+
+fn status(error: Error) -> Status {
+    match error {
+        Error::Index(IndexError::ReadOnly) => Status::failed_precondition("database is read-only"),
+        Error::Backpressure => Status::resource_exhausted("too many writes are waiting for a flush"),
+        error => Status::internal(error.to_string()),
+    }
+}
+
+#[tonic::async_trait]
+impl cendb_server::Cendb for GrpcService {
+    async fn get(&self, request: Request<GetRequest>) -> std::result::Result<Response<GetResponse>, Status> {
+        let value = self.db.get(&request.into_inner().key).await.map_err(status)?;
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> std::result::Result<Response<PutResponse>, Status> {
+        let PutRequest { key, value } = request.into_inner();
+        self.db.put(&key, &value).await.map_err(status)?;
+        Ok(Response::new(PutResponse {}))
+    }
+
+    async fn delete(&self, request: Request<DeleteRequest>) -> std::result::Result<Response<DeleteResponse>, Status> {
+        self.db.delete(&request.into_inner().key).await.map_err(status)?;
+        Ok(Response::new(DeleteResponse {}))
+    }
+
+    async fn scan(&self, request: Request<ScanRequest>) -> std::result::Result<Response<ScanResponse>, Status> {
+        let ScanRequest { start, prefix, limit } = request.into_inner();
+        let limit = match limit {
+            0 => DEFAULT_SCAN_LIMIT,
+            limit => limit.min(MAX_SCAN_LIMIT),
+        } as usize;
+        let response = self.db.run(move |db| {
+            let start = start.max(prefix.clone());
+            // One entry past the page tells whether there is a next one
+            let mut entries = db.range(start.as_slice()..)
+                .take_while(|entry| entry.as_ref().map_or(true, |(key, _)| key.starts_with(&prefix)))
+                .take(limit + 1)
+                .map(|entry| entry.map(|(key, value)| Entry { key, value }))
+                .collect::<Result<Vec<_>>>()?;
+            let next = (entries.len() > limit).then(|| entries.pop().map(|entry| entry.key)).flatten();
+            Ok(ScanResponse { entries, next })
+        }).await.map_err(status)?;
+        Ok(Response::new(response))
+    }
+
+    async fn batch_write(
+        &self,
+        request: Request<BatchWriteRequest>,
+    ) -> std::result::Result<Response<BatchWriteResponse>, Status> {
+        let mut batch = WriteBatch::new();
+        for operation in request.into_inner().operations {
+            match operation.value {
+                Some(value) => batch.put(&operation.key, &value),
+                None => batch.delete(&operation.key),
+            };
+        }
+        self.db.run(move |db| db.write(batch)).await.map_err(status)?;
+        Ok(Response::new(BatchWriteResponse {}))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::transport::server::TcpIncoming;
+
+    #[test]
+    fn test_grpc() -> Result<()> {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+        runtime.block_on(async {
+            let db = Db::open_temp()?;
+            let incoming = TcpIncoming::bind("127.0.0.1:0".parse().map_err(|_| "invalid address")?)?;
+            let address = incoming.local_addr()?;
+            let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+            let server = tokio::spawn(
+                tonic::transport::Server::builder()
+                    .add_service(GrpcService::new(db.clone()).into_server())
+                    .serve_with_incoming_shutdown(incoming, async { stopped.await.unwrap_or_default() }),
+            );
+
+            let grpc_error = |e: Status| Error::Custom(e.to_string());
+            let mut client = CendbClient::connect(format!("http://{address}")).await
+                .map_err(|e| Error::Custom(e.to_string()))?;
+            client.put(PutRequest { key: b"a".to_vec(), value: b"1".to_vec() }).await.map_err(grpc_error)?;
+            let operations = vec![
+                BatchOperation { key: b"b1".to_vec(), value: Some(b"2".to_vec()) },
+                BatchOperation { key: b"b2".to_vec(), value: Some(b"3".to_vec()) },
+                BatchOperation { key: b"a".to_vec(), value: None },
+            ];
+            client.batch_write(BatchWriteRequest { operations }).await.map_err(grpc_error)?;
+            client.put(PutRequest { key: b"c".to_vec(), value: b"4".to_vec() }).await.map_err(grpc_error)?;
+            client.delete(DeleteRequest { key: b"c".to_vec() }).await.map_err(grpc_error)?;
+
+            let get = client.get(GetRequest { key: b"b1".to_vec() }).await.map_err(grpc_error)?;
+            assert_eq!(get.into_inner().value, Some(b"2".to_vec()));
+            let get = client.get(GetRequest { key: b"a".to_vec() }).await.map_err(grpc_error)?;
+            assert_eq!(get.into_inner().value, None);
+
+            let scan = ScanRequest { start: Vec::new(), prefix: b"b".to_vec(), limit: 1 };
+            let page = client.scan(scan).await.map_err(grpc_error)?.into_inner();
+            assert_eq!(page.entries, [Entry { key: b"b1".to_vec(), value: b"2".to_vec() }]);
+            assert_eq!(page.next, Some(b"b2".to_vec()));
+            let scan = ScanRequest { start: b"b2".to_vec(), prefix: b"b".to_vec(), limit: 1 };
+            let page = client.scan(scan).await.map_err(grpc_error)?.into_inner();
+            assert_eq!((page.entries.len(), page.next), (1, None));
+
+            drop(client);
+            let _ = stop.send(());
+            server.await.map_err(|e| Error::Custom(e.to_string()))?.map_err(|e| Error::Custom(e.to_string()))?;
+            db.destroy()
+        })
+    }
+}
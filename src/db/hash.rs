@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hasher};
+
+/// Hash function of the in-memory tables that map keys to their entries, see
+/// [`DbOptions::hasher`](crate::DbOptions::hasher).
+///
+/// Only affects memory, the files are the same whichever function built them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum KeyHasher {
+    /// SipHash with random keys, the hash function of the standard library. Keys chosen by
+    /// untrusted clients can't be crafted to collide.
+    #[default]
+    SipHash,
+    /// FxHash, the hash function of the Rust compiler. Several times faster on short keys, but
+    /// keys can be crafted to collide and slow every lookup down.
+    Fx,
+}
+
+// Map from keys hashed with the function of the table
+pub(crate) type KeyMap<V> = HashMap<Vec<u8>, V, KeyHashState>;
+
+// Builds the hashers of a KeyMap. The default is SipHash, maps collected without a state of their
+// own use it.
+#[derive(Debug, Clone)]
+pub(crate) enum KeyHashState {
+    SipHash(RandomState),
+    Fx,
+}
+
+impl KeyHashState {
+    pub(crate) fn new(hasher: KeyHasher) -> Self {
+        match hasher {
+            KeyHasher::SipHash => KeyHashState::SipHash(RandomState::new()),
+            KeyHasher::Fx => KeyHashState::Fx,
+        }
+    }
+}
+
+impl Default for KeyHashState {
+    fn default() -> Self {
+        KeyHashState::new(KeyHasher::default())
+    }
+}
+
+impl BuildHasher for KeyHashState {
+    type Hasher = KeyHash;
+
+    fn build_hasher(&self) -> KeyHash {
+        match self {
+            KeyHashState::SipHash(state) => KeyHash::SipHash(state.build_hasher()),
+            KeyHashState::Fx => KeyHash::Fx(FxHasher::default()),
+        }
+    }
+}
+
+pub(crate) enum KeyHash {
+    SipHash(DefaultHasher),
+    Fx(FxHasher),
+}
+
+impl Hasher for KeyHash {
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            KeyHash::SipHash(hasher) => hasher.write(bytes),
+            KeyHash::Fx(hasher) => hasher.write(bytes),
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        match self {
+            KeyHash::SipHash(hasher) => hasher.finish(),
+            KeyHash::Fx(hasher) => hasher.finish(),
+        }
+    }
+}
+
+// Mixes in a word at a time by rotating, xoring and multiplying with a fixed odd constant,
+// as the 64 bit FxHasher of rustc does
+#[derive(Default)]
+pub(crate) struct FxHasher {
+    hash: u64,
+}
+
+const FX_SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+impl FxHasher {
+    fn add(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(FX_SEED);
+    }
+}
+
+impl Hasher for FxHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            self.add(u64::from_le_bytes(word.try_into().unwrap()));
+        }
+        let mut rest = words.remainder();
+        if rest.len() >= 4 {
+            self.add(u32::from_le_bytes(rest[..4].try_into().unwrap()) as u64);
+            rest = &rest[4..];
+        }
+        for &byte in rest {
+            self.add(byte as u64);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::options::DbOptions;
+    use crate::db::temp::TempDir;
+    use crate::error::Result;
+
+    #[test]
+    fn test_key_map() {
+        for hasher in [KeyHasher::SipHash, KeyHasher::Fx] {
+            let mut map = KeyMap::with_hasher(KeyHashState::new(hasher));
+            for i in 0..1000u32 {
+                map.insert(i.to_be_bytes().to_vec(), i);
+            }
+            assert!((0..1000u32).all(|i| map.get(&i.to_be_bytes()[..]) == Some(&i)));
+        }
+        let state = KeyHashState::new(KeyHasher::Fx);
+        assert_eq!(state.hash_one(b"key"), state.hash_one(b"key"));
+        assert_ne!(state.hash_one(b"key"), state.hash_one(b"kez"));
+    }
+
+    #[test]
+    fn test_fx_database() -> Result<()> {
+        let dir = TempDir::new()?;
+        let options = DbOptions::new().path(dir.path()).hasher(KeyHasher::Fx);
+        let db = options.open()?;
+        db.put(b"a", b"1")?;
+        db.flush()?;
+        db.put(b"b", b"2")?;
+        db.close()?;
+        // Written with one hash function, read with another
+        let db = DbOptions::new().path(dir.path()).open()?;
+        assert_eq!((db.get(b"a")?, db.get(b"b")?), (Some(b"1".to_vec()), Some(b"2".to_vec())));
+        Ok(())
+    }
+}
@@ -0,0 +1,841 @@
+use std::collections::{BTreeSet, HashSet};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::db::backend::{OpenMode, StorageBackend, StorageFile};
+use crate::db::bloom::BloomFilter;
+use crate::db::engine::{EngineIter, StorageEngine};
+use crate::db::hash::{KeyHashState, KeyMap};
+use crate::db::header::{FileHeader, HEADER_SIZE, MAP_MAGIC};
+use crate::db::lookup::{
+    EntryLocation, IndexEntry, KeyIndex, LookupTable, MapError, SortedMap, Tombstone,
+    BLOOM_FILE_NAME, CHECKSUM_SIZE, DELTA_PUT, DELTA_REMOVE, EXACT_RANGE_COUNT, EXPIRY_SIZE, KEY_SAMPLES, LOCATION_SIZE,
+    LSN_SIZE, MAP_FILE_NAME, TOMBSTONES_VERSION, TOMBSTONE_BLOCK,
+};
+use crate::db::lsm::LsmIndex;
+use crate::db::options::{DbOptions, LogLevel};
+use crate::db::recovery::RecoveryReport;
+use crate::db::trace::{event, warning};
+use crate::error::{Error, Result};
+
+// Key index of Engine::Hash that holds every entry in memory, in a hash map for point lookups and
+// an ordered set of the keys for scans. map.db holds all of them, a flush only writes the entries
+// that changed since the last one to a delta file next to it.
+pub(crate) struct HashIndex {
+    backend: Arc<dyn StorageBackend>,
+    folder: PathBuf,
+    map_file: Arc<dyn StorageFile>,
+    map_path: PathBuf,
+    // Numbers of the delta files written on top of map.db, in the order they apply
+    map_deltas: Vec<u64>,
+    // Bytes of map.db and of its deltas, the map is rewritten in full once the deltas outgrow it
+    map_size: u64,
+    map_deltas_size: u64,
+    max_map_deltas: usize,
+    // Block size of data.db, recorded in the header of every file the index writes
+    block_size: usize,
+    log_level: LogLevel,
+    // Keys put or deleted since the last flush, the entries a delta holds
+    dirty: HashSet<Vec<u8>>,
+    map: KeyMap<EntryLocation>,
+    // Every key of map in sorted order, used for range scans, and their summed length
+    keys: BTreeSet<Vec<u8>>,
+    key_bytes: usize,
+    // Keys spread evenly over keys, range counts are estimated from them. Taken again once the
+    // number of keys changed by half since.
+    key_samples: Vec<Vec<u8>>,
+    sampled_keys: usize,
+    // Expiry time in milliseconds since the Unix epoch of the keys that were written with a TTL
+    expiries: KeyMap<u64>,
+    // Removed keys, never in map at the same time
+    tombstones: KeyMap<Tombstone>,
+    // Only kept when DbOptions::bloom_filter sets a false positive rate
+    bloom: Option<BloomFilter>,
+    bloom_rate: Option<f64>,
+    // map.db of a low-memory handle, searched on disk for point lookups until load reads it.
+    // Until then map, expiries and tombstones only hold the entries of the deltas, keys none.
+    base: Option<SortedMap>,
+    // Sequence number of the last record committed, and of the last one the files cover
+    lsn: u64,
+    flushed_lsn: u64,
+}
+
+// Entries of a map delta with the sequence number of the last record it covers
+type MapDelta = (Vec<(Vec<u8>, IndexEntry)>, u64);
+
+impl HashIndex {
+    // Reads map.db and its deltas. With lazy set map.db is searched on disk instead, see base.
+    pub fn open(folder: &Path, options: &DbOptions, lazy: bool, recovery: &mut RecoveryReport) -> Result<Self> {
+        let backend = options.backend.as_ref();
+        let map_path = folder.join(MAP_FILE_NAME);
+        let mode = if options.read_only { OpenMode::Read } else { OpenMode::Create };
+        let hasher = KeyHashState::new(options.hasher);
+        let map_file = backend.open(&map_path, mode).map_err(MapError::io(&map_path, "open"))?;
+        if FileHeader::init_or_validate(map_file.as_ref(), MAP_MAGIC, options.block_size, options.read_only)? {
+            recovery.repaired_files.push(map_path.clone());
+        }
+        let base = match lazy {
+            true => SortedMap::open(Arc::clone(&map_file), &map_path)?,
+            false => None,
+        };
+        let loaded = match &base {
+            Some(base) => Ok((KeyMap::with_hasher(hasher.clone()), KeyMap::with_hasher(hasher.clone()), KeyMap::with_hasher(hasher.clone()), base.lsn)),
+            None => LookupTable::get_map_from_file(map_file.as_ref(), &map_path, &hasher, false),
+        };
+        let (mut map, mut expiries, mut tombstones, mut map_lsn, mut map_rebuilt) = match loaded {
+            Err(Error::Map(error @ (MapError::ChecksumMismatch{..} | MapError::CorruptRecord{..})))
+                if options.repair && !options.read_only =>
+            {
+                warning!(options.log_level, path = %map_path.display(), error = %error; "rebuilding corrupt map from the readable entries and the WAL");
+                let (map, expiries, tombstones, map_lsn) = LookupTable::get_map_from_file(map_file.as_ref(), &map_path, &hasher, true)?;
+                (map, expiries, tombstones, map_lsn, true)
+            }
+            result => {
+                let (map, expiries, tombstones, map_lsn) = result?;
+                (map, expiries, tombstones, map_lsn, false)
+            }
+        };
+        let map_size = backend.file_len(&map_path)?;
+        let base_lsn = map_lsn;
+        let mut map_deltas = Vec::new();
+        let mut map_deltas_size = 0;
+        // Runs of Engine::Lsm apply like deltas, map.db is rewritten without them right away
+        let runs = LsmIndex::run_files(backend, folder)?;
+        let converted = !runs.is_empty();
+        let files = runs.into_iter().map(|(_, path)| (None, path))
+            .chain(HashIndex::map_delta_files(backend, folder)?.into_iter().map(|(number, path)| (Some(number), path)));
+        for (number, path) in files {
+            let read = match number {
+                Some(_) => HashIndex::read_map_delta(backend, &path),
+                None => LsmIndex::read_run(backend, &path),
+            };
+            let delta = match read {
+                // Left behind by a crash before a full rewrite removed it, map.db already holds it
+                Ok((_, lsn)) if lsn <= base_lsn && !map_rebuilt => {
+                    if !options.read_only {
+                        backend.remove_file(&path)?;
+                        recovery.repaired_files.push(path);
+                    }
+                    continue;
+                }
+                Err(Error::Map(error @ (MapError::ChecksumMismatch{..} | MapError::CorruptRecord{..})))
+                    if options.repair && !options.read_only =>
+                {
+                    // The deltas after it are dropped with it by the full rewrite, the WAL is replayed from the start
+                    warning!(options.log_level, path = %path.display(), error = %error; "rebuilding map without a corrupt delta or run and the files after it");
+                    map_rebuilt = true;
+                    map_lsn = 0;
+                    break;
+                }
+                delta => delta?,
+            };
+            let (entries, lsn) = delta;
+            for (key, entry) in entries {
+                match entry {
+                    IndexEntry::Put(location, expires_at) => {
+                        match expires_at {
+                            0 => expiries.remove(&key),
+                            _ => expiries.insert(key.clone(), expires_at),
+                        };
+                        tombstones.remove(&key);
+                        map.insert(key, location);
+                    }
+                    IndexEntry::Remove(tombstone) => {
+                        expiries.remove(&key);
+                        map.remove(&key);
+                        // Keys deleted outright only have to hide the keys of map.db a low-memory handle searches
+                        match tombstone != Tombstone::DELETED || base.is_some() {
+                            true => tombstones.insert(key, tombstone),
+                            false => tombstones.remove(&key),
+                        };
+                    }
+                }
+            }
+            // A salvaged map.db may have lost records the deltas don't repeat
+            if !map_rebuilt {
+                map_lsn = lsn;
+            }
+            if let Some(number) = number {
+                map_deltas.push(number);
+                map_deltas_size += backend.file_len(&path)?;
+            }
+        }
+        if map_rebuilt {
+            recovery.map_rebuilt = true;
+            recovery.repaired_files.push(map_path.clone());
+        }
+        let keys = map.keys().cloned().collect();
+        let key_bytes = map.keys().map(Vec::len).sum();
+        let bloom = options.bloom_false_positive_rate.and_then(|rate| match &base {
+            // There are no keys to build a filter from yet, a saved one is used if it fits
+            Some(base) => BloomFilter::load(backend, &folder.join(BLOOM_FILE_NAME))
+                .filter(|bloom| bloom.fits(base.keys + map.len(), rate)),
+            None => Some(HashIndex::load_bloom_filter(backend, folder, &map, rate)),
+        });
+        let mut index = HashIndex {
+            backend: Arc::clone(&options.backend), folder: folder.to_path_buf(),
+            map_file, map_path, map_deltas, map_size, map_deltas_size, max_map_deltas: options.max_map_deltas,
+            block_size: options.block_size, log_level: options.log_level,
+            dirty: HashSet::new(), map, keys, key_bytes, key_samples: Vec::new(), sampled_keys: 0, expiries, tombstones,
+            bloom, bloom_rate: options.bloom_false_positive_rate, base, lsn: map_lsn, flushed_lsn: map_lsn,
+        };
+        index.sample_keys();
+        // A rebuilt map is written in full once the WAL is replayed
+        if converted && !options.read_only && !map_rebuilt {
+            index.persist(true)?;
+        }
+        Ok(index)
+    }
+
+    // Utility function to delete the deltas and the bloom filter in folder, map.db is left to the table
+    pub fn cleanup(backend: &dyn StorageBackend, folder: &Path) -> Result<()> {
+        for (_, path) in HashIndex::map_delta_files(backend, folder)? {
+            backend.remove_file(&path)?;
+        }
+        let bloom_path = folder.join(BLOOM_FILE_NAME);
+        if backend.exists(&bloom_path) {
+            backend.remove_file(&bloom_path)?;
+        }
+        Ok(())
+    }
+
+    // Removed keys stay in a persisted filter until the next full rewrite of the map, only false positives
+    // come from that. A filter too small for the map is rebuilt.
+    fn load_bloom_filter(
+        backend: &dyn StorageBackend,
+        folder: &Path,
+        map: &KeyMap<EntryLocation>,
+        rate: f64,
+    ) -> BloomFilter {
+        match BloomFilter::load(backend, &folder.join(BLOOM_FILE_NAME)) {
+            Some(bloom) if bloom.fits(map.len(), rate) => bloom,
+            _ => BloomFilter::from_keys(map.keys(), rate),
+        }
+    }
+
+    fn insert_location(&mut self, key: &[u8], location: EntryLocation) {
+        if self.map.insert(key.to_vec(), location).is_none() {
+            self.keys.insert(key.to_vec());
+            self.key_bytes += key.len();
+            if let Some(bloom) = &mut self.bloom {
+                bloom.insert(key);
+            }
+        }
+    }
+
+    fn remove_location(&mut self, key: &[u8]) {
+        if self.map.remove(key).is_some() {
+            self.keys.remove(key);
+            self.key_bytes -= key.len();
+        }
+    }
+
+    fn entry_of(&self, key: &[u8], location: EntryLocation) -> IndexEntry {
+        IndexEntry::Put(location, self.expiries.get(key).copied().unwrap_or(0))
+    }
+
+    // Every entry as a record of map.db, in key order
+    fn map_records(&self) -> Vec<(&[u8], EntryLocation, u64)> {
+        let mut records: Vec<_> = self.map.iter()
+            .map(|(key, location)| (key.as_slice(), *location, self.expiries.get(key).copied().unwrap_or(0)))
+            .chain(self.tombstones.iter()
+                .filter(|(_, tombstone)| **tombstone != Tombstone::DELETED)
+                .map(|(key, tombstone)| (key.as_slice(), tombstone.location(), tombstone.deleted_at)))
+            .collect();
+        records.sort_unstable_by_key(|(key, _, _)| *key);
+        records
+    }
+
+    // Writes the map with its deltas or in full, see flush and compact
+    fn persist(&mut self, rewrite: bool) -> Result<()> {
+        // A delta only persists the entries that changed since the last flush, map.db is rewritten
+        // once there are max_map_deltas of them or they take more space than the map itself
+        let full = rewrite || self.map_deltas.len() >= self.max_map_deltas || self.map_deltas_size > self.map_size;
+        // Saved before the map, so a persisted filter always covers every key of map.db and its deltas.
+        // Without a filter a stale one from an earlier open would miss the keys flushed now.
+        let backend = Arc::clone(&self.backend);
+        let bloom_path = self.folder.join(BLOOM_FILE_NAME);
+        match (&self.bloom, self.bloom_rate) {
+            (_, Some(rate)) if full => {
+                let bloom = BloomFilter::from_keys(self.map.keys(), rate);
+                bloom.save(backend.as_ref(), &bloom_path, self.block_size)?;
+                self.bloom = Some(bloom);
+            }
+            (Some(bloom), _) if !self.dirty.is_empty() => bloom.save(backend.as_ref(), &bloom_path, self.block_size)?,
+            (_, Some(_)) => {}
+            (_, None) if backend.exists(&bloom_path) => backend.remove_file(&bloom_path)?,
+            (_, None) => {}
+        }
+        if full {
+            self.map_file = HashIndex::write_map_to_file(backend.as_ref(), &self.map_path, self.map_records(), self.lsn, self.block_size)?;
+            self.map_size = backend.file_len(&self.map_path)?;
+            self.remove_map_deltas()?;
+        } else if !self.dirty.is_empty() || self.flushed_lsn < self.lsn {
+            // Even without changed keys, so map.db and its deltas always cover the records up to flushed_lsn
+            let number = self.map_deltas.last().map_or(1, |number| number + 1);
+            let path = HashIndex::map_delta_path(&self.folder, number);
+            self.map_deltas_size += self.write_map_delta(&path)?;
+            self.map_deltas.push(number);
+        }
+        self.dirty.clear();
+        self.flushed_lsn = self.lsn;
+        self.resample_keys();
+        event!(self.log_level, DEBUG, keys = self.map.len(), full, "flushed hash index");
+        Ok(())
+    }
+
+    fn tmp_map_path(map_path: &Path) -> PathBuf {
+        map_path.with_extension("db.tmp")
+    }
+
+    // Writes the records to map.db.tmp and atomically renames it over map.db, so a crash
+    // at any point leaves either the complete old or the complete new map behind
+    fn write_map_to_file(
+        backend: &dyn StorageBackend,
+        map_path: &Path,
+        records: Vec<(&[u8], EntryLocation, u64)>,
+        lsn: u64,
+        block_size: usize,
+    ) -> Result<Arc<dyn StorageFile>> {
+        let tmp_path = HashIndex::tmp_map_path(map_path);
+        let file = LookupTable::write_map_file(backend, &tmp_path, records, lsn, block_size)?;
+        backend.rename(&tmp_path, map_path).map_err(MapError::io(map_path, "rename"))?;
+        Ok(file)
+    }
+
+    // Writes the entries of the dirty keys to map-<number>.db.tmp and renames it into place,
+    // returning the size of the delta
+    fn write_map_delta(&self, path: &Path) -> Result<u64> {
+        // In key order, so deltas are sorted runs that merge like the map
+        let mut keys: Vec<&Vec<u8>> = self.dirty.iter().collect();
+        keys.sort_unstable();
+        let entries = keys.into_iter().map(|key| {
+            let entry = match self.map.get(key) {
+                Some(location) => self.entry_of(key, *location),
+                // A key deleted since still has to be hidden in the files before
+                None => IndexEntry::Remove(self.tombstones.get(key).copied().unwrap_or(Tombstone::DELETED)),
+            };
+            (key.as_slice(), entry)
+        });
+        let buffer = HashIndex::encode_map_delta(entries, self.lsn, self.block_size);
+        let tmp_path = HashIndex::tmp_map_path(path);
+        let file = self.backend.open(&tmp_path, OpenMode::Truncate).map_err(MapError::io(&tmp_path, "create"))?;
+        file.write_at(&buffer, 0).map_err(MapError::io(&tmp_path, "write"))?;
+        file.sync().map_err(MapError::io(&tmp_path, "sync"))?;
+        self.backend.rename(&tmp_path, path).map_err(MapError::io(path, "rename"))?;
+        self.backend.sync_dir(&self.folder)?;
+        Ok(buffer.len() as u64)
+    }
+
+    fn encode_map_delta<'a>(entries: impl Iterator<Item = (&'a [u8], IndexEntry)>, lsn: u64, block_size: usize) -> Vec<u8> {
+        let mut buffer = FileHeader::new(MAP_MAGIC, block_size).encode().to_vec();
+        buffer.extend_from_slice(&lsn.to_le_bytes());
+        for (key, entry) in entries {
+            LookupTable::encode_key(&mut buffer, key);
+            match entry {
+                IndexEntry::Put(location, expires_at) => {
+                    buffer.push(DELTA_PUT);
+                    LookupTable::encode_location(&mut buffer, &location);
+                    buffer.extend_from_slice(&expires_at.to_le_bytes());
+                }
+                IndexEntry::Remove(tombstone) => {
+                    buffer.push(DELTA_REMOVE);
+                    buffer.extend_from_slice(&tombstone.lsn.to_le_bytes());
+                    buffer.extend_from_slice(&tombstone.deleted_at.to_le_bytes());
+                }
+            }
+        }
+        let checksum = crc32fast::hash(&buffer);
+        buffer.extend_from_slice(&checksum.to_le_bytes());
+        buffer
+    }
+
+    pub(crate) fn read_map_delta(backend: &dyn StorageBackend, path: &Path) -> Result<MapDelta> {
+        let buffer = backend.read(path).map_err(MapError::io(path, "read"))?;
+        let version = FileHeader::decode(&buffer, MAP_MAGIC)?.version();
+        let end = buffer.len().saturating_sub(CHECKSUM_SIZE).max(HEADER_SIZE);
+        let valid = buffer.get(end..).and_then(|bytes| bytes.try_into().ok())
+            .is_some_and(|checksum| crc32fast::hash(&buffer[..end]) == u32::from_le_bytes(checksum));
+        if !valid {
+            return Err(Error::Map(MapError::ChecksumMismatch { path: path.to_path_buf() }));
+        }
+        let records = &buffer[..end];
+        let corrupt = |offset| Error::Map(MapError::CorruptRecord { path: path.to_path_buf(), offset });
+        let lsn_bytes = records.get(HEADER_SIZE..HEADER_SIZE + LSN_SIZE).ok_or(corrupt(HEADER_SIZE))?;
+        let lsn = u64::from_le_bytes(lsn_bytes.try_into()?);
+        let mut entries = Vec::new();
+        let mut offset = HEADER_SIZE + LSN_SIZE;
+        while offset < records.len() {
+            let (key, next) = LookupTable::read_key(records, offset).ok_or(corrupt(offset))?;
+            match records.get(next) {
+                Some(&DELTA_PUT) => {
+                    let location = LookupTable::read_location(records, next + 1).ok_or(corrupt(offset))?;
+                    let expiry_start = next + 1 + LOCATION_SIZE;
+                    let expiry_bytes = records.get(expiry_start..expiry_start + EXPIRY_SIZE).ok_or(corrupt(offset))?;
+                    entries.push((key, IndexEntry::Put(location, u64::from_le_bytes(expiry_bytes.try_into()?))));
+                    offset = expiry_start + EXPIRY_SIZE;
+                }
+                Some(&DELTA_REMOVE) if version >= TOMBSTONES_VERSION => {
+                    let fields = records.get(next + 1..next + 1 + LSN_SIZE + EXPIRY_SIZE).ok_or(corrupt(offset))?;
+                    let tombstone = Tombstone {
+                        lsn: u64::from_le_bytes(fields[..LSN_SIZE].try_into()?),
+                        deleted_at: u64::from_le_bytes(fields[LSN_SIZE..].try_into()?),
+                    };
+                    entries.push((key, IndexEntry::Remove(tombstone)));
+                    offset = next + 1 + LSN_SIZE + EXPIRY_SIZE;
+                }
+                // Removes of older versions have no time, their tombstones are past any retention
+                Some(&DELTA_REMOVE) => {
+                    entries.push((key, IndexEntry::Remove(Tombstone { lsn, deleted_at: 0 })));
+                    offset = next + 1;
+                }
+                _ => return Err(corrupt(offset)),
+            }
+        }
+        Ok((entries, lsn))
+    }
+
+    // Removes every delta, and the runs of Engine::Lsm, called once map.db holds their entries
+    fn remove_map_deltas(&mut self) -> Result<()> {
+        for (_, path) in HashIndex::map_delta_files(self.backend.as_ref(), &self.folder)? {
+            self.backend.remove_file(&path)?;
+        }
+        LsmIndex::cleanup(self.backend.as_ref(), &self.folder)?;
+        self.map_deltas.clear();
+        self.map_deltas_size = 0;
+        Ok(())
+    }
+
+    fn map_delta_path(folder: &Path, number: u64) -> PathBuf {
+        folder.join(format!("map-{number:06}.db"))
+    }
+
+    // Map deltas in folder in the order they apply
+    pub(crate) fn map_delta_files(backend: &dyn StorageBackend, folder: &Path) -> Result<Vec<(u64, PathBuf)>> {
+        let mut deltas = Vec::new();
+        if !backend.exists(folder) {
+            return Ok(deltas);
+        }
+        for path in backend.list(folder)? {
+            let number = path.file_name().and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("map-"))
+                .and_then(|rest| rest.strip_suffix(".db"))
+                .and_then(|number| number.parse().ok());
+            if let Some(number) = number {
+                deltas.push((number, path));
+            }
+        }
+        deltas.sort();
+        Ok(deltas)
+    }
+
+    fn sample_keys(&mut self) {
+        let step = self.keys.len().div_ceil(KEY_SAMPLES).max(1);
+        self.key_samples = self.keys.iter().step_by(step).cloned().collect();
+        self.sampled_keys = self.keys.len();
+    }
+
+    fn resample_keys(&mut self) {
+        if self.keys.len().abs_diff(self.sampled_keys) > self.sampled_keys / 2 {
+            self.sample_keys();
+        }
+    }
+}
+
+impl StorageEngine for HashIndex {
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        match IndexEntry::decode(value)? {
+            IndexEntry::Put(location, expires_at) => {
+                match expires_at {
+                    0 => self.expiries.remove(key),
+                    _ => self.expiries.insert(key.to_vec(), expires_at),
+                };
+                self.tombstones.remove(key);
+                self.insert_location(key, location);
+            }
+            IndexEntry::Remove(tombstone) => {
+                self.expiries.remove(key);
+                self.remove_location(key);
+                self.tombstones.insert(key.to_vec(), tombstone);
+            }
+        }
+        self.dirty.insert(key.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(tombstone) = self.tombstones.get(key) {
+            return Ok((*tombstone != Tombstone::DELETED).then(|| IndexEntry::Remove(*tombstone).encode()));
+        }
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(key) {
+                return Ok(None);
+            }
+        }
+        match (self.map.get(key), &self.base) {
+            (Some(location), _) => Ok(Some(self.entry_of(key, *location).encode())),
+            // Keys the deltas of a low-memory handle don't mention are searched in map.db
+            (None, Some(base)) => Ok(base.get(key)?.map(|entry| entry.encode())),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.expiries.remove(key);
+        self.remove_location(key);
+        match &self.base {
+            Some(_) => self.tombstones.insert(key.to_vec(), Tombstone::DELETED),
+            None => self.tombstones.remove(key),
+        };
+        self.dirty.insert(key.to_vec());
+        Ok(())
+    }
+
+    // Answered from the ordered key set, which is empty until a low-memory handle is loaded
+    fn scan(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> EngineIter<'_> {
+        let bounds = (start.map(<[u8]>::to_vec), end.map(<[u8]>::to_vec));
+        let keys = match LookupTable::is_empty_range(&bounds) {
+            true => None,
+            false => Some(self.keys.range::<Vec<u8>, _>(bounds)),
+        };
+        Box::new(keys.into_iter().flatten().map(|key| Ok((key.clone(), self.entry_of(key, self.map[key]).encode()))))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.persist(false)
+    }
+
+    // Rewrites map.db in full, without the deltas
+    fn compact(&mut self) -> Result<()> {
+        self.persist(true)
+    }
+}
+
+impl KeyIndex for HashIndex {
+    fn flushed_lsn(&self) -> u64 {
+        self.flushed_lsn
+    }
+
+    fn commit(&mut self, lsn: u64) -> Result<()> {
+        self.lsn = lsn;
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn tombstone_count(&self) -> usize {
+        self.tombstones.len()
+    }
+
+    fn expired(&self, now: u64) -> Vec<Vec<u8>> {
+        self.expiries.iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    fn expired_count(&self, now: u64) -> usize {
+        self.expiries.values().filter(|expires_at| **expires_at <= now).count()
+    }
+
+    // Those of a low-memory handle hide the keys of map.db until it is loaded
+    fn expired_tombstones(&self, horizon: u64) -> Vec<Vec<u8>> {
+        if self.base.is_some() {
+            return Vec::new();
+        }
+        self.tombstones.iter()
+            .filter(|(_, tombstone)| tombstone.deleted_at <= horizon)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    // Counted up to EXACT_RANGE_COUNT keys, beyond that estimated from the share of the key samples
+    // within bounds
+    fn estimate_range_count(&self, bounds: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> usize {
+        if LookupTable::is_empty_range(bounds) {
+            return 0;
+        }
+        let counted = self.keys.range::<Vec<u8>, _>(bounds.clone()).take(EXACT_RANGE_COUNT + 1).count();
+        if counted <= EXACT_RANGE_COUNT || self.key_samples.is_empty() {
+            return counted;
+        }
+        let sampled = self.key_samples.iter().filter(|key| bounds.contains(*key)).count();
+        (sampled * self.keys.len() / self.key_samples.len()).max(counted)
+    }
+
+    // Value sizes of at most KEY_SAMPLES keys spread over bounds, taken from the key samples where
+    // estimate_range_count estimates too, scaled up to the estimated number of keys
+    fn approximate_size(&self, bounds: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Result<u64> {
+        if LookupTable::is_empty_range(bounds) {
+            return Ok(0);
+        }
+        let counted = self.keys.range::<Vec<u8>, _>(bounds.clone()).take(EXACT_RANGE_COUNT + 1).count();
+        let locations: Vec<EntryLocation> = match counted <= EXACT_RANGE_COUNT || self.key_samples.is_empty() {
+            true => {
+                let step = counted.div_ceil(KEY_SAMPLES).max(1);
+                self.keys.range::<Vec<u8>, _>(bounds.clone()).step_by(step).map(|key| self.map[key]).collect()
+            }
+            // Sampled keys deleted since are left out
+            false => self.key_samples.iter()
+                .filter(|key| bounds.contains(*key))
+                .filter_map(|key| self.map.get(key).copied())
+                .collect(),
+        };
+        if locations.is_empty() {
+            return Ok(0);
+        }
+        let sampled: u128 = locations.iter().map(|location| location.size as u128).sum();
+        Ok((sampled * self.estimate_range_count(bounds) as u128 / locations.len() as u128) as u64)
+    }
+
+    fn write_relocated(&self, path: &Path, relocate: &dyn Fn(&EntryLocation) -> Result<EntryLocation>) -> Result<()> {
+        let mut records = self.map_records();
+        for (_, location, _) in &mut records {
+            if location.block != TOMBSTONE_BLOCK {
+                *location = relocate(location)?;
+            }
+        }
+        LookupTable::write_map_file(self.backend.as_ref(), path, records, self.lsn, self.block_size)?;
+        Ok(())
+    }
+
+    fn commit_relocated(&mut self, path: &Path) -> Result<()> {
+        self.backend.rename(path, &self.map_path).map_err(MapError::io(&self.map_path, "rename"))?;
+        self.map_file = self.backend.open(&self.map_path, OpenMode::Create).map_err(MapError::io(&self.map_path, "open"))?;
+        let (map, expiries, tombstones, _) = LookupTable::get_map_from_file(self.map_file.as_ref(), &self.map_path, self.map.hasher(), false)?;
+        self.map = map;
+        self.expiries = expiries;
+        self.tombstones = tombstones;
+        self.map_size = self.backend.file_len(&self.map_path)?;
+        // The relocated map covers the same sequence number as the deltas
+        self.remove_map_deltas()
+    }
+
+    fn is_lazy(&self) -> bool {
+        self.base.is_some()
+    }
+
+    fn load(&mut self) -> Result<()> {
+        if self.base.is_none() {
+            return Ok(());
+        }
+        let (mut map, mut expiries, mut tombstones, _) = LookupTable::get_map_from_file(self.map_file.as_ref(), &self.map_path, self.map.hasher(), false)?;
+        // The entries loaded from the deltas replace those of map.db
+        for (key, location) in self.map.drain() {
+            match self.expiries.remove(&key) {
+                Some(expires_at) => expiries.insert(key.clone(), expires_at),
+                None => expiries.remove(&key),
+            };
+            tombstones.remove(&key);
+            map.insert(key, location);
+        }
+        for (key, tombstone) in self.tombstones.drain() {
+            expiries.remove(&key);
+            map.remove(&key);
+            match tombstone != Tombstone::DELETED {
+                true => tombstones.insert(key, tombstone),
+                false => tombstones.remove(&key),
+            };
+        }
+        self.keys = map.keys().cloned().collect();
+        self.key_bytes = map.keys().map(Vec::len).sum();
+        // Only a saved filter that fit was used so far
+        self.bloom = self.bloom_rate.map(|rate| HashIndex::load_bloom_filter(self.backend.as_ref(), &self.folder, &map, rate));
+        self.map = map;
+        self.expiries = expiries;
+        self.tombstones = tombstones;
+        self.base = None;
+        self.sample_keys();
+        Ok(())
+    }
+
+    // Deltas are never merged in the background
+    fn finish_merge(&mut self, _wait: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = vec![self.map_path.clone()];
+        files.extend(HashIndex::map_delta_files(self.backend.as_ref(), &self.folder)?.into_iter().map(|(_, path)| path));
+        let bloom_path = self.folder.join(BLOOM_FILE_NAME);
+        if self.backend.exists(&bloom_path) {
+            files.push(bloom_path);
+        }
+        Ok(files)
+    }
+
+    fn corrupt_files(&self) -> Result<Vec<PathBuf>> {
+        let backend = self.backend.as_ref();
+        let mut corrupt = Vec::new();
+        if LookupTable::get_map_from_file(self.map_file.as_ref(), &self.map_path, self.map.hasher(), false).is_err() {
+            corrupt.push(self.map_path.clone());
+        }
+        for (_, path) in HashIndex::map_delta_files(backend, &self.folder)? {
+            if HashIndex::read_map_delta(backend, &path).is_err() {
+                corrupt.push(path);
+            }
+        }
+        Ok(corrupt)
+    }
+
+    // Keys that are not in the map are counted with the average length of those that are
+    fn memory_usage(&self) -> usize {
+        const KEY: usize = size_of::<Vec<u8>>();
+        // Hash tables take a control byte per entry
+        let average_key = self.key_bytes.checked_div(self.map.len()).unwrap_or(0);
+        let map = self.map.len() * (KEY + size_of::<EntryLocation>() + 1) + self.key_bytes;
+        let keys = self.keys.len() * KEY + self.key_bytes;
+        let expiries = self.expiries.len() * (KEY + size_of::<u64>() + 1 + average_key);
+        let tombstones = self.tombstones.len() * (KEY + size_of::<Tombstone>() + 1 + average_key);
+        let bloom = self.bloom.as_ref().map_or(0, BloomFilter::memory_size);
+        map + keys + expiries + tombstones + bloom
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::backend::FileSystem;
+    use crate::db::options::Engine;
+    use crate::db::temp::TempDir;
+    use std::fs;
+
+    fn open(dir: &TempDir, options: &DbOptions) -> Result<HashIndex> {
+        HashIndex::open(dir.path(), options, false, &mut RecoveryReport::default())
+    }
+
+    // Puts the entry as the next record
+    fn put(index: &mut HashIndex, key: &[u8], entry: IndexEntry) -> Result<()> {
+        index.put(key, &entry.encode())?;
+        index.commit(index.lsn + 1)
+    }
+
+    #[test]
+    fn test_bloom_filter() -> Result<()> {
+        let dir = TempDir::new()?;
+        let options = DbOptions::new().bloom_filter(0.01);
+        let entry = IndexEntry::Put(EntryLocation { block: 0, pointer: 4, size: 0 }, 0);
+        let mut index = open(&dir, &options)?;
+        put(&mut index, b"flushed", entry)?;
+        index.flush()?;
+        let bloom_path = dir.path().join(BLOOM_FILE_NAME);
+        assert!(BloomFilter::load(&FileSystem, &bloom_path).is_some());
+
+        drop(index);
+        let mut index = open(&dir, &options)?;
+        put(&mut index, b"written", entry)?;
+        assert_eq!((index.get(b"flushed")?, index.get(b"written")?), (Some(entry.encode()), Some(entry.encode())));
+        assert_eq!(index.get(b"missing")?, None);
+        assert!(index.bloom.as_ref().unwrap().may_contain(b"written"));
+
+        // A damaged filter is rebuilt from the map
+        fs::write(&bloom_path, b"garbage")?;
+        drop(index);
+        let index = open(&dir, &options)?;
+        assert_eq!(index.get(b"flushed")?, Some(entry.encode()));
+        drop(index);
+
+        // Flushing without the filter removes the stale one
+        let mut index = open(&dir, &DbOptions::new())?;
+        index.flush()?;
+        assert!(!bloom_path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_deltas() -> Result<()> {
+        let dir = TempDir::new()?;
+        let options = DbOptions::new().max_map_deltas(2);
+        let entry = IndexEntry::Put(EntryLocation { block: 0, pointer: 4, size: 0 }, 0);
+        let moved = IndexEntry::Put(EntryLocation { block: 1, pointer: 8, size: 0 }, u64::MAX);
+        let deltas = || -> Result<Vec<u64>> {
+            Ok(HashIndex::map_delta_files(&FileSystem, dir.path())?.into_iter().map(|(number, _)| number).collect())
+        };
+        let mut index = open(&dir, &options)?;
+        for i in 0..20u8 {
+            put(&mut index, &[i], entry)?;
+        }
+        // The first flush writes a delta, the second finds it larger than the empty map.db
+        index.flush()?;
+        assert_eq!(deltas()?, [1]);
+        put(&mut index, b"k", entry)?;
+        index.flush()?;
+        assert!(deltas()?.is_empty());
+
+        // Only the keys that changed go into a delta
+        index.delete(&[0])?;
+        put(&mut index, b"new", entry)?;
+        index.flush()?;
+        put(&mut index, &[1], moved)?;
+        index.flush()?;
+        assert_eq!(deltas()?, [1, 2]);
+        let (entries, lsn) = HashIndex::read_map_delta(&FileSystem, &HashIndex::map_delta_path(dir.path(), 2))?;
+        assert_eq!((entries, lsn), (vec![(vec![1], moved)], index.lsn));
+        drop(index);
+        let mut index = open(&dir, &options)?;
+        assert_eq!(index.flushed_lsn(), 23);
+        assert_eq!((index.len(), index.get(&[0])?, index.get(&[1])?), (21, None, Some(moved.encode())));
+
+        // A delta left behind by a crash during a full rewrite is covered by map.db
+        let stale = fs::read(HashIndex::map_delta_path(dir.path(), 1))?;
+        put(&mut index, b"c", entry)?;
+        index.flush()?;
+        assert!(deltas()?.is_empty());
+        fs::write(HashIndex::map_delta_path(dir.path(), 1), &stale)?;
+        drop(index);
+        let mut recovery = RecoveryReport::default();
+        let mut index = HashIndex::open(dir.path(), &options, false, &mut recovery)?;
+        assert_eq!(recovery.repaired_files, [HashIndex::map_delta_path(dir.path(), 1)]);
+        assert!(deltas()?.is_empty());
+        assert_eq!((index.len(), index.get(b"new")?), (22, Some(entry.encode())));
+
+        // A corrupt delta is dropped with the ones after it by a repairing open
+        index.delete(b"c")?;
+        index.commit(index.lsn + 1)?;
+        index.flush()?;
+        let path = HashIndex::map_delta_path(dir.path(), 1);
+        let mut bytes = fs::read(&path)?;
+        bytes[HEADER_SIZE + 2] ^= 0xff;
+        fs::write(&path, &bytes)?;
+        drop(index);
+        assert!(matches!(open(&dir, &options), Err(Error::Map(MapError::ChecksumMismatch{..}))));
+        let mut recovery = RecoveryReport::default();
+        let mut index = HashIndex::open(dir.path(), &DbOptions::new().max_map_deltas(2).repair(true), false, &mut recovery)?;
+        // The removal is left to the WAL, which is replayed from the start
+        assert!(recovery.map_rebuilt);
+        assert_eq!((index.flushed_lsn(), index.len(), index.get(b"c")?), (0, 22, Some(entry.encode())));
+        index.compact()?;
+        assert!(deltas()?.is_empty());
+        Ok(())
+    }
+
+    // Runs that Engine::Lsm left are read like deltas and map.db is rewritten without them
+    #[test]
+    fn test_lsm_runs() -> Result<()> {
+        let dir = TempDir::new()?;
+        let el = |i: u8| IndexEntry::Put(EntryLocation { block: i as u64, pointer: 4, size: 0 }, 0);
+        let options = DbOptions::new().engine(Engine::Lsm);
+        let mut lsm = LookupTable::open_index(dir.path(), &options, false, &mut RecoveryReport::default())?;
+        for i in 0..100u8 {
+            lsm.put(&[i], &el(i).encode())?;
+        }
+        lsm.commit(100)?;
+        lsm.flush()?;
+        lsm.finish_merge(true)?;
+        lsm.delete(&[0])?;
+        lsm.put(&[1], &el(101).encode())?;
+        lsm.commit(101)?;
+        lsm.flush()?;
+        drop(lsm);
+        assert_eq!(LsmIndex::run_files(&FileSystem, dir.path())?.len(), 1);
+
+        let index = open(&dir, &DbOptions::new())?;
+        assert!(LsmIndex::run_files(&FileSystem, dir.path())?.is_empty());
+        assert_eq!((index.len(), index.flushed_lsn()), (99, 101));
+        assert_eq!((index.get(&[0])?, index.get(&[1])?), (None, Some(el(101).encode())));
+        Ok(())
+    }
+}
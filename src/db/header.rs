@@ -0,0 +1,155 @@
+use crate::db::backend::StorageFile;
+use crate::db::btree::valid_block_size;
+use crate::error::{Error, Result};
+
+// map.db, wal.db, bloom.db and shards.db start with a fixed header
+// [magic: 4 bytes][format version: u16][byte order: u16][block size: u32][flags: u32]
+// The block size is the one of data.db, which has no header. The map.db of the default keyspace
+// has the last word on it, see DbOptions::block_size. Only WAL segments set flags.
+pub(crate) const HEADER_SIZE: usize = 16;
+// Version 2 added key expiry times to map records and the WAL, version 3 a checksum at the end of map.db,
+// version 4 sequence numbers to WAL records and map.db, version 5 checkpoint records to the WAL,
+// version 6 delta files of map.db, version 7 sharded keyspaces with batches logged in parts,
+// version 8 compressed values in data.db, version 9 prefix-compressed keys in map.db,
+// version 10 values spanning several blocks of data.db, version 11 WAL records with varint fields,
+// version 12 tombstones of removed keys in map.db, version 13 a clean shutdown flag in WAL segments,
+// version 14 counts of the keys and tombstones in map.db, version 15 restart offsets in map.db,
+// version 16 value sizes in the locations of map.db and the WAL, version 17 sorted runs of map.db
+pub(crate) const FORMAT_VERSION: u16 = 17;
+// Written little endian, a file written with the other byte order reads back as 0x0201
+const BYTE_ORDER_MARK: u16 = 0x0102;
+// Set in the active WAL segment once the database was closed, the map covers every record then
+const CLEAN_SHUTDOWN: u32 = 1;
+const FLAGS_OFFSET: u64 = 12;
+
+pub(crate) const MAP_MAGIC: [u8; 4] = *b"cDBm";
+pub(crate) const WAL_MAGIC: [u8; 4] = *b"cDBw";
+pub(crate) const BLOOM_MAGIC: [u8; 4] = *b"cDBb";
+pub(crate) const SHARDS_MAGIC: [u8; 4] = *b"cDBs";
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct FileHeader {
+    magic: [u8; 4],
+    version: u16,
+    block_size: u32,
+    flags: u32,
+}
+
+impl FileHeader {
+    pub fn new(magic: [u8; 4], block_size: usize) -> Self {
+        Self { magic, version: FORMAT_VERSION, block_size: block_size as u32, flags: 0 }
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size as usize
+    }
+
+    pub fn clean_shutdown(&self) -> bool {
+        self.flags & CLEAN_SHUTDOWN != 0
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.magic);
+        bytes[4..6].copy_from_slice(&self.version.to_le_bytes());
+        bytes[6..8].copy_from_slice(&BYTE_ORDER_MARK.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.block_size.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.flags.to_le_bytes());
+        bytes
+    }
+
+    // Decodes a header and checks that this build can read the file behind it
+    pub fn decode(bytes: &[u8], magic: [u8; 4]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE || bytes[0..4] != magic {
+            return Err(Error::InvalidFormat(format!(
+                "missing {} header", String::from_utf8_lossy(&magic)
+            )));
+        }
+        let version = u16::from_le_bytes(bytes[4..6].try_into()?);
+        if version > FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let byte_order = u16::from_le_bytes(bytes[6..8].try_into()?);
+        if byte_order != BYTE_ORDER_MARK {
+            return Err(Error::InvalidFormat(format!("unknown byte order mark {byte_order:#06x}")));
+        }
+        let block_size = u32::from_le_bytes(bytes[8..12].try_into()?);
+        if !valid_block_size(block_size as usize) {
+            return Err(Error::InvalidFormat(format!("invalid block size {block_size}")));
+        }
+        let flags = u32::from_le_bytes(bytes[12..16].try_into()?);
+        Ok(Self { magic, version, block_size, flags })
+    }
+
+    // Sets or clears the clean shutdown flag of the file in place and syncs it
+    pub fn set_clean_shutdown(file: &dyn StorageFile, clean: bool) -> std::io::Result<()> {
+        let flags = if clean { CLEAN_SHUTDOWN } else { 0 };
+        file.write_at(&flags.to_le_bytes(), FLAGS_OFFSET)?;
+        file.sync()
+    }
+
+    // Validates the header of an existing file or writes one to a new file.
+    // A header that was torn while the file was created is written again, returns whether that happened.
+    pub fn init_or_validate(file: &dyn StorageFile, magic: [u8; 4], block_size: usize, read_only: bool) -> Result<bool> {
+        let header = FileHeader::new(magic, block_size).encode();
+        let len = file.len()? as usize;
+        let torn = len > 0 && len < HEADER_SIZE && !read_only;
+        if len < HEADER_SIZE {
+            let mut existing = vec![0; len];
+            file.read_at(&mut existing, 0)?;
+            if existing[..] != header[..len] {
+                FileHeader::decode(&existing, magic)?;
+            }
+            if !read_only {
+                file.write_at(&header, 0)?;
+                file.sync()?;
+            }
+        } else {
+            let mut existing = [0; HEADER_SIZE];
+            file.read_at(&mut existing, 0)?;
+            FileHeader::decode(&existing, magic)?;
+        }
+        Ok(torn)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::btree::DEFAULT_BLOCK_SIZE;
+
+    #[test]
+    fn test_header_validation() -> Result<()> {
+        let bytes = FileHeader::new(MAP_MAGIC, DEFAULT_BLOCK_SIZE).encode();
+        assert_eq!(FileHeader::decode(&bytes, MAP_MAGIC)?, FileHeader::new(MAP_MAGIC, DEFAULT_BLOCK_SIZE));
+        assert!(matches!(FileHeader::decode(&bytes, WAL_MAGIC), Err(Error::InvalidFormat(_))));
+        assert!(matches!(FileHeader::decode(&bytes[..8], MAP_MAGIC), Err(Error::InvalidFormat(_))));
+
+        let mut newer = bytes;
+        newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(matches!(FileHeader::decode(&newer, MAP_MAGIC), Err(Error::UnsupportedVersion(_))));
+
+        let mut swapped = bytes;
+        swapped.swap(6, 7);
+        assert!(matches!(FileHeader::decode(&swapped, MAP_MAGIC), Err(Error::InvalidFormat(_))));
+
+        let mut clean = bytes;
+        clean[12..16].copy_from_slice(&CLEAN_SHUTDOWN.to_le_bytes());
+        assert!(FileHeader::decode(&clean, MAP_MAGIC)?.clean_shutdown());
+        assert!(!FileHeader::decode(&bytes, MAP_MAGIC)?.clean_shutdown());
+
+        let mut other_block_size = bytes;
+        other_block_size[8..12].copy_from_slice(&16384u32.to_le_bytes());
+        assert_eq!(FileHeader::decode(&other_block_size, MAP_MAGIC)?.block_size(), 16384);
+        for invalid in [0u32, 1000, 256, 65536] {
+            other_block_size[8..12].copy_from_slice(&invalid.to_le_bytes());
+            assert!(matches!(FileHeader::decode(&other_block_size, MAP_MAGIC), Err(Error::InvalidFormat(_))));
+        }
+        Ok(())
+    }
+}
@@ -1,13 +1,1270 @@
-use crate::db::lookup::LookupTable;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::db::backend::{OpenMode, StorageBackend, StorageFile};
+use crate::db::cache::{BlockCache, CacheStats};
+use crate::db::changefeed::{ChangeEvent, Changefeed};
+use crate::db::check::CheckReport;
+use crate::db::batch::{BatchOperation, WriteBatch};
+use crate::db::files::copy_durably;
+use crate::db::header::{FileHeader, HEADER_SIZE, MAP_MAGIC};
+use crate::db::lookup::{EntryLocation, LookupTable, WalOperation};
+use crate::db::pin::{Pin, PinRegistry};
+use crate::db::shard::ShardedTable;
+use crate::db::options::{Backpressure, DbOptions, LogLevel};
+use crate::db::recovery::RecoveryReport;
+use crate::db::replication::{ReplicatedRecord, ReplicatedWrite};
+use crate::db::snapshot::{Snapshot, SnapshotRegistry};
+use crate::db::stats::{Counters, DiskUsage, KeyspaceUsage, MemoryUsage, Stats};
+use crate::db::storage::{ValueLog, ValueRef, COMPACTED_DATA_FILE_NAME, DATA_FILE_NAME};
+use crate::db::sync::{BackgroundSync, SyncPolicy};
+use crate::db::temp::TempDir;
+use crate::db::trace::{event, warning};
 use crate::error::{Error, Result};
 
+/// Failure of a database operation that is not caused by the files underneath.
+#[derive(Debug)]
+pub enum IndexError {
+    /// No database exists at the path and `create_if_missing` is off.
+    NotFound { path: PathBuf },
+    /// A write was attempted through a read-only handle.
+    ReadOnly,
+    /// A write was attempted on a follower that was not promoted, see
+    /// [`ReplicationHandle`](crate::ReplicationHandle).
+    Follower,
+    UnknownColumnFamily(String),
+    InvalidColumnFamilyName(String),
+    /// [`Db::merge`](crate::Db::merge) was called without a merge operator configured.
+    NoMergeOperator,
+    /// [`Db::increment`](crate::Db::increment) found a value that is not an 8 byte counter.
+    NotACounter { key: Vec<u8>, length: usize },
+    CounterOverflow { key: Vec<u8> },
+    /// [`Db::close`](crate::Db::close) or [`Db::destroy`](crate::Db::destroy) was called while other
+    /// handles were alive.
+    InUse,
+    /// A value was read from a data file that a [`Db::compact`](crate::Db::compact) or a
+    /// [`Db::vacuum`](crate::Db::vacuum) replaced and no longer keeps. Iterators and cursors
+    /// keep the file they read from.
+    InvalidatedByCompaction,
+    /// [`Db::vacuum`](crate::Db::vacuum) was called while snapshots or read transactions were open.
+    SnapshotsOpen,
+    /// A write was attempted after the database was closed.
+    Closed,
+}
+
+impl std::fmt::Display for IndexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for IndexError {}
+
 pub struct Index {
-    lookup_table: LookupTable,
+    lookup_table: ShardedTable,
+    // Named keyspaces, each with its own lookup tables and WALs in cf/<name>/
+    column_families: BTreeMap<String, ShardedTable>,
+    // Sequence number of the last WAL record, counted across every lookup table
+    lsn: Arc<AtomicU64>,
+    // Snapshots pin versions in every lookup table, not only in the one they were taken from
+    snapshots: SnapshotRegistry,
+    values: ValueLog,
+    background_sync: Option<BackgroundSync>,
+    // WAL segment of every lookup table the background sync was started for
+    synced_wal_segments: Vec<u64>,
+    cache: Arc<BlockCache>,
+    options: DbOptions,
+    folder: PathBuf,
+    // Number of compactions since open, locations handed out before one point into the replaced value file
+    compactions: u64,
+    // Pins on the value file of a compaction count, and the replaced value files that are still
+    // pinned by that count
+    pins: PinRegistry,
+    retired: BTreeMap<u64, ValueLog>,
+    recovery: RecoveryReport,
+    counters: Counters,
+    changefeed: Changefeed,
+    // Set while the database follows a leader, only replicated writes are accepted then
+    following: bool,
+    // Set once the pending state was flushed on close, nothing is written after that
+    closed: bool,
+    // Folder of Db::open_temp, declared last so it is removed after the files above are closed
+    temp_dir: Option<TempDir>,
 }
 
+// Values copied per batch during compaction
+const COMPACTION_BATCH_SIZE: usize = 1024;
+// Memory the logged operations take at least before DbOptions::memory_limit flushes early
+const MIN_EARLY_FLUSH_MEMORY: u64 = 64 * 1024;
+const COLUMN_FAMILY_FOLDER: &str = "cf";
+// A vacuum builds the database anew in <folder>.vacuum, then renames the folder to <folder>.old
+// and the new one into its place
+const VACUUM_SUFFIX: &str = "vacuum";
+const VACUUMED_SUFFIX: &str = "old";
+
+// Keys with the locations of their values, in key order
+type Locations = Vec<(Vec<u8>, EntryLocation)>;
+
 impl Index {
     pub fn new(name: String) -> Result<Self> {
-        let lookup_table = LookupTable::new(&name)?;
-        Ok( Self { lookup_table } )
+        Index::open(&DbOptions::new().path(name))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip_all, fields(path = ?options.path)))]
+    pub fn open(options: &DbOptions) -> Result<Self> {
+        options.validate()?;
+        let folder = options.folder()?;
+        let backend = options.backend.as_ref();
+        if !options.create_if_missing && !backend.exists(&folder.join("map.db")) {
+            return Err(Error::Index(IndexError::NotFound { path: folder.to_path_buf() }));
+        }
+        if !options.read_only {
+            Index::finish_vacuum(backend, folder, options.log_level)?;
+        }
+        let mut options = options.clone();
+        if !options.reset {
+            if let Some(block_size) = Index::stored_block_size(backend, folder)? {
+                options.block_size = block_size;
+            }
+        }
+        let options = &options;
+        // Opening the lookup table takes the directory lock, nothing is reset before that
+        let mut lookup_table = ShardedTable::open(folder, options)?;
+        let column_family_folder = folder.join(COLUMN_FAMILY_FOLDER);
+        if options.reset {
+            let data_path = folder.join(DATA_FILE_NAME);
+            if backend.exists(&data_path) {
+                backend.remove_file(&data_path)?;
+            }
+            if backend.exists(&column_family_folder) {
+                backend.remove_dir_all(&column_family_folder)?;
+            }
+        }
+        let mut column_families = BTreeMap::new();
+        if backend.exists(&column_family_folder) {
+            for path in backend.list(&column_family_folder)? {
+                let Some(name) = path.file_name().and_then(|name| name.to_str()) else { continue };
+                if backend.is_dir(&path) {
+                    column_families.insert(name.to_string(), ShardedTable::open(&path, options)?);
+                }
+            }
+        }
+        let lsn = Arc::new(AtomicU64::new(0));
+        let snapshots = SnapshotRegistry::default();
+        let mut recovery = RecoveryReport::default();
+        if !options.read_only {
+            Index::remove_vacuum_leftovers(backend, folder, options.log_level, &mut recovery)?;
+        }
+        for table in std::iter::once(&mut lookup_table).chain(column_families.values_mut()) {
+            table.share(&lsn, &snapshots);
+            recovery.merge(table.recovery());
+        }
+        // Opened after every lookup table had the chance to recover an interrupted compaction
+        let cache = Arc::new(BlockCache::new(options.block_cache_size / options.block_size));
+        let values = ValueLog::open_recovering(folder, options, Arc::clone(&cache), &mut recovery)?;
+        let mut index = Self {
+            lookup_table, column_families, lsn, snapshots, values, background_sync: None, synced_wal_segments: Vec::new(),
+            cache, options: options.clone(), folder: folder.to_path_buf(), compactions: 0,
+            pins: PinRegistry::default(), retired: BTreeMap::new(), recovery,
+            counters: Counters::default(), changefeed: Changefeed::default(), following: false, closed: false,
+            temp_dir: None,
+        };
+        index.start_background_sync()?;
+        let recovery = &index.recovery;
+        if !recovery.is_clean() {
+            warning!(
+                index.options.log_level,
+                wal_bytes_discarded = %recovery.wal_bytes_discarded, repaired_files = %recovery.repaired_files.len();
+                "recovered from an unclean shutdown"
+            );
+        }
+        event!(
+            index.options.log_level, INFO,
+            column_families = index.column_families.len(), wal_records = recovery.wal_records_replayed,
+            "opened database"
+        );
+        Ok(index)
+    }
+
+    // Syncs handles to the current files, so it is restarted whenever a file is swapped
+    fn start_background_sync(&mut self) -> Result<()> {
+        self.background_sync = None;
+        self.synced_wal_segments = self.shards().map(|table| table.wal_segment()).collect();
+        if let SyncPolicy::Interval(interval) = self.options.sync_policy {
+            if !self.options.read_only {
+                let mut files: Vec<_> = self.shards().map(|table| table.wal_handle()).collect();
+                files.push(self.values.file_handle());
+                self.background_sync = Some(BackgroundSync::start(files, interval));
+            }
+        }
+        Ok(())
+    }
+
+    // Called after every WAL write, which may have rotated to a new segment
+    fn refresh_background_sync(&mut self) -> Result<()> {
+        let segments_changed = !self.shards().map(|table| table.wal_segment()).eq(self.synced_wal_segments.iter().copied());
+        match self.background_sync.is_some() && segments_changed {
+            true => self.start_background_sync(),
+            false => Ok(()),
+        }
+    }
+
+    // The default keyspace followed by the column families in name order
+    fn tables(&self) -> impl Iterator<Item = &ShardedTable> {
+        std::iter::once(&self.lookup_table).chain(self.column_families.values())
+    }
+
+    // Lookup table of every shard of every keyspace
+    fn shards(&self) -> impl Iterator<Item = &LookupTable> {
+        self.tables().flat_map(ShardedTable::shards)
+    }
+
+    // Tables with the name of their column family, None for the default keyspace
+    fn named_tables(&self) -> impl Iterator<Item = (Option<&str>, &ShardedTable)> {
+        let column_families = self.column_families.iter().map(|(name, table)| (Some(name.as_str()), table));
+        std::iter::once((None, &self.lookup_table)).chain(column_families)
+    }
+
+    // Lookup table of a column family, None selects the default keyspace
+    fn table(&self, column_family: Option<&str>) -> Result<&ShardedTable> {
+        match column_family {
+            None => Ok(&self.lookup_table),
+            Some(name) => self.column_families.get(name)
+                .ok_or_else(|| Error::Index(IndexError::UnknownColumnFamily(name.to_string()))),
+        }
+    }
+
+    fn table_mut(&mut self, column_family: Option<&str>) -> Result<&mut ShardedTable> {
+        match column_family {
+            None => Ok(&mut self.lookup_table),
+            Some(name) => self.column_families.get_mut(name)
+                .ok_or_else(|| Error::Index(IndexError::UnknownColumnFamily(name.to_string()))),
+        }
+    }
+
+    // Whether a low-memory handle still searches map.db on disk, point lookups are all it answers then
+    pub fn is_lazy(&self) -> bool {
+        self.lookup_table.is_lazy() || self.column_families.values().any(ShardedTable::is_lazy)
+    }
+
+    // Loads the maps a low-memory handle searched on disk so far
+    pub fn load_maps(&mut self) -> Result<()> {
+        self.lookup_table.load_base()?;
+        for table in self.column_families.values_mut() {
+            table.load_base()?;
+        }
+        Ok(())
+    }
+
+    pub fn column_families(&self) -> Vec<String> {
+        self.column_families.keys().cloned().collect()
+    }
+
+    pub fn has_column_family(&self, name: &str) -> bool {
+        self.column_families.contains_key(name)
+    }
+
+    pub fn create_column_family(&mut self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let valid = !name.is_empty() && name.len() <= 64
+            && name.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-');
+        if !valid {
+            // Names become folder names, so they are limited to up to 64 ASCII letters, digits, '_' or '-'
+            return Err(Error::Index(IndexError::InvalidColumnFamilyName(name.to_string())));
+        }
+        if self.column_families.contains_key(name) {
+            return Ok(());
+        }
+        let folder = self.folder.join(COLUMN_FAMILY_FOLDER).join(name);
+        let mut table = ShardedTable::open(&folder, &self.options)?;
+        table.share(&self.lsn, &self.snapshots);
+        self.column_families.insert(name.to_string(), table);
+        self.start_background_sync()
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        match (self.options.read_only, self.closed) {
+            (true, _) => Err(Error::Index(IndexError::ReadOnly)),
+            (_, true) => Err(Error::Index(IndexError::Closed)),
+            _ => Ok(()),
+        }
+    }
+
+    // Writes of keys, as opposed to flushes and compactions, are also rejected while following a leader
+    fn check_leader(&self) -> Result<()> {
+        self.check_writable()?;
+        match self.following {
+            true => Err(Error::Index(IndexError::Follower)),
+            false => Ok(()),
+        }
+    }
+
+    // Flushes or fails, depending on DbOptions::backpressure, while the writes logged since the
+    // last flush are over the limits of the options
+    fn admit_write(&mut self) -> Result<()> {
+        self.check_leader()?;
+        let (bytes, writes) = self.tables().map(ShardedTable::unflushed)
+            .fold((0, 0), |(bytes, writes), (table_bytes, table_writes)| (bytes + table_bytes, writes + table_writes));
+        let over_limit = self.options.max_unflushed_wal_bytes.is_some_and(|max| bytes >= max)
+            || self.options.max_unflushed_writes.is_some_and(|max| writes >= max);
+        // A flush only releases the logged operations, while they take little memory it would
+        // not help and is skipped
+        if let Some(limit) = self.options.memory_limit {
+            let memory = self.memory_usage();
+            if memory.total() > limit && memory.wal >= MIN_EARLY_FLUSH_MEMORY {
+                event!(self.options.log_level, DEBUG, memory = memory.total(), limit, "flushing to stay within the memory limit");
+                return self.flush();
+            }
+        }
+        match (over_limit, self.options.backpressure) {
+            (false, _) => Ok(()),
+            (true, Backpressure::Block) => {
+                event!(self.options.log_level, DEBUG, bytes, writes, "flushing to make room for writes");
+                self.flush()
+            }
+            (true, Backpressure::Fail) => Err(Error::Backpressure),
+        }
+    }
+
+    pub fn insert(&mut self, column_family: Option<&str>, key: &[u8], value: &[u8]) -> Result<()> {
+        let started = Instant::now();
+        self.admit_write()?;
+        self.table(column_family)?;
+        let changes = self.changes(column_family, &[(key, Some(value))])?;
+        let location = self.values.append(value)?;
+        self.table_mut(column_family)?.add(key, location)?;
+        self.publish(column_family, changes);
+        Counters::add(&self.counters.puts, 1);
+        self.counters.put_latency.record(started.elapsed());
+        self.refresh_background_sync()
+    }
+
+    // Insert that returns the value it replaced, read under the same lock
+    pub fn replace(&mut self, column_family: Option<&str>, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let previous = self.read(column_family, key)?;
+        self.insert(column_family, key, value)?;
+        Ok(previous)
+    }
+
+    // The key reads as absent once ttl has passed and is removed by the next flush
+    pub fn insert_with_ttl(&mut self, column_family: Option<&str>, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let started = Instant::now();
+        self.admit_write()?;
+        let expires_at = self.table(column_family)?.now().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX));
+        let changes = self.changes(column_family, &[(key, Some(value))])?;
+        let location = self.values.append(value)?;
+        self.table_mut(column_family)?.add_expiring(key, location, expires_at)?;
+        self.publish(column_family, changes);
+        Counters::add(&self.counters.puts, 1);
+        self.counters.put_latency.record(started.elapsed());
+        self.refresh_background_sync()
+    }
+
+    // Read, merge and write happen under the write lock, so concurrent merges never lose an update
+    pub fn merge(&mut self, column_family: Option<&str>, key: &[u8], operand: &[u8]) -> Result<()> {
+        let started = Instant::now();
+        self.admit_write()?;
+        let operator = self.options.merge_operator.clone()
+            .ok_or(Error::Index(IndexError::NoMergeOperator))?;
+        let current = self.read(column_family, key)?;
+        let merged = operator.merge(key, current.as_deref(), operand);
+        let changes = self.changes(column_family, &[(key, Some(&merged))])?;
+        let location = self.values.append(&merged)?;
+        let table = self.table_mut(column_family)?;
+        // An absent or expired key starts over without an expiry time
+        match current {
+            Some(_) => table.merge(key, location)?,
+            None => table.add(key, location)?,
+        }
+        self.publish(column_family, changes);
+        Counters::add(&self.counters.puts, 1);
+        self.counters.put_latency.record(started.elapsed());
+        self.refresh_background_sync()
+    }
+
+    // Counters are stored as 8 byte little endian values, an absent or expired key counts from 0
+    pub fn increment(&mut self, column_family: Option<&str>, key: &[u8], delta: u64) -> Result<u64> {
+        let started = Instant::now();
+        self.admit_write()?;
+        let current = match self.read(column_family, key)? {
+            Some(value) => Some(u64::from_le_bytes(value.as_slice().try_into().map_err(|_| {
+                Error::Index(IndexError::NotACounter { key: key.to_vec(), length: value.len() })
+            })?)),
+            None => None,
+        };
+        let count = current.unwrap_or(0).checked_add(delta)
+            .ok_or_else(|| Error::Index(IndexError::CounterOverflow { key: key.to_vec() }))?;
+        let changes = self.changes(column_family, &[(key, Some(&count.to_le_bytes()))])?;
+        let location = self.values.append(&count.to_le_bytes())?;
+        let table = self.table_mut(column_family)?;
+        match current {
+            Some(_) => table.increment(key, location)?,
+            None => table.add(key, location)?,
+        }
+        self.publish(column_family, changes);
+        Counters::add(&self.counters.puts, 1);
+        self.counters.put_latency.record(started.elapsed());
+        self.refresh_background_sync()?;
+        Ok(count)
+    }
+
+    // Runs write with the value file and the WAL of the keyspace synced after every write when sync
+    // is set, whatever the sync policy, which applies again once it returns. Without sync the policy
+    // applies as it is, a write option never syncs less than the database was opened with.
+    pub fn with_sync<T>(&mut self, column_family: Option<&str>, sync: bool, write: impl FnOnce(&mut Index) -> Result<T>) -> Result<T> {
+        if !sync || self.options.sync_policy.sync_each_write() {
+            return write(self);
+        }
+        // Looked up before anything is switched, so an unknown column family changes nothing
+        self.table_mut(column_family)?.set_sync_policy(SyncPolicy::Always);
+        self.values.set_sync_policy(SyncPolicy::Always);
+        let written = write(self);
+        let policy = self.options.sync_policy;
+        self.values.set_sync_policy(policy);
+        if let Ok(table) = self.table_mut(column_family) {
+            table.set_sync_policy(policy);
+        }
+        written
+    }
+
+    pub fn get(&self, column_family: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let started = Instant::now();
+        Counters::add(&self.counters.gets, 1);
+        let value = self.read(column_family, key)?;
+        self.counters.get_latency.record(started.elapsed());
+        Ok(value)
+    }
+
+    // Answered by the lookup table alone, the value file is not read
+    pub fn contains_key(&self, column_family: Option<&str>, key: &[u8]) -> Result<bool> {
+        Ok(self.table(column_family)?.get(key)?.is_some())
+    }
+
+    pub fn subscribe(&mut self, column_family: Option<&str>, prefix: &[u8]) -> Receiver<ChangeEvent> {
+        self.changefeed.subscribe(column_family, prefix)
+    }
+
+    // Changes that writes of (key, new value) in the order they are applied make to the keys that
+    // someone subscribed to, with the values they replace. publish numbers them once they are logged.
+    fn changes(&self, column_family: Option<&str>, writes: &[(&[u8], Option<&[u8]>)]) -> Result<Vec<ChangeEvent>> {
+        let mut changes: Vec<ChangeEvent> = Vec::new();
+        for (key, value) in writes {
+            if !self.changefeed.is_watched(column_family, key) {
+                continue;
+            }
+            // A key written earlier in the same batch replaces that value
+            let old_value = match changes.iter().rev().find(|change| change.key == *key) {
+                Some(change) => change.new_value.clone(),
+                None => self.read(column_family, key)?,
+            };
+            changes.push(ChangeEvent { key: key.to_vec(), old_value, new_value: value.map(<[u8]>::to_vec), sequence: 0 });
+        }
+        Ok(changes)
+    }
+
+    fn publish(&mut self, column_family: Option<&str>, mut changes: Vec<ChangeEvent>) {
+        if changes.is_empty() {
+            return;
+        }
+        let sequence = self.last_sequence();
+        for change in &mut changes {
+            change.sequence = sequence;
+        }
+        self.changefeed.publish(column_family, &changes);
+    }
+
+    // Uncounted get for the reads of merges and increments
+    fn read(&self, column_family: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.table(column_family)?.get(key)? {
+            Some(location) => Ok(Some(self.values.read(location)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_ref(&self, column_family: Option<&str>, key: &[u8]) -> Result<Option<ValueRef<'static>>> {
+        let started = Instant::now();
+        Counters::add(&self.counters.gets, 1);
+        let value = match self.table(column_family)?.get(key)? {
+            Some(location) => Some(self.values.read_ref(location)?),
+            None => None,
+        };
+        self.counters.get_latency.record(started.elapsed());
+        Ok(value)
+    }
+
+    // Values of all keys, None for the absent ones
+    pub fn multi_get<K: AsRef<[u8]>>(&self, column_family: Option<&str>, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        let started = Instant::now();
+        let table = self.table(column_family)?;
+        Counters::add(&self.counters.gets, keys.len());
+        let locations = keys.iter()
+            .map(|key| table.get(key.as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+        let found: Vec<EntryLocation> = locations.iter().flatten().copied().collect();
+        let mut values = self.values.read_many(&found)?.into_iter();
+        self.counters.get_latency.record(started.elapsed());
+        Ok(locations.iter().map(|location| location.and_then(|_| values.next())).collect())
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.lookup_table.snapshot()
+    }
+
+    pub fn get_at(&self, column_family: Option<&str>, key: &[u8], snapshot: &Snapshot) -> Result<Option<Vec<u8>>> {
+        let started = Instant::now();
+        Counters::add(&self.counters.gets, 1);
+        let value = match self.table(column_family)?.get_at(key, snapshot)? {
+            Some(location) => Some(self.values.read(location)?),
+            None => None,
+        };
+        self.counters.get_latency.record(started.elapsed());
+        Ok(value)
+    }
+
+    // First of keys that was written after the snapshot was taken
+    pub(crate) fn first_written_since<'a>(
+        &self,
+        column_family: Option<&str>,
+        keys: impl IntoIterator<Item = &'a Vec<u8>>,
+        snapshot: &Snapshot,
+    ) -> Result<Option<&'a Vec<u8>>> {
+        let table = self.table(column_family)?;
+        Ok(keys.into_iter().find(|key| table.written_since(key, snapshot.sequence())))
+    }
+
+    // Keys within range as the snapshot sees them, their values are read with get_at
+    pub(crate) fn range_at<K, R>(&self, column_family: Option<&str>, range: R, snapshot: &Snapshot) -> Result<Vec<Vec<u8>>>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        self.table(column_family)?.range_at(range, snapshot)
+    }
+
+    // Key/value pairs within range in ascending key order, values are read lazily
+    pub fn range<K, R>(&self, range: R) -> impl DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        self.lookup_table.range(range)
+            .map(|entry| entry.and_then(|(key, location)| Ok((key, self.values.read(location)?))))
+    }
+
+    pub fn scan_prefix(&self, prefix: &[u8]) -> impl DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        self.lookup_table.scan_prefix(prefix)
+            .map(|entry| entry.and_then(|(key, location)| Ok((key, self.values.read(location)?))))
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        self.range::<&[u8], _>(..)
+    }
+
+    // Owned copies of the keys and locations within range, for iterators that outlive a lock guard
+    // An unknown column family has no keys
+    pub(crate) fn range_locations<K, R>(&self, column_family: Option<&str>, range: R) -> Result<Locations>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let Ok(table) = self.table(column_family) else { return Ok(Vec::new()) };
+        table.range(range).collect()
+    }
+
+    // The keys and locations within range split into parts contiguous sub-ranges of about the
+    // same number of keys. Always at least one part, empty ones when there are fewer keys than parts
+    pub(crate) fn partition_locations<K, R>(&self, column_family: Option<&str>, range: R, parts: usize) -> Result<Vec<Locations>>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let mut entries = self.range_locations(column_family, range)?;
+        let parts = parts.max(1);
+        let len = entries.len();
+        let mut partitions: Vec<_> = (1..parts).rev()
+            .map(|part| entries.split_off(len * part / parts))
+            .collect();
+        partitions.push(entries);
+        partitions.reverse();
+        Ok(partitions)
+    }
+
+    // Keys within range with their values, read in block order so every block is read once
+    pub(crate) fn range_values<K, R>(&self, column_family: Option<&str>, range: R) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let (keys, locations): (Vec<Vec<u8>>, Vec<EntryLocation>) = self.range_locations(column_family, range)?.into_iter().unzip();
+        Counters::add(&self.counters.gets, keys.len());
+        Ok(keys.into_iter().zip(self.values.read_many(&locations)?).collect())
+    }
+
+    // Unknown column families have no keys
+    pub fn len(&self, column_family: Option<&str>) -> usize {
+        self.table(column_family).map_or(0, ShardedTable::len)
+    }
+
+    pub fn estimate_range_count<K, R>(&self, column_family: Option<&str>, range: R) -> usize
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        self.table(column_family).map_or(0, |table| table.estimate_range_count(range))
+    }
+
+    // Sizes of the sampled values within range scaled up to the estimated number of keys, from
+    // the locations alone
+    pub fn approximate_size<K, R>(&self, column_family: Option<&str>, range: R) -> Result<u64>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let Ok(table) = self.table(column_family) else { return Ok(0) };
+        let bounds: (Bound<Vec<u8>>, Bound<Vec<u8>>) = (
+            range.start_bound().map(|b| b.as_ref().to_vec()),
+            range.end_bound().map(|b| b.as_ref().to_vec()),
+        );
+        table.approximate_size(&bounds)
+    }
+
+    pub(crate) fn prefix_locations(&self, column_family: Option<&str>, prefix: &[u8]) -> Result<Locations> {
+        let Ok(table) = self.table(column_family) else { return Ok(Vec::new()) };
+        table.scan_prefix(prefix).collect()
+    }
+
+    // First entry within bounds, or the last one when reverse is set
+    pub(crate) fn seek_location(
+        &self,
+        column_family: Option<&str>,
+        bounds: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        reverse: bool,
+    ) -> Result<Option<(Vec<u8>, EntryLocation)>> {
+        let Ok(table) = self.table(column_family) else { return Ok(None) };
+        let mut entries = table.range(bounds);
+        match reverse {
+            true => entries.next_back().transpose(),
+            false => entries.next().transpose(),
+        }
+    }
+
+    pub(crate) fn read_value(&self, location: EntryLocation) -> Result<Vec<u8>> {
+        self.values.read(location)
+    }
+
+    // Keeps locations handed out now readable across compactions until the pin is dropped
+    pub(crate) fn pin(&self) -> Pin {
+        self.pins.acquire(self.compactions)
+    }
+
+    // Reads a location that was handed out together with pin
+    pub(crate) fn read_pinned(&self, pin: &Pin, location: EntryLocation) -> Result<Vec<u8>> {
+        if pin.epoch() == self.compactions {
+            return self.values.read(location);
+        }
+        match self.retired.get(&pin.epoch()) {
+            Some(values) => values.read(location),
+            None => Err(Error::Index(IndexError::InvalidatedByCompaction)),
+        }
+    }
+
+    pub fn remove(&mut self, column_family: Option<&str>, key: &[u8]) -> Result<()> {
+        self.admit_write()?;
+        self.table(column_family)?;
+        let changes = self.changes(column_family, &[(key, None)])?;
+        self.table_mut(column_family)?.remove(key)?;
+        self.publish(column_family, changes);
+        Counters::add(&self.counters.deletes, 1);
+        self.refresh_background_sync()
+    }
+
+    pub fn write_batch(&mut self, column_family: Option<&str>, batch: WriteBatch) -> Result<()> {
+        let started = Instant::now();
+        self.admit_write()?;
+        self.table(column_family)?;
+        let writes: Vec<(&[u8], Option<&[u8]>)> = batch.operations.iter()
+            .map(|operation| match operation {
+                BatchOperation::Put{key, value} => (key.as_slice(), Some(value.as_slice())),
+                BatchOperation::Delete{key} => (key.as_slice(), None),
+            })
+            .collect();
+        let changes = self.changes(column_family, &writes)?;
+        let values: Vec<&[u8]> = batch.operations.iter()
+            .filter_map(|operation| match operation {
+                BatchOperation::Put{value, ..} => Some(value.as_slice()),
+                BatchOperation::Delete{..} => None,
+            })
+            .collect();
+        let mut locations = self.values.append_batch(&values)?.into_iter();
+        let (values, batch_len) = (values.len(), batch.len());
+        let mut wal_operations = Vec::with_capacity(batch.len());
+        for operation in batch.operations {
+            wal_operations.push(match operation {
+                BatchOperation::Put{key, ..} => {
+                    let location = locations.next().ok_or("missing location for batched value")?;
+                    WalOperation::Insert{key, location}
+                }
+                BatchOperation::Delete{key} => WalOperation::Remove{key},
+            });
+        }
+        self.table_mut(column_family)?.write_batch(wal_operations)?;
+        self.publish(column_family, changes);
+        Counters::add(&self.counters.puts, values);
+        Counters::add(&self.counters.deletes, batch_len - values);
+        self.counters.put_latency.record(started.elapsed());
+        self.refresh_background_sync()
+    }
+
+    pub(crate) fn set_following(&mut self, following: bool) {
+        self.following = following;
+    }
+
+    // Writes logged after sequence number `after` with their values in the order they were made,
+    // for shipping to a follower. None if a flush already removed some of them from the WAL.
+    pub(crate) fn replication_records(&self, after: u64) -> Result<Option<Vec<ReplicatedRecord>>> {
+        let mut records: Vec<ReplicatedRecord> = Vec::new();
+        for (column_family, table) in self.named_tables() {
+            let Some(logged) = table.logged_since(after) else { return Ok(None) };
+            for (sequence, operation) in logged {
+                // The records of a rewritten WAL share the sequence number of the state they hold
+                let extends_last = records.last().is_some_and(|record| {
+                    record.sequence == *sequence && record.column_family.as_deref() == column_family
+                });
+                if !extends_last {
+                    let column_family = column_family.map(str::to_string);
+                    records.push(ReplicatedRecord { sequence: *sequence, column_family, writes: Vec::new() });
+                }
+                let record = records.last_mut().ok_or("missing replicated record")?;
+                self.replicated_writes(operation, &mut record.writes)?;
+            }
+        }
+        records.sort_by_key(|record| record.sequence);
+        Ok(Some(records))
+    }
+
+    fn replicated_writes(&self, operation: &WalOperation, writes: &mut Vec<ReplicatedWrite>) -> Result<()> {
+        let write = match operation {
+            WalOperation::Insert{key, location} => {
+                ReplicatedWrite::Put { key: key.clone(), value: self.values.read(*location)?, expires_at: None }
+            }
+            WalOperation::InsertExpiring{key, location, expires_at} => {
+                ReplicatedWrite::Put { key: key.clone(), value: self.values.read(*location)?, expires_at: Some(*expires_at) }
+            }
+            WalOperation::Merge{key, location} | WalOperation::Increment{key, location} => {
+                ReplicatedWrite::Update { key: key.clone(), value: self.values.read(*location)? }
+            }
+            WalOperation::Remove{key} => ReplicatedWrite::Delete { key: key.clone() },
+            WalOperation::Batch(operations) => {
+                for operation in operations {
+                    self.replicated_writes(operation, writes)?;
+                }
+                return Ok(());
+            }
+        };
+        writes.push(write);
+        Ok(())
+    }
+
+    // Every live key of every keyspace with its value, one record per keyspace at the last sequence number
+    pub(crate) fn replication_snapshot(&self) -> Result<Vec<ReplicatedRecord>> {
+        let sequence = self.last_sequence();
+        self.named_tables()
+            .map(|(column_family, table)| {
+                let writes = table.range::<&[u8], _>(..)
+                    .map(|entry| {
+                        let (key, location) = entry?;
+                        let expires_at = table.expiry(&key)?;
+                        Ok(ReplicatedWrite::Put { key, value: self.values.read(location)?, expires_at })
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(ReplicatedRecord { sequence, column_family: column_family.map(str::to_string), writes })
+            })
+            .collect()
+    }
+
+    // Applies a record shipped by the leader under its sequence number, the ones applied before are skipped
+    pub(crate) fn apply_replicated(&mut self, record: ReplicatedRecord) -> Result<()> {
+        match record.sequence > self.last_sequence() {
+            true => self.apply_record(record),
+            false => Ok(()),
+        }
+    }
+
+    // Replaces the keys of every keyspace with a snapshot of the leader
+    pub(crate) fn apply_snapshot(&mut self, snapshot: Vec<ReplicatedRecord>) -> Result<()> {
+        let Some(sequence) = snapshot.first().map(|record| record.sequence) else { return Ok(()) };
+        let mut replaced: BTreeMap<Option<String>, Vec<Vec<u8>>> = self.named_tables()
+            .map(|(column_family, table)| Ok((column_family.map(str::to_string), table.keys()?)))
+            .collect::<Result<_>>()?;
+        for mut record in snapshot {
+            let deletes = replaced.remove(&record.column_family).unwrap_or_default().into_iter()
+                .map(|key| ReplicatedWrite::Delete { key });
+            record.writes.splice(0..0, deletes);
+            self.apply_record(record)?;
+        }
+        for (column_family, keys) in replaced {
+            let writes = keys.into_iter().map(|key| ReplicatedWrite::Delete { key }).collect();
+            self.apply_record(ReplicatedRecord { sequence, column_family, writes })?;
+        }
+        self.flush()
+    }
+
+    fn apply_record(&mut self, record: ReplicatedRecord) -> Result<()> {
+        let column_family = record.column_family.as_deref();
+        if let Some(name) = column_family {
+            self.create_column_family(name)?;
+        }
+        let writes: Vec<(&[u8], Option<&[u8]>)> = record.writes.iter().map(|write| (write.key(), write.value())).collect();
+        let changes = self.changes(column_family, &writes)?;
+        let values: Vec<&[u8]> = record.writes.iter().filter_map(ReplicatedWrite::value).collect();
+        let mut locations = self.values.append_batch(&values)?.into_iter();
+        let mut operations = Vec::with_capacity(record.writes.len());
+        for write in record.writes {
+            let mut location = || locations.next().ok_or("missing location for replicated value");
+            operations.push(match write {
+                ReplicatedWrite::Put{key, expires_at: None, ..} => WalOperation::Insert{key, location: location()?},
+                ReplicatedWrite::Put{key, expires_at: Some(expires_at), ..} => {
+                    WalOperation::InsertExpiring{key, location: location()?, expires_at}
+                }
+                ReplicatedWrite::Update{key, ..} => WalOperation::Merge{key, location: location()?},
+                ReplicatedWrite::Delete{key} => WalOperation::Remove{key},
+            });
+        }
+        self.table_mut(column_family)?.write_batch_at(record.sequence, operations)?;
+        self.publish(column_family, changes);
+        self.refresh_background_sync()
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn flush(&mut self) -> Result<()> {
+        self.flush_with(ShardedTable::flush)
+    }
+
+    // Flushes the values, then every table with the given flush of ShardedTable
+    fn flush_with(&mut self, flush: fn(&mut ShardedTable) -> Result<()>) -> Result<()> {
+        let started = Instant::now();
+        self.check_writable()?;
+        self.values.sync()?;
+        flush(&mut self.lookup_table)?;
+        for table in self.column_families.values_mut() {
+            flush(table)?;
+        }
+        Counters::add(&self.counters.flushes, 1);
+        self.counters.flush_latency.record(started.elapsed());
+        self.release_retired();
+        Ok(())
+    }
+
+    // Copies the values that are still reachable into a new data file and swaps it in,
+    // reclaiming the space of overwritten and deleted values
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip_all))]
+    pub fn compact(&mut self) -> Result<()> {
+        let started = Instant::now();
+        // The maps are rewritten without their overwritten entries along with the data file
+        self.flush_with(ShardedTable::compact)?;
+        let mut locations = Vec::new();
+        for table in self.tables() {
+            locations.extend(table.live_locations()?);
+        }
+        // Copying in file order keeps values that were written together close together
+        locations.sort_by_key(|location| (location.block, location.pointer));
+        locations.dedup();
+
+        let mut compacted = ValueLog::create(&self.folder.join(COMPACTED_DATA_FILE_NAME), &self.options)?;
+        let relocated = self.copy_values(&locations, &mut compacted)?;
+        compacted.sync()?;
+        event!(self.options.log_level, INFO, values = locations.len(), "copied reachable values");
+        let compacted_path = compacted.path().to_path_buf();
+        drop(compacted);
+
+        let relocations = self.tables()
+            .map(|table| table.prepare_relocation(&relocated))
+            .collect::<Result<Vec<_>>>()?;
+        // Commit point, every table then swaps in its relocated map
+        self.options.backend.rename(&compacted_path, self.values.path())?;
+        let tables = std::iter::once(&mut self.lookup_table).chain(self.column_families.values_mut());
+        for (table, relocation) in tables.zip(relocations) {
+            table.commit_relocation(relocation)?;
+        }
+        self.swap_values()?;
+        event!(self.options.log_level, INFO, size = self.options.backend.file_len(self.values.path())?, "swapped in compacted data file");
+        self.counters.compaction_latency.record(started.elapsed());
+        self.start_background_sync()
+    }
+
+    // Opens the data file that replaced the current one. The replaced file stays open while pins
+    // taken before are alive, its handle still reads it after the rename over it.
+    fn swap_values(&mut self) -> Result<()> {
+        let replaced = std::mem::replace(&mut self.values, ValueLog::open(&self.folder, &self.options, Arc::clone(&self.cache))?);
+        self.retired.insert(self.compactions, replaced);
+        self.compactions += 1;
+        self.release_retired();
+        Ok(())
+    }
+
+    fn release_retired(&mut self) {
+        let pins = &self.pins;
+        self.retired.retain(|epoch, _| pins.is_pinned(*epoch));
+    }
+
+    // Copies the values at locations to the end of dest, returning where every one of them went
+    fn copy_values(&self, locations: &[EntryLocation], dest: &mut ValueLog) -> Result<HashMap<EntryLocation, EntryLocation>> {
+        let mut relocated = HashMap::with_capacity(locations.len());
+        for chunk in locations.chunks(COMPACTION_BATCH_SIZE) {
+            let values = chunk.iter()
+                .map(|location| self.values.read(*location))
+                .collect::<Result<Vec<_>>>()?;
+            let values: Vec<&[u8]> = values.iter().map(|value| value.as_slice()).collect();
+            let moved = dest.append_batch(&values)?;
+            relocated.extend(chunk.iter().copied().zip(moved));
+        }
+        Ok(relocated)
+    }
+
+    // Writes the live values and the maps of every keyspace into a new folder next to the database
+    // and swaps it in. Unlike a compaction it leaves WAL segments, map deltas and bloom filters behind,
+    // the tables reopened from the new folder start over with fresh ones.
+    //
+    // Renaming the new folder into place is the commit point, finish_vacuum completes a swap
+    // interrupted before that.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip_all))]
+    pub fn vacuum(&mut self) -> Result<()> {
+        self.check_writable()?;
+        // The history they read is not carried over
+        if self.snapshots.oldest().is_some() {
+            return Err(Error::Index(IndexError::SnapshotsOpen));
+        }
+        self.flush()?;
+        let backend = Arc::clone(&self.options.backend);
+        let vacuum_folder = Index::sibling_folder(&self.folder, VACUUM_SUFFIX)?;
+        let vacuumed_folder = Index::sibling_folder(&self.folder, VACUUMED_SUFFIX)?;
+        if backend.exists(&vacuum_folder) {
+            backend.remove_dir_all(&vacuum_folder)?;
+        }
+        backend.create_dir_all(&vacuum_folder)?;
+
+        let mut locations = Vec::new();
+        for table in self.tables() {
+            locations.extend(table.live_locations()?);
+        }
+        locations.sort_by_key(|location| (location.block, location.pointer));
+        locations.dedup();
+        let mut values = ValueLog::create(&vacuum_folder.join(DATA_FILE_NAME), &self.options)?;
+        let relocated = self.copy_values(&locations, &mut values)?;
+        values.sync()?;
+        drop(values);
+        for (name, table) in self.named_tables() {
+            let folder = match name {
+                Some(name) => vacuum_folder.join(COLUMN_FAMILY_FOLDER).join(name),
+                None => vacuum_folder.clone(),
+            };
+            table.write_vacuumed(backend.as_ref(), &folder, &relocated)?;
+        }
+        backend.sync_dir(&vacuum_folder)?;
+        event!(self.options.log_level, INFO, values = locations.len(), "rebuilt database");
+
+        self.background_sync = None;
+        backend.rename(&self.folder, &vacuumed_folder)?;
+        if let Err(e) = backend.rename(&vacuum_folder, &self.folder) {
+            backend.rename(&vacuumed_folder, &self.folder)?;
+            return Err(e.into());
+        }
+        let options = self.options.clone().reset(false);
+        self.lookup_table = ShardedTable::open(&self.folder, &options)?;
+        self.lookup_table.share(&self.lsn, &self.snapshots);
+        for (name, table) in self.column_families.iter_mut() {
+            *table = ShardedTable::open(&self.folder.join(COLUMN_FAMILY_FOLDER).join(name), &options)?;
+            table.share(&self.lsn, &self.snapshots);
+        }
+        self.swap_values()?;
+        backend.remove_dir_all(&vacuumed_folder)?;
+        event!(self.options.log_level, INFO, size = backend.file_len(self.values.path())?, "swapped in vacuumed database");
+        self.start_background_sync()
     }
-}
\ No newline at end of file
+
+    // Reads back the value of every key and the files of every lookup table
+    pub fn check(&self) -> Result<CheckReport> {
+        let mut report = CheckReport::default();
+        let mut broken = HashSet::new();
+        for (name, table) in self.named_tables() {
+            for entry in table.range::<[u8], _>(..) {
+                let (key, location) = entry?;
+                report.keys_checked += 1;
+                if self.values.read(location).is_err() {
+                    report.broken_keys.push((name.map(str::to_string), key));
+                    broken.insert(location);
+                }
+            }
+            report.corrupt_files.extend(table.corrupt_files()?);
+        }
+        let mut live = Vec::new();
+        for table in self.tables() {
+            live.extend(table.live_locations()?.into_iter().filter(|location| !broken.contains(location)));
+        }
+        report.orphaned_bytes = self.values.used_size().saturating_sub(self.values.stored_size(&live)?);
+        Ok(report)
+    }
+
+    // Deletes the broken keys and vacuums, which rewrites every file the check found corrupt
+    // and reclaims the orphaned data
+    pub fn check_and_repair(&mut self) -> Result<CheckReport> {
+        let mut report = self.check()?;
+        if report.is_clean() && report.orphaned_bytes == 0 {
+            return Ok(report);
+        }
+        for (name, key) in &report.broken_keys {
+            warning!(self.options.log_level, keyspace = %name.as_deref().unwrap_or("default"), key = %key.escape_ascii(); "deleting key with an unreadable value");
+            self.remove(name.as_deref(), key)?;
+        }
+        self.vacuum()?;
+        report.repaired = true;
+        Ok(report)
+    }
+
+    // <folder>.<suffix> next to folder
+    fn sibling_folder(folder: &Path, suffix: &str) -> Result<PathBuf> {
+        let name = folder.file_name()
+            .ok_or_else(|| Error::Custom(format!("{} has no folder name", folder.display())))?;
+        let mut name = name.to_os_string();
+        name.push(format!(".{suffix}"));
+        Ok(folder.with_file_name(name))
+    }
+
+    // Block size the database was created with, recorded in the header of the map.db of the
+    // default keyspace. None for a new database.
+    fn stored_block_size(backend: &dyn StorageBackend, folder: &Path) -> Result<Option<usize>> {
+        let path = folder.join("map.db");
+        if !backend.exists(&path) {
+            return Ok(None);
+        }
+        let file = backend.open(&path, OpenMode::Read)?;
+        if file.len()? < HEADER_SIZE as u64 {
+            return Ok(None);
+        }
+        let mut header = [0; HEADER_SIZE];
+        file.read_at(&mut header, 0)?;
+        // A damaged header is reported by the lookup table when it opens the file
+        Ok(FileHeader::decode(&header, MAP_MAGIC).ok().map(|header| header.block_size()))
+    }
+
+    // A missing folder next to a vacuumed one means a vacuum was interrupted between its renames,
+    // its new folder was complete by then
+    fn finish_vacuum(backend: &dyn StorageBackend, folder: &Path, log_level: LogLevel) -> Result<()> {
+        if folder.file_name().is_none() || backend.exists(folder) {
+            return Ok(());
+        }
+        let vacuum_folder = Index::sibling_folder(folder, VACUUM_SUFFIX)?;
+        let vacuumed_folder = Index::sibling_folder(folder, VACUUMED_SUFFIX)?;
+        if backend.exists(&vacuumed_folder) && backend.exists(&vacuum_folder) {
+            event!(log_level, INFO, path = %folder.display(), "completing an interrupted vacuum");
+            backend.rename(&vacuum_folder, folder)?;
+        }
+        Ok(())
+    }
+
+    // Folders of a vacuum that never committed or didn't get to remove the old database,
+    // removed once the database is locked
+    fn remove_vacuum_leftovers(
+        backend: &dyn StorageBackend,
+        folder: &Path,
+        log_level: LogLevel,
+        recovery: &mut RecoveryReport,
+    ) -> Result<()> {
+        if folder.file_name().is_none() {
+            return Ok(());
+        }
+        for suffix in [VACUUM_SUFFIX, VACUUMED_SUFFIX] {
+            let path = Index::sibling_folder(folder, suffix)?;
+            if backend.is_dir(&path) {
+                warning!(log_level, path = %path.display(); "removing folder left behind by a vacuum");
+                backend.remove_dir_all(&path)?;
+                recovery.repaired_files.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    // Copies every lookup table into dest and hands out the value file for copying it afterwards.
+    // Called under the write lock, returns the value file with its length and last block, the
+    // only part of it that later writes change.
+    pub fn backup_tables(&self, dest: &Path) -> Result<(Arc<dyn StorageFile>, u64, Vec<u8>)> {
+        let backend = self.options.backend.as_ref();
+        for table in self.tables() {
+            // Column families and shards keep their subfolders in the copy
+            for path in table.files()? {
+                let copy = dest.join(path.strip_prefix(&self.folder).map_err(|_| "table file outside the database folder")?);
+                backend.create_dir_all(copy.parent().ok_or("table file without a folder")?)?;
+                copy_durably(backend, &path, &copy)?;
+            }
+        }
+        self.values.sync()?;
+        let data = self.values.file_handle();
+        let len = data.len()?;
+        let tail_len = len.min(self.options.block_size as u64);
+        let mut tail = vec![0; tail_len as usize];
+        data.read_at(&mut tail, len - tail_len)?;
+        Ok((data, len, tail))
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        let (mut index_size, mut wal_size) = (0, 0);
+        for table in self.tables() {
+            index_size += table.disk_size()?;
+            wal_size += table.wal_size()?;
+        }
+        Ok(Stats {
+            wal_bytes_written: self.tables().map(|table| table.wal_bytes_written()).sum(),
+            tombstones: self.tables().map(|table| table.tombstone_count() as u64).sum(),
+            compactions: self.compactions,
+            cache: self.cache_stats(),
+            memory: self.memory_usage(),
+            data_size: self.options.backend.file_len(self.values.path())?,
+            index_size,
+            wal_size,
+            ..self.counters.stats()
+        })
+    }
+
+    pub fn log_level(&self) -> LogLevel {
+        self.options.log_level
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let (index, wal) = self.tables().map(ShardedTable::memory_usage)
+            .fold((0, 0), |(index, wal), (table_index, table_wal)| (index + table_index, wal + table_wal));
+        MemoryUsage { index: index as u64, wal: wal as u64, cache: self.cache.memory_size() as u64 }
+    }
+
+    pub fn size_on_disk(&self) -> Result<DiskUsage> {
+        let mut keyspaces = Vec::new();
+        let mut locations = Vec::new();
+        for (name, table) in self.named_tables() {
+            let table_locations = table.live_locations()?;
+            let wal_size = table.wal_size()?;
+            keyspaces.push(KeyspaceUsage {
+                name: name.map(str::to_string),
+                live_bytes: self.values.stored_size(&table_locations)?,
+                wal_size,
+                index_size: table.disk_size()? - wal_size,
+            });
+            locations.extend(table_locations);
+        }
+        let data_size = self.options.backend.file_len(self.values.path())?;
+        let live_bytes = self.values.stored_size(&locations)?;
+        let dead_bytes = self.values.used_size().saturating_sub(live_bytes);
+        Ok(DiskUsage { data_size, live_bytes, dead_bytes, keyspaces })
+    }
+
+    pub(crate) fn backend(&self) -> Arc<dyn StorageBackend> {
+        Arc::clone(&self.options.backend)
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    pub fn last_sequence(&self) -> u64 {
+        self.lsn.load(Ordering::SeqCst)
+    }
+
+    pub fn recovery_report(&self) -> RecoveryReport {
+        self.recovery.clone()
+    }
+
+    // Truncates the WAL of every table in folder after the record with sequence number lsn
+    pub fn truncate_wals(backend: &dyn StorageBackend, folder: &Path, lsn: u64) -> Result<()> {
+        ShardedTable::truncate_wal(backend, folder, lsn)?;
+        let column_family_folder = folder.join(COLUMN_FAMILY_FOLDER);
+        if backend.exists(&column_family_folder) {
+            for path in backend.list(&column_family_folder)? {
+                if backend.is_dir(&path) {
+                    ShardedTable::truncate_wal(backend, &path, lsn)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn folder(&self) -> &Path {
+        &self.folder
+    }
+
+    // Skips the flush on drop, like a process that died would
+    #[cfg(test)]
+    pub(crate) fn abandon(&mut self) {
+        self.closed = true;
+    }
+
+    // Removes dir once the index is dropped
+    pub(crate) fn remove_on_close(&mut self, dir: TempDir) {
+        self.temp_dir = Some(dir);
+    }
+
+    // Flushes the lookup tables and syncs every file, so the next open can skip reading the WALs.
+    // Also done when the index is dropped, where a failure can only be logged. Nothing can be
+    // written afterwards.
+    pub fn close(&mut self) -> Result<()> {
+        if self.closed || self.options.read_only {
+            return Ok(());
+        }
+        self.background_sync = None;
+        let flushed = self.flush();
+        self.closed = true;
+        flushed?;
+        self.lookup_table.finish_merges()?;
+        for table in self.column_families.values_mut() {
+            table.finish_merges()?;
+        }
+        // Last, the flag tells the next open that the maps cover every WAL record
+        for table in self.shards() {
+            table.mark_clean_shutdown()?;
+        }
+        event!(self.options.log_level, INFO, path = %self.folder.display(), "closed database");
+        Ok(())
+    }
+
+    // Utility function to delete every file backing the index
+    pub fn cleanup(mut self) -> Result<()> {
+        // Nothing is flushed to files that are about to be deleted
+        self.closed = true;
+        let backend = Arc::clone(&self.options.backend);
+        let log_level = self.options.log_level;
+        let folder = self.folder.clone();
+        let values_path = self.values.path().to_path_buf();
+        let lock_path = self.lookup_table.lock_path();
+        // Removed last, like when the index is dropped
+        let temp_dir = self.temp_dir.take();
+        // The lock is released once the lookup tables are closed
+        drop(self);
+        ShardedTable::cleanup(backend.as_ref(), &folder, log_level)?;
+        let column_family_folder = folder.join(COLUMN_FAMILY_FOLDER);
+        if backend.exists(&column_family_folder) {
+            backend.remove_dir_all(&column_family_folder)?;
+        }
+        if backend.exists(&values_path) {
+            backend.remove_file(&values_path)?;
+        }
+        if backend.exists(&lock_path) {
+            backend.remove_file(&lock_path)?;
+        }
+        drop(temp_dir);
+        Ok(())
+    }
+}
+
+impl Drop for Index {
+    fn drop(&mut self) {
+        // A panic may have left a write half applied, that is for recovery to sort out
+        if std::thread::panicking() {
+            return;
+        }
+        if let Err(e) = self.close() {
+            warning!(self.options.log_level, error = %e; "failed to flush the database on close");
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use crate::db::database::Db;
+use crate::db::lookup::EntryLocation;
+use crate::db::pin::Pin;
+use crate::db::snapshot::Snapshot;
+use crate::error::{Error, Result};
+
+/// Iterator over key/value pairs returned by [`Db::range`], [`Db::scan_prefix`] and [`Db::iter`].
+///
+/// The matching keys are collected when the iterator is created, values are read lazily
+/// and reflect the entries as they were at that moment.
+/// A [`Db::compact`] keeps the replaced data file open for the iterator until it is dropped.
+pub struct DbIter {
+    db: Db,
+    entries: VecDeque<(Vec<u8>, EntryLocation)>,
+    // Keeps the data file the locations point into readable
+    pin: Pin,
+    // Failure to collect the entries, returned as the first item
+    error: Option<Error>,
+}
+
+impl DbIter {
+    pub(crate) fn new(db: Db, entries: Result<Vec<(Vec<u8>, EntryLocation)>>, pin: Pin) -> Self {
+        let (entries, error) = match entries {
+            Ok(entries) => (entries.into(), None),
+            Err(error) => (VecDeque::new(), Some(error)),
+        };
+        Self { db, entries, pin, error }
+    }
+
+    fn read(&self, (key, location): (Vec<u8>, EntryLocation)) -> Result<(Vec<u8>, Vec<u8>)> {
+        let value = self.db.index().read_pinned(&self.pin, location)?;
+        Ok((key, value))
+    }
+}
+
+impl Iterator for DbIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(error) = self.error.take() {
+            return Some(Err(error));
+        }
+        let entry = self.entries.pop_front()?;
+        Some(self.read(entry))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.entries.len() + usize::from(self.error.is_some());
+        (len, Some(len))
+    }
+}
+
+impl DoubleEndedIterator for DbIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if let Some(error) = self.error.take() {
+            return Some(Err(error));
+        }
+        let entry = self.entries.pop_back()?;
+        Some(self.read(entry))
+    }
+}
+
+/// Iterator over key/value pairs of a [`ReadTransaction`](crate::ReadTransaction).
+///
+/// Unlike a [`DbIter`] it keeps reading the values the transaction sees across writes and compactions.
+pub struct SnapshotIter {
+    db: Db,
+    snapshot: Arc<Snapshot>,
+    column_family: Option<String>,
+    keys: VecDeque<Vec<u8>>,
+    // Failure to collect the keys, returned as the first item
+    error: Option<Error>,
+}
+
+impl SnapshotIter {
+    pub(crate) fn new(db: Db, snapshot: Arc<Snapshot>, column_family: Option<String>, keys: Result<Vec<Vec<u8>>>) -> Self {
+        let (keys, error) = match keys {
+            Ok(keys) => (keys.into(), None),
+            Err(error) => (VecDeque::new(), Some(error)),
+        };
+        Self { db, snapshot, column_family, keys, error }
+    }
+
+    fn read(&self, key: Vec<u8>) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        // None for a key that expired after the keys were collected, it is skipped
+        self.db.index().get_at(self.column_family.as_deref(), &key, &self.snapshot)
+            .map(|value| value.map(|value| (key, value)))
+            .transpose()
+    }
+}
+
+impl Iterator for SnapshotIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(error) = self.error.take() {
+            return Some(Err(error));
+        }
+        while let Some(key) = self.keys.pop_front() {
+            if let Some(entry) = self.read(key) {
+                return Some(entry);
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.keys.len() + usize::from(self.error.is_some());
+        (0, Some(len))
+    }
+}
+
+impl DoubleEndedIterator for SnapshotIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if let Some(error) = self.error.take() {
+            return Some(Err(error));
+        }
+        while let Some(key) = self.keys.pop_back() {
+            if let Some(entry) = self.read(key) {
+                return Some(entry);
+            }
+        }
+        None
+    }
+}
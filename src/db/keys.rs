@@ -0,0 +1,330 @@
+use std::time::{Duration, SystemTime};
+use crate::error::{Error, Result};
+
+/// Element of a [`Tuple`].
+///
+/// Encoded keys sort by the type of their first differing element in the order of the variants,
+/// then by its value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Element {
+    Null,
+    Bytes(Vec<u8>),
+    String(String),
+    U64(u64),
+    I64(i64),
+    /// Point in time, to the nanosecond. Times before the Unix epoch sort before it.
+    Timestamp(SystemTime),
+    /// The 16 bytes of a UUID in their usual order.
+    Uuid([u8; 16]),
+}
+
+/// Composite key whose encoding sorts like its elements, so range scans over encoded keys see
+/// them in the order of the first element, then the second and so on.
+///
+/// The encoding of a tuple is a prefix of the encoding of every tuple that starts with its
+/// elements, so [`Db::scan_prefix`](crate::Db::scan_prefix) with an encoded tuple finds all
+/// longer ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tuple {
+    elements: Vec<Element>,
+}
+
+// Type codes that start every encoded element, in sort order
+const NULL: u8 = 0x00;
+const BYTES: u8 = 0x01;
+const STRING: u8 = 0x02;
+const U64: u8 = 0x10;
+const I64: u8 = 0x11;
+const TIMESTAMP: u8 = 0x20;
+const UUID: u8 = 0x30;
+// Bytes and strings end with TERMINATOR, a 0 byte inside them is followed by ESCAPE.
+// ESCAPE is above every type code, so a longer string sorts after a shorter one it starts with.
+const TERMINATOR: u8 = 0x00;
+const ESCAPE: u8 = 0xff;
+
+impl Tuple {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, element: impl Into<Element>) -> &mut Self {
+        self.elements.push(element.into());
+        self
+    }
+
+    pub fn elements(&self) -> &[Element] {
+        &self.elements
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for element in &self.elements {
+            match element {
+                Element::Null => buffer.push(NULL),
+                Element::Bytes(bytes) => Tuple::encode_escaped(&mut buffer, BYTES, bytes),
+                Element::String(string) => Tuple::encode_escaped(&mut buffer, STRING, string.as_bytes()),
+                Element::U64(value) => {
+                    buffer.push(U64);
+                    buffer.extend_from_slice(&value.to_be_bytes());
+                }
+                Element::I64(value) => {
+                    buffer.push(I64);
+                    buffer.extend_from_slice(&Tuple::flip_sign(*value).to_be_bytes());
+                }
+                Element::Timestamp(time) => {
+                    // Seconds since the epoch, negative before it, and the nanoseconds into that second
+                    let (seconds, nanos) = match time.duration_since(SystemTime::UNIX_EPOCH) {
+                        Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+                        Err(e) => {
+                            let before = e.duration();
+                            match before.subsec_nanos() {
+                                0 => (-(before.as_secs() as i64), 0),
+                                nanos => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+                            }
+                        }
+                    };
+                    buffer.push(TIMESTAMP);
+                    buffer.extend_from_slice(&Tuple::flip_sign(seconds).to_be_bytes());
+                    buffer.extend_from_slice(&nanos.to_be_bytes());
+                }
+                Element::Uuid(uuid) => {
+                    buffer.push(UUID);
+                    buffer.extend_from_slice(uuid);
+                }
+            }
+        }
+        buffer
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Tuple> {
+        let mut tuple = Tuple::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let code = bytes[offset];
+            offset += 1;
+            let element = match code {
+                NULL => Element::Null,
+                BYTES => Element::Bytes(Tuple::decode_escaped(bytes, &mut offset)?),
+                STRING => {
+                    let string = String::from_utf8(Tuple::decode_escaped(bytes, &mut offset)?)
+                        .map_err(|e| Error::Serialization(format!("tuple string is not UTF-8: {e}")))?;
+                    Element::String(string)
+                }
+                U64 => Element::U64(u64::from_be_bytes(Tuple::take(bytes, &mut offset)?)),
+                I64 => Element::I64(Tuple::unflip_sign(u64::from_be_bytes(Tuple::take(bytes, &mut offset)?))),
+                TIMESTAMP => {
+                    let seconds = Tuple::unflip_sign(u64::from_be_bytes(Tuple::take(bytes, &mut offset)?));
+                    let nanos = u32::from_be_bytes(Tuple::take(bytes, &mut offset)?);
+                    if nanos >= 1_000_000_000 {
+                        return Err(Error::Serialization(format!("tuple timestamp with {nanos} nanoseconds")));
+                    }
+                    let time = match seconds {
+                        0.. => SystemTime::UNIX_EPOCH.checked_add(Duration::new(seconds as u64, nanos)),
+                        _ => SystemTime::UNIX_EPOCH.checked_sub(Duration::from_secs(seconds.unsigned_abs()))
+                            .and_then(|time| time.checked_add(Duration::from_nanos(nanos as u64))),
+                    };
+                    Element::Timestamp(time.ok_or_else(|| Error::Serialization("tuple timestamp out of range".to_string()))?)
+                }
+                UUID => Element::Uuid(Tuple::take(bytes, &mut offset)?),
+                code => return Err(Error::Serialization(format!("unknown tuple type code {code:#04x}"))),
+            };
+            tuple.elements.push(element);
+        }
+        Ok(tuple)
+    }
+
+    fn encode_escaped(buffer: &mut Vec<u8>, code: u8, bytes: &[u8]) {
+        buffer.push(code);
+        for &byte in bytes {
+            buffer.push(byte);
+            if byte == TERMINATOR {
+                buffer.push(ESCAPE);
+            }
+        }
+        buffer.push(TERMINATOR);
+    }
+
+    fn decode_escaped(bytes: &[u8], offset: &mut usize) -> Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        loop {
+            let Some(&byte) = bytes.get(*offset) else {
+                return Err(Error::Serialization("unterminated tuple bytes".to_string()));
+            };
+            *offset += 1;
+            if byte != TERMINATOR {
+                decoded.push(byte);
+            } else if bytes.get(*offset) == Some(&ESCAPE) {
+                decoded.push(TERMINATOR);
+                *offset += 1;
+            } else {
+                return Ok(decoded);
+            }
+        }
+    }
+
+    fn take<const N: usize>(bytes: &[u8], offset: &mut usize) -> Result<[u8; N]> {
+        let taken = bytes.get(*offset..*offset + N)
+            .ok_or_else(|| Error::Serialization("truncated tuple element".to_string()))?;
+        *offset += N;
+        Ok(taken.try_into()?)
+    }
+
+    // Big endian with the sign bit flipped sorts negative numbers before positive ones
+    fn flip_sign(value: i64) -> u64 {
+        (value as u64) ^ (1 << 63)
+    }
+
+    fn unflip_sign(value: u64) -> i64 {
+        (value ^ (1 << 63)) as i64
+    }
+}
+
+impl From<Vec<Element>> for Tuple {
+    fn from(elements: Vec<Element>) -> Self {
+        Tuple { elements }
+    }
+}
+
+impl From<u64> for Element {
+    fn from(value: u64) -> Self {
+        Element::U64(value)
+    }
+}
+
+impl From<i64> for Element {
+    fn from(value: i64) -> Self {
+        Element::I64(value)
+    }
+}
+
+impl From<&str> for Element {
+    fn from(value: &str) -> Self {
+        Element::String(value.to_string())
+    }
+}
+
+impl From<String> for Element {
+    fn from(value: String) -> Self {
+        Element::String(value)
+    }
+}
+
+impl From<&[u8]> for Element {
+    fn from(value: &[u8]) -> Self {
+        Element::Bytes(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Element {
+    fn from(value: Vec<u8>) -> Self {
+        Element::Bytes(value)
+    }
+}
+
+impl From<SystemTime> for Element {
+    fn from(value: SystemTime) -> Self {
+        Element::Timestamp(value)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let before_epoch = SystemTime::UNIX_EPOCH - Duration::new(5, 300);
+        let tuple = Tuple::from(vec![
+            Element::Null,
+            Element::from(&b"a\0b"[..]),
+            Element::from("user"),
+            Element::from(u64::MAX),
+            Element::from(-42i64),
+            Element::from(before_epoch),
+            Element::from(SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789)),
+            Element::Uuid([7; 16]),
+        ]);
+        assert_eq!(Tuple::decode(&tuple.encode())?, tuple);
+        assert!(Tuple::decode(&[STRING, b'a']).is_err());
+        assert!(Tuple::decode(&[U64, 1, 2]).is_err());
+        assert!(Tuple::decode(&[0x99]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix() {
+        let user = Tuple::new().push("user").push(7u64).encode();
+        let order = Tuple::new().push("user").push(7u64).push("orders").push(1u64).encode();
+        assert!(order.starts_with(&user));
+    }
+
+    fn element() -> impl Strategy<Value = Element> {
+        prop_oneof![
+            Just(Element::Null),
+            any::<Vec<u8>>().prop_map(Element::Bytes),
+            ".{0,8}".prop_map(Element::String),
+            any::<u64>().prop_map(Element::U64),
+            any::<i64>().prop_map(Element::I64),
+            (-1_000_000i64..1_000_000, 0..1_000_000_000u32).prop_map(|(seconds, nanos)| {
+                let time = match seconds {
+                    0.. => SystemTime::UNIX_EPOCH + Duration::from_secs(seconds as u64),
+                    _ => SystemTime::UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs()),
+                };
+                Element::Timestamp(time + Duration::from_nanos(nanos as u64))
+            }),
+            any::<[u8; 16]>().prop_map(Element::Uuid),
+        ]
+    }
+
+    fn rank(element: &Element) -> u8 {
+        match element {
+            Element::Null => 0,
+            Element::Bytes(_) => 1,
+            Element::String(_) => 2,
+            Element::U64(_) => 3,
+            Element::I64(_) => 4,
+            Element::Timestamp(_) => 5,
+            Element::Uuid(_) => 6,
+        }
+    }
+
+    fn compare(a: &[Element], b: &[Element]) -> std::cmp::Ordering {
+        for (a, b) in a.iter().zip(b) {
+            let ordering = rank(a).cmp(&rank(b)).then_with(|| match (a, b) {
+                (Element::Bytes(a), Element::Bytes(b)) => a.cmp(b),
+                (Element::String(a), Element::String(b)) => a.cmp(b),
+                (Element::U64(a), Element::U64(b)) => a.cmp(b),
+                (Element::I64(a), Element::I64(b)) => a.cmp(b),
+                (Element::Timestamp(a), Element::Timestamp(b)) => a.cmp(b),
+                (Element::Uuid(a), Element::Uuid(b)) => a.cmp(b),
+                _ => std::cmp::Ordering::Equal,
+            });
+            if ordering.is_ne() {
+                return ordering;
+            }
+        }
+        a.len().cmp(&b.len())
+    }
+
+    proptest! {
+        #[test]
+        fn test_encoding_sorts_like_elements(
+            a in prop::collection::vec(element(), 0..4),
+            b in prop::collection::vec(element(), 0..4),
+        ) {
+            let (a, b) = (Tuple::from(a), Tuple::from(b));
+            prop_assert_eq!(a.encode().cmp(&b.encode()), compare(a.elements(), b.elements()));
+            prop_assert_eq!(Tuple::decode(&a.encode()).ok(), Some(a));
+        }
+    }
+}
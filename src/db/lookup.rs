@@ -1,183 +1,2384 @@
-use std::fs::{self, OpenOptions, File};
-use std::collections::HashMap;
-use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::ops::{Bound, Range, RangeBounds};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::db::backend::{OpenMode, StorageBackend, StorageFile};
+use crate::db::clock::Clock;
+use crate::db::engine::StorageEngine;
+use crate::db::hash::{KeyHashState, KeyMap};
+use crate::db::hash_index::HashIndex;
+use crate::db::header::{FileHeader, HEADER_SIZE, MAP_MAGIC, WAL_MAGIC};
+use crate::db::lsm::LsmIndex;
+use crate::db::options::{DbOptions, Engine, LogLevel};
+use crate::db::recovery::RecoveryReport;
+use crate::db::snapshot::{Snapshot, SnapshotRegistry};
+use crate::db::storage::COMPACTED_DATA_FILE_NAME;
+use crate::db::sync::SyncPolicy;
+use crate::db::trace::{event, warning};
+use crate::db::varint::{decode_varint, encode_varint};
 use crate::error::{Error, Result};
 use std::path::{Path, PathBuf};
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+// Block number in data.db and byte offset of the entry inside that block, with the bytes the
+// value takes there including its overflow blocks. The size is 0 if it was written before format
+// version 16.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct EntryLocation {
     pub block: u64,
-    pub pointer: u64
+    pub pointer: u64,
+    pub size: u32,
 }
 
 impl EntryLocation {
-    fn bit_offset(&self) -> usize {
-        BTREE_BLOCK_SIZE * (self.block as usize) + (self.pointer as usize)
+    // Pointer as it is written to map.db, its deltas and the WAL, see VALUE_SIZES_VERSION
+    fn packed_pointer(&self) -> u64 {
+        match self.block {
+            TOMBSTONE_BLOCK => self.pointer,
+            _ => self.pointer | (self.size as u64) << 32,
+        }
+    }
+
+    fn unpack(block: u64, pointer: u64) -> Self {
+        match block {
+            TOMBSTONE_BLOCK => EntryLocation { block, pointer, size: 0 },
+            _ => EntryLocation { block, pointer: pointer & u32::MAX as u64, size: (pointer >> 32) as u32 },
+        }
+    }
+}
+
+// Left in the map by the remove of a key, with its sequence number and the time in milliseconds since
+// the Unix epoch it was applied at. Kept until it is older than DbOptions::tombstone_retention.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Tombstone {
+    pub lsn: u64,
+    pub deleted_at: u64,
+}
+
+impl Tombstone {
+    // Left for a key deleted outright, it only hides the key in the files written before and is
+    // never read back as a tombstone
+    pub(crate) const DELETED: Tombstone = Tombstone { lsn: 0, deleted_at: 0 };
+
+    // Location of the map record that holds the tombstone
+    pub(crate) fn location(&self) -> EntryLocation {
+        EntryLocation { block: TOMBSTONE_BLOCK, pointer: self.lsn, size: 0 }
+    }
+}
+
+// Entry of a key in a key index, a location with its expiry time, 0 if the key never expires,
+// or the tombstone of the key. Stored as the value of the key, encoded like a map record.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum IndexEntry {
+    Put(EntryLocation, u64),
+    Remove(Tombstone),
+}
+
+pub(crate) const INDEX_ENTRY_SIZE: usize = LOCATION_SIZE + EXPIRY_SIZE;
+
+impl IndexEntry {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let (location, expiry) = self.fields();
+        let mut buffer = Vec::with_capacity(INDEX_ENTRY_SIZE);
+        LookupTable::encode_location(&mut buffer, &location);
+        buffer.extend_from_slice(&expiry.to_le_bytes());
+        buffer
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self> {
+        match (bytes.len(), LookupTable::read_location(bytes, 0)) {
+            (INDEX_ENTRY_SIZE, Some(location)) => {
+                Ok(IndexEntry::from_record(location, u64::from_le_bytes(bytes[LOCATION_SIZE..].try_into()?)))
+            }
+            _ => Err(Error::InvalidFormat("corrupt index entry".to_string())),
+        }
+    }
+
+    // Location and expiry time as a map record holds them
+    pub(crate) fn fields(&self) -> (EntryLocation, u64) {
+        match self {
+            IndexEntry::Put(location, expires_at) => (*location, *expires_at),
+            IndexEntry::Remove(tombstone) => (tombstone.location(), tombstone.deleted_at),
+        }
+    }
+
+    pub(crate) fn from_record(location: EntryLocation, expiry: u64) -> Self {
+        match location.block {
+            TOMBSTONE_BLOCK => IndexEntry::Remove(Tombstone { lsn: location.pointer, deleted_at: expiry }),
+            _ => IndexEntry::Put(location, expiry),
+        }
+    }
+}
+
+// Key index of a lookup table, a storage engine whose values are encoded IndexEntry. get also
+// returns the tombstones of removed keys, scan only the keys with a location, expired ones
+// included. compact writes everything into map.db, flush whatever the engine writes instead.
+// Chosen by DbOptions::engine, see LookupTable::open_index.
+pub(crate) trait KeyIndex: StorageEngine {
+    // Sequence number the files of the index cover
+    fn flushed_lsn(&self) -> u64;
+
+    // Called after the entries of the record with sequence number lsn were put
+    fn commit(&mut self, lsn: u64) -> Result<()>;
+
+    // Keys with a location, expired ones included
+    fn len(&self) -> usize;
+
+    fn tombstone_count(&self) -> usize;
+
+    // Keys that expired by now
+    fn expired(&self, now: u64) -> Vec<Vec<u8>>;
+
+    fn expired_count(&self, now: u64) -> usize;
+
+    // Keys whose tombstones were removed before horizon
+    fn expired_tombstones(&self, horizon: u64) -> Vec<Vec<u8>>;
+
+    fn estimate_range_count(&self, bounds: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> usize;
+
+    fn approximate_size(&self, bounds: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Result<u64>;
+
+    // Writes every entry to a map.db at path with its location passed through relocate
+    fn write_relocated(&self, path: &Path, relocate: &dyn Fn(&EntryLocation) -> Result<EntryLocation>) -> Result<()>;
+
+    // Renames the map written by write_relocated over map.db and reads it back
+    fn commit_relocated(&mut self, path: &Path) -> Result<()>;
+
+    // Whether map.db is still searched on disk, see DbOptions::low_memory
+    fn is_lazy(&self) -> bool;
+
+    fn load(&mut self) -> Result<()>;
+
+    // Swaps in the result of a background merge, waiting for it with wait set
+    fn finish_merge(&mut self, wait: bool) -> Result<()>;
+
+    fn files(&self) -> Result<Vec<PathBuf>>;
+
+    fn corrupt_files(&self) -> Result<Vec<PathBuf>>;
+
+    fn memory_usage(&self) -> usize;
+}
+
+/// Failure of the write-ahead log of a lookup table.
+#[derive(Debug)]
+pub enum WalError {
+    /// A read-only handle found no WAL segment to read in the folder.
+    MissingSegment { folder: PathBuf },
+    /// An operation that needs an empty WAL was attempted before flushing.
+    NotFlushed { operation: &'static str },
+    /// An I/O operation on a WAL segment failed.
+    Io { path: PathBuf, operation: &'static str, source: std::io::Error },
+}
+
+/// Failure of the persisted key map of a lookup table.
+#[derive(Debug)]
+pub enum MapError {
+    /// A record of map.db could not be decoded.
+    CorruptRecord { path: PathBuf, offset: usize },
+    /// The checksum at the end of map.db does not match its contents.
+    ChecksumMismatch { path: PathBuf },
+    /// A compaction did not copy a value the map still points at.
+    MissingRelocation { block: u64, pointer: u64 },
+    /// An I/O operation on a map file failed.
+    Io { path: PathBuf, operation: &'static str, source: std::io::Error },
+}
+
+impl WalError {
+    // Adds the segment and operation to an I/O error, for use with map_err
+    pub(crate) fn io<'a>(path: &'a Path, operation: &'static str) -> impl FnOnce(std::io::Error) -> Error + 'a {
+        move |source| Error::Wal(WalError::Io { path: path.to_path_buf(), operation, source })
+    }
+}
+
+impl MapError {
+    pub(crate) fn io<'a>(path: &'a Path, operation: &'static str) -> impl FnOnce(std::io::Error) -> Error + 'a {
+        move |source| Error::Map(MapError::Io { path: path.to_path_buf(), operation, source })
+    }
+}
+
+impl std::fmt::Display for WalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::fmt::Display for MapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for WalError {}
+
+impl std::error::Error for MapError {}
+
+pub(crate) struct LookupTable {
+    backend: Arc<dyn StorageBackend>,
+    clock: Arc<dyn Clock>,
+    // Held for the lifetime of the table, the lock is released when the file is closed
+    _lock_file: Option<Arc<dyn StorageFile>>,
+    // Entries of the keys by the engine DbOptions::engine chose, see KeyIndex
+    index: Box<dyn KeyIndex>,
+    map_path: PathBuf,
+    // Block size of data.db, recorded in the header of every file the table writes
+    block_size: usize,
+    tombstone_retention: u64,
+    folder: PathBuf,
+    // Active WAL segment, records are appended here until it exceeds max_wal_segment_size
+    wal_file: Arc<dyn StorageFile>,
+    wal_path: PathBuf,
+    wal_segment: u64,
+    wal_segment_size: u64,
+    max_wal_segment_size: u64,
+    // Bytes in all WAL segments, the WAL is rewritten without superseded records once it exceeds next_wal_rewrite
+    wal_size: u64,
+    wal_rewrite_threshold: u64,
+    next_wal_rewrite: u64,
+    // Bytes appended to the WAL since the table was opened, and since the last flush
+    wal_bytes_written: u64,
+    unflushed_wal_bytes: u64,
+    // Operations logged since the last flush with their sequence numbers, the number of keys
+    // they write or remove and the memory they take
+    wal: Vec<(u64, WalOperation)>,
+    unflushed_writes: usize,
+    wal_memory: usize,
+    sync_policy: SyncPolicy,
+    log_level: LogLevel,
+    // Last sequence number handed to a WAL record, shared by every table of the database
+    lsn: Arc<AtomicU64>,
+    // Sequence number of the last record applied to this table, the index stores it on flush
+    applied_lsn: u64,
+    // Sequence number the index covers on disk, the records up to it are no longer in the WAL
+    flushed_lsn: u64,
+    // Locations that were replaced while snapshots were alive, as (sequence number of the replacing record,
+    // previous location)
+    history: HashMap<Vec<u8>, Vec<(u64, Option<EntryLocation>)>>,
+    snapshots: SnapshotRegistry,
+    recovery: RecoveryReport,
+}
+
+// Keys are variable length and written as a u32 length followed by the key bytes
+const KEY_LENGTH_SIZE: usize = 4;
+pub(crate) const LOCATION_SIZE: usize = 16;
+// Map records of format version 2 end with the expiry time, 0 if the key never expires
+pub(crate) const EXPIRY_SIZE: usize = 8;
+// Since format version 3 map.db ends with a crc32 of everything before it
+pub(crate) const CHECKSUM_SIZE: usize = 4;
+// Since format version 6 a flush may write a delta file next to map.db instead of rewriting it.
+// Its records are keys followed by DELTA_PUT with the map record fields or by DELTA_REMOVE.
+pub(crate) const DELTA_PUT: u8 = 1;
+pub(crate) const DELTA_REMOVE: u8 = 0;
+// Since format version 12 map.db holds the tombstones of removed keys like other records, with
+// TOMBSTONE_BLOCK as block, the sequence number of the remove as pointer and its time as expiry.
+// DELTA_REMOVE is followed by the sequence number and the time.
+pub(crate) const TOMBSTONE_BLOCK: u64 = u64::MAX;
+pub(crate) const TOMBSTONES_VERSION: u16 = 12;
+// Since format version 4 the header of map.db is followed by the sequence number of the last
+// WAL record the map covers, and every WAL record body starts with SEQUENCED_RECORD and its own
+pub(crate) const LSN_SIZE: usize = 8;
+pub(crate) const SEQUENCED_RECORD: u8 = 6;
+// Since format version 5 a flush logs the sequence number the new map covers in a record of its own,
+// the body is CHECKPOINT_RECORD followed by it
+pub(crate) const CHECKPOINT_RECORD: u8 = 7;
+// Since format version 7 a batch spanning several shards is logged as one part per shard, the body is
+// PART_RECORD, the sequence number they share, the number of shards and their u32 indexes, then the
+// operations on the keys of the shard
+pub(crate) const PART_RECORD: u8 = 8;
+pub(crate) const SHARD_INDEX_SIZE: usize = 4;
+// Since format version 9 map.db holds its keys in order, each written as the u16 length of the prefix
+// it shares with the key before it followed by the rest of the key like any other
+const SHARED_PREFIX_SIZE: usize = 2;
+const PREFIXED_KEYS_VERSION: u16 = 9;
+// Counts of operations in a batch and of shards in a part
+pub(crate) const COUNT_SIZE: usize = 4;
+// Since format version 11 WAL records are compact, see WalFormat. Segments of older versions are
+// still read but never appended to.
+const COMPACT_WAL_VERSION: u16 = 11;
+// Since format version 13 closing the database flags the header of the active WAL segment, an open
+// that finds the flag skips reading the WAL. Active segments of older versions are sealed on open.
+const CLEAN_SHUTDOWN_VERSION: u16 = 13;
+const WAL_CHECKSUM_SIZE: usize = 4;
+// Since format version 14 the sequence number in map.db is followed by the number of keys and of
+// tombstones, u64 each, so loading can size the tables up front
+const KEY_COUNTS_VERSION: u16 = 14;
+const COUNT_BYTES: usize = 8;
+// map.db is read this many bytes at a time, it never has to fit into memory as a whole
+const MAP_READ_CHUNK: usize = 1024 * 1024;
+// Since format version 15 every MAP_RESTART_INTERVAL-th record of map.db is a restart record, whose
+// key shares no prefix with the one before it. The records are followed by the u64 offsets of the
+// restart records and their u64 count, so a key can be found by binary search, see SortedMap.
+pub(crate) const SORTED_MAP_VERSION: u16 = 15;
+const MAP_RESTART_INTERVAL: usize = 64;
+// Since format version 16 the upper 32 bits of the pointer of a location in map.db, its deltas and
+// the WAL hold the bytes its value takes in data.db, saturated at u32::MAX. Pointers never reach
+// them, locations of older versions read as values of unknown size 0. Tombstone records keep
+// their sequence number as it is.
+const VALUE_SIZES_VERSION: u16 = 16;
+pub(crate) const LOCK_FILE_NAME: &str = "LOCK";
+// Range counts are exact up to EXACT_RANGE_COUNT keys and estimated from KEY_SAMPLES keys beyond that
+pub(crate) const EXACT_RANGE_COUNT: usize = 1024;
+pub(crate) const KEY_SAMPLES: usize = 256;
+// Least bytes of WAL, and operations, a recovery thread is given. Smaller logs use fewer threads.
+const PARALLEL_REPLAY_BYTES: usize = 1024 * 1024;
+const PARALLEL_REPLAY_OPERATIONS: usize = 16 * 1024;
+// Databases written before WAL segments existed have a single wal.db, it is read as segment 0
+const LEGACY_WAL_FILE_NAME: &str = "wal.db";
+pub(crate) const BLOOM_FILE_NAME: &str = "bloom.db";
+pub(crate) const MAP_FILE_NAME: &str = "map.db";
+
+// A relocated map that was written to disk but not yet swapped in, with the versions kept for
+// snapshots relocated alike
+pub(crate) struct Relocation {
+    history: HashMap<Vec<u8>, Vec<(u64, Option<EntryLocation>)>>,
+}
+
+// Number, path and open file of a WAL segment
+type WalSegment = (u64, PathBuf, Arc<dyn StorageFile>);
+// Locations, expiry times, tombstones and the sequence number of the last record they cover, read from map.db
+type MapContents = (KeyMap<EntryLocation>, KeyMap<u64>, KeyMap<Tombstone>, u64);
+// Operation replayed from the WAL with its sequence number, numbered by position if the record had none
+type ReplayedOperation = (u64, WalOperation);
+// Sequence number of a replayed part with the shards that logged the batch it belongs to
+pub(crate) type ReplayedPart = (u64, Vec<u32>);
+
+// A record decoded from the WAL
+#[derive(Debug)]
+pub(crate) struct WalRecord {
+    // None in records written before format version 4
+    pub lsn: Option<u64>,
+    // None for checkpoint records
+    pub operation: Option<WalOperation>,
+    // Shards holding a part of the same batch, empty unless the record is one
+    pub shards: Vec<u32>,
+}
+
+// Reads the records of map.db front to back a chunk at a time, computing the checksum of the bytes
+// before it on the way
+struct MapReader<'a> {
+    file: &'a dyn StorageFile,
+    // Bytes read and not consumed yet start at consumed, the first is at start in the file
+    buffer: Vec<u8>,
+    consumed: usize,
+    start: u64,
+    // Where the records end, and where the checksum is if the file has one. The restart offsets
+    // are in between.
+    end: u64,
+    checksum_at: u64,
+    checksum: Option<crc32fast::Hasher>,
+    chunk: usize,
+}
+
+impl<'a> MapReader<'a> {
+    fn new(file: &'a dyn StorageFile, end: u64, checksum_at: Option<u64>, chunk: usize) -> Self {
+        let checksum = checksum_at.map(|_| crc32fast::Hasher::new());
+        let checksum_at = checksum_at.unwrap_or(end);
+        MapReader { file, buffer: Vec::new(), consumed: 0, start: 0, end, checksum_at, checksum, chunk }
+    }
+
+    // Bytes read and not consumed yet
+    fn remaining(&self) -> &[u8] {
+        &self.buffer[self.consumed..]
+    }
+
+    // Offset in the file of the first byte not consumed yet
+    fn offset(&self) -> u64 {
+        self.start + self.consumed as u64
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.consumed += len;
+    }
+
+    // The next len bytes, None if the records end before
+    fn take(&mut self, len: usize) -> io::Result<Option<Vec<u8>>> {
+        while self.remaining().len() < len {
+            if !self.read_more()? {
+                return Ok(None);
+            }
+        }
+        let bytes = self.remaining()[..len].to_vec();
+        self.consume(len);
+        Ok(Some(bytes))
+    }
+
+    // Reads the next chunk after dropping the consumed bytes, false if the records were read to the end
+    fn read_more(&mut self) -> io::Result<bool> {
+        let read_to = self.start + self.buffer.len() as u64;
+        if read_to >= self.end {
+            return Ok(false);
+        }
+        self.buffer.drain(..self.consumed);
+        self.start += self.consumed as u64;
+        self.consumed = 0;
+        let filled = self.buffer.len();
+        let len = (self.end - read_to).min(self.chunk.max(1) as u64) as usize;
+        self.buffer.resize(filled + len, 0);
+        self.file.read_at(&mut self.buffer[filled..], read_to)?;
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update(&self.buffer[filled..]);
+        }
+        Ok(true)
+    }
+
+    // Reads the rest of the file and compares its checksum with the one at its end. Files without
+    // one always match.
+    fn checksum_matches(&mut self) -> io::Result<bool> {
+        while self.read_more()? {
+            self.consumed = self.buffer.len();
+        }
+        let Some(mut checksum) = self.checksum.take() else { return Ok(true) };
+        let mut offset = self.end;
+        while offset < self.checksum_at {
+            let mut bytes = vec![0; (self.checksum_at - offset).min(self.chunk.max(1) as u64) as usize];
+            self.file.read_at(&mut bytes, offset)?;
+            checksum.update(&bytes);
+            offset += bytes.len() as u64;
+        }
+        let mut stored = [0; CHECKSUM_SIZE];
+        match self.file.read_at(&mut stored, self.checksum_at) {
+            Ok(()) => Ok(checksum.finalize() == u32::from_le_bytes(stored)),
+            // Too short to hold one
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// map.db of format version 15 or later searched on disk instead of loaded, see DbOptions::low_memory.
+// A binary search over the keys of the restart records finds the run of records that may hold a
+// key, which is then read and scanned.
+pub(crate) struct SortedMap {
+    file: Arc<dyn StorageFile>,
+    path: PathBuf,
+    // Offsets of the restart records, and where the records end
+    restarts: Vec<u64>,
+    end: u64,
+    // Sequence number of the last WAL record the map covers, and the number of keys and of
+    // tombstones it holds
+    pub lsn: u64,
+    pub keys: usize,
+    pub tombstones: usize,
+}
+
+// Records of map.db start after the sequence number and the key counts since version 14
+const SORTED_MAP_RECORDS: u64 = (HEADER_SIZE + LSN_SIZE + 2 * COUNT_BYTES) as u64;
+
+impl SortedMap {
+    // None if the map has no restart offsets to search, it was written before version 15 or is new.
+    // The whole file is checked against its checksum, a chunk at a time.
+    pub fn open(file: Arc<dyn StorageFile>, path: &Path) -> Result<Option<SortedMap>> {
+        let len = file.len().map_err(MapError::io(path, "read"))?;
+        if len <= HEADER_SIZE as u64 {
+            return Ok(None);
+        }
+        let mut header = [0; HEADER_SIZE];
+        file.read_at(&mut header, 0).map_err(MapError::io(path, "read"))?;
+        if FileHeader::decode(&header, MAP_MAGIC)?.version() < SORTED_MAP_VERSION {
+            return Ok(None);
+        }
+        let checksum_at = len.saturating_sub(CHECKSUM_SIZE as u64).max(HEADER_SIZE as u64);
+        let mut reader = MapReader::new(file.as_ref(), checksum_at, Some(checksum_at), MAP_READ_CHUNK);
+        if !reader.checksum_matches().map_err(MapError::io(path, "read"))? {
+            return Err(Error::Map(MapError::ChecksumMismatch { path: path.to_path_buf() }));
+        }
+        let corrupt = |offset: u64| Error::Map(MapError::CorruptRecord { path: path.to_path_buf(), offset: offset as usize });
+        let end = SortedMap::records_end(file.as_ref(), checksum_at)
+            .map_err(MapError::io(path, "read"))?
+            .ok_or_else(|| corrupt(HEADER_SIZE as u64))?;
+        let mut counts = [0; LSN_SIZE + 2 * COUNT_BYTES];
+        file.read_at(&mut counts, HEADER_SIZE as u64).map_err(MapError::io(path, "read"))?;
+        let lsn = u64::from_le_bytes(counts[..LSN_SIZE].try_into()?);
+        let keys = u64::from_le_bytes(counts[LSN_SIZE..LSN_SIZE + COUNT_BYTES].try_into()?) as usize;
+        let tombstones = u64::from_le_bytes(counts[LSN_SIZE + COUNT_BYTES..].try_into()?) as usize;
+        let mut footer = vec![0; (checksum_at - end) as usize - COUNT_BYTES];
+        file.read_at(&mut footer, end).map_err(MapError::io(path, "read"))?;
+        let mut restarts = Vec::with_capacity(footer.len() / COUNT_BYTES);
+        for bytes in footer.chunks_exact(COUNT_BYTES) {
+            let offset = u64::from_le_bytes(bytes.try_into()?);
+            // In order, within the records and starting with the first of them
+            let previous = restarts.last().copied();
+            if offset >= end || previous.is_some_and(|previous| offset <= previous)
+                || (previous.is_none() && offset != SORTED_MAP_RECORDS)
+            {
+                return Err(corrupt(end));
+            }
+            restarts.push(offset);
+        }
+        if restarts.is_empty() != (end == SORTED_MAP_RECORDS) {
+            return Err(corrupt(end));
+        }
+        Ok(Some(SortedMap { file, path: path.to_path_buf(), restarts, end, lsn, keys, tombstones }))
+    }
+
+    // Where the records of a map of version 15 or later end, given where its restart offsets end.
+    // None if the count before that doesn't leave room for them.
+    fn records_end(file: &dyn StorageFile, footer_end: u64) -> io::Result<Option<u64>> {
+        if footer_end < SORTED_MAP_RECORDS + COUNT_BYTES as u64 {
+            return Ok(None);
+        }
+        let mut count = [0; COUNT_BYTES];
+        file.read_at(&mut count, footer_end - COUNT_BYTES as u64)?;
+        let footer = u64::from_le_bytes(count).checked_mul(COUNT_BYTES as u64)
+            .and_then(|offsets| offsets.checked_add(COUNT_BYTES as u64));
+        Ok(footer.and_then(|footer| footer_end.checked_sub(footer)).filter(|end| *end >= SORTED_MAP_RECORDS))
+    }
+
+    // Entry of key as the map holds it, None if it doesn't
+    pub fn get(&self, key: &[u8]) -> Result<Option<IndexEntry>> {
+        let Some(block) = self.block_of(key)? else { return Ok(None) };
+        let (start, records) = self.read_records(block)?;
+        let mut offset = 0;
+        let mut previous = Vec::new();
+        while offset < records.len() {
+            let Some((record_key, location, expires_at, next)) = LookupTable::read_record(&records, offset, &previous) else {
+                return Err(Error::Map(MapError::CorruptRecord { path: self.path.clone(), offset: (start as usize) + offset }));
+            };
+            match record_key.as_slice().cmp(key) {
+                std::cmp::Ordering::Less => {}
+                std::cmp::Ordering::Equal => return Ok(Some(IndexEntry::from_record(location, expires_at))),
+                std::cmp::Ordering::Greater => break,
+            }
+            previous = record_key;
+            offset = next;
+        }
+        Ok(None)
+    }
+
+    // Blocks of records that may hold the keys within bounds, each starting at a restart record
+    pub fn blocks(&self, bounds: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Result<Range<usize>> {
+        let first = match &bounds.0 {
+            Bound::Included(key) | Bound::Excluded(key) => self.block_of(key)?.unwrap_or(0),
+            Bound::Unbounded => 0,
+        };
+        let last = match &bounds.1 {
+            Bound::Included(key) | Bound::Excluded(key) => self.block_of(key)?.map_or(0, |block| block + 1),
+            Bound::Unbounded => self.restarts.len(),
+        };
+        Ok(first..last.max(first))
+    }
+
+    // Key, location and expiry time of every record of a block
+    pub fn read_block(&self, block: usize) -> Result<Vec<(Vec<u8>, EntryLocation, u64)>> {
+        let (start, records) = self.read_records(block)?;
+        let mut block_records: Vec<(Vec<u8>, EntryLocation, u64)> = Vec::with_capacity(MAP_RESTART_INTERVAL);
+        let mut offset = 0;
+        while offset < records.len() {
+            let previous = block_records.last().map_or(&[][..], |(key, _, _)| key.as_slice());
+            let Some((key, location, expires_at, next)) = LookupTable::read_record(&records, offset, previous) else {
+                return Err(Error::Map(MapError::CorruptRecord { path: self.path.clone(), offset: (start as usize) + offset }));
+            };
+            block_records.push((key, location, expires_at));
+            offset = next;
+        }
+        Ok(block_records)
+    }
+
+    // The first record of a block, read without the others
+    pub fn restart_record(&self, block: usize) -> Result<(Vec<u8>, EntryLocation, u64)> {
+        let key = self.restart_key(block)?;
+        let start = self.restarts[block] + (SHARED_PREFIX_SIZE + KEY_LENGTH_SIZE + key.len()) as u64;
+        let corrupt = || Error::Map(MapError::CorruptRecord { path: self.path.clone(), offset: self.restarts[block] as usize });
+        if start + INDEX_ENTRY_SIZE as u64 > self.end {
+            return Err(corrupt());
+        }
+        let mut fields = [0; INDEX_ENTRY_SIZE];
+        self.file.read_at(&mut fields, start).map_err(MapError::io(&self.path, "read"))?;
+        let location = LookupTable::read_location(&fields, 0).ok_or_else(corrupt)?;
+        Ok((key, location, u64::from_le_bytes(fields[LOCATION_SIZE..].try_into()?)))
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.restarts.len()
+    }
+
+    // Bytes the restart offsets take in memory
+    pub fn memory_size(&self) -> usize {
+        self.restarts.len() * size_of::<u64>()
+    }
+
+    // The last block whose restart key is at most key, None if key comes before all of them
+    fn block_of(&self, key: &[u8]) -> Result<Option<usize>> {
+        let (mut low, mut high) = (0, self.restarts.len());
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.restart_key(middle)?.as_slice() <= key {
+                true => low = middle + 1,
+                false => high = middle,
+            }
+        }
+        Ok(low.checked_sub(1))
+    }
+
+    // Offset and bytes of the records of a block
+    fn read_records(&self, block: usize) -> Result<(u64, Vec<u8>)> {
+        let start = self.restarts[block];
+        let stop = self.restarts.get(block + 1).copied().unwrap_or(self.end);
+        let mut records = vec![0; (stop - start) as usize];
+        self.file.read_at(&mut records, start).map_err(MapError::io(&self.path, "read"))?;
+        Ok((start, records))
+    }
+
+    // Key of the restart record of the given block, written without a shared prefix
+    fn restart_key(&self, block: usize) -> Result<Vec<u8>> {
+        let offset = self.restarts[block];
+        let mut lengths = [0; SHARED_PREFIX_SIZE + KEY_LENGTH_SIZE];
+        self.file.read_at(&mut lengths, offset).map_err(MapError::io(&self.path, "read"))?;
+        let length = u32::from_le_bytes(lengths[SHARED_PREFIX_SIZE..].try_into()?) as u64;
+        let start = offset + lengths.len() as u64;
+        if lengths[..SHARED_PREFIX_SIZE] != [0; SHARED_PREFIX_SIZE] || start + length > self.end {
+            return Err(Error::Map(MapError::CorruptRecord { path: self.path.clone(), offset: offset as usize }));
+        }
+        let mut key = vec![0; length as usize];
+        self.file.read_at(&mut key, start).map_err(MapError::io(&self.path, "read"))?;
+        Ok(key)
+    }
+}
+
+// Builds a map.db of the current version from records given in key order
+pub(crate) struct MapEncoder {
+    buffer: Vec<u8>,
+    previous: Vec<u8>,
+    restarts: Vec<u64>,
+    keys: u64,
+    tombstones: u64,
+}
+
+impl MapEncoder {
+    pub fn new(lsn: u64, block_size: usize) -> Self {
+        let mut buffer = FileHeader::new(MAP_MAGIC, block_size).encode().to_vec();
+        buffer.extend_from_slice(&lsn.to_le_bytes());
+        // The key counts are filled in by finish
+        buffer.extend_from_slice(&[0; 2 * COUNT_BYTES]);
+        MapEncoder { buffer, previous: Vec::new(), restarts: Vec::new(), keys: 0, tombstones: 0 }
+    }
+
+    pub fn push(&mut self, key: &[u8], location: &EntryLocation, expiry: u64) {
+        if (self.keys + self.tombstones).is_multiple_of(MAP_RESTART_INTERVAL as u64) {
+            self.restarts.push(self.buffer.len() as u64);
+            self.previous.clear();
+        }
+        LookupTable::encode_prefixed_key(&mut self.buffer, key, &self.previous);
+        LookupTable::encode_location(&mut self.buffer, location);
+        self.buffer.extend_from_slice(&expiry.to_le_bytes());
+        self.previous.clear();
+        self.previous.extend_from_slice(key);
+        match location.block == TOMBSTONE_BLOCK {
+            true => self.tombstones += 1,
+            false => self.keys += 1,
+        }
+    }
+
+    // The whole file, ending with the restart offsets and the checksum
+    pub fn finish(mut self) -> Vec<u8> {
+        let counts = HEADER_SIZE + LSN_SIZE;
+        self.buffer[counts..counts + COUNT_BYTES].copy_from_slice(&self.keys.to_le_bytes());
+        self.buffer[counts + COUNT_BYTES..counts + 2 * COUNT_BYTES].copy_from_slice(&self.tombstones.to_le_bytes());
+        for offset in &self.restarts {
+            self.buffer.extend_from_slice(&offset.to_le_bytes());
+        }
+        self.buffer.extend_from_slice(&(self.restarts.len() as u64).to_le_bytes());
+        let checksum = crc32fast::hash(&self.buffer);
+        self.buffer.extend_from_slice(&checksum.to_le_bytes());
+        self.buffer
+    }
+}
+
+// Change that the replayed operations on a key make to it, see LookupTable::apply_parallel
+struct KeyReplay {
+    // None if the key ends up without a location
+    location: Option<EntryLocation>,
+    // 0 if the key never expires
+    expires_at: u64,
+    // Left by the last remove of the key while it had a location
+    tombstone: Option<Tombstone>,
+    // Whether the operations changed the entry of the key
+    dirty: bool,
+}
+
+impl KeyReplay {
+    fn new(entry: Option<IndexEntry>) -> Self {
+        let (location, expires_at) = match entry {
+            Some(IndexEntry::Put(location, expires_at)) => (Some(location), expires_at),
+            _ => (None, 0),
+        };
+        KeyReplay { location, expires_at, tombstone: None, dirty: false }
+    }
+
+    // Folds in a single operation the way LookupTable::apply_operation applies it
+    fn apply(&mut self, lsn: u64, operation: &WalOperation, now: u64) {
+        match operation {
+            WalOperation::Insert{location, ..} => {
+                self.expires_at = 0;
+                self.location = Some(*location);
+                self.dirty = true;
+            }
+            WalOperation::InsertExpiring{location, expires_at, ..} => {
+                self.expires_at = *expires_at;
+                self.location = Some(*location);
+                self.dirty = true;
+            }
+            WalOperation::Merge{location, ..} | WalOperation::Increment{location, ..} => {
+                self.location = Some(*location);
+                self.dirty = true;
+            }
+            WalOperation::Remove{..} => {
+                if self.location.take().is_some() {
+                    self.expires_at = 0;
+                    self.tombstone = Some(Tombstone { lsn, deleted_at: now });
+                    self.dirty = true;
+                }
+            }
+            WalOperation::Batch(operations) => {
+                for operation in operations {
+                    self.apply(lsn, operation, now);
+                }
+            }
+        }
+    }
+
+    // Entry the operations leave the key with, None if they left it as it was
+    fn entry(&self) -> Option<IndexEntry> {
+        match (self.location, self.tombstone) {
+            _ if !self.dirty => None,
+            (Some(location), _) => Some(IndexEntry::Put(location, self.expires_at)),
+            (None, tombstone) => tombstone.map(IndexEntry::Remove),
+        }
+    }
+}
+
+// Encoding of the records of a WAL segment, given by the format version in its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WalFormat {
+    // [crc32 of body: u32][body length: u32][body], the integers in the body have a fixed width
+    Fixed,
+    // [body length: varint][body][crc32 of length and body: u32], the integers in the body are varints
+    Compact,
+}
+
+impl WalFormat {
+    pub(crate) fn of_version(version: u16) -> Self {
+        match version >= COMPACT_WAL_VERSION {
+            true => WalFormat::Compact,
+            false => WalFormat::Fixed,
+        }
+    }
+
+    // Format of the segment starting with buffer, one without a valid header has no records to read
+    pub(crate) fn of_segment(buffer: &[u8]) -> Self {
+        FileHeader::decode(buffer, WAL_MAGIC).map_or(WalFormat::Compact, |header| WalFormat::of_version(header.version()))
+    }
+
+    // Reads an integer that takes width bytes in the fixed format, returning it with the offset just past it
+    pub(crate) fn read_int(self, buffer: &[u8], offset: usize, width: usize) -> Option<(u64, usize)> {
+        match self {
+            WalFormat::Fixed => {
+                let bytes = buffer.get(offset..offset.checked_add(width)?)?;
+                let mut value = [0; 8];
+                value[..width].copy_from_slice(bytes);
+                Some((u64::from_le_bytes(value), offset + width))
+            }
+            WalFormat::Compact => decode_varint(buffer, offset),
+        }
+    }
+
+    fn write_int(self, buffer: &mut Vec<u8>, value: u64, width: usize) {
+        match self {
+            WalFormat::Fixed => buffer.extend_from_slice(&value.to_le_bytes()[..width]),
+            WalFormat::Compact => encode_varint(buffer, value),
+        }
+    }
+
+    // Locates the record at offset, returning the range of its body, whether it matches its checksum
+    // and the offset of the next record. None if the record runs past the end of buffer.
+    pub(crate) fn frame(self, buffer: &[u8], offset: usize) -> Option<(Range<usize>, bool, usize)> {
+        match self {
+            WalFormat::Fixed => {
+                let checksum = u32::from_le_bytes(buffer.get(offset..offset.checked_add(4)?)?.try_into().ok()?);
+                let (length, start) = self.read_int(buffer, offset + 4, 4)?;
+                let end = start.checked_add(usize::try_from(length).ok()?)?;
+                let body = buffer.get(start..end)?;
+                Some((start..end, crc32fast::hash(body) == checksum, end))
+            }
+            WalFormat::Compact => {
+                let (length, start) = decode_varint(buffer, offset)?;
+                let end = start.checked_add(usize::try_from(length).ok()?)?;
+                let checksum = buffer.get(end..end.checked_add(WAL_CHECKSUM_SIZE)?)?;
+                let valid = crc32fast::hash(&buffer[offset..end]) == u32::from_le_bytes(checksum.try_into().ok()?);
+                Some((start..end, valid, end + WAL_CHECKSUM_SIZE))
+            }
+        }
+    }
+
+    // Offset of the record after the one at offset, without checking it. None if the record runs
+    // past the end of buffer.
+    fn skip(self, buffer: &[u8], offset: usize) -> Option<usize> {
+        let end = match self {
+            WalFormat::Fixed => {
+                let (length, start) = self.read_int(buffer, offset.checked_add(4)?, 4)?;
+                start.checked_add(usize::try_from(length).ok()?)?
+            }
+            WalFormat::Compact => {
+                let (length, start) = decode_varint(buffer, offset)?;
+                start.checked_add(usize::try_from(length).ok()?)?.checked_add(WAL_CHECKSUM_SIZE)?
+            }
+        };
+        (end <= buffer.len()).then_some(end)
+    }
+
+    fn frame_record(self, buffer: &mut Vec<u8>, body: &[u8]) {
+        match self {
+            WalFormat::Fixed => {
+                buffer.extend_from_slice(&crc32fast::hash(body).to_le_bytes());
+                buffer.extend_from_slice(&(body.len() as u32).to_le_bytes());
+                buffer.extend_from_slice(body);
+            }
+            WalFormat::Compact => {
+                let start = buffer.len();
+                encode_varint(buffer, body.len() as u64);
+                buffer.extend_from_slice(body);
+                let checksum = crc32fast::hash(&buffer[start..]);
+                buffer.extend_from_slice(&checksum.to_le_bytes());
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum WalOperation {
+    Insert{key: Vec<u8>, location: EntryLocation},
+    // Insert of a key that expires at the given time in milliseconds since the Unix epoch
+    InsertExpiring{key: Vec<u8>, location: EntryLocation, expires_at: u64},
+    Remove{key: Vec<u8>},
+    // Result of merging an operand into the current value, unlike Insert it keeps the expiry time
+    Merge{key: Vec<u8>, location: EntryLocation},
+    // New value of a counter, also keeps the expiry time
+    Increment{key: Vec<u8>, location: EntryLocation},
+    // Operations that are logged as one record so they are replayed all or not at all
+    Batch(Vec<WalOperation>),
+}
+
+impl WalOperation {
+    // Several operations are logged as a batch, a single one as itself
+    pub(crate) fn batched(mut operations: Vec<WalOperation>) -> Self {
+        match operations.len() {
+            1 => operations.remove(0),
+            _ => WalOperation::Batch(operations),
+        }
+    }
+
+    // Key written or removed by a single operation, None for a batch
+    pub(crate) fn key(&self) -> Option<&[u8]> {
+        match self {
+            WalOperation::Insert{key, ..}
+            | WalOperation::InsertExpiring{key, ..}
+            | WalOperation::Merge{key, ..}
+            | WalOperation::Increment{key, ..}
+            | WalOperation::Remove{key} => Some(key),
+            WalOperation::Batch(_) => None,
+        }
+    }
+
+    // Approximate bytes the operation takes in memory
+    fn memory_size(&self) -> usize {
+        size_of::<(u64, WalOperation)>() + match self {
+            WalOperation::Batch(operations) => operations.iter().map(WalOperation::memory_size).sum(),
+            operation => operation.key().map_or(0, <[u8]>::len),
+        }
+    }
+
+    // The operations of a batch, or the operation itself
+    fn operations(&self) -> std::slice::Iter<'_, WalOperation> {
+        match self {
+            WalOperation::Batch(operations) => operations.iter(),
+            operation => std::slice::from_ref(operation).iter(),
+        }
+    }
+
+    // Keys written or removed by the operation
+    fn key_count(&self) -> usize {
+        match self {
+            WalOperation::Batch(operations) => operations.iter().map(WalOperation::key_count).sum(),
+            _ => 1,
+        }
+    }
+
+    fn collect_keys<'a>(&'a self, keys: &mut BTreeSet<&'a [u8]>) {
+        match self {
+            WalOperation::Insert{key, ..}
+            | WalOperation::InsertExpiring{key, ..}
+            | WalOperation::Merge{key, ..}
+            | WalOperation::Increment{key, ..}
+            | WalOperation::Remove{key} => {
+                keys.insert(key);
+            }
+            WalOperation::Batch(operations) => {
+                for operation in operations {
+                    operation.collect_keys(keys);
+                }
+            }
+        }
+    }
+}
+
+impl LookupTable {
+    // Shorthands for the tests, the database loads its lookup tables through ShardedTable
+    #[cfg(test)]
+    pub fn new(folder: impl AsRef<Path>) -> Result<Self> {
+        LookupTable::new_reset(folder, false)
+    }
+
+    #[cfg(test)]
+    pub fn new_reset(folder: impl AsRef<Path>, reset: bool) -> Result<Self> {
+        LookupTable::open(folder.as_ref(), &DbOptions::new().reset(reset))
+    }
+
+    #[cfg(test)]
+    pub fn open(folder: &Path, options: &DbOptions) -> Result<Self> {
+        let (mut table, _) = LookupTable::load(folder, options)?;
+        table.replay(&BTreeSet::new(), options)?;
+        Ok(table)
+    }
+
+    // First step of opening, reads the map and the WAL records after it without applying them yet.
+    // Also returns the parts among those records, which shard can be trusted to hold a whole batch
+    // is only known once every shard of the keyspace was loaded.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(folder = %folder.display())))]
+    pub(crate) fn load(folder: &Path, options: &DbOptions) -> Result<(Self, Vec<ReplayedPart>)> {
+        let backend = options.backend.as_ref();
+        let map_path = folder.join(MAP_FILE_NAME);
+        if !options.read_only {
+            backend.create_dir_all(folder)?;
+        }
+        let lock_file = LookupTable::lock(backend, folder, options.read_only)?;
+        if options.reset {
+            Self::cleanup(backend, folder, options.log_level)?;
+        }
+        let mut recovery = RecoveryReport::default();
+        if !options.read_only {
+            // Column families keep their tables in subfolders, the data file is in the database folder
+            let data_folder = options.path.as_deref().unwrap_or(folder);
+            LookupTable::recover_compaction(backend, data_folder, &map_path, options.log_level, &mut recovery)?;
+            LookupTable::remove_index_leftovers(backend, folder, options.log_level, &mut recovery)?;
+        }
+        // Low-memory handles search map.db on disk, as long as the WAL holds nothing it doesn't cover
+        let lazy = options.low_memory && options.read_only
+            && LookupTable::clean_wal_segment(backend, folder, options)?.is_some();
+        let index = LookupTable::open_index(folder, options, lazy, &mut recovery)?;
+        let map_lsn = index.flushed_lsn();
+
+        // After repairs the WAL is read anyway, a map rebuilt from what was readable may miss records it still has
+        let (wal, parts, segment) = match LookupTable::clean_wal_segment(backend, folder, options)? {
+            Some(segment) if recovery.is_clean() => {
+                event!(options.log_level, DEBUG, path = %segment.1.display(), "skipping the WAL of a cleanly closed table");
+                (Vec::new(), Vec::new(), Some(segment))
+            }
+            _ => LookupTable::replay_wal_segments(backend, folder, map_lsn, options, &mut recovery)?,
+        };
+        let applied_lsn = wal.last().map_or(map_lsn, |(lsn, _)| *lsn);
+        let (wal_segment, wal_path, wal_file) = match segment {
+            // Records are only appended in the compact format with value sizes to a segment that can be
+            // flagged on close
+            Some((number, _, file)) if !options.read_only && LookupTable::wal_header(file.as_ref())?
+                .is_some_and(|header| header.version() < VALUE_SIZES_VERSION) =>
+            {
+                let (path, file) = LookupTable::create_wal_segment(backend, folder, number + 1, options.block_size)?;
+                (number + 1, path, file)
+            }
+            Some(segment) => segment,
+            None if options.read_only => {
+                return Err(Error::Wal(WalError::MissingSegment { folder: folder.to_path_buf() }));
+            }
+            None => {
+                let (path, file) = LookupTable::create_wal_segment(backend, folder, 1, options.block_size)?;
+                (1, path, file)
+            }
+        };
+        let wal_segment_size = wal_file.len()?;
+        let mut wal_size = 0;
+        for (_, path) in LookupTable::wal_segments(backend, folder)? {
+            wal_size += backend.file_len(&path)?;
+        }
+        let mut table = Self {
+            backend: Arc::clone(&options.backend), clock: Arc::clone(&options.clock), _lock_file: lock_file, index, map_path,
+            block_size: options.block_size, tombstone_retention: options.tombstone_retention.as_millis() as u64,
+            folder: folder.to_path_buf(), wal_file, wal_path, wal_segment, wal_segment_size,
+            max_wal_segment_size: options.max_wal_segment_size,
+            wal_size, wal_rewrite_threshold: options.wal_rewrite_threshold,
+            next_wal_rewrite: options.wal_rewrite_threshold, wal_bytes_written: 0, unflushed_wal_bytes: 0,
+            wal, unflushed_writes: 0, wal_memory: 0, sync_policy: options.sync_policy, log_level: options.log_level,
+            lsn: Arc::new(AtomicU64::new(applied_lsn)), applied_lsn, flushed_lsn: map_lsn, history: HashMap::new(), snapshots: SnapshotRegistry::default(), recovery,
+        };
+        table.collect_garbage()?;
+        Ok((table, parts))
+    }
+
+    // Second step of opening, applies the operations logged after the last flush except the parts
+    // with the given sequence numbers, whose batch did not reach every shard before a crash.
+    // Their records stay in the WAL, so the map is rewritten to cover them.
+    pub(crate) fn replay(&mut self, discarded: &BTreeSet<u64>, options: &DbOptions) -> Result<()> {
+        let read_only = options.read_only;
+        let logged = self.wal.len();
+        let wal: Vec<ReplayedOperation> = std::mem::take(&mut self.wal).into_iter()
+            .filter(|(lsn, _)| !discarded.contains(lsn))
+            .collect();
+        let threads = options.recovery_thread_count().min(wal.len() / PARALLEL_REPLAY_OPERATIONS);
+        // Versions for snapshots are kept operation by operation
+        if threads > 1 && self.snapshots.oldest().is_none() {
+            self.apply_parallel(&wal, threads)?;
+        } else {
+            for (lsn, operation) in &wal {
+                self.apply(*lsn, operation)?;
+            }
+        }
+        self.index.commit(self.applied_lsn)?;
+        self.wal = wal;
+        self.unflushed_writes = self.wal.iter().map(|(_, operation)| operation.key_count()).sum();
+        self.wal_memory = self.wal.iter().map(|(_, operation)| operation.memory_size()).sum();
+        self.recovery.wal_records_replayed = self.wal.len();
+        // Set after a repair, the readable entries only go back to disk with a full rewrite
+        let mut rewrite_map = self.recovery.map_rebuilt;
+        if self.wal.len() < logged && !read_only {
+            warning!(options.log_level, records = %(logged - self.wal.len()), path = %self.wal_path.display(); "discarding WAL records of batches that were not logged by every shard");
+            rewrite_map = true;
+            self.recovery.repaired_files.push(self.wal_path.clone());
+        }
+        if rewrite_map {
+            self.compact()?;
+        }
+        event!(options.log_level, DEBUG, keys = self.index.len(), wal_records = self.recovery.wal_records_replayed, "opened lookup table");
+        Ok(())
+    }
+
+    // Key index of the engine options.engine names, over the map files in folder
+    pub(crate) fn open_index(folder: &Path, options: &DbOptions, lazy: bool, recovery: &mut RecoveryReport) -> Result<Box<dyn KeyIndex>> {
+        match options.engine {
+            Engine::Hash => Ok(Box::new(HashIndex::open(folder, options, lazy, recovery)?)),
+            // Searches its files on disk anyway
+            Engine::Lsm => Ok(Box::new(LsmIndex::open(folder, options, recovery)?)),
+        }
+    }
+
+    // Files of the key index left behind by a flush or a merge that was interrupted by a crash, map.db
+    // and the files written next to it are still intact in that case
+    fn remove_index_leftovers(backend: &dyn StorageBackend, folder: &Path, log_level: LogLevel, recovery: &mut RecoveryReport) -> Result<()> {
+        for path in backend.list(folder)? {
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            let written = name.ends_with(".db.tmp") || name.ends_with(".db.merge");
+            if written && (name.starts_with("map") || name.starts_with("run-")) {
+                event!(log_level, DEBUG, path = %path.display(), "removing key index file left behind by an interrupted flush or merge");
+                backend.remove_file(&path)?;
+                recovery.repaired_files.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    // Advisory lock on the LOCK file, exclusive for writers and shared for read-only handles.
+    // Read-only handles skip locking if no writer ever created the file.
+    fn lock(backend: &dyn StorageBackend, folder: &Path, read_only: bool) -> Result<Option<Arc<dyn StorageFile>>> {
+        let lock_path = folder.join(LOCK_FILE_NAME);
+        if read_only && !backend.exists(&lock_path) {
+            return Ok(None);
+        }
+        let mode = if read_only { OpenMode::Read } else { OpenMode::Create };
+        let file = backend.open(&lock_path, mode)?;
+        match file.try_lock(read_only)? {
+            true => Ok(Some(file)),
+            false => Err(Error::DatabaseLocked(folder.to_path_buf())),
+        }
+    }
+
+    // Makes the table number its records from the counter shared by all tables of the database,
+    // which is moved past every sequence number the table has seen, and keep versions for the
+    // snapshots of every table
+    pub fn share(&mut self, lsn: &Arc<AtomicU64>, snapshots: &SnapshotRegistry) {
+        lsn.fetch_max(self.lsn.load(Ordering::SeqCst), Ordering::SeqCst);
+        self.lsn = Arc::clone(lsn);
+        self.snapshots = snapshots.clone();
+    }
+
+    fn apply(&mut self, lsn: u64, operation: &WalOperation) -> Result<()> {
+        let retain_versions = self.snapshots.oldest().is_some();
+        self.apply_operation(lsn, operation, retain_versions)
+    }
+
+    fn apply_operation(&mut self, lsn: u64, operation: &WalOperation, retain_versions: bool) -> Result<()> {
+        if let WalOperation::Batch(operations) = operation {
+            for operation in operations {
+                self.apply_operation(lsn, operation, retain_versions)?;
+            }
+            return Ok(());
+        }
+        let Some(key) = operation.key() else { return Ok(()) };
+        // An insert replaces the entry whatever it was, only versions kept for snapshots need it
+        let previous = match operation {
+            WalOperation::Insert{..} if !retain_versions => None,
+            _ => self.entry(key)?,
+        };
+        let (previous_location, previous_expiry) = match previous {
+            Some(IndexEntry::Put(location, expires_at)) => (Some(location), expires_at),
+            _ => (None, 0),
+        };
+        if retain_versions {
+            self.history.entry(key.to_vec()).or_default().push((lsn, previous_location));
+        }
+        let entry = match operation {
+            WalOperation::Insert{location, ..} => IndexEntry::Put(*location, 0),
+            WalOperation::InsertExpiring{location, expires_at, ..} => IndexEntry::Put(*location, *expires_at),
+            // Keeps the expiry time of the value it replaces
+            WalOperation::Merge{location, ..} | WalOperation::Increment{location, ..} => IndexEntry::Put(*location, previous_expiry),
+            WalOperation::Remove{..} | WalOperation::Batch(_) => match previous_location {
+                Some(_) => IndexEntry::Remove(Tombstone { lsn, deleted_at: self.now() }),
+                // Removing a key that is already gone leaves its tombstone as it was, a flush has nothing to write
+                None => return Ok(()),
+            },
+        };
+        self.index.put(key, &entry.encode())
+    }
+
+    // Applies the operations with their keys split over threads. Each thread folds the operations
+    // on its keys into the change they make to the key, then the changes are applied, once per key
+    // rather than once per operation.
+    fn apply_parallel(&mut self, wal: &[ReplayedOperation], threads: usize) -> Result<()> {
+        let now = self.now();
+        let index = &self.index;
+        let changes: Vec<Vec<(&[u8], KeyReplay)>> = std::thread::scope(|scope| {
+            let folds: Vec<_> = (0..threads)
+                .map(|thread| scope.spawn(move || -> Result<Vec<(&[u8], KeyReplay)>> {
+                    let mut changes: HashMap<&[u8], KeyReplay> = HashMap::new();
+                    for (lsn, operation) in wal {
+                        for operation in operation.operations() {
+                            let Some(key) = operation.key() else { continue };
+                            // Shards of a keyspace already split keys by the low bits of the hash
+                            if crc32fast::hash(key).rotate_right(16) as usize % threads != thread {
+                                continue;
+                            }
+                            let change = match changes.entry(key) {
+                                Entry::Occupied(change) => change.into_mut(),
+                                Entry::Vacant(change) => {
+                                    let entry = index.get(key)?.map(|value| IndexEntry::decode(&value)).transpose()?;
+                                    change.insert(KeyReplay::new(entry))
+                                }
+                            };
+                            change.apply(*lsn, operation, now);
+                        }
+                    }
+                    Ok(changes.into_iter().collect())
+                }))
+                .collect();
+            folds.into_iter()
+                .map(|fold| fold.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .collect::<Result<_>>()
+        })?;
+        for (key, change) in changes.into_iter().flatten() {
+            if let Some(entry) = change.entry() {
+                self.index.put(key, &entry.encode())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) {
+        self.sync_policy = sync_policy;
+    }
+
+    pub fn add(&mut self, key: &[u8], location: EntryLocation) -> Result<()> {
+        self.log(WalOperation::Insert{key: key.to_vec(), location})
+    }
+
+    pub fn add_expiring(&mut self, key: &[u8], location: EntryLocation, expires_at: u64) -> Result<()> {
+        self.log(WalOperation::InsertExpiring{key: key.to_vec(), location, expires_at})
+    }
+
+    pub fn merge(&mut self, key: &[u8], location: EntryLocation) -> Result<()> {
+        self.log(WalOperation::Merge{key: key.to_vec(), location})
+    }
+
+    pub fn increment(&mut self, key: &[u8], location: EntryLocation) -> Result<()> {
+        self.log(WalOperation::Increment{key: key.to_vec(), location})
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.log(WalOperation::Remove{key: key.to_vec()})
+    }
+
+    // Logs all operations as a single batch record, so after a crash either all or none are replayed
+    pub fn write_batch(&mut self, operations: Vec<WalOperation>) -> Result<()> {
+        match operations.is_empty() {
+            true => Ok(()),
+            false => self.log(WalOperation::batched(operations)),
+        }
+    }
+
+    // Logs operations as a batch under the given sequence number instead of the next one,
+    // for records replicated from another database and removals that are no writes of their own
+    pub fn write_batch_at(&mut self, lsn: u64, operations: Vec<WalOperation>) -> Result<()> {
+        self.lsn.fetch_max(lsn, Ordering::SeqCst);
+        match operations.is_empty() {
+            true => Ok(()),
+            false => self.append(lsn, WalOperation::batched(operations)),
+        }
+    }
+
+    // Part of a batch spanning the given shards, logged under their shared sequence number without
+    // applying it. Returns the offset of the record for discard_part, commit_part applies it once
+    // every shard logged its part.
+    pub(crate) fn write_part(&mut self, lsn: u64, operation: &WalOperation, shards: &[u32]) -> Result<u64> {
+        self.lsn.fetch_max(lsn, Ordering::SeqCst);
+        self.write_record(lsn, operation, shards)
+    }
+
+    pub(crate) fn commit_part(&mut self, lsn: u64, operation: WalOperation) -> Result<()> {
+        self.commit_record(lsn, operation)
+    }
+
+    // Removes a part that another shard failed to log. Left in place it would be replayed as soon
+    // as that shard logs a later record.
+    pub(crate) fn discard_part(&mut self, offset: u64) -> Result<()> {
+        self.wal_file.set_len(offset).map_err(WalError::io(&self.wal_path, "truncate"))?;
+        self.wal_file.sync().map_err(WalError::io(&self.wal_path, "sync"))?;
+        self.wal_size -= self.wal_segment_size - offset;
+        self.wal_segment_size = offset;
+        Ok(())
+    }
+
+    // Hands out the next sequence number
+    pub(crate) fn next_lsn(&self) -> u64 {
+        // A record that fails to be written leaves a gap in the sequence numbers, they stay increasing
+        self.lsn.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn log(&mut self, operation: WalOperation) -> Result<()> {
+        let lsn = self.next_lsn();
+        self.append(lsn, operation)
+    }
+
+    fn append(&mut self, lsn: u64, operation: WalOperation) -> Result<()> {
+        self.write_record(lsn, &operation, &[])?;
+        self.commit_record(lsn, operation)
+    }
+
+    // Returns the offset of the record in the active segment
+    fn write_record(&mut self, lsn: u64, operation: &WalOperation, shards: &[u32]) -> Result<u64> {
+        // Nothing logged since the last checkpoint, every segment is covered by the map
+        if self.wal.is_empty() && self.wal_size > HEADER_SIZE as u64 {
+            self.truncate_covered_wal()?;
+        }
+        let mut buffer = Vec::new();
+        match shards.is_empty() {
+            true => LookupTable::encode_wal_record(&mut buffer, lsn, operation),
+            false => LookupTable::encode_part_record(&mut buffer, lsn, operation, shards),
+        }
+        self.write_wal(&buffer, self.sync_policy.sync_each_write())
+    }
+
+    fn commit_record(&mut self, lsn: u64, operation: WalOperation) -> Result<()> {
+        self.apply(lsn, &operation)?;
+        self.index.commit(lsn)?;
+        self.applied_lsn = lsn;
+        self.unflushed_writes += operation.key_count();
+        self.wal_memory += operation.memory_size();
+        self.wal.push((lsn, operation));
+        if self.wal_size > self.next_wal_rewrite {
+            self.rewrite_wal()?;
+        }
+        Ok(())
+    }
+
+    // Appends buffer to the active segment and syncs it if asked to, returning the offset it was
+    // written at
+    fn write_wal(&mut self, buffer: &[u8], sync: bool) -> Result<u64> {
+        // A record never spans segments, one larger than the limit gets a segment of its own
+        let segment_has_records = self.wal_segment_size > HEADER_SIZE as u64;
+        if segment_has_records && self.wal_segment_size + buffer.len() as u64 > self.max_wal_segment_size {
+            self.rotate_wal_segment()?;
+        }
+        let offset = self.wal_segment_size;
+        match sync {
+            true => self.wal_file.write_and_sync_at(buffer, offset).map_err(WalError::io(&self.wal_path, "append"))?,
+            false => self.wal_file.write_at(buffer, offset).map_err(WalError::io(&self.wal_path, "append"))?,
+        }
+        self.wal_segment_size += buffer.len() as u64;
+        self.wal_size += buffer.len() as u64;
+        self.wal_bytes_written += buffer.len() as u64;
+        self.unflushed_wal_bytes += buffer.len() as u64;
+        event!(self.log_level, TRACE, bytes = buffer.len(), segment = self.wal_segment, "appended WAL record");
+        Ok(offset)
+    }
+
+    // Removes the records a checkpoint covers, the active segment is reused
+    fn truncate_covered_wal(&mut self) -> Result<()> {
+        let backend = self.backend.as_ref();
+        for (segment, path) in LookupTable::wal_segments(backend, &self.folder)? {
+            if segment < self.wal_segment {
+                backend.remove_file(&path)?;
+            }
+        }
+        self.wal_file.set_len(HEADER_SIZE as u64).map_err(WalError::io(&self.wal_path, "truncate"))?;
+        self.wal_file.sync().map_err(WalError::io(&self.wal_path, "sync"))?;
+        self.wal_segment_size = HEADER_SIZE as u64;
+        self.wal_size = HEADER_SIZE as u64;
+        self.next_wal_rewrite = self.wal_rewrite_threshold;
+        event!(self.log_level, DEBUG, segment = self.wal_segment, "truncated checkpointed WAL");
+        Ok(())
+    }
+
+    // Replaces all WAL segments with a single one that only holds the current state of every
+    // logged key, dropping the records it supersedes without writing the map.
+    // The new segment is complete before the old ones are removed. Its records set absolute
+    // states, so replaying it after old segments left behind by a crash gives the same result.
+    // They all carry the sequence number of the last record applied, the state they hold.
+    fn rewrite_wal(&mut self) -> Result<()> {
+        let mut logged = BTreeSet::new();
+        for (_, operation) in &self.wal {
+            operation.collect_keys(&mut logged);
+        }
+        let mut operations = Vec::with_capacity(logged.len());
+        for key in logged {
+            operations.push(match self.entry(key)? {
+                Some(IndexEntry::Put(location, 0)) => WalOperation::Insert{key: key.to_vec(), location},
+                Some(IndexEntry::Put(location, expires_at)) => WalOperation::InsertExpiring{key: key.to_vec(), location, expires_at},
+                Some(IndexEntry::Remove(_)) | None => WalOperation::Remove{key: key.to_vec()},
+            });
+        }
+        let mut buffer = Vec::new();
+        for operation in &operations {
+            LookupTable::encode_wal_record(&mut buffer, self.applied_lsn, operation);
+        }
+        let backend = self.backend.as_ref();
+        let (path, file) = LookupTable::create_wal_segment(backend, &self.folder, self.wal_segment + 1, self.block_size)?;
+        file.write_at(&buffer, HEADER_SIZE as u64).map_err(WalError::io(&path, "append"))?;
+        file.sync().map_err(WalError::io(&path, "sync"))?;
+        self.wal_bytes_written += buffer.len() as u64;
+        for (segment, path) in LookupTable::wal_segments(backend, &self.folder)? {
+            if segment <= self.wal_segment {
+                backend.remove_file(&path)?;
+            }
+        }
+        self.wal_segment += 1;
+        self.wal_path = path;
+        self.wal_file = file;
+        self.wal_segment_size = (HEADER_SIZE + buffer.len()) as u64;
+        self.wal_size = self.wal_segment_size;
+        self.wal = operations.into_iter().map(|operation| (self.applied_lsn, operation)).collect();
+        self.unflushed_writes = self.wal.len();
+        self.wal_memory = self.wal.iter().map(|(_, operation)| operation.memory_size()).sum();
+        event!(self.log_level, DEBUG, records = self.wal.len(), bytes = self.wal_size, segment = self.wal_segment, "rewrote WAL");
+        // Keys that are all distinct would otherwise be rewritten on every write
+        self.next_wal_rewrite = self.wal_rewrite_threshold.max(self.wal_size * 2);
+        Ok(())
+    }
+
+    // Seals the active segment and continues in a new one
+    fn rotate_wal_segment(&mut self) -> Result<()> {
+        self.wal_file.sync().map_err(WalError::io(&self.wal_path, "sync"))?;
+        let (path, file) = LookupTable::create_wal_segment(self.backend.as_ref(), &self.folder, self.wal_segment + 1, self.block_size)?;
+        self.wal_segment += 1;
+        self.wal_path = path;
+        self.wal_file = file;
+        self.wal_segment_size = HEADER_SIZE as u64;
+        self.wal_size += HEADER_SIZE as u64;
+        event!(self.log_level, DEBUG, segment = self.wal_segment, "rotated WAL segment");
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(folder = %self.folder.display()))
+    )]
+    pub fn flush(&mut self) -> Result<()> {
+        self.persist(false)
+    }
+
+    // Flush that merges everything into map.db, dropping overwritten entries and past tombstones
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(folder = %self.folder.display()))
+    )]
+    pub fn compact(&mut self) -> Result<()> {
+        self.persist(true)
+    }
+
+    fn persist(&mut self, compact: bool) -> Result<()> {
+        self.purge_expired()?;
+        self.collect_garbage()?;
+        // Once the index is durably on disk a checkpoint marks the WAL up to it as covered.
+        // Replay skips the records the index covers, so the WAL is only truncated with the next write.
+        match compact {
+            true => self.index.compact()?,
+            false => self.index.flush()?,
+        }
+        self.flushed_lsn = self.applied_lsn;
+        // Without records since the last checkpoint that one still covers the whole WAL
+        if !self.wal.is_empty() {
+            self.wal.clear();
+            self.unflushed_writes = 0;
+            self.wal_memory = 0;
+            let mut buffer = Vec::new();
+            LookupTable::encode_checkpoint_record(&mut buffer, self.flushed_lsn);
+            self.write_wal(&buffer, true)?;
+        }
+        self.unflushed_wal_bytes = 0;
+        event!(self.log_level, DEBUG, keys = self.index.len(), compact, "flushed lookup table");
+        Ok(())
+    }
+
+    // Expired keys are only hidden from reads until the next flush removes them.
+    // The removal is logged like any other, so a crash before the map is written replays it.
+    // It takes no sequence number of its own, a follower expires the keys by itself.
+    fn purge_expired(&mut self) -> Result<()> {
+        let expired: Vec<WalOperation> = self.index.expired(self.now()).into_iter()
+            .map(|key| WalOperation::Remove{key})
+            .collect();
+        self.write_batch_at(self.applied_lsn, expired)
+    }
+
+    // Milliseconds since the Unix epoch by the clock of the database
+    pub fn now(&self) -> u64 {
+        self.clock.now_millis()
+    }
+
+    // Keeps the WAL records of the table in folder up to sequence number `lsn` and empties the log
+    // after them, so that opening it replays the table only up to that point.
+    // Records written before format version 4 are numbered by their position in the log.
+    pub fn truncate_wal(backend: &dyn StorageBackend, folder: &Path, lsn: u64) -> Result<()> {
+        let mut last = 0;
+        for (_, path) in LookupTable::wal_segments(backend, folder)? {
+            let buffer = backend.read(&path).map_err(WalError::io(&path, "read"))?;
+            let format = WalFormat::of_segment(&buffer);
+            let mut offset = HEADER_SIZE.min(buffer.len());
+            while let Some((record, next)) = LookupTable::read_wal_record(&buffer, offset, format) {
+                let record_lsn = record.lsn.unwrap_or(last + 1);
+                if record_lsn > lsn {
+                    break;
+                }
+                last = record_lsn;
+                offset = next;
+            }
+            if offset < buffer.len() {
+                let file = backend.open(&path, OpenMode::Write).map_err(WalError::io(&path, "open"))?;
+                file.set_len(offset as u64).map_err(WalError::io(&path, "truncate"))?;
+                file.sync().map_err(WalError::io(&path, "sync"))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Utility function to delete the key index and every WAL segment in folder
+    pub fn cleanup(backend: &dyn StorageBackend, folder: &Path, log_level: LogLevel) -> Result<()> {
+        let map_path = folder.join(MAP_FILE_NAME);
+        if backend.exists(&map_path) {
+            event!(log_level, DEBUG, path = %map_path.display(), "removing map");
+            backend.remove_file(&map_path)?;
+        }
+        for (_, path) in LookupTable::wal_segments(backend, folder)? {
+            event!(log_level, DEBUG, path = %path.display(), "removing WAL segment");
+            backend.remove_file(&path)?;
+        }
+        HashIndex::cleanup(backend, folder)?;
+        LsmIndex::cleanup(backend, folder)
+    }
+
+    fn wal_segment_path(folder: &Path, segment: u64) -> PathBuf {
+        folder.join(format!("wal-{segment:06}.db"))
+    }
+
+    // WAL segments in folder in replay order
+    pub(crate) fn wal_segments(backend: &dyn StorageBackend, folder: &Path) -> Result<Vec<(u64, PathBuf)>> {
+        let mut segments = Vec::new();
+        if !backend.exists(folder) {
+            return Ok(segments);
+        }
+        for path in backend.list(folder)? {
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else { continue };
+            if name == LEGACY_WAL_FILE_NAME {
+                segments.push((0, path));
+            } else if let Some(segment) = name.strip_prefix("wal-")
+                .and_then(|rest| rest.strip_suffix(".db"))
+                .and_then(|number| number.parse().ok())
+            {
+                segments.push((segment, path));
+            }
+        }
+        segments.sort();
+        Ok(segments)
+    }
+
+    fn create_wal_segment(
+        backend: &dyn StorageBackend,
+        folder: &Path,
+        segment: u64,
+        block_size: usize,
+    ) -> Result<(PathBuf, Arc<dyn StorageFile>)> {
+        let path = LookupTable::wal_segment_path(folder, segment);
+        let file = backend.open(&path, OpenMode::CreateNew).map_err(WalError::io(&path, "create"))?;
+        FileHeader::init_or_validate(file.as_ref(), WAL_MAGIC, block_size, false)?;
+        backend.sync_dir(folder)?;
+        Ok((path, file))
+    }
+
+    // Replays the segments in order and returns the operations with the last segment, which stays active.
+    // A corrupt record ends the log, later segments were written after it and are discarded.
+    // Records up to map_lsn are already in the map and skipped, a flush leaves them behind until the
+    // next write truncates the WAL. Records without a sequence number get the one after the last.
+    fn replay_wal_segments(
+        backend: &dyn StorageBackend,
+        folder: &Path,
+        map_lsn: u64,
+        options: &DbOptions,
+        recovery: &mut RecoveryReport,
+    ) -> Result<(Vec<ReplayedOperation>, Vec<ReplayedPart>, Option<WalSegment>)> {
+        let read_only = options.read_only;
+        let mut last = 0;
+        let mut wal = Vec::new();
+        let mut parts = Vec::new();
+        let mut active = None;
+        let mut segments = LookupTable::wal_segments(backend, folder)?.into_iter();
+        for (segment, path) in segments.by_ref() {
+            let mode = if read_only { OpenMode::Read } else { OpenMode::Write };
+            let file = backend.open(&path, mode).map_err(WalError::io(&path, "open"))?;
+            if FileHeader::init_or_validate(file.as_ref(), WAL_MAGIC, options.block_size, read_only)? {
+                recovery.repaired_files.push(path.clone());
+            }
+            let (operations, discarded) = LookupTable::get_wal_from_file(file.as_ref(), &path, read_only, options.recovery_thread_count())?;
+            let complete = discarded == 0;
+            if !complete {
+                recovery.wal_bytes_discarded += discarded;
+                if !read_only {
+                    warning!(options.log_level, path = %path.display(), bytes = %discarded; "discarded invalid WAL data");
+                    recovery.repaired_files.push(path.clone());
+                }
+            }
+            for record in operations {
+                let lsn = record.lsn.unwrap_or(last + 1);
+                match record.operation {
+                    Some(operation) if lsn > map_lsn => {
+                        if !record.shards.is_empty() {
+                            parts.push((lsn, record.shards));
+                        }
+                        wal.push((lsn, operation));
+                    }
+                    _ => {}
+                }
+                last = last.max(lsn);
+            }
+            active = Some((segment, path, file));
+            if !complete {
+                break;
+            }
+        }
+        for (_, path) in segments {
+            recovery.wal_bytes_discarded += backend.file_len(&path)?;
+            if !read_only {
+                warning!(options.log_level, path = %path.display(); "discarding WAL segment after a corrupt record");
+                backend.remove_file(&path)?;
+                recovery.repaired_files.push(path);
+            }
+        }
+        Ok((wal, parts, active))
+    }
+
+    // A checksum mismatch or bytes that don't form whole records mean the file is corrupt.
+    // With salvage set the records up to the first unreadable one are returned instead of an error.
+    pub(crate) fn get_map_from_file(file: &dyn StorageFile, path: &Path, hasher: &KeyHashState, salvage: bool) -> Result<MapContents> {
+        LookupTable::read_map(file, path, hasher, salvage, MAP_READ_CHUNK)
+    }
+
+    fn read_map(file: &dyn StorageFile, path: &Path, hasher: &KeyHashState, salvage: bool, chunk: usize) -> Result<MapContents> {
+        let mut hashmap = KeyMap::with_hasher(hasher.clone());
+        let mut expiries = KeyMap::with_hasher(hasher.clone());
+        let mut tombstones = KeyMap::with_hasher(hasher.clone());
+        let len = file.len().map_err(MapError::io(path, "read"))?;
+        // A new file holds only the header, a read-only handle may see it without one yet
+        if len <= HEADER_SIZE as u64 {
+            return Ok((hashmap, expiries, tombstones, 0));
+        }
+        let mut header = [0; HEADER_SIZE];
+        file.read_at(&mut header, 0).map_err(MapError::io(path, "read"))?;
+        let version = FileHeader::decode(&header, MAP_MAGIC)?.version();
+        let checksum_at = (version >= 3).then(|| len.saturating_sub(CHECKSUM_SIZE as u64).max(HEADER_SIZE as u64));
+        let end = match version >= SORTED_MAP_VERSION {
+            // A footer that doesn't fit leaves the records running to the checksum, which then fails
+            true => SortedMap::records_end(file, checksum_at.unwrap_or(len))
+                .map_err(MapError::io(path, "read"))?
+                .unwrap_or(checksum_at.unwrap_or(len)),
+            false => checksum_at.unwrap_or(len),
+        };
+        let mut reader = MapReader::new(file, end, checksum_at, chunk);
+        // Reports a record that can't be read as what it is a symptom of if the checksum doesn't match
+        let corrupt = |reader: &mut MapReader, offset| -> Result<MapContents> {
+            match reader.checksum_matches().map_err(MapError::io(path, "read"))? {
+                true => Err(Error::Map(MapError::CorruptRecord { path: path.to_path_buf(), offset })),
+                false => Err(Error::Map(MapError::ChecksumMismatch { path: path.to_path_buf() })),
+            }
+        };
+        let record_end = match version {
+            1 => LOCATION_SIZE,
+            _ => LOCATION_SIZE + EXPIRY_SIZE,
+        };
+        let mut lsn = 0;
+        let mut previous = Vec::new();
+        // Read again for the checksum, which covers the header
+        reader.take(HEADER_SIZE).map_err(MapError::io(path, "read"))?;
+        if version >= 4 {
+            let bytes = reader.take(LSN_SIZE).map_err(MapError::io(path, "read"))?;
+            match bytes {
+                // A salvaged map may have lost records, the whole WAL is replayed on top of it
+                Some(bytes) if !salvage => lsn = u64::from_le_bytes(bytes[..].try_into()?),
+                None if !salvage => return corrupt(&mut reader, HEADER_SIZE),
+                _ => {}
+            }
+        }
+        if version >= KEY_COUNTS_VERSION {
+            let offset = reader.offset() as usize;
+            match reader.take(2 * COUNT_BYTES).map_err(MapError::io(path, "read"))? {
+                Some(counts) => {
+                    // Bounded by the records the file can hold, a corrupt count must not exhaust memory
+                    let most = (end / (LOCATION_SIZE + EXPIRY_SIZE) as u64) as usize;
+                    let keys = u64::from_le_bytes(counts[..COUNT_BYTES].try_into()?) as usize;
+                    let removed = u64::from_le_bytes(counts[COUNT_BYTES..].try_into()?) as usize;
+                    hashmap.reserve(keys.min(most));
+                    tombstones.reserve(removed.min(most));
+                }
+                None if !salvage => return corrupt(&mut reader, offset),
+                None => {}
+            }
+        }
+        loop {
+            let records = reader.remaining();
+            let key = match version >= PREFIXED_KEYS_VERSION {
+                true => LookupTable::read_prefixed_key(records, 0, &previous),
+                false => LookupTable::read_key(records, 0),
+            };
+            let parsed = key.and_then(|(key, next)| {
+                let record = records.get(next..next + record_end)?;
+                Some((key, LookupTable::read_location(record, 0)?, record, next))
+            });
+            let Some((key, location, record, next)) = parsed else {
+                // The record may continue in the next chunk
+                if reader.read_more().map_err(MapError::io(path, "read"))? {
+                    continue;
+                }
+                let offset = reader.offset() as usize;
+                match (reader.remaining().is_empty(), salvage) {
+                    (true, _) | (false, true) => break,
+                    (false, false) => return corrupt(&mut reader, offset),
+                }
+            };
+            let expires_at = match record_end > LOCATION_SIZE {
+                true => u64::from_le_bytes(record[LOCATION_SIZE..].try_into()?),
+                false => 0,
+            };
+            reader.consume(next + record_end);
+            if version >= PREFIXED_KEYS_VERSION {
+                previous.clone_from(&key);
+            }
+            if version >= TOMBSTONES_VERSION && location.block == TOMBSTONE_BLOCK {
+                tombstones.insert(key, Tombstone { lsn: location.pointer, deleted_at: expires_at });
+            } else {
+                if expires_at != 0 {
+                    expiries.insert(key.clone(), expires_at);
+                }
+                hashmap.insert(key, location);
+            }
+        }
+        if !salvage && !reader.checksum_matches().map_err(MapError::io(path, "read"))? {
+            return Err(Error::Map(MapError::ChecksumMismatch { path: path.to_path_buf() }));
+        }
+        Ok((hashmap, expiries, tombstones, lsn))
+    }
+
+    // Records are framed as WalFormat describes for the version of the segment.
+    // Replay stops at the first torn or corrupt record and the file is truncated there,
+    // so that new records are never appended after garbage.
+    // Also returns the number of bytes discarded, 0 if the whole file was valid.
+    pub(crate) fn get_wal_from_file(file: &dyn StorageFile, path: &Path, read_only: bool, threads: usize) -> Result<(Vec<WalRecord>, u64)> {
+        let mut buffer = vec![0; file.len().map_err(WalError::io(path, "read"))? as usize];
+        file.read_at(&mut buffer, 0).map_err(WalError::io(path, "read"))?;
+        let format = WalFormat::of_segment(&buffer);
+        let threads = threads.min(buffer.len() / PARALLEL_REPLAY_BYTES);
+        let (wal, offset) = LookupTable::decode_wal_records(&buffer, HEADER_SIZE.min(buffer.len()), format, threads);
+        let discarded = (buffer.len() - offset) as u64;
+        if discarded > 0 && !read_only {
+            file.set_len(offset as u64).map_err(WalError::io(path, "truncate"))?;
+            file.sync().map_err(WalError::io(path, "sync"))?;
+        }
+        Ok((wal, discarded))
+    }
+
+    // Decodes the records from offset on, returning them with the offset of the first one that is
+    // torn or corrupt, or the end of buffer. With several threads the records are located first,
+    // then checked and decoded in parallel, a run of them per thread.
+    fn decode_wal_records(buffer: &[u8], mut offset: usize, format: WalFormat, threads: usize) -> (Vec<WalRecord>, usize) {
+        if threads <= 1 {
+            let mut wal = Vec::new();
+            while let Some((record, next)) = LookupTable::read_wal_record(buffer, offset, format) {
+                wal.push(record);
+                offset = next;
+            }
+            return (wal, offset);
+        }
+        let mut starts = Vec::new();
+        while let Some(next) = format.skip(buffer, offset) {
+            starts.push(offset);
+            offset = next;
+        }
+        starts.push(offset);
+        let records = &starts[..starts.len() - 1];
+        let run_length = records.len().div_ceil(threads).max(1);
+        let runs: Vec<Vec<WalRecord>> = std::thread::scope(|scope| {
+            let decodes: Vec<_> = records.chunks(run_length)
+                .map(|run| scope.spawn(move || {
+                    run.iter()
+                        .map_while(|&start| LookupTable::read_wal_record(buffer, start, format).map(|(record, _)| record))
+                        .collect()
+                }))
+                .collect();
+            decodes.into_iter()
+                .map(|decode| decode.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .collect()
+        });
+        let mut wal = Vec::with_capacity(records.len());
+        for run in runs {
+            let complete = run.len() == run_length;
+            wal.extend(run);
+            // The records after a corrupt one are discarded, whether they could be read or not
+            if !complete {
+                break;
+            }
+        }
+        let end = starts[wal.len()];
+        (wal, end)
+    }
+
+    // Decodes the record at offset, returning it with the offset of the next record
+    pub(crate) fn read_wal_record(buffer: &[u8], offset: usize, format: WalFormat) -> Option<(WalRecord, usize)> {
+        let (body, valid, next) = format.frame(buffer, offset)?;
+        if !valid {
+            return None;
+        }
+        let body = &buffer[body];
+        let mut shards = Vec::new();
+        let (lsn, operation_start) = match body.first() {
+            Some(&CHECKPOINT_RECORD) => {
+                let (lsn, end) = format.read_int(body, 1, LSN_SIZE)?;
+                if end != body.len() {
+                    return None;
+                }
+                return Some((WalRecord { lsn: Some(lsn), operation: None, shards }, next));
+            }
+            Some(&SEQUENCED_RECORD) => {
+                let (lsn, start) = format.read_int(body, 1, LSN_SIZE)?;
+                (Some(lsn), start)
+            }
+            Some(&PART_RECORD) => {
+                let (lsn, start) = format.read_int(body, 1, LSN_SIZE)?;
+                let (count, mut start) = format.read_int(body, start, COUNT_SIZE)?;
+                for _ in 0..count {
+                    let (shard, end) = format.read_int(body, start, SHARD_INDEX_SIZE)?;
+                    shards.push(u32::try_from(shard).ok()?);
+                    start = end;
+                }
+                (Some(lsn), start)
+            }
+            _ => (None, 0),
+        };
+        let (operation, end) = LookupTable::decode_wal_operation(body, operation_start, format, true)?;
+        if end != body.len() {
+            return None;
+        }
+        Some((WalRecord { lsn, operation: Some(operation), shards }, next))
+    }
+
+    // Decodes the operation at offset, returning it with the offset just past it
+    fn decode_wal_operation(body: &[u8], offset: usize, format: WalFormat, allow_batch: bool) -> Option<(WalOperation, usize)> {
+        let op_type = *body.get(offset)?;
+        let read_key = || LookupTable::read_wal_key(body, offset + 1, format);
+        let read_location = |offset| {
+            let (block, next) = format.read_int(body, offset, LOCATION_SIZE / 2)?;
+            let (pointer, next) = format.read_int(body, next, LOCATION_SIZE / 2)?;
+            Some((EntryLocation::unpack(block, pointer), next))
+        };
+        match op_type {
+            0 => {
+                let (key, next) = read_key()?;
+                let (location, next) = read_location(next)?;
+                Some((WalOperation::Insert{key, location}, next))
+            }
+            1 => {
+                let (key, next) = read_key()?;
+                Some((WalOperation::Remove{key}, next))
+            }
+            3 => {
+                let (key, next) = read_key()?;
+                let (location, next) = read_location(next)?;
+                let (expires_at, next) = format.read_int(body, next, EXPIRY_SIZE)?;
+                Some((WalOperation::InsertExpiring{key, location, expires_at}, next))
+            }
+            4 => {
+                let (key, next) = read_key()?;
+                let (location, next) = read_location(next)?;
+                Some((WalOperation::Merge{key, location}, next))
+            }
+            5 => {
+                let (key, next) = read_key()?;
+                let (location, next) = read_location(next)?;
+                Some((WalOperation::Increment{key, location}, next))
+            }
+            2 if allow_batch => {
+                let (count, mut next) = format.read_int(body, offset + 1, COUNT_SIZE)?;
+                let mut operations = Vec::new();
+                for _ in 0..count {
+                    let (operation, end) = LookupTable::decode_wal_operation(body, next, format, false)?;
+                    operations.push(operation);
+                    next = end;
+                }
+                Some((WalOperation::Batch(operations), next))
+            }
+            _ => None,
+        }
+    }
+
+    fn read_wal_key(body: &[u8], offset: usize, format: WalFormat) -> Option<(Vec<u8>, usize)> {
+        let (length, start) = format.read_int(body, offset, KEY_LENGTH_SIZE)?;
+        let end = start.checked_add(usize::try_from(length).ok()?)?;
+        Some((body.get(start..end)?.to_vec(), end))
+    }
+
+    // Reads a length-prefixed key at offset, returning it with the offset just past it
+    pub(crate) fn read_key(buffer: &[u8], offset: usize) -> Option<(Vec<u8>, usize)> {
+        let length_bytes = buffer.get(offset..offset + KEY_LENGTH_SIZE)?;
+        let length = u32::from_le_bytes(length_bytes.try_into().ok()?) as usize;
+        let start = offset + KEY_LENGTH_SIZE;
+        let key = buffer.get(start..start.checked_add(length)?)?;
+        Some((key.to_vec(), start + length))
+    }
+
+    // Reads a key that shares a prefix with previous, returning it with the offset just past it
+    pub(crate) fn read_prefixed_key(buffer: &[u8], offset: usize, previous: &[u8]) -> Option<(Vec<u8>, usize)> {
+        let shared_bytes = buffer.get(offset..offset + SHARED_PREFIX_SIZE)?;
+        let shared = u16::from_le_bytes(shared_bytes.try_into().ok()?) as usize;
+        let (suffix, next) = LookupTable::read_key(buffer, offset + SHARED_PREFIX_SIZE)?;
+        let mut key = previous.get(..shared)?.to_vec();
+        key.extend_from_slice(&suffix);
+        Some((key, next))
+    }
+
+    // Reads a map record of version 9 or later, returning its key, location and expiry time with
+    // the offset just past it
+    pub(crate) fn read_record(buffer: &[u8], offset: usize, previous: &[u8]) -> Option<(Vec<u8>, EntryLocation, u64, usize)> {
+        let (key, next) = LookupTable::read_prefixed_key(buffer, offset, previous)?;
+        let location = LookupTable::read_location(buffer, next)?;
+        let expiry = buffer.get(next + LOCATION_SIZE..next + LOCATION_SIZE + EXPIRY_SIZE)?;
+        Some((key, location, u64::from_le_bytes(expiry.try_into().ok()?), next + LOCATION_SIZE + EXPIRY_SIZE))
+    }
+
+    pub(crate) fn read_location(buffer: &[u8], offset: usize) -> Option<EntryLocation> {
+        let bytes = buffer.get(offset..offset + LOCATION_SIZE)?;
+        let block = u64::from_le_bytes(bytes[0..8].try_into().ok()?);
+        let pointer = u64::from_le_bytes(bytes[8..16].try_into().ok()?);
+        Some(EntryLocation::unpack(block, pointer))
+    }
+
+    pub(crate) fn encode_key(buffer: &mut Vec<u8>, key: &[u8]) {
+        buffer.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(key);
+    }
+
+    pub(crate) fn encode_prefixed_key(buffer: &mut Vec<u8>, key: &[u8], previous: &[u8]) {
+        let shared = key.iter().zip(previous)
+            .take(u16::MAX as usize)
+            .take_while(|(a, b)| a == b)
+            .count();
+        buffer.extend_from_slice(&(shared as u16).to_le_bytes());
+        LookupTable::encode_key(buffer, &key[shared..]);
+    }
+
+    pub(crate) fn encode_location(buffer: &mut Vec<u8>, location: &EntryLocation) {
+        buffer.extend_from_slice(&location.block.to_le_bytes());
+        buffer.extend_from_slice(&location.packed_pointer().to_le_bytes());
+    }
+
+    fn compacted_map_path(map_path: &Path) -> PathBuf {
+        map_path.with_extension("db.compact")
+    }
+
+    // Writes a map.db of the current version holding the records, given in key order
+    pub(crate) fn write_map_file<'a>(
+        backend: &dyn StorageBackend,
+        path: &Path,
+        records: impl IntoIterator<Item = (&'a [u8], EntryLocation, u64)>,
+        lsn: u64,
+        block_size: usize,
+    ) -> Result<Arc<dyn StorageFile>> {
+        let file = backend.open(path, OpenMode::Truncate).map_err(MapError::io(path, "create"))?;
+        let mut encoder = MapEncoder::new(lsn, block_size);
+        for (key, location, expiry) in records {
+            encoder.push(key, &location, expiry);
+        }
+        let buffer = encoder.finish();
+        file.write_at(&buffer, 0).map_err(MapError::io(path, "write"))?;
+        file.sync().map_err(MapError::io(path, "sync"))?;
+        Ok(file)
+    }
+
+    fn encode_wal_operation(body: &mut Vec<u8>, operation: &WalOperation, format: WalFormat) {
+        let write_key = |body: &mut Vec<u8>, key: &[u8]| {
+            format.write_int(body, key.len() as u64, KEY_LENGTH_SIZE);
+            body.extend_from_slice(key);
+        };
+        let write_location = |body: &mut Vec<u8>, location: &EntryLocation| {
+            format.write_int(body, location.block, LOCATION_SIZE / 2);
+            format.write_int(body, location.packed_pointer(), LOCATION_SIZE / 2);
+        };
+        match operation {
+            WalOperation::Insert{key, location} => {
+                body.push(0);
+                write_key(body, key);
+                write_location(body, location);
+            }
+            WalOperation::Remove{key} => {
+                body.push(1);
+                write_key(body, key);
+            }
+            WalOperation::InsertExpiring{key, location, expires_at} => {
+                body.push(3);
+                write_key(body, key);
+                write_location(body, location);
+                format.write_int(body, *expires_at, EXPIRY_SIZE);
+            }
+            WalOperation::Merge{key, location} => {
+                body.push(4);
+                write_key(body, key);
+                write_location(body, location);
+            }
+            WalOperation::Increment{key, location} => {
+                body.push(5);
+                write_key(body, key);
+                write_location(body, location);
+            }
+            WalOperation::Batch(operations) => {
+                body.push(2);
+                format.write_int(body, operations.len() as u64, COUNT_SIZE);
+                for operation in operations {
+                    LookupTable::encode_wal_operation(body, operation, format);
+                }
+            }
+        }
+    }
+
+    // New records are always compact, see WalFormat
+    fn encode_wal_record(buffer: &mut Vec<u8>, lsn: u64, operation: &WalOperation) {
+        let mut body = vec![SEQUENCED_RECORD];
+        encode_varint(&mut body, lsn);
+        LookupTable::encode_wal_operation(&mut body, operation, WalFormat::Compact);
+        WalFormat::Compact.frame_record(buffer, &body);
+    }
+
+    fn encode_part_record(buffer: &mut Vec<u8>, lsn: u64, operation: &WalOperation, shards: &[u32]) {
+        let mut body = vec![PART_RECORD];
+        encode_varint(&mut body, lsn);
+        encode_varint(&mut body, shards.len() as u64);
+        for shard in shards {
+            encode_varint(&mut body, *shard as u64);
+        }
+        LookupTable::encode_wal_operation(&mut body, operation, WalFormat::Compact);
+        WalFormat::Compact.frame_record(buffer, &body);
+    }
+
+    fn encode_checkpoint_record(buffer: &mut Vec<u8>, lsn: u64) {
+        let mut body = vec![CHECKPOINT_RECORD];
+        encode_varint(&mut body, lsn);
+        WalFormat::Compact.frame_record(buffer, &body);
+    }
+
+    // Header of a segment, None if it is torn or invalid
+    fn wal_header(file: &dyn StorageFile) -> Result<Option<FileHeader>> {
+        let mut header = vec![0; (file.len()? as usize).min(HEADER_SIZE)];
+        file.read_at(&mut header, 0)?;
+        Ok(FileHeader::decode(&header, WAL_MAGIC).ok())
+    }
+
+    // The last WAL segment if the database was closed cleanly, the map covers every record of the WAL then.
+    // Its flag is cleared unless read_only, before anything is appended.
+    fn clean_wal_segment(backend: &dyn StorageBackend, folder: &Path, options: &DbOptions) -> Result<Option<WalSegment>> {
+        let Some((segment, path)) = LookupTable::wal_segments(backend, folder)?.pop() else { return Ok(None) };
+        let mode = if options.read_only { OpenMode::Read } else { OpenMode::Write };
+        let file = backend.open(&path, mode).map_err(WalError::io(&path, "open"))?;
+        match LookupTable::wal_header(file.as_ref())? {
+            Some(header) if header.version() >= CLEAN_SHUTDOWN_VERSION && header.clean_shutdown() => {
+                if !options.read_only {
+                    FileHeader::set_clean_shutdown(file.as_ref(), false).map_err(WalError::io(&path, "write"))?;
+                }
+                Ok(Some((segment, path, file)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Called last when the database is closed, after a flush. Flags the active WAL segment so the
+    // next open knows the map covers all of the WAL.
+    pub fn mark_clean_shutdown(&self) -> Result<()> {
+        if !self.wal.is_empty() || self.flushed_lsn < self.applied_lsn {
+            return Ok(());
+        }
+        FileHeader::set_clean_shutdown(self.wal_file.as_ref(), true).map_err(WalError::io(&self.wal_path, "write"))
+    }
+
+    // Shared handle to the active WAL segment for syncing it from another thread
+    pub fn wal_handle(&self) -> Arc<dyn StorageFile> {
+        Arc::clone(&self.wal_file)
+    }
+
+    // Entry of key as the index holds it, with the tombstone of a removed key and before expiry
+    fn entry(&self, key: &[u8]) -> Result<Option<IndexEntry>> {
+        match self.index.get(key)? {
+            Some(value) => Ok(Some(IndexEntry::decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<EntryLocation>> {
+        let now = self.now();
+        match self.entry(key)? {
+            Some(IndexEntry::Put(location, expires_at)) if expires_at == 0 || expires_at > now => Ok(Some(location)),
+            _ => Ok(None),
+        }
+    }
+
+    // Keys within range in ascending order together with their locations
+    pub fn range<'a, K, R>(&'a self, range: R) -> impl DoubleEndedIterator<Item = Result<(Vec<u8>, EntryLocation)>> + 'a
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let start = range.start_bound().map(|b| b.as_ref().to_vec());
+        let end = range.end_bound().map(|b| b.as_ref().to_vec());
+        let now = self.now();
+        self.index.scan(start.as_ref().map(Vec::as_slice), end.as_ref().map(Vec::as_slice))
+            .filter_map(move |entry| match entry.and_then(|(key, value)| Ok((key, IndexEntry::decode(&value)?))) {
+                Ok((key, IndexEntry::Put(location, expires_at))) if expires_at == 0 || expires_at > now => Some(Ok((key, location))),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            })
+    }
+
+    // Keys starting with prefix in ascending order, answered from the ordered key set
+    #[cfg(test)]
+    pub fn scan_prefix<'a>(&'a self, prefix: &[u8]) -> impl DoubleEndedIterator<Item = Result<(Vec<u8>, EntryLocation)>> + 'a {
+        self.range(LookupTable::prefix_bounds(prefix))
+    }
+
+    // [prefix, successor of prefix), the successor is the shortest key greater than every key with the prefix
+    pub(crate) fn prefix_bounds(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        let mut end = prefix.to_vec();
+        while let Some(last) = end.pop() {
+            if last < u8::MAX {
+                end.push(last + 1);
+                return (Bound::Included(prefix.to_vec()), Bound::Excluded(end));
+            }
+        }
+        (Bound::Included(prefix.to_vec()), Bound::Unbounded)
+    }
+
+    // BTreeSet::range panics on these instead of returning nothing
+    pub(crate) fn is_empty_range(bounds: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> bool {
+        match bounds {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+            _ => false,
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshots.acquire(self.lsn.load(Ordering::SeqCst))
+    }
+
+    // Location of key as it was when the snapshot was taken
+    pub fn get_at(&self, key: &[u8], snapshot: &Snapshot) -> Result<Option<EntryLocation>> {
+        let replaced = self.history.get(key)
+            .and_then(|versions| versions.iter().find(|(seq, _)| *seq > snapshot.sequence()));
+        match replaced {
+            Some((_, previous)) => Ok(*previous),
+            None => self.get(key),
+        }
+    }
+
+    // Whether key was written after sequence number lsn, a snapshot taken at lsn must still be alive
+    pub fn written_since(&self, key: &[u8], lsn: u64) -> bool {
+        self.history.get(key).is_some_and(|versions| versions.iter().any(|(seq, _)| *seq > lsn))
+    }
+
+    // Keys within range that the snapshot sees, in ascending order
+    pub fn range_at<K, R>(&self, range: R, snapshot: &Snapshot) -> Result<Vec<Vec<u8>>>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let bounds = (
+            range.start_bound().map(|b| b.as_ref().to_vec()),
+            range.end_bound().map(|b| b.as_ref().to_vec()),
+        );
+        if LookupTable::is_empty_range(&bounds) {
+            return Ok(Vec::new());
+        }
+        // Keys removed since the snapshot are only left in the history
+        let mut keys = BTreeSet::new();
+        for entry in self.index.scan(bounds.0.as_ref().map(Vec::as_slice), bounds.1.as_ref().map(Vec::as_slice)) {
+            keys.insert(entry?.0);
+        }
+        keys.extend(self.history.keys().filter(|key| bounds.contains(*key)).cloned());
+        let mut visible = Vec::new();
+        for key in keys {
+            if self.get_at(&key, snapshot)?.is_some() {
+                visible.push(key);
+            }
+        }
+        Ok(visible)
+    }
+
+    // Drops versions that no live snapshot can read anymore and tombstones past the retention
+    fn collect_garbage(&mut self) -> Result<()> {
+        match self.snapshots.oldest() {
+            None => self.history.clear(),
+            Some(oldest) => self.history.retain(|_, versions| {
+                versions.retain(|(seq, _)| *seq > oldest);
+                !versions.is_empty()
+            }),
+        }
+        // Files written before keep the dropped tombstones until the next full rewrite of the map
+        let horizon = self.now().saturating_sub(self.tombstone_retention);
+        for key in self.index.expired_tombstones(horizon) {
+            self.index.delete(&key)?;
+        }
+        Ok(())
+    }
+
+    // Swaps in the result of a background merge of the index, see KeyIndex::finish_merge
+    pub fn finish_merge(&mut self, wait: bool) -> Result<()> {
+        self.index.finish_merge(wait)
+    }
+
+    // Whether the table still searches map.db on disk, see load_base
+    pub fn is_lazy(&self) -> bool {
+        self.index.is_lazy()
     }
-}
 
-pub(crate) struct LookupTable {
-    map_file: File,
-    map_path: PathBuf,
-    map: HashMap<u64, EntryLocation>,
-    wal_file: File,
-    wal_path: PathBuf,
-    wal: Vec<WalOperation>,
-}
+    // Loads the map a low-memory handle searched on disk so far, everything but point lookups needs it
+    pub fn load_base(&mut self) -> Result<()> {
+        if !self.index.is_lazy() {
+            return Ok(());
+        }
+        self.index.load()?;
+        self.collect_garbage()
+    }
+
+    pub fn tombstone_count(&self) -> usize {
+        self.index.tombstone_count()
+    }
 
-const BTREE_BLOCK_SIZE: usize = 4096;
-const WAL_BLOCK_SIZE: usize = 25;
-const MAP_BLOCK_SIZE: usize = 24;
+    // Keys that reads see, expired ones are left out before a flush purges them
+    pub fn len(&self) -> usize {
+        self.index.len() - self.index.expired_count(self.now())
+    }
 
-#[derive(Debug, Copy, Clone)]
-pub(crate) enum WalOperation {
-    Insert{key: u64, location: EntryLocation},
-    Remove{key: u64},
-}
+    // Counted up to EXACT_RANGE_COUNT keys, beyond that estimated from a sample of the keys
+    // within bounds. Expired keys are counted.
+    pub fn estimate_range_count(&self, bounds: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> usize {
+        self.index.estimate_range_count(bounds)
+    }
 
-impl LookupTable {
-    pub fn new(folder: &str) -> Result<Self> {
-        LookupTable::new_reset(folder, false)
+    // Value sizes of a sample of the keys within bounds scaled up to the estimated number of keys.
+    // Only the map is read, locations written before format version 16 count as empty values.
+    pub fn approximate_size(&self, bounds: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Result<u64> {
+        self.index.approximate_size(bounds)
     }
 
-    pub fn new_reset(folder: &str, reset: bool) -> Result<Self> {
-        let map_path = Path::new(folder).join("map.db");
-        if let Some(parent) = map_path.parent() {
-            fs::create_dir_all(parent).expect("Failed to create directory for map.db");
-        }
-        let wal_path = Path::new(folder).join("wal.db");
-        if let Some(parent) = wal_path.parent() {
-            fs::create_dir_all(parent).expect("Failed to create directory for wal.db");
+    // Operations logged after sequence number lsn, None if a flush already removed some of them from the WAL
+    pub fn logged_since(&self, lsn: u64) -> Option<impl Iterator<Item = &(u64, WalOperation)>> {
+        (self.flushed_lsn <= lsn).then(|| self.wal.iter().filter(move |(logged, _)| *logged > lsn))
+    }
+
+    pub fn expiry(&self, key: &[u8]) -> Result<Option<u64>> {
+        match self.entry(key)? {
+            Some(IndexEntry::Put(_, expires_at)) if expires_at != 0 => Ok(Some(expires_at)),
+            _ => Ok(None),
         }
-        if reset {
-            Self::cleanup(map_path.clone(), wal_path.clone())?;
+    }
+
+    // Every key of the map in sorted order, including expired ones
+    pub fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        self.index.scan(Bound::Unbounded, Bound::Unbounded).map(|entry| Ok(entry?.0)).collect()
+    }
+
+    // Every location that the current map or a live snapshot can still read
+    pub fn live_locations(&self) -> Result<Vec<EntryLocation>> {
+        let mut locations = Vec::new();
+        for entry in self.index.scan(Bound::Unbounded, Bound::Unbounded) {
+            locations.push(IndexEntry::decode(&entry?.1)?.fields().0);
         }
-        let mut map_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(map_path.clone())
-            ?;
+        locations.extend(self.history.values().flatten().filter_map(|(_, location)| *location));
+        Ok(locations)
+    }
 
-        let mut wal_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(wal_path.clone())
-            ?;
-        let map = LookupTable::get_map_from_file(&mut map_file)?;
-        let wal = LookupTable::get_wal_from_file(&mut wal_file)?;
-        Ok(Self {map_file, map_path, map, wal_file, wal_path, wal})
+    // First step of swapping in a compacted data file: writes a map pointing every location
+    // at its copy there next to map.db. Compaction runs on a flushed table, so the WAL holds
+    // no locations into the old file.
+    //
+    // Renaming the compacted data file over data.db is the commit point, commit_relocation
+    // then renames the map. recover_compaction finishes that if a crash happens in between.
+    pub fn prepare_relocation(&self, relocated: &HashMap<EntryLocation, EntryLocation>) -> Result<Relocation> {
+        if !self.wal.is_empty() {
+            return Err(Error::Wal(WalError::NotFlushed { operation: "relocate" }));
+        }
+        let moved = |location: &EntryLocation| LookupTable::relocate(relocated, location);
+        let mut history = self.history.clone();
+        for versions in history.values_mut() {
+            for (_, location) in versions.iter_mut() {
+                if let Some(location) = location {
+                    *location = moved(location)?;
+                }
+            }
+        }
+        self.index.write_relocated(&LookupTable::compacted_map_path(&self.map_path), &moved)?;
+        Ok(Relocation { history })
     }
 
-    pub fn add(&mut self, key: u64, location: EntryLocation) -> Result<()> {
-        self.map.insert(key, location);
-        let wal_operation = WalOperation::Insert{key, location};
-        self.wal.push(wal_operation);
-        LookupTable::write_wal_operation_to_file(&mut self.wal_file, &wal_operation)?;
-        Ok(())
+    fn relocate(relocated: &HashMap<EntryLocation, EntryLocation>, location: &EntryLocation) -> Result<EntryLocation> {
+        relocated.get(location).copied()
+            .ok_or(Error::Map(MapError::MissingRelocation { block: location.block, pointer: location.pointer }))
     }
 
-    pub fn remove(&mut self, key: u64) -> Result<()> {
-        self.map.remove(&key);
-        let wal_operation = WalOperation::Remove{key};
-        self.wal.push(wal_operation);
-        LookupTable::write_wal_operation_to_file(&mut self.wal_file, &wal_operation)?;
-        Ok(())
+    // Writes the map with every location moved to its copy in relocated as map.db of folder, the only
+    // file a vacuumed table starts out with. Versions kept for snapshots are left behind.
+    pub fn write_vacuumed(&self, folder: &Path, relocated: &HashMap<EntryLocation, EntryLocation>) -> Result<()> {
+        if !self.wal.is_empty() {
+            return Err(Error::Wal(WalError::NotFlushed { operation: "vacuum" }));
+        }
+        self.backend.create_dir_all(folder)?;
+        self.index.write_relocated(&folder.join(MAP_FILE_NAME), &|location| LookupTable::relocate(relocated, location))
     }
 
-    pub fn flush(&mut self) -> Result<()> {
-        LookupTable::write_map_to_file(&mut self.map_file, &self.map)?;
-        self.wal.clear();
-        self.wal_file.set_len(0)?;
-        self.wal_file.sync_all()?;
+    pub fn commit_relocation(&mut self, relocation: Relocation) -> Result<()> {
+        self.index.commit_relocated(&LookupTable::compacted_map_path(&self.map_path))?;
+        self.history = relocation.history;
         Ok(())
     }
 
-    // Utility function to delete map.db and wal.db files
-    pub fn cleanup(map_path: PathBuf, wal_path: PathBuf) -> Result<()> {
-        if map_path.exists() {
-            println!("Removing map file");
-            fs::remove_file(map_path.to_str().unwrap())?;
+    // A compacted map without its compacted data file means the data file was already
+    // swapped in and the map has to follow. With both present the compaction never committed,
+    // the value log removes the data file once every table has seen it.
+    fn recover_compaction(
+        backend: &dyn StorageBackend,
+        data_folder: &Path,
+        map_path: &Path,
+        log_level: LogLevel,
+        recovery: &mut RecoveryReport,
+    ) -> Result<()> {
+        let compacted_map_path = LookupTable::compacted_map_path(map_path);
+        if !backend.exists(&compacted_map_path) {
+            return Ok(());
         }
-        if wal_path.exists() {
-            println!("Removing wal file");
-            fs::remove_file(wal_path.to_str().unwrap())?;
+        if backend.exists(&data_folder.join(COMPACTED_DATA_FILE_NAME)) {
+            event!(log_level, INFO, path = %compacted_map_path.display(), "rolling back an interrupted compaction");
+            backend.remove_file(&compacted_map_path)?;
+            recovery.repaired_files.push(compacted_map_path);
+        } else {
+            event!(log_level, INFO, path = %map_path.display(), "completing an interrupted compaction");
+            backend.rename(&compacted_map_path, map_path)?;
+            recovery.repaired_files.push(map_path.to_path_buf());
         }
         Ok(())
     }
 
-    fn get_map_from_file(file: &mut File) -> Result<HashMap<u64, EntryLocation>> {
-        let file_size = file.metadata()?.len() as usize;
-        let mut reader = BufReader::new(file);
-        let mut buffer = Vec::with_capacity(file_size);
-        let mut hashmap = HashMap::new();
+    #[cfg(test)]
+    pub fn paths(&self) -> (PathBuf, PathBuf) {
+        (self.map_path.clone(), self.wal_path.clone())
+    }
 
-        reader.read_to_end(&mut buffer)?;
-        for chunk in buffer.chunks_exact(MAP_BLOCK_SIZE) {
-            let key = u64::from_le_bytes(chunk[0..8].try_into()?);
-            let block = u64::from_le_bytes(chunk[8..16].try_into()?);
-            let pointer = u64::from_le_bytes(chunk[16..24].try_into()?);
-            hashmap.insert(key, EntryLocation { block, pointer });
-        }
-        Ok(hashmap)
+    // Files holding the state of the table, the WAL is synced first so copies of it are complete
+    pub fn files(&self) -> Result<Vec<PathBuf>> {
+        self.wal_file.sync().map_err(WalError::io(&self.wal_path, "sync"))?;
+        self.file_paths()
     }
 
-    fn get_wal_from_file(file: &mut File) -> Result<Vec<WalOperation>> {
-        let file_size = file.metadata()?.len() as usize;
-        let mut reader = BufReader::new(file);
-        let mut buffer = Vec::with_capacity(file_size);
-        let mut wal = Vec::new();
-        reader.read_to_end(&mut buffer)?;
-        for chunk in buffer.chunks_exact(WAL_BLOCK_SIZE) {
-            let op_type = chunk[0];
-            let key = u64::from_le_bytes(chunk[1..9].try_into()?);
-            if op_type == 0 {
-                let block = u64::from_le_bytes(chunk[9..17].try_into()?);
-                let pointer = u64::from_le_bytes(chunk[17..25].try_into()?);
-                wal.push(WalOperation::Insert{key, location: EntryLocation { block, pointer }});
-            } else if op_type == 1 {
-                wal.push(WalOperation::Remove{key});
+    // Files of the table whose checksum or records no longer check out when read again
+    pub fn corrupt_files(&self) -> Result<Vec<PathBuf>> {
+        let backend = self.backend.as_ref();
+        let mut corrupt = self.index.corrupt_files()?;
+        for (_, path) in LookupTable::wal_segments(backend, &self.folder)? {
+            let file = backend.open(&path, OpenMode::Read).map_err(WalError::io(&path, "open"))?;
+            let valid = FileHeader::init_or_validate(file.as_ref(), WAL_MAGIC, self.block_size, true).is_ok()
+                && LookupTable::get_wal_from_file(file.as_ref(), &path, true, 1).is_ok_and(|(_, discarded)| discarded == 0);
+            if !valid {
+                corrupt.push(path);
             }
         }
-        Ok(wal)
+        Ok(corrupt)
     }
 
-    fn write_map_to_file(file: &mut File, map: &HashMap<u64, EntryLocation>) -> Result<()> {
-        file.seek(SeekFrom::Start(0))?;
-        file.set_len(0)?;
-        for (key, location) in map {
-            let mut buffer = vec![0; MAP_BLOCK_SIZE];
-            buffer[0..8].copy_from_slice(&key.to_le_bytes());
-            buffer[8..16].copy_from_slice(&location.block.to_le_bytes());
-            buffer[16..24].copy_from_slice(&location.pointer.to_le_bytes());
-            file.write_all(&buffer)?;
+    // Bytes of the files holding the state of the table
+    pub fn disk_size(&self) -> Result<u64> {
+        let mut size = 0;
+        for path in self.file_paths()? {
+            size += self.backend.file_len(&path)?;
         }
-        file.sync_all()?;
-        Ok(())
+        Ok(size)
     }
 
-    fn write_wal_operation_to_file(file: &mut File, operation: &WalOperation) -> Result<()> {
-        let mut buffer = vec![0; WAL_BLOCK_SIZE];
-        match operation {
-            WalOperation::Insert{key, location} => {
-                buffer[0] = 0;
-                buffer[1..9].copy_from_slice(&key.to_le_bytes());
-                buffer[9..17].copy_from_slice(&location.block.to_le_bytes());
-                buffer[17..25].copy_from_slice(&location.pointer.to_le_bytes());
-            }
-            WalOperation::Remove{key} => {
-                buffer[0] = 1;
-                buffer[1..9].copy_from_slice(&(*key as u64).to_le_bytes());
-            }
+    fn file_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut files = self.index.files()?;
+        files.extend(LookupTable::wal_segments(self.backend.as_ref(), &self.folder)?.into_iter().map(|(_, path)| path));
+        Ok(files)
+    }
+
+    pub fn wal_bytes_written(&self) -> u64 {
+        self.wal_bytes_written
+    }
+
+    // Approximate bytes of memory taken by the key index and the kept versions, then by the
+    // operations logged since the last flush
+    pub fn memory_usage(&self) -> (usize, usize) {
+        let history: usize = self.history.keys().map(|key| 2 * size_of::<Vec<u8>>() + 1 + key.len()).sum();
+        (self.index.memory_usage() + history, self.wal_memory)
+    }
+
+    // Bytes appended to the WAL and keys written or removed since the last flush
+    pub fn unflushed(&self) -> (u64, usize) {
+        (self.unflushed_wal_bytes, self.unflushed_writes)
+    }
+
+    // Bytes of the WAL segments, part of disk_size
+    pub fn wal_size(&self) -> Result<u64> {
+        let mut size = 0;
+        for (_, path) in LookupTable::wal_segments(self.backend.as_ref(), &self.folder)? {
+            size += self.backend.file_len(&path)?;
         }
-        file.write_all(&buffer)?;
-        file.sync_all()?;
-        Ok(())
+        Ok(size)
     }
 
-    fn get(&self, key: u64) -> Result<Option<EntryLocation>> {
-        Ok(self.map.get(&key).cloned())
+    // Sequence number of the last record the table holds, in its map or its WAL
+    pub fn applied_lsn(&self) -> u64 {
+        self.applied_lsn
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn recovery(&self) -> &RecoveryReport {
+        &self.recovery
+    }
+
+    // Number of the active WAL segment, file handles to the WAL go stale when it changes
+    pub fn wal_segment(&self) -> u64 {
+        self.wal_segment
+    }
+
+    pub fn lock_path(&self) -> PathBuf {
+        self.folder.join(LOCK_FILE_NAME)
     }
 }
 
@@ -185,62 +2386,949 @@ impl LookupTable {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serial_test::serial;
+    use crate::db::backend::FileSystem;
+    use crate::db::btree::DEFAULT_BLOCK_SIZE;
+    use crate::db::clock::ManualClock;
+    use crate::db::temp::TempDir;
+    use crate::db::wal::{dump, WalOpType};
+    use std::fs;
+    use std::time::Duration;
+
+    // Closes the table and opens it again, as a restarted process would
+    fn reopen(lt: LookupTable) -> Result<LookupTable> {
+        let folder = lt.folder.clone();
+        drop(lt);
+        LookupTable::new(folder)
+    }
+
+    // The entries as records of map.db, in key order
+    fn records<'a>(
+        map: &'a KeyMap<EntryLocation>,
+        expiries: &KeyMap<u64>,
+        tombstones: &'a KeyMap<Tombstone>,
+    ) -> Vec<(&'a [u8], EntryLocation, u64)> {
+        let mut records: Vec<_> = map.iter()
+            .map(|(key, location)| (key.as_slice(), *location, expiries.get(key).copied().unwrap_or(0)))
+            .chain(tombstones.iter().map(|(key, tombstone)| (key.as_slice(), tombstone.location(), tombstone.deleted_at)))
+            .collect();
+        records.sort_unstable_by_key(|(key, _, _)| *key);
+        records
+    }
+
+    fn cleanup(lt: LookupTable) -> Result<()> {
+        let lock_path = lt.lock_path();
+        let folder = lt.folder.clone();
+        drop(lt);
+        LookupTable::cleanup(&FileSystem, &folder, LogLevel::default())?;
+        fs::remove_file(lock_path)?;
+        Ok(())
+    }
 
     #[test]
-    #[serial]
     fn test_add() -> Result<()> {
-        let mut lt = LookupTable::new_reset("test", true)?;
-        let el1= EntryLocation { block: 0, pointer: 0 };
-        let el2= EntryLocation { block: 0, pointer: 1 };
-        lt.add(1, el1)?;
-        lt.add(2, el2)?;
-
-        let el1_actual = lt.get(1)?;
-        let el2_actual = lt.get(2)?;
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let el1= EntryLocation { block: 0, pointer: 0, size: 0 };
+        let el2= EntryLocation { block: 0, pointer: 1, size: 0 };
+        lt.add(b"1", el1)?;
+        lt.add(b"2", el2)?;
+
+        let el1_actual = lt.get(b"1")?;
+        let el2_actual = lt.get(b"2")?;
         assert_eq!(Some(el1), el1_actual);
         assert_eq!(Some(el2), el2_actual);
-        LookupTable::cleanup(lt.map_path, lt.wal_path)?;
+        cleanup(lt)?;
         Ok(())
     }
 
     #[test]
-    #[serial]
     fn test_remove() -> Result<()>{
-        let mut lt = LookupTable::new_reset("test", true)?;
-        let el1= EntryLocation { block: 0, pointer: 0 };
-        let el2= EntryLocation { block: 0, pointer: 1 };
-        lt.add(1, el1)?;
-        lt.add(2, el2)?;
-        lt.remove(1)?;
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let el1= EntryLocation { block: 0, pointer: 0, size: 0 };
+        let el2= EntryLocation { block: 0, pointer: 1, size: 0 };
+        lt.add(b"1", el1)?;
+        lt.add(b"2", el2)?;
+        lt.remove(b"1")?;
 
-        assert_eq!(lt.map.len(), 1);
-        assert_eq!(lt.map.get(&1), None);
-        LookupTable::cleanup(lt.map_path, lt.wal_path)?;
+        assert_eq!(lt.len(), 1);
+        assert_eq!(lt.get(b"1")?, None);
+        cleanup(lt)?;
         Ok(())
     }
 
     #[test]
-    #[serial]
     fn test_flush() -> Result<()> {
-        let mut lt = LookupTable::new_reset("test", true)?;
-        let el1= EntryLocation { block: 0, pointer: 0 };
-        let el2= EntryLocation { block: 0, pointer: 1 };
-        lt.add(1, el1)?;
-        lt.add(2, el2)?;
-        lt.remove(1)?;
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let el1= EntryLocation { block: 0, pointer: 0, size: 0 };
+        let el2= EntryLocation { block: 0, pointer: 1, size: 24 };
+        lt.add(b"1", el1)?;
+        lt.add(b"2", el2)?;
+        lt.remove(b"1")?;
+        lt.flush()?;
+        assert_eq!(lt.get(b"1")?, None);
+        assert_eq!(lt.get(b"2")?, Some(EntryLocation { block: 0, pointer: 1, size: 24 }));
+        assert_eq!(lt.len(), 1);
+        let lt2 = reopen(lt)?;
+        assert_eq!(lt2.get(b"1")?, None);
+        assert_eq!(lt2.get(b"2")?, Some(EntryLocation { block: 0, pointer: 1, size: 24 }));
+        assert_eq!(lt2.len(), 1);
+
+        cleanup(lt2)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_variable_length_keys() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let long_key = vec![b'k'; 300];
+        let el1 = EntryLocation { block: 3, pointer: 17, size: 0 };
+        let el2 = EntryLocation { block: 1, pointer: 4, size: 0 };
+        lt.add(&long_key, el1)?;
+        lt.add(b"", el2)?;
+        lt.add(b"gone", el2)?;
+        lt.remove(b"gone")?;
+
+        let mut lt2 = reopen(lt)?;
+        assert_eq!(lt2.wal.len(), 4);
+        lt2.flush()?;
+        let lt3 = reopen(lt2)?;
+        assert_eq!(lt3.get(&long_key)?, Some(el1));
+        assert_eq!(lt3.get(b"")?, Some(el2));
+        assert_eq!(lt3.get(b"gone")?, None);
+        cleanup(lt3)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_stops_at_corrupt_record() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let el = EntryLocation { block: 0, pointer: 4, size: 0 };
+        lt.add(b"1", el)?;
+        lt.add(b"2", el)?;
+        lt.add(b"3", el)?;
+        let record_size = (lt.wal_file.len()? - HEADER_SIZE as u64) / 3;
+
+        // Flip a byte inside the second record and tear the third one
+        let mut bytes = fs::read(&lt.wal_path)?;
+        bytes[HEADER_SIZE + record_size as usize + 10] ^= 0xff;
+        bytes.truncate(bytes.len() - 3);
+        fs::write(&lt.wal_path, &bytes)?;
+
+        let mut lt2 = reopen(lt)?;
+        assert_eq!(lt2.wal.len(), 1);
+        assert_eq!(lt2.wal_file.len()?, HEADER_SIZE as u64 + record_size);
+
+        // Records appended after recovery are readable again
+        lt2.add(b"4", el)?;
+        let lt3 = reopen(lt2)?;
+        assert_eq!(lt3.wal.len(), 2);
+        cleanup(lt3)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_without_flush() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let el1 = EntryLocation { block: 0, pointer: 4, size: 0 };
+        let el2 = EntryLocation { block: 2, pointer: 8, size: 70_000 };
+        lt.add(b"1", el1)?;
+        lt.flush()?;
+        lt.add(b"2", el1)?;
+        lt.add(b"2", el2)?;
+        lt.remove(b"1")?;
+        let lt = reopen(lt)?;
+        assert_eq!(lt.get(b"1")?, None);
+        assert_eq!(lt.get(b"2")?, Some(el2));
+        assert_eq!(lt.len(), 1);
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_after_crash_mid_write() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let el = EntryLocation { block: 1, pointer: 4, size: 0 };
+        lt.add(b"1", el)?;
+        lt.add(b"2", el)?;
+        // Simulate the process dying halfway through writing the next record
+        let mut record = Vec::new();
+        record.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef, 40, 0, 0, 0, 0, 1]);
+        lt.wal_file.write_at(&record, lt.wal_file.len()?)?;
+        let mut lt = reopen(lt)?;
+        assert_eq!(lt.get(b"1")?, Some(el));
+        assert_eq!(lt.get(b"2")?, Some(el));
+        lt.add(b"3", el)?;
+        let lt = reopen(lt)?;
+        assert_eq!(lt.len(), 3);
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_batch() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let el = EntryLocation { block: 0, pointer: 4, size: 0 };
+        lt.write_batch(vec![
+            WalOperation::Insert{key: b"1".to_vec(), location: el},
+            WalOperation::Insert{key: b"2".to_vec(), location: el},
+            WalOperation::Remove{key: b"1".to_vec()},
+        ])?;
+        assert_eq!(lt.get(b"1")?, None);
+        assert_eq!(lt.get(b"2")?, Some(el));
+
+        let lt2 = reopen(lt)?;
+        assert_eq!(lt2.wal.len(), 1);
+        assert_eq!(lt2.len(), 1);
+        cleanup(lt2)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_batch_is_not_applied() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let el = EntryLocation { block: 0, pointer: 4, size: 0 };
+        lt.add(b"before", el)?;
+        let before = lt.wal_file.len()?;
+        lt.write_batch(vec![
+            WalOperation::Insert{key: b"1".to_vec(), location: el},
+            WalOperation::Insert{key: b"2".to_vec(), location: el},
+        ])?;
+        // Crash after the first half of the batch reached the disk
+        let after = lt.wal_file.len()?;
+        lt.wal_file.set_len(before + (after - before) / 2)?;
+
+        let lt2 = reopen(lt)?;
+        assert_eq!(lt2.get(b"before")?, Some(el));
+        assert_eq!(lt2.get(b"1")?, None);
+        assert_eq!(lt2.get(b"2")?, None);
+        cleanup(lt2)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_versions() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let el1 = EntryLocation { block: 0, pointer: 4, size: 0 };
+        let el2 = EntryLocation { block: 0, pointer: 20, size: 0 };
+        lt.add(b"1", el1)?;
+        lt.add(b"2", el1)?;
+        lt.add(b"overwritten before", el1)?;
+        lt.add(b"overwritten before", el2)?;
+        let snapshot = lt.snapshot();
+        lt.add(b"1", el2)?;
+        lt.remove(b"2")?;
+        lt.write_batch(vec![
+            WalOperation::Insert{key: b"3".to_vec(), location: el1},
+            WalOperation::Insert{key: b"3".to_vec(), location: el2},
+        ])?;
+        let later = lt.snapshot();
+        lt.remove(b"3")?;
+
+        assert_eq!(lt.get_at(b"1", &snapshot)?, Some(el1));
+        assert_eq!(lt.get_at(b"2", &snapshot)?, Some(el1));
+        assert_eq!(lt.get_at(b"3", &snapshot)?, None);
+        assert_eq!(lt.get_at(b"overwritten before", &snapshot)?, Some(el2));
+        assert_eq!(lt.get_at(b"3", &later)?, Some(el2));
+        assert_eq!(lt.get(b"3")?, None);
+
+        drop(snapshot);
+        lt.flush()?;
+        assert_eq!(lt.history.len(), 1);
+        assert_eq!(lt.get_at(b"3", &later)?, Some(el2));
+        drop(later);
+        lt.flush()?;
+        assert!(lt.history.is_empty());
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_crash_during_flush() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let el1 = EntryLocation { block: 0, pointer: 4, size: 0 };
+        let el2 = EntryLocation { block: 1, pointer: 4, size: 0 };
+        lt.add(b"1", el1)?;
+        lt.flush()?;
+        lt.add(b"2", el2)?;
+        // Crash while the next map was half written
+        let tmp_path = dir.path().join("map.db.tmp");
+        fs::write(&tmp_path, [1, 0, 0])?;
+
+        let mut lt = reopen(lt)?;
+        assert!(!tmp_path.exists());
+        assert_eq!(lt.get(b"1")?, Some(el1));
+        assert_eq!(lt.get(b"2")?, Some(el2));
+
+        lt.flush()?;
+        let lt2 = reopen(lt)?;
+        assert_eq!(lt2.len(), 2);
+        assert!(lt2.wal.is_empty());
+        cleanup(lt2)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_skips_flushed_records() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let el1 = EntryLocation { block: 0, pointer: 4, size: 0 };
+        let el2 = EntryLocation { block: 1, pointer: 4, size: 0 };
+        lt.add(b"1", el1)?;
+        lt.add(b"2", el1)?;
+        lt.remove(b"1")?;
+        let wal_path = lt.wal_path.clone();
+        let wal = fs::read(&wal_path)?;
+        lt.flush()?;
+        assert_eq!(lt.applied_lsn, 3);
+        // Crash after the new map was renamed into place, before the WAL was truncated
+        fs::write(&wal_path, &wal)?;
+
+        let mut lt = reopen(lt)?;
+        assert!(lt.wal.is_empty());
+        assert_eq!(lt.recovery().wal_records_replayed, 0);
+        assert_eq!(lt.get(b"1")?, None);
+        lt.add(b"2", el2)?;
+        assert_eq!(lt.applied_lsn, 4);
+        let lt = reopen(lt)?;
+        assert_eq!(lt.wal.len(), 1);
+        assert_eq!(lt.get(b"2")?, Some(el2));
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_range() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        for (i, key) in [b"d", b"a", b"c", b"b", b"e"].iter().enumerate() {
+            lt.add(*key, EntryLocation { block: 0, pointer: i as u64, size: 0 })?;
+        }
+        lt.remove(b"c")?;
+        lt.flush()?;
+        lt.add(b"c", EntryLocation { block: 1, pointer: 4, size: 0 })?;
+
+        let lt2 = reopen(lt)?;
+        let all: Vec<Vec<u8>> = lt2.range::<&[u8], _>(..).map(|entry| entry.map(|(key, _)| key)).collect::<Result<_>>()?;
+        assert_eq!(all, vec![b"a", b"b", b"c", b"d", b"e"]);
+        let middle: Vec<Vec<u8>> = lt2.range(b"b".as_slice()..b"d".as_slice()).map(|entry| entry.map(|(key, _)| key)).collect::<Result<_>>()?;
+        assert_eq!(middle, vec![b"b", b"c"]);
+        assert_eq!(lt2.range(b"c".as_slice()..).next().transpose()?, Some((b"c".to_vec(), EntryLocation { block: 1, pointer: 4, size: 0 })));
+        assert_eq!(lt2.range(b"d".as_slice()..b"b".as_slice()).count(), 0);
+        cleanup(lt2)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_prefix() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let el = EntryLocation { block: 0, pointer: 4, size: 0 };
+        for key in [&b"ab"[..], b"abc", b"ab\xff", b"ab\xff\x01", b"ac", b"a", b"\xff\xff", b"\xff\xff\x00"] {
+            lt.add(key, el)?;
+        }
+        let keys: Vec<Vec<u8>> = lt.scan_prefix(b"ab").map(|entry| entry.map(|(key, _)| key)).collect::<Result<_>>()?;
+        assert_eq!(keys, vec![&b"ab"[..], b"abc", b"ab\xff", b"ab\xff\x01"]);
+        let keys: Vec<Vec<u8>> = lt.scan_prefix(b"ab\xff").map(|entry| entry.map(|(key, _)| key)).collect::<Result<_>>()?;
+        assert_eq!(keys, vec![&b"ab\xff"[..], b"ab\xff\x01"]);
+        let keys: Vec<Vec<u8>> = lt.scan_prefix(b"\xff\xff").map(|entry| entry.map(|(key, _)| key)).collect::<Result<_>>()?;
+        assert_eq!(keys, vec![&b"\xff\xff"[..], b"\xff\xff\x00"]);
+        assert_eq!(lt.scan_prefix(b"").count(), 8);
+        assert_eq!(lt.scan_prefix(b"b").count(), 0);
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_segments() -> Result<()> {
+        let dir = TempDir::new()?;
+        let options = DbOptions::new().max_wal_segment_size(80);
+        let open = || LookupTable::open(dir.path(), &options);
+        let el = EntryLocation { block: 0, pointer: 4, size: 0 };
+        drop(LookupTable::new_reset(&dir, true)?);
+        let mut lt = open()?;
+        for i in 0..10u8 {
+            lt.add(&[i; 20], el)?;
+        }
+        // Records with a 20 byte key take 30 bytes, so a segment fits two of them
+        assert_eq!(lt.wal_segment(), 5);
+        let segments = LookupTable::wal_segments(&FileSystem, dir.path())?;
+        assert_eq!(segments.iter().map(|(segment, _)| *segment).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+
+        drop(lt);
+        let mut lt = open()?;
+        assert_eq!(lt.wal.len(), 10);
+        assert_eq!(lt.len(), 10);
+        lt.add(b"after reopen", el)?;
+        assert_eq!(lt.wal_segment(), 6);
+
+        // Replay stops at a corrupt segment and drops the ones written after it
+        let mut bytes = fs::read(&segments[2].1)?;
+        bytes[HEADER_SIZE + 10] ^= 0xff;
+        fs::write(&segments[2].1, &bytes)?;
+        drop(lt);
+        let mut lt = open()?;
+        assert_eq!(lt.len(), 4);
+        assert_eq!(lt.wal_segment(), 3);
+        assert_eq!(LookupTable::wal_segments(&FileSystem, dir.path())?.len(), 3);
+
+        // Covered segments are only removed with the next write
+        lt.flush()?;
+        assert_eq!(LookupTable::wal_segments(&FileSystem, dir.path())?.len(), 3);
+        lt.add(b"legacy", el)?;
+        assert_eq!(LookupTable::wal_segments(&FileSystem, dir.path())?, vec![(3, lt.wal_path.clone())]);
+
+        // A WAL from before segments is replayed as segment 0
+        let wal_path = lt.wal_path.clone();
+        drop(lt);
+        fs::rename(&wal_path, dir.path().join(LEGACY_WAL_FILE_NAME))?;
+        let mut lt = open()?;
+        assert_eq!(lt.get(b"legacy")?, Some(el));
+        lt.flush()?;
+        lt.add(&[0; 60], el)?;
+        lt.add(&[1; 60], el)?;
+        assert_eq!(lt.wal_segment(), 1);
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint() -> Result<()> {
+        let dir = TempDir::new()?;
+        let el = EntryLocation { block: 0, pointer: 4, size: 0 };
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        lt.add(b"1", el)?;
+        lt.remove(b"1")?;
+        lt.flush()?;
+        lt.flush()?;
+        let (_, wal_path) = lt.paths();
+        let records = dump(&wal_path)?;
+        let summary: Vec<_> = records.iter().map(|record| (record.sequence, record.op_type)).collect();
+        assert_eq!(summary, [(Some(1), WalOpType::Insert), (Some(2), WalOpType::Remove), (Some(2), WalOpType::Checkpoint)]);
+
+        // The covered records are not replayed, the first write after them truncates the WAL
+        let mut lt = reopen(lt)?;
+        assert_eq!((lt.recovery().wal_records_replayed, lt.applied_lsn), (0, 2));
+        assert_eq!(lt.get(b"1")?, None);
+        lt.add(b"2", el)?;
+        let records = dump(&wal_path)?;
+        assert_eq!(records.iter().map(|record| record.sequence).collect::<Vec<_>>(), [Some(3)]);
+
+        // A crash after the map was written but before the checkpoint made it to the WAL
+        lt.flush()?;
+        let checkpoint = dump(&wal_path)?.pop().ok_or("no checkpoint record")?;
+        let bytes = fs::read(&wal_path)?;
+        fs::write(&wal_path, &bytes[..checkpoint.offset as usize])?;
+        let lt = reopen(lt)?;
+        assert_eq!((lt.recovery().wal_records_replayed, lt.get(b"2")?), (0, Some(el)));
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_rewrite() -> Result<()> {
+        let dir = TempDir::new()?;
+        let options = DbOptions::new().wal_rewrite_threshold(400);
+        let open = || LookupTable::open(dir.path(), &options);
+        drop(LookupTable::new_reset(&dir, true)?);
+        let mut lt = open()?;
+        lt.add(b"removed", EntryLocation { block: 0, pointer: 4, size: 0 })?;
+        lt.add_expiring(b"expiring", EntryLocation { block: 0, pointer: 8, size: 0 }, u64::MAX)?;
+        lt.write_batch(vec![
+            WalOperation::Remove{key: b"removed".to_vec()},
+            WalOperation::Merge{key: b"expiring".to_vec(), location: EntryLocation { block: 1, pointer: 4, size: 0 }},
+        ])?;
+        for i in 0..100u64 {
+            lt.add(b"counter", EntryLocation { block: i, pointer: 4, size: 0 })?;
+        }
+        // Three records remain after every rewrite, so the WAL stays far below 100 records
+        assert!(lt.wal_size < 500);
+        assert!(lt.wal.len() < 40);
+        assert_eq!(LookupTable::wal_segments(&FileSystem, dir.path())?.len(), 1);
+
+        drop(lt);
+        let lt = open()?;
+        assert_eq!(lt.get(b"counter")?, Some(EntryLocation { block: 99, pointer: 4, size: 0 }));
+        assert_eq!(lt.get(b"expiring")?, Some(EntryLocation { block: 1, pointer: 4, size: 0 }));
+        assert_eq!(lt.expiry(b"expiring")?, Some(u64::MAX));
+        assert_eq!(lt.get(b"removed")?, None);
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_compaction() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        let old = EntryLocation { block: 5, pointer: 4, size: 0 };
+        let new = EntryLocation { block: 0, pointer: 4, size: 0 };
+        lt.add(b"1", old)?;
+        lt.flush()?;
+        let lsn = lt.applied_lsn;
+        let compacted_map_path = LookupTable::compacted_map_path(&lt.map_path);
+        let compacted_data_path = dir.path().join(COMPACTED_DATA_FILE_NAME);
+        let compacted_map = [(b"1".as_slice(), new, 0)];
+
+        // Crash before the data file was swapped in, the old map stays
+        LookupTable::write_map_file(&FileSystem, &compacted_map_path, compacted_map, lsn, DEFAULT_BLOCK_SIZE)?;
+        fs::write(&compacted_data_path, b"")?;
+        let lt = reopen(lt)?;
+        assert_eq!(lt.get(b"1")?, Some(old));
+        assert!(!compacted_map_path.exists());
+        // Removing the compacted data file is left to the value log
+        fs::remove_file(&compacted_data_path)?;
+
+        // Crash after the data file was swapped in, the compacted map takes over
+        LookupTable::write_map_file(&FileSystem, &compacted_map_path, compacted_map, lsn, DEFAULT_BLOCK_SIZE)?;
+        let lt = reopen(lt)?;
+        assert_eq!(lt.get(b"1")?, Some(new));
+        assert!(!compacted_map_path.exists());
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_foreign_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let lt = LookupTable::new_reset(&dir, true)?;
+        let (map_path, wal_path) = lt.paths();
+        cleanup(lt)?;
+
+        fs::write(&map_path, b"certainly not a lookup table")?;
+        assert!(matches!(LookupTable::new(&dir), Err(Error::InvalidFormat(_))));
+
+        // A fresh map with an incompatible WAL next to it
+        fs::remove_file(&map_path)?;
+        let mut header = FileHeader::new(WAL_MAGIC, DEFAULT_BLOCK_SIZE).encode();
+        header[4..6].copy_from_slice(&u16::MAX.to_le_bytes());
+        fs::write(&wal_path, header)?;
+        assert!(matches!(LookupTable::new(&dir), Err(Error::UnsupportedVersion(u16::MAX))));
+
+        // A header torn while the file was created is rewritten
+        fs::write(&wal_path, &FileHeader::new(WAL_MAGIC, DEFAULT_BLOCK_SIZE).encode()[..5])?;
+        let lt = LookupTable::new(&dir)?;
+        assert_eq!(fs::read(&wal_path)?, FileHeader::new(WAL_MAGIC, DEFAULT_BLOCK_SIZE).encode());
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_reads_version_1_map() -> Result<()> {
+        let dir = TempDir::new()?;
+        let lt = LookupTable::new_reset(&dir, true)?;
+        let (map_path, _) = lt.paths();
+        cleanup(lt)?;
+
+        // Version 1 records have no expiry time
+        let mut map = FileHeader::new(MAP_MAGIC, DEFAULT_BLOCK_SIZE).encode();
+        map[4..6].copy_from_slice(&1u16.to_le_bytes());
+        let mut map = map.to_vec();
+        LookupTable::encode_key(&mut map, b"old");
+        LookupTable::encode_location(&mut map, &EntryLocation { block: 3, pointer: 7, size: 0 });
+        fs::write(&map_path, map)?;
+        let mut lt = LookupTable::new(&dir)?;
+        assert_eq!(lt.get(b"old")?, Some(EntryLocation { block: 3, pointer: 7, size: 0 }));
+
+        lt.add_expiring(b"expired", EntryLocation { block: 0, pointer: 0, size: 0 }, 1)?;
+        assert_eq!(lt.get(b"expired")?, None);
+        lt.flush()?;
+        let lt = reopen(lt)?;
+        assert_eq!(lt.get(b"old")?, Some(EntryLocation { block: 3, pointer: 7, size: 0 }));
+        assert!(!matches!(lt.entry(b"expired")?, Some(IndexEntry::Put(..))));
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_reads_fixed_wal_records() -> Result<()> {
+        let dir = TempDir::new()?;
+        let lt = LookupTable::new_reset(&dir, true)?;
+        let (_, wal_path) = lt.paths();
+        cleanup(lt)?;
+
+        let el = EntryLocation { block: 3, pointer: 7, size: 0 };
+        let mut wal = FileHeader::new(WAL_MAGIC, DEFAULT_BLOCK_SIZE).encode();
+        wal[4..6].copy_from_slice(&10u16.to_le_bytes());
+        let mut wal = wal.to_vec();
+        let operations = [
+            WalOperation::Insert{key: b"a".to_vec(), location: el},
+            WalOperation::Batch(vec![
+                WalOperation::InsertExpiring{key: b"b".to_vec(), location: el, expires_at: u64::MAX},
+                WalOperation::Remove{key: b"a".to_vec()},
+            ]),
+        ];
+        for (lsn, operation) in (1..).zip(&operations) {
+            let mut body = vec![SEQUENCED_RECORD];
+            body.extend_from_slice(&(lsn as u64).to_le_bytes());
+            LookupTable::encode_wal_operation(&mut body, operation, WalFormat::Fixed);
+            WalFormat::Fixed.frame_record(&mut wal, &body);
+        }
+        fs::write(&wal_path, &wal)?;
+
+        // The old segment is sealed and new records go to a compact one
+        let mut lt = LookupTable::new(&dir)?;
+        assert_eq!((lt.applied_lsn, lt.get(b"a")?, lt.get(b"b")?), (2, None, Some(el)));
+        assert_eq!(lt.wal_segment(), 2);
+        lt.add(b"c", el)?;
+        assert_eq!(fs::read(&wal_path)?, wal);
+        let records = dump(&dir)?;
+        let summary: Vec<_> = records.iter().map(|record| (record.sequence, record.op_type, record.keys.len())).collect();
+        assert_eq!(summary, [(Some(1), WalOpType::Insert, 1), (Some(2), WalOpType::Batch, 2), (Some(3), WalOpType::Insert, 1)]);
+        // 1 byte each for the length, the sequence number, the key length, the block and the pointer
+        assert_eq!(records[2].length, 7);
+
+        let lt = reopen(lt)?;
+        assert_eq!((lt.applied_lsn, lt.wal_segment()), (3, 2));
+        assert_eq!(lt.get(b"c")?, Some(el));
+        assert_eq!(lt.expiry(b"b")?, Some(u64::MAX));
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_reads_version_3_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let lt = LookupTable::new_reset(&dir, true)?;
+        let (map_path, wal_path) = lt.paths();
+        cleanup(lt)?;
+
+        // Neither the map nor the WAL records of version 3 have sequence numbers
+        let el = EntryLocation { block: 3, pointer: 7, size: 0 };
+        let version_3 = |magic| {
+            let mut header = FileHeader::new(magic, DEFAULT_BLOCK_SIZE).encode();
+            header[4..6].copy_from_slice(&3u16.to_le_bytes());
+            header.to_vec()
+        };
+        let mut map = version_3(MAP_MAGIC);
+        LookupTable::encode_key(&mut map, b"flushed");
+        LookupTable::encode_location(&mut map, &el);
+        map.extend_from_slice(&0u64.to_le_bytes());
+        map.extend_from_slice(&crc32fast::hash(&map).to_le_bytes());
+        fs::write(&map_path, map)?;
+        let mut wal = version_3(WAL_MAGIC);
+        for key in [b"a", b"b"] {
+            let mut body = Vec::new();
+            LookupTable::encode_wal_operation(&mut body, &WalOperation::Insert{key: key.to_vec(), location: el}, WalFormat::Fixed);
+            WalFormat::Fixed.frame_record(&mut wal, &body);
+        }
+        fs::write(&wal_path, wal)?;
+
+        let mut lt = LookupTable::new(&dir)?;
+        assert_eq!(lt.applied_lsn, 2);
+        lt.add(b"c", el)?;
+        assert_eq!(lt.applied_lsn, 3);
+        let mut lt = reopen(lt)?;
+        assert_eq!(lt.applied_lsn, 3);
+        // Rewritten in full instead of getting a delta
+        lt.compact()?;
+        let lt = reopen(lt)?;
+        assert_eq!(FileHeader::decode(&fs::read(&map_path)?, MAP_MAGIC)?.version(), crate::db::header::FORMAT_VERSION);
+        assert_eq!(lt.applied_lsn, 3);
+        for key in [b"flushed".as_slice(), b"a", b"b", b"c"] {
+            assert_eq!(lt.get(key)?, Some(el));
+        }
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefixed_keys() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("map.db");
+        let mut map = KeyMap::default();
+        let mut expiries = KeyMap::default();
+        for i in 0..1000u64 {
+            let key = format!("https://example.com/articles/2024/{i:04}").into_bytes();
+            expiries.insert(key.clone(), i);
+            map.insert(key, EntryLocation { block: i, pointer: 4, size: 0 });
+        }
+        // Shares nothing with the key before it, or more than fits the prefix length
+        map.insert(Vec::new(), EntryLocation { block: 1, pointer: 4, size: 0 });
+        map.insert(vec![b'x'; 70_000], EntryLocation { block: 2, pointer: 4, size: 0 });
+        map.insert(vec![b'x'; 70_001], EntryLocation { block: 3, pointer: 4, size: 0 });
+        let file = LookupTable::write_map_file(&FileSystem, &path, records(&map, &expiries, &KeyMap::default()), 7, DEFAULT_BLOCK_SIZE)?;
+        let key_bytes: usize = map.keys().map(|key| key.len()).sum();
+        assert!(fs::metadata(&path)?.len() < key_bytes as u64);
+
+        let (read, read_expiries, _, lsn) = LookupTable::get_map_from_file(file.as_ref(), &path, &KeyHashState::default(), false)?;
+        assert_eq!(read, map);
+        expiries.remove(b"https://example.com/articles/2024/0000".as_slice());
+        assert_eq!(read_expiries, expiries);
+        assert_eq!(lsn, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tombstones() -> Result<()> {
+        let dir = TempDir::new()?;
+        let clock = ManualClock::new(1_000);
+        let options = DbOptions::new().clock(clock.clone()).tombstone_retention(Duration::from_secs(60));
+        let open = || LookupTable::open(dir.path(), &options);
+        let el = EntryLocation { block: 0, pointer: 4, size: 0 };
+        drop(LookupTable::new_reset(&dir, true)?);
+        let mut lt = open()?;
+        lt.add(b"a", el)?;
+        lt.add(b"b", el)?;
+        lt.flush()?;
+        lt.remove(b"a")?;
+        let tombstone = Some(IndexEntry::Remove(Tombstone { lsn: 3, deleted_at: 1_000 }));
+        assert_eq!(lt.entry(b"a")?, tombstone);
+        lt.flush()?;
+
+        // Removing it again or removing a key that never existed leaves the tombstone as it was
+        lt.remove(b"a")?;
+        lt.remove(b"never")?;
+        assert_eq!((lt.tombstone_count(), lt.entry(b"a")?, lt.entry(b"never")?), (1, tombstone, None));
+
+        // Kept by deltas and by a full rewrite of the map
+        drop(lt);
+        let mut lt = open()?;
+        assert_eq!(lt.entry(b"a")?, tombstone);
+        lt.compact()?;
+        assert!(HashIndex::map_delta_files(&FileSystem, dir.path())?.is_empty());
+        drop(lt);
+        let mut lt = open()?;
+        assert_eq!((lt.len(), lt.entry(b"a")?), (1, tombstone));
+
+        // Writing the key again clears its tombstone, an old one is dropped by the next flush
+        lt.remove(b"b")?;
+        lt.add(b"a", el)?;
+        assert_eq!(lt.entry(b"a")?, Some(IndexEntry::Put(el, 0)));
+        clock.advance(Duration::from_secs(61));
         lt.flush()?;
-        assert_eq!(lt.get(1)?, None);
-        assert_eq!(lt.get(2)?, Some(EntryLocation { block: 0, pointer: 1 }));
-        assert_eq!(lt.map.len(), 1);
-        let lt2 = LookupTable::new("test")?;
-        println!("{:?}", lt2.map);
-        assert_eq!(lt2.map.get(&1), None);
-        assert_eq!(lt2.get(2)?, Some(EntryLocation { block: 0, pointer: 1 }));
-        assert_eq!(lt2.map.len(), 1);
-
-        LookupTable::cleanup(lt.map_path, lt.wal_path)?;
-        LookupTable::cleanup(lt2.map_path, lt2.wal_path)?;
+        drop(lt);
+        let lt = open()?;
+        assert_eq!((lt.len(), lt.tombstone_count()), (1, 0));
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_map() -> Result<()> {
+        let dir = TempDir::new()?;
+        let el = EntryLocation { block: 0, pointer: 4, size: 0 };
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        lt.add(b"flushed", el)?;
+        lt.add(b"second", el)?;
+        lt.compact()?;
+        lt.add(b"logged", el)?;
+        let (map_path, _) = lt.paths();
+        drop(lt);
+
+        let mut bytes = fs::read(&map_path)?;
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&map_path, &bytes)?;
+        assert!(matches!(LookupTable::new(&dir), Err(Error::Map(MapError::ChecksumMismatch{..}))));
+
+        // Repair keeps the records that still parse and replays the WAL
+        let repair = DbOptions::new().repair(true);
+        let lt = LookupTable::open(dir.path(), &repair)?;
+        assert!(lt.recovery().map_rebuilt);
+        assert_eq!((lt.get(b"flushed")?, lt.get(b"logged")?), (Some(el), Some(el)));
+        let lt = reopen(lt)?;
+        assert!(lt.recovery().is_clean());
+        assert_eq!(lt.len(), 3);
+        drop(lt);
+
+        // Torn in the middle of a record, the records before it are salvaged and the part of the
+        // WAL that is still around is replayed on top
+        let bytes = fs::read(&map_path)?;
+        fs::write(&map_path, &bytes[..HEADER_SIZE + 10])?;
+        assert!(matches!(LookupTable::new(&dir), Err(Error::Map(MapError::ChecksumMismatch{..}))));
+        let lt = LookupTable::open(dir.path(), &repair)?;
+        assert_eq!(lt.len(), 1);
+        assert_eq!(lt.get(b"logged")?, Some(el));
+        cleanup(lt)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        let lt = LookupTable::new_reset(&dir, true)?;
+        let read_only = DbOptions::new().read_only(true);
+        assert!(matches!(LookupTable::new(&dir), Err(Error::DatabaseLocked(_))));
+        assert!(matches!(LookupTable::open(dir.path(), &read_only), Err(Error::DatabaseLocked(_))));
+        drop(lt);
+
+        // Readers share the lock but keep writers out
+        let reader = LookupTable::open(dir.path(), &read_only)?;
+        let other_reader = LookupTable::open(dir.path(), &read_only)?;
+        assert!(matches!(LookupTable::new(&dir), Err(Error::DatabaseLocked(_))));
+        drop(reader);
+        drop(other_reader);
+        cleanup(LookupTable::new(&dir)?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sorted_map_search() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("map.db");
+        let mut map = KeyMap::default();
+        let mut expiries = KeyMap::default();
+        let mut tombstones = KeyMap::default();
+        for i in 0..1000u32 {
+            let key = format!("key{i:04}").into_bytes();
+            match i % 10 {
+                0 => { tombstones.insert(key, Tombstone { lsn: i as u64, deleted_at: 1 }); }
+                1 => {
+                    expiries.insert(key.clone(), 50);
+                    map.insert(key, EntryLocation { block: i as u64, pointer: 1, size: 0 });
+                }
+                _ => { map.insert(key, EntryLocation { block: i as u64, pointer: 1, size: 0 }); }
+            }
+        }
+        let file = LookupTable::write_map_file(&FileSystem, &path, records(&map, &expiries, &tombstones), 9, DEFAULT_BLOCK_SIZE)?;
+        let sorted = SortedMap::open(Arc::clone(&file), &path)?.unwrap();
+        assert_eq!((sorted.lsn, sorted.keys, sorted.restarts.len()), (9, 900, 1000usize.div_ceil(MAP_RESTART_INTERVAL)));
+        for i in 0..1000u32 {
+            let key = format!("key{i:04}").into_bytes();
+            let expected = match tombstones.get(&key) {
+                Some(tombstone) => Some(IndexEntry::Remove(*tombstone)),
+                None => map.get(&key).map(|location| IndexEntry::Put(*location, expiries.get(&key).copied().unwrap_or(0))),
+            };
+            assert_eq!(sorted.get(&key)?, expected, "key {i}");
+        }
+        assert_eq!(sorted.get(b"key0001")?, Some(IndexEntry::Put(EntryLocation { block: 1, pointer: 1, size: 0 }, 50)));
+        for absent in [&b"a"[..], b"key0000x", b"key0999x", b"zzz"] {
+            assert_eq!(sorted.get(absent)?, None);
+        }
+        // The footer is skipped when the map is loaded
+        let (read, _, read_tombstones, lsn) = LookupTable::get_map_from_file(file.as_ref(), &path, &KeyHashState::default(), false)?;
+        assert_eq!((read, read_tombstones.len(), lsn), (map, 100, 9));
+
+        let empty = LookupTable::write_map_file(&FileSystem, &path, [], 3, DEFAULT_BLOCK_SIZE)?;
+        assert_eq!(SortedMap::open(empty, &path)?.unwrap().get(b"key")?, None);
+
+        let mut bytes = fs::read(&path)?;
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, bytes)?;
+        let file = FileSystem.open(&path, OpenMode::Read)?;
+        assert!(matches!(SortedMap::open(file, &path), Err(Error::Map(MapError::ChecksumMismatch{..}))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_read_in_chunks() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut lt = LookupTable::new_reset(&dir, true)?;
+        for i in 0..300u32 {
+            let el = EntryLocation { block: i as u64, pointer: 4, size: 0 };
+            match i % 3 {
+                0 => lt.add(&i.to_be_bytes(), el)?,
+                1 => lt.add_expiring(&i.to_be_bytes(), el, u64::MAX / 2)?,
+                _ => lt.remove(&(i - 2).to_be_bytes())?,
+            }
+        }
+        // Into map.db rather than a delta
+        lt.compact()?;
+        let hasher = KeyHashState::default();
+        let map_file = FileSystem.open(&lt.map_path, OpenMode::Read)?;
+        let whole = LookupTable::get_map_from_file(map_file.as_ref(), &lt.map_path, &hasher, false)?;
+        assert_eq!((whole.0.len(), whole.1.len(), whole.2.len()), (100, 100, 100));
+        for chunk in [1, 7, 100] {
+            let chunked = LookupTable::read_map(map_file.as_ref(), &lt.map_path, &hasher, false, chunk)?;
+            assert_eq!((&chunked.0, &chunked.1, &chunked.2, chunked.3), (&whole.0, &whole.1, &whole.2, whole.3));
+        }
+
+        let mut bytes = fs::read(&lt.map_path)?;
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        fs::write(&lt.map_path, &bytes)?;
+        for chunk in [7, MAP_READ_CHUNK] {
+            let read = LookupTable::read_map(map_file.as_ref(), &lt.map_path, &hasher, false, chunk);
+            assert!(matches!(read, Err(Error::Map(MapError::ChecksumMismatch{..}))));
+        }
+        cleanup(lt)
+    }
+
+    #[test]
+    fn test_parallel_wal_decode() {
+        let el = EntryLocation { block: 1, pointer: 4, size: 0 };
+        let mut buffer = vec![0; HEADER_SIZE];
+        for i in 0..1000u64 {
+            let key = i.to_be_bytes().to_vec();
+            let operation = match i % 3 {
+                0 => WalOperation::Insert{key, location: el},
+                1 => WalOperation::Remove{key},
+                _ => WalOperation::Batch(vec![WalOperation::Insert{key: key.clone(), location: el}, WalOperation::Remove{key}]),
+            };
+            LookupTable::encode_wal_record(&mut buffer, i + 1, &operation);
+        }
+        let decode = |buffer: &[u8], threads| {
+            let (records, end) = LookupTable::decode_wal_records(buffer, HEADER_SIZE, WalFormat::Compact, threads);
+            (records.iter().map(|record| record.lsn).collect::<Vec<_>>(), end)
+        };
+        let serial = decode(&buffer, 1);
+        assert_eq!(serial, ((1..=1000).map(Some).collect(), buffer.len()));
+        assert_eq!(decode(&buffer, 4), serial);
+
+        // Every thread count stops at a corrupt record, whatever follows it
+        let corrupt = buffer.len() / 3;
+        buffer[corrupt] ^= 0xff;
+        buffer.truncate(buffer.len() - 2);
+        let serial = decode(&buffer, 1);
+        assert!(serial.0.len() < 400);
+        for threads in [2, 3, 7] {
+            assert_eq!(decode(&buffer, threads), serial);
+        }
+    }
+
+    #[test]
+    fn test_parallel_replay() -> Result<()> {
+        let dirs = [TempDir::new()?, TempDir::new()?];
+        let mut tables = Vec::new();
+        for dir in &dirs {
+            let mut lt = LookupTable::new_reset(dir, true)?;
+            for i in 0..100u32 {
+                lt.add_expiring(&i.to_be_bytes(), EntryLocation { block: 0, pointer: i as u64, size: 0 }, u64::MAX / 2)?;
+            }
+            lt.remove(&0u32.to_be_bytes())?;
+            lt.flush()?;
+            tables.push(lt);
+        }
+        let mut wal = Vec::new();
+        for i in 0..2000u64 {
+            let key = ((i * 7 % 300) as u32).to_be_bytes().to_vec();
+            let location = EntryLocation { block: 1, pointer: i, size: 0 };
+            let operation = match i % 5 {
+                0 => WalOperation::Insert{key, location},
+                1 => WalOperation::Remove{key},
+                2 => WalOperation::InsertExpiring{key, location, expires_at: i},
+                3 => WalOperation::Merge{key, location},
+                _ => WalOperation::Batch(vec![WalOperation::Remove{key: key.clone()}, WalOperation::Increment{key, location}]),
+            };
+            wal.push((i + 1, operation));
+        }
+        let [serial, parallel] = tables.as_mut_slice() else { unreachable!() };
+        for (lsn, operation) in &wal {
+            serial.apply(*lsn, operation)?;
+        }
+        parallel.apply_parallel(&wal, 4)?;
+
+        assert_eq!((serial.len(), serial.tombstone_count()), (parallel.len(), parallel.tombstone_count()));
+        // Tombstones are stamped with the time they were replayed at
+        let entry = |lt: &LookupTable, key: &[u8]| -> Result<_> {
+            Ok(match lt.entry(key)? {
+                Some(IndexEntry::Remove(tombstone)) => Some(Err(tombstone.lsn)),
+                entry => entry.map(Ok),
+            })
+        };
+        for i in 0..300u32 {
+            assert_eq!(entry(serial, &i.to_be_bytes())?, entry(parallel, &i.to_be_bytes())?, "key {i}");
+        }
         Ok(())
     }
 }
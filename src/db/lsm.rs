@@ -0,0 +1,1012 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::{Bound, Range, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use crate::db::backend::{OpenMode, StorageBackend, StorageFile};
+use crate::db::bloom::BloomFilter;
+use crate::db::engine::{EngineIter, StorageEngine};
+use crate::db::hash::{KeyHashState, KeyMap};
+use crate::db::hash_index::HashIndex;
+use crate::db::header::{FileHeader, HEADER_SIZE, MAP_MAGIC};
+use crate::db::lookup::{
+    EntryLocation, IndexEntry, KeyIndex, LookupTable, MapEncoder, MapError, SortedMap, Tombstone,
+    BLOOM_FILE_NAME, EXACT_RANGE_COUNT, KEY_SAMPLES, MAP_FILE_NAME, TOMBSTONE_BLOCK,
+};
+use crate::db::options::{DbOptions, LogLevel};
+use crate::db::recovery::RecoveryReport;
+use crate::db::trace::{event, warning};
+use crate::error::{Error, Result};
+
+// Since format version 17 a flush with Engine::Lsm writes the entries that changed since the last
+// one to a sorted run next to map.db, run-<number>.db in the format of map.db. Keys deleted outright
+// are written as tombstones of Tombstone::DELETED, which hide the keys of the files before.
+const RUN_PREFIX: &str = "run-";
+const DELETED: IndexEntry = IndexEntry::Remove(Tombstone::DELETED);
+
+// Key index that searches the files it writes on disk and only holds the entries written since
+// the last flush in memory, in a memtable. A flush writes those to a new sorted run. Lookups search
+// the memtable, then the runs from the newest, then map.db. Runs are merged with each other in a
+// background thread, and with map.db once they take more space than it.
+pub(crate) struct LsmIndex {
+    backend: Arc<dyn StorageBackend>,
+    folder: PathBuf,
+    map_path: PathBuf,
+    // None while map.db holds no records
+    base: Option<Arc<SortedFile>>,
+    map_size: u64,
+    // Numbers of the runs with their files, oldest first
+    runs: Vec<(u64, Arc<SortedFile>)>,
+    max_runs: usize,
+    merge: Option<RunMerge>,
+    // Entries put or deleted since the last flush, and the bytes their keys take
+    memtable: BTreeMap<Vec<u8>, IndexEntry>,
+    memtable_bytes: usize,
+    // Keys with a location, counted by their newest entry
+    keys: usize,
+    // Expiry times of the keys written with a TTL and deletion times of the tombstones, over every
+    // file and the memtable, and the bytes their keys take
+    expiries: KeyMap<u64>,
+    deletions: KeyMap<u64>,
+    timed_key_bytes: usize,
+    bloom_rate: Option<f64>,
+    // Block size of data.db, recorded in the header of every file the index writes
+    block_size: usize,
+    log_level: LogLevel,
+    // Sequence number of the last record committed, and of the last one the files cover
+    lsn: u64,
+    flushed_lsn: u64,
+}
+
+// map.db or a run, with a filter of its keys when DbOptions::bloom_filter sets a rate
+struct SortedFile {
+    map: SortedMap,
+    size: u64,
+    bloom: Option<BloomFilter>,
+}
+
+// Merge of runs running in the background, see LsmIndex::start_merge
+struct RunMerge {
+    // Numbers of the merged runs, and whether map.db is merged with them
+    runs: Vec<u64>,
+    into_map: bool,
+    // Written by the merge, renamed into place once it is swapped in
+    output: PathBuf,
+    handle: JoinHandle<Result<SortedFile>>,
+}
+
+// Entries of a run with the sequence number of the last record it covers
+type Run = (Vec<(Vec<u8>, IndexEntry)>, u64);
+type Bounds = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+type Layer<'a> = Box<dyn DoubleEndedIterator<Item = Result<(Vec<u8>, IndexEntry)>> + 'a>;
+
+const UNBOUNDED: Bounds = (Bound::Unbounded, Bound::Unbounded);
+
+impl LsmIndex {
+    // Opens map.db and the runs written after it. Entries that can't be searched on disk, those of a
+    // map.db written before format version 15 or of the deltas of Engine::Hash, are read into the
+    // memtable and merged into map.db right away.
+    pub fn open(folder: &Path, options: &DbOptions, recovery: &mut RecoveryReport) -> Result<Self> {
+        let backend = options.backend.as_ref();
+        let map_path = folder.join(MAP_FILE_NAME);
+        let mode = if options.read_only { OpenMode::Read } else { OpenMode::Create };
+        let hasher = KeyHashState::new(options.hasher);
+        let map_file = backend.open(&map_path, mode).map_err(MapError::io(&map_path, "open"))?;
+        if FileHeader::init_or_validate(map_file.as_ref(), MAP_MAGIC, options.block_size, options.read_only)? {
+            recovery.repaired_files.push(map_path.clone());
+        }
+        let rate = options.bloom_false_positive_rate;
+        let repairs = options.repair && !options.read_only;
+        let mut map_rebuilt = false;
+        let base = match SortedFile::open(Arc::clone(&map_file), &map_path, rate) {
+            Err(Error::Map(error @ (MapError::ChecksumMismatch{..} | MapError::CorruptRecord{..}))) if repairs => {
+                warning!(options.log_level, path = %map_path.display(), error = %error; "rebuilding corrupt map from the readable entries and the WAL");
+                map_rebuilt = true;
+                None
+            }
+            result => result?.map(Arc::new),
+        };
+        let mut memtable = BTreeMap::new();
+        let mut map_lsn = match &base {
+            Some(base) => base.map.lsn,
+            None => {
+                let (map, expiries, tombstones, lsn) = LookupTable::get_map_from_file(map_file.as_ref(), &map_path, &hasher, map_rebuilt)?;
+                for (key, location) in map {
+                    let expires_at = expiries.get(&key).copied().unwrap_or(0);
+                    memtable.insert(key, IndexEntry::Put(location, expires_at));
+                }
+                memtable.extend(tombstones.into_iter().map(|(key, tombstone)| (key, IndexEntry::Remove(tombstone))));
+                lsn
+            }
+        };
+        let map_size = backend.file_len(&map_path)?;
+        let base_lsn = map_lsn;
+        let mut runs = Vec::new();
+        for (number, path) in LsmIndex::run_files(backend, folder)? {
+            let run = match LsmIndex::open_run(backend, &path, rate) {
+                // Left behind by a crash before a merge into map.db removed it, map.db already holds it
+                Ok(run) if run.map.lsn <= base_lsn && !map_rebuilt => {
+                    if !options.read_only {
+                        backend.remove_file(&path)?;
+                        recovery.repaired_files.push(path);
+                    }
+                    continue;
+                }
+                Err(Error::Map(error @ (MapError::ChecksumMismatch{..} | MapError::CorruptRecord{..}))) if repairs => {
+                    // The runs after it are dropped with it by the full rewrite, the WAL is replayed from the start
+                    warning!(options.log_level, path = %path.display(), error = %error; "rebuilding map without a corrupt run and the ones after it");
+                    map_rebuilt = true;
+                    map_lsn = 0;
+                    break;
+                }
+                run => run?,
+            };
+            // A salvaged map.db may have lost records the runs don't repeat
+            if !map_rebuilt {
+                map_lsn = run.map.lsn;
+            }
+            match memtable.is_empty() {
+                true => runs.push((number, Arc::new(run))),
+                // Newer than the entries read into the memtable, so read in on top of them
+                false => memtable.extend(LsmIndex::read_entries(&run.map)?),
+            }
+        }
+        // Engine::Lsm never leaves deltas next to runs, they apply on top of map.db like a memtable
+        for (_, path) in HashIndex::map_delta_files(backend, folder)? {
+            let entries = match HashIndex::read_map_delta(backend, &path) {
+                Ok((_, lsn)) if lsn <= base_lsn && !map_rebuilt => {
+                    if !options.read_only {
+                        backend.remove_file(&path)?;
+                        recovery.repaired_files.push(path);
+                    }
+                    continue;
+                }
+                Err(Error::Map(error @ (MapError::ChecksumMismatch{..} | MapError::CorruptRecord{..}))) if repairs => {
+                    warning!(options.log_level, path = %path.display(), error = %error; "rebuilding map without a corrupt delta and the ones after it");
+                    map_rebuilt = true;
+                    map_lsn = 0;
+                    break;
+                }
+                delta => {
+                    let (entries, lsn) = delta?;
+                    if !map_rebuilt {
+                        map_lsn = lsn;
+                    }
+                    entries
+                }
+            };
+            memtable.extend(entries);
+        }
+        if map_rebuilt {
+            recovery.map_rebuilt = true;
+            recovery.repaired_files.push(map_path.clone());
+        }
+        // The filter Engine::Hash saves would miss the keys flushed from now on
+        let bloom_path = folder.join(BLOOM_FILE_NAME);
+        if !options.read_only && backend.exists(&bloom_path) {
+            backend.remove_file(&bloom_path)?;
+        }
+        let memtable_bytes = memtable.keys().map(Vec::len).sum();
+        let mut index = LsmIndex {
+            backend: Arc::clone(&options.backend), folder: folder.to_path_buf(), map_path, base, map_size, runs,
+            max_runs: options.max_map_deltas, merge: None, memtable, memtable_bytes, keys: 0,
+            expiries: KeyMap::with_hasher(hasher.clone()), deletions: KeyMap::with_hasher(hasher), timed_key_bytes: 0,
+            bloom_rate: rate, block_size: options.block_size, log_level: options.log_level, lsn: map_lsn, flushed_lsn: map_lsn,
+        };
+        index.count()?;
+        // A rebuilt map is written in full once the WAL is replayed
+        if !index.memtable.is_empty() && !options.read_only && !map_rebuilt {
+            index.compact()?;
+        }
+        Ok(index)
+    }
+
+    // Utility function to delete the runs in folder, map.db is left to the table
+    pub fn cleanup(backend: &dyn StorageBackend, folder: &Path) -> Result<()> {
+        for (_, path) in LsmIndex::run_files(backend, folder)? {
+            backend.remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    // Counts the keys and collects the expiry and deletion times over every file and the memtable
+    fn count(&mut self) -> Result<()> {
+        let mut keys = 0;
+        let mut expiries = KeyMap::with_hasher(self.expiries.hasher().clone());
+        let mut deletions = KeyMap::with_hasher(self.deletions.hasher().clone());
+        for entry in self.entries(&UNBOUNDED)? {
+            match entry? {
+                (key, IndexEntry::Put(_, expires_at)) => {
+                    keys += 1;
+                    if expires_at != 0 {
+                        expiries.insert(key, expires_at);
+                    }
+                }
+                (_, DELETED) => {}
+                (key, IndexEntry::Remove(tombstone)) => {
+                    deletions.insert(key, tombstone.deleted_at);
+                }
+            }
+        }
+        self.timed_key_bytes = expiries.keys().chain(deletions.keys()).map(Vec::len).sum();
+        self.keys = keys;
+        self.expiries = expiries;
+        self.deletions = deletions;
+        Ok(())
+    }
+
+    // Newest entry of key, tombstones included
+    fn entry(&self, key: &[u8]) -> Result<Option<IndexEntry>> {
+        if let Some(entry) = self.memtable.get(key) {
+            return Ok(Some(*entry));
+        }
+        for file in self.files_newest_first() {
+            if let Some(entry) = file.get(key)? {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    // Newest entry of every key within bounds in key order, tombstones included
+    fn entries(&self, bounds: &Bounds) -> Result<MergedEntries<'_>> {
+        if LookupTable::is_empty_range(bounds) {
+            return Ok(MergedEntries::new(Vec::new()));
+        }
+        let memtable = self.memtable.range::<Vec<u8>, _>(bounds.clone()).map(|(key, entry)| Ok((key.clone(), *entry)));
+        let mut layers: Vec<Layer<'_>> = vec![Box::new(memtable)];
+        for file in self.files_newest_first() {
+            layers.push(Box::new(FileRange::new(&file.map, bounds.clone())?));
+        }
+        Ok(MergedEntries::new(layers))
+    }
+
+    fn files_newest_first(&self) -> impl Iterator<Item = &SortedFile> {
+        self.runs.iter().rev().map(|(_, run)| run.as_ref()).chain(self.base.as_deref())
+    }
+
+    // Puts the entry into the memtable, keeping the counts over every file up to date
+    fn set(&mut self, key: &[u8], entry: IndexEntry) -> Result<()> {
+        if let Some(IndexEntry::Put(..)) = self.entry(key)? {
+            self.keys -= 1;
+        }
+        let (expiry, deletion) = match entry {
+            IndexEntry::Put(_, expires_at) => {
+                self.keys += 1;
+                ((expires_at != 0).then_some(expires_at), None)
+            }
+            DELETED => (None, None),
+            IndexEntry::Remove(tombstone) => (None, Some(tombstone.deleted_at)),
+        };
+        LsmIndex::set_time(&mut self.expiries, &mut self.timed_key_bytes, key, expiry);
+        LsmIndex::set_time(&mut self.deletions, &mut self.timed_key_bytes, key, deletion);
+        if self.memtable.insert(key.to_vec(), entry).is_none() {
+            self.memtable_bytes += key.len();
+        }
+        Ok(())
+    }
+
+    fn set_time(times: &mut KeyMap<u64>, key_bytes: &mut usize, key: &[u8], time: Option<u64>) {
+        let previous = match time {
+            Some(time) => times.insert(key.to_vec(), time),
+            None => times.remove(key),
+        };
+        match (previous, time) {
+            (None, Some(_)) => *key_bytes += key.len(),
+            (Some(_), None) => *key_bytes -= key.len(),
+            _ => {}
+        }
+    }
+
+    // Writes the entries to path in key order and syncs them, returning the file
+    fn write_file<K: AsRef<[u8]>>(
+        backend: &dyn StorageBackend,
+        path: &Path,
+        entries: impl Iterator<Item = Result<(K, IndexEntry)>>,
+        lsn: u64,
+        block_size: usize,
+    ) -> Result<Arc<dyn StorageFile>> {
+        let mut encoder = MapEncoder::new(lsn, block_size);
+        for entry in entries {
+            let (key, entry) = entry?;
+            let (location, expiry) = entry.fields();
+            encoder.push(key.as_ref(), &location, expiry);
+        }
+        let buffer = encoder.finish();
+        let file = backend.open(path, OpenMode::Truncate).map_err(MapError::io(path, "create"))?;
+        file.write_at(&buffer, 0).map_err(MapError::io(path, "write"))?;
+        file.sync().map_err(MapError::io(path, "sync"))?;
+        Ok(file)
+    }
+
+    // Writes the entries to <path>.tmp and atomically renames it over path, so a crash at any
+    // point leaves either the complete old or the complete new file behind
+    fn replace_file<K: AsRef<[u8]>>(&self, path: &Path, entries: impl Iterator<Item = Result<(K, IndexEntry)>>) -> Result<SortedFile> {
+        let tmp_path = path.with_extension("db.tmp");
+        let file = LsmIndex::write_file(self.backend.as_ref(), &tmp_path, entries, self.lsn, self.block_size)?;
+        self.backend.rename(&tmp_path, path).map_err(MapError::io(path, "rename"))?;
+        self.backend.sync_dir(&self.folder)?;
+        SortedFile::open(file, path, self.bloom_rate)?.ok_or_else(|| LsmIndex::missing_records(path))
+    }
+
+    // Takes map.db in place of the runs and the memtable once it holds all of their entries
+    fn replace_layers(&mut self, map: SortedFile) -> Result<()> {
+        self.map_size = map.size;
+        self.base = Some(Arc::new(map));
+        self.runs.clear();
+        self.memtable.clear();
+        self.memtable_bytes = 0;
+        LsmIndex::cleanup(self.backend.as_ref(), &self.folder)?;
+        for (_, path) in HashIndex::map_delta_files(self.backend.as_ref(), &self.folder)? {
+            self.backend.remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    // Merges the runs in a background thread once there are max_map_deltas of them, at least two,
+    // into the newest of them. Runs that take more space than map.db are merged into it instead,
+    // which leaves out the tombstones of keys deleted outright as there is nothing left to hide.
+    fn start_merge(&mut self) {
+        let runs_size: u64 = self.runs.iter().map(|(_, run)| run.size).sum();
+        let into_map = runs_size > self.map_size;
+        if self.merge.is_some() || self.runs.is_empty() || !(into_map || self.runs.len() >= self.max_runs.max(2)) {
+            return;
+        }
+        let runs: Vec<u64> = self.runs.iter().map(|(number, _)| *number).collect();
+        let newest = &self.runs[self.runs.len() - 1];
+        let lsn = newest.1.map.lsn;
+        let path = match into_map {
+            true => self.map_path.clone(),
+            false => LsmIndex::run_path(&self.folder, newest.0),
+        };
+        let output = path.with_extension("db.merge");
+        let mut files: Vec<Arc<SortedFile>> = self.runs.iter().rev().map(|(_, run)| Arc::clone(run)).collect();
+        files.extend(self.base.iter().filter(|_| into_map).cloned());
+        let backend = Arc::clone(&self.backend);
+        let merged_path = output.clone();
+        let (block_size, bloom_rate) = (self.block_size, self.bloom_rate);
+        event!(self.log_level, DEBUG, runs = runs.len(), into_map, "merging sorted runs");
+        let handle = std::thread::spawn(move || {
+            let layers = files.iter()
+                .map(|file| Ok(Box::new(FileRange::new(&file.map, UNBOUNDED)?) as Layer<'_>))
+                .collect::<Result<_>>()?;
+            let entries = MergedEntries::new(layers).filter(|entry| !into_map || !matches!(entry, Ok((_, DELETED))));
+            let file = LsmIndex::write_file(backend.as_ref(), &merged_path, entries, lsn, block_size)?;
+            SortedFile::open(file, &path, bloom_rate)?.ok_or_else(|| LsmIndex::missing_records(&merged_path))
+        });
+        self.merge = Some(RunMerge { runs, into_map, output, handle });
+    }
+
+    fn missing_records(path: &Path) -> Error {
+        Error::Map(MapError::CorruptRecord { path: path.to_path_buf(), offset: HEADER_SIZE })
+    }
+
+    // Runs are never empty files, they hold the restart offsets of their records at least
+    fn open_run(backend: &dyn StorageBackend, path: &Path, bloom_rate: Option<f64>) -> Result<SortedFile> {
+        let file = backend.open(path, OpenMode::Read).map_err(MapError::io(path, "open"))?;
+        SortedFile::open(file, path, bloom_rate)?.ok_or_else(|| LsmIndex::missing_records(path))
+    }
+
+    // Every entry of a run, for Engine::Hash to read it into memory
+    pub(crate) fn read_run(backend: &dyn StorageBackend, path: &Path) -> Result<Run> {
+        let run = LsmIndex::open_run(backend, path, None)?;
+        Ok((LsmIndex::read_entries(&run.map)?, run.map.lsn))
+    }
+
+    fn read_entries(map: &SortedMap) -> Result<Vec<(Vec<u8>, IndexEntry)>> {
+        FileRange::new(map, UNBOUNDED)?.collect()
+    }
+
+    fn run_path(folder: &Path, number: u64) -> PathBuf {
+        folder.join(format!("{RUN_PREFIX}{number:06}.db"))
+    }
+
+    // Runs in folder from the oldest
+    pub(crate) fn run_files(backend: &dyn StorageBackend, folder: &Path) -> Result<Vec<(u64, PathBuf)>> {
+        let mut runs = Vec::new();
+        if !backend.exists(folder) {
+            return Ok(runs);
+        }
+        for path in backend.list(folder)? {
+            let number = path.file_name().and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(RUN_PREFIX))
+                .and_then(|rest| rest.strip_suffix(".db"))
+                .and_then(|number| number.parse().ok());
+            if let Some(number) = number {
+                runs.push((number, path));
+            }
+        }
+        runs.sort();
+        Ok(runs)
+    }
+}
+
+impl StorageEngine for LsmIndex {
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.set(key, IndexEntry::decode(value)?)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.entry(key)?.filter(|entry| *entry != DELETED).map(|entry| entry.encode()))
+    }
+
+    // Hidden by a tombstone of Tombstone::DELETED until a merge into map.db drops both
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        match self.entry(key)? {
+            None | Some(DELETED) => Ok(()),
+            Some(_) => self.set(key, DELETED),
+        }
+    }
+
+    fn scan(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> EngineIter<'_> {
+        let bounds = (start.map(<[u8]>::to_vec), end.map(<[u8]>::to_vec));
+        match self.entries(&bounds) {
+            Ok(entries) => Box::new(entries.filter_map(|entry| match entry {
+                Ok((key, entry @ IndexEntry::Put(..))) => Some(Ok((key, entry.encode()))),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            })),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    // Writes the memtable to a new run. Even an empty one, so the files always cover the records
+    // up to flushed_lsn.
+    fn flush(&mut self) -> Result<()> {
+        self.finish_merge(false)?;
+        if !self.memtable.is_empty() || self.flushed_lsn < self.lsn {
+            let number = self.runs.last().map_or(1, |(number, _)| number + 1);
+            let path = LsmIndex::run_path(&self.folder, number);
+            let run = self.replace_file(&path, self.memtable.iter().map(|(key, entry)| Ok((key, *entry))))?;
+            self.runs.push((number, Arc::new(run)));
+            self.memtable.clear();
+            self.memtable_bytes = 0;
+        }
+        self.flushed_lsn = self.lsn;
+        self.start_merge();
+        event!(self.log_level, DEBUG, keys = self.keys, runs = self.runs.len(), "flushed LSM index");
+        Ok(())
+    }
+
+    // Merges the memtable and every run into map.db
+    fn compact(&mut self) -> Result<()> {
+        self.finish_merge(true)?;
+        let entries = self.entries(&UNBOUNDED)?.filter(|entry| !matches!(entry, Ok((_, DELETED))));
+        let map = self.replace_file(&self.map_path, entries)?;
+        self.replace_layers(map)?;
+        self.flushed_lsn = self.lsn;
+        Ok(())
+    }
+}
+
+impl KeyIndex for LsmIndex {
+    fn flushed_lsn(&self) -> u64 {
+        self.flushed_lsn
+    }
+
+    fn commit(&mut self, lsn: u64) -> Result<()> {
+        self.lsn = lsn;
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.keys
+    }
+
+    fn tombstone_count(&self) -> usize {
+        self.deletions.len()
+    }
+
+    fn expired(&self, now: u64) -> Vec<Vec<u8>> {
+        self.expiries.iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    fn expired_count(&self, now: u64) -> usize {
+        self.expiries.values().filter(|expires_at| **expires_at <= now).count()
+    }
+
+    fn expired_tombstones(&self, horizon: u64) -> Vec<Vec<u8>> {
+        self.deletions.iter()
+            .filter(|(_, deleted_at)| **deleted_at <= horizon)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    // Counted up to EXACT_RANGE_COUNT keys, beyond that estimated from the share of the blocks of
+    // every file within bounds, which counts keys in several files once for each
+    fn estimate_range_count(&self, bounds: &Bounds) -> usize {
+        let counted = match self.entries(bounds) {
+            Ok(entries) => entries.filter(|entry| matches!(entry, Ok((_, IndexEntry::Put(..))))).take(EXACT_RANGE_COUNT + 1).count(),
+            Err(_) => 0,
+        };
+        if counted <= EXACT_RANGE_COUNT {
+            return counted;
+        }
+        let mut estimate = self.memtable.range::<Vec<u8>, _>(bounds.clone())
+            .filter(|(_, entry)| matches!(entry, IndexEntry::Put(..)))
+            .count();
+        for file in self.files_newest_first() {
+            if let Ok(blocks) = file.map.blocks(bounds) {
+                estimate += file.map.keys * blocks.len() / file.map.block_count().max(1);
+            }
+        }
+        estimate.min(self.keys).max(counted)
+    }
+
+    // Value sizes of the keys within bounds if there are at most EXACT_RANGE_COUNT of them. Beyond
+    // that those of the first records of at most KEY_SAMPLES blocks of every file are added, and
+    // their average is scaled up to the estimated number of keys.
+    fn approximate_size(&self, bounds: &Bounds) -> Result<u64> {
+        let mut sizes = Vec::new();
+        for entry in self.entries(bounds)? {
+            if let (_, IndexEntry::Put(location, _)) = entry? {
+                sizes.push(location.size);
+                if sizes.len() > EXACT_RANGE_COUNT {
+                    break;
+                }
+            }
+        }
+        if sizes.len() <= EXACT_RANGE_COUNT {
+            return Ok(sizes.iter().map(|size| *size as u64).sum());
+        }
+        for file in self.files_newest_first() {
+            let blocks = file.map.blocks(bounds)?;
+            let step = blocks.len().div_ceil(KEY_SAMPLES).max(1);
+            for block in blocks.step_by(step) {
+                let (key, location, _) = file.map.restart_record(block)?;
+                if bounds.contains(&key) && location.block != TOMBSTONE_BLOCK {
+                    sizes.push(location.size);
+                }
+            }
+        }
+        let sampled: u128 = sizes.iter().map(|size| *size as u128).sum();
+        Ok((sampled * self.estimate_range_count(bounds) as u128 / sizes.len() as u128) as u64)
+    }
+
+    fn write_relocated(&self, path: &Path, relocate: &dyn Fn(&EntryLocation) -> Result<EntryLocation>) -> Result<()> {
+        let entries = self.entries(&UNBOUNDED)?
+            .filter(|entry| !matches!(entry, Ok((_, DELETED))))
+            .map(|entry| match entry? {
+                (key, IndexEntry::Put(location, expires_at)) => Ok((key, IndexEntry::Put(relocate(&location)?, expires_at))),
+                removed => Ok(removed),
+            });
+        LsmIndex::write_file(self.backend.as_ref(), path, entries, self.lsn, self.block_size)?;
+        Ok(())
+    }
+
+    // The relocated map covers the same sequence number as the runs
+    fn commit_relocated(&mut self, path: &Path) -> Result<()> {
+        self.finish_merge(true)?;
+        self.backend.rename(path, &self.map_path).map_err(MapError::io(&self.map_path, "rename"))?;
+        let file = self.backend.open(&self.map_path, OpenMode::Read).map_err(MapError::io(&self.map_path, "open"))?;
+        let map = SortedFile::open(file, &self.map_path, self.bloom_rate)?.ok_or_else(|| LsmIndex::missing_records(&self.map_path))?;
+        self.replace_layers(map)
+    }
+
+    fn is_lazy(&self) -> bool {
+        false
+    }
+
+    fn load(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Swaps in the result of a background merge once it completed, with wait set after waiting for
+    // it. A merge whose runs were replaced in the meantime by a compaction is dropped. So is one
+    // that failed, the runs are merged again after a later flush.
+    fn finish_merge(&mut self, wait: bool) -> Result<()> {
+        if !wait && !self.merge.as_ref().is_some_and(|merge| merge.handle.is_finished()) {
+            return Ok(());
+        }
+        let Some(merge) = self.merge.take() else { return Ok(()) };
+        let merged = merge.handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        let current = merge.runs.iter().all(|number| self.runs.iter().any(|(run, _)| run == number));
+        let file = match merged {
+            Ok(file) if current => Arc::new(file),
+            result => {
+                if let (Err(e), true) = (result, current) {
+                    warning!(self.log_level, error = %e; "failed to merge sorted runs");
+                }
+                if self.backend.exists(&merge.output) {
+                    self.backend.remove_file(&merge.output)?;
+                }
+                return Ok(());
+            }
+        };
+        // Renamed into place first, a crash before the merged runs are removed leaves runs behind
+        // that the merged file already holds, under it or in map.db
+        let newest = merge.runs[merge.runs.len() - 1];
+        let kept = match merge.into_map {
+            true => None,
+            false => Some(newest),
+        };
+        let path = match kept {
+            Some(number) => LsmIndex::run_path(&self.folder, number),
+            None => self.map_path.clone(),
+        };
+        self.backend.rename(&merge.output, &path).map_err(MapError::io(&path, "rename"))?;
+        self.backend.sync_dir(&self.folder)?;
+        for number in merge.runs.iter().filter(|number| Some(**number) != kept) {
+            self.backend.remove_file(&LsmIndex::run_path(&self.folder, *number))?;
+        }
+        self.runs.retain(|(number, _)| !merge.runs.contains(number) || Some(*number) == kept);
+        match kept {
+            Some(number) => {
+                for run in self.runs.iter_mut().filter(|(run, _)| *run == number) {
+                    run.1 = Arc::clone(&file);
+                }
+            }
+            None => {
+                self.map_size = file.size;
+                self.base = Some(file);
+            }
+        }
+        event!(self.log_level, DEBUG, runs = merge.runs.len(), into_map = merge.into_map, "swapped in merged sorted runs");
+        Ok(())
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        let backend = self.backend.as_ref();
+        let mut files = vec![self.map_path.clone()];
+        files.extend(LsmIndex::run_files(backend, &self.folder)?.into_iter().map(|(_, path)| path));
+        files.extend(HashIndex::map_delta_files(backend, &self.folder)?.into_iter().map(|(_, path)| path));
+        Ok(files)
+    }
+
+    fn corrupt_files(&self) -> Result<Vec<PathBuf>> {
+        let backend = self.backend.as_ref();
+        let mut corrupt = Vec::new();
+        let map_file = backend.open(&self.map_path, OpenMode::Read).map_err(MapError::io(&self.map_path, "open"))?;
+        if LookupTable::get_map_from_file(map_file.as_ref(), &self.map_path, self.expiries.hasher(), false).is_err() {
+            corrupt.push(self.map_path.clone());
+        }
+        for (_, path) in LsmIndex::run_files(backend, &self.folder)? {
+            if LsmIndex::read_run(backend, &path).is_err() {
+                corrupt.push(path);
+            }
+        }
+        for (_, path) in HashIndex::map_delta_files(backend, &self.folder)? {
+            if HashIndex::read_map_delta(backend, &path).is_err() {
+                corrupt.push(path);
+            }
+        }
+        Ok(corrupt)
+    }
+
+    // The files take the memory of their restart offsets and filters, hash tables a control byte per entry
+    fn memory_usage(&self) -> usize {
+        const KEY: usize = size_of::<Vec<u8>>();
+        let memtable = self.memtable.len() * (KEY + size_of::<IndexEntry>()) + self.memtable_bytes;
+        let times = (self.expiries.len() + self.deletions.len()) * (KEY + size_of::<u64>() + 1) + self.timed_key_bytes;
+        let files: usize = self.files_newest_first().map(SortedFile::memory_size).sum();
+        memtable + times + files
+    }
+}
+
+impl SortedFile {
+    // None if the file holds no records or was written before format version 15
+    fn open(file: Arc<dyn StorageFile>, path: &Path, bloom_rate: Option<f64>) -> Result<Option<Self>> {
+        let size = file.len().map_err(MapError::io(path, "read"))?;
+        let Some(map) = SortedMap::open(file, path)? else { return Ok(None) };
+        let bloom = match bloom_rate {
+            Some(rate) => {
+                let mut bloom = BloomFilter::new((map.keys + map.tombstones).max(1), rate);
+                for entry in FileRange::new(&map, UNBOUNDED)? {
+                    bloom.insert(&entry?.0);
+                }
+                Some(bloom)
+            }
+            None => None,
+        };
+        Ok(Some(SortedFile { map, size, bloom }))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<IndexEntry>> {
+        match &self.bloom {
+            Some(bloom) if !bloom.may_contain(key) => Ok(None),
+            _ => self.map.get(key),
+        }
+    }
+
+    fn memory_size(&self) -> usize {
+        self.map.memory_size() + self.bloom.as_ref().map_or(0, BloomFilter::memory_size)
+    }
+}
+
+// Entries of a sorted file within bounds, read a block at a time from either end
+struct FileRange<'a> {
+    map: &'a SortedMap,
+    bounds: Bounds,
+    // Blocks not read yet
+    blocks: Range<usize>,
+    front: VecDeque<(Vec<u8>, IndexEntry)>,
+    back: VecDeque<(Vec<u8>, IndexEntry)>,
+}
+
+impl<'a> FileRange<'a> {
+    fn new(map: &'a SortedMap, bounds: Bounds) -> Result<Self> {
+        let blocks = map.blocks(&bounds)?;
+        Ok(FileRange { map, bounds, blocks, front: VecDeque::new(), back: VecDeque::new() })
+    }
+
+    // The entries of a block within bounds. Nothing is left to read after an error.
+    fn read(&mut self, block: usize) -> Result<VecDeque<(Vec<u8>, IndexEntry)>> {
+        let records = self.map.read_block(block).inspect_err(|_| {
+            self.blocks = 0..0;
+            self.front.clear();
+            self.back.clear();
+        })?;
+        Ok(records.into_iter()
+            .filter(|(key, _, _)| self.bounds.contains(key))
+            .map(|(key, location, expires_at)| (key, IndexEntry::from_record(location, expires_at)))
+            .collect())
+    }
+}
+
+impl Iterator for FileRange<'_> {
+    type Item = Result<(Vec<u8>, IndexEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.front.is_empty() && !self.blocks.is_empty() {
+            match self.read(self.blocks.start) {
+                Ok(entries) => self.front = entries,
+                Err(e) => return Some(Err(e)),
+            }
+            self.blocks.start += 1;
+        }
+        self.front.pop_front().or_else(|| self.back.pop_front()).map(Ok)
+    }
+}
+
+impl DoubleEndedIterator for FileRange<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while self.back.is_empty() && !self.blocks.is_empty() {
+            match self.read(self.blocks.end - 1) {
+                Ok(entries) => self.back = entries,
+                Err(e) => return Some(Err(e)),
+            }
+            self.blocks.end -= 1;
+        }
+        self.back.pop_back().or_else(|| self.front.pop_back()).map(Ok)
+    }
+}
+
+// Entries of several layers in ascending key order, merged lazily from either end. Layers are
+// given from the newest, whose entry of a key replaces those of the older ones. An error of a
+// layer is returned as soon as it is read.
+struct MergedEntries<'a> {
+    layers: Vec<Layer<'a>>,
+    // Entries taken from the front and the back of every layer that were not returned yet
+    fronts: Vec<Option<(Vec<u8>, IndexEntry)>>,
+    backs: Vec<Option<(Vec<u8>, IndexEntry)>>,
+}
+
+impl<'a> MergedEntries<'a> {
+    fn new(layers: Vec<Layer<'a>>) -> Self {
+        let (fronts, backs) = layers.iter().map(|_| (None, None)).unzip();
+        MergedEntries { layers, fronts, backs }
+    }
+
+    // Drops the entries of the older layers with the key of the one returned
+    fn drop_older(taken: &mut [Option<(Vec<u8>, IndexEntry)>], key: &[u8]) {
+        for entry in taken {
+            if entry.as_ref().is_some_and(|(entry_key, _)| entry_key == key) {
+                *entry = None;
+            }
+        }
+    }
+}
+
+impl Iterator for MergedEntries<'_> {
+    type Item = Result<(Vec<u8>, IndexEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (layer, entries) in self.layers.iter_mut().enumerate() {
+            if self.fronts[layer].is_none() {
+                // Once a layer runs out the entry taken from its back is the last one left
+                self.fronts[layer] = match entries.next() {
+                    Some(Ok(entry)) => Some(entry),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => self.backs[layer].take(),
+                };
+            }
+        }
+        // The lowest layer holding the first key is the newest
+        let (_, newest) = self.fronts.iter().enumerate()
+            .filter_map(|(layer, entry)| Some((&entry.as_ref()?.0, layer)))
+            .min()?;
+        let entry = self.fronts[newest].take()?;
+        MergedEntries::drop_older(&mut self.fronts[newest + 1..], &entry.0);
+        Some(Ok(entry))
+    }
+}
+
+impl DoubleEndedIterator for MergedEntries<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        for (layer, entries) in self.layers.iter_mut().enumerate() {
+            if self.backs[layer].is_none() {
+                self.backs[layer] = match entries.next_back() {
+                    Some(Ok(entry)) => Some(entry),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => self.fronts[layer].take(),
+                };
+            }
+        }
+        let (_, newest) = self.backs.iter().enumerate()
+            .filter_map(|(layer, entry)| Some((&entry.as_ref()?.0, std::cmp::Reverse(layer))))
+            .max()?;
+        let entry = self.backs[newest.0].take()?;
+        MergedEntries::drop_older(&mut self.backs[newest.0 + 1..], &entry.0);
+        Some(Ok(entry))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::backend::FileSystem;
+    use crate::db::temp::TempDir;
+    use std::fs;
+
+    fn open(dir: &TempDir, options: &DbOptions) -> Result<LsmIndex> {
+        LsmIndex::open(dir.path(), options, &mut RecoveryReport::default())
+    }
+
+    fn el(i: u32) -> IndexEntry {
+        IndexEntry::Put(EntryLocation { block: i as u64, pointer: 4, size: 0 }, 0)
+    }
+
+    // Puts the entry as the next record
+    fn put(index: &mut LsmIndex, key: u32, entry: IndexEntry) -> Result<()> {
+        index.put(&key.to_be_bytes(), &entry.encode())?;
+        index.commit(index.lsn + 1)
+    }
+
+    fn get(index: &LsmIndex, key: u32) -> Result<Option<IndexEntry>> {
+        index.get(&key.to_be_bytes())?.map(|value| IndexEntry::decode(&value)).transpose()
+    }
+
+    fn runs(dir: &TempDir) -> Result<Vec<u64>> {
+        Ok(LsmIndex::run_files(&FileSystem, dir.path())?.into_iter().map(|(number, _)| number).collect())
+    }
+
+    #[test]
+    fn test_runs() -> Result<()> {
+        let dir = TempDir::new()?;
+        let options = DbOptions::new().max_map_deltas(3).bloom_filter(0.01);
+        let mut index = open(&dir, &options)?;
+        for i in 0..100 {
+            put(&mut index, i, el(i))?;
+        }
+        // A run larger than the empty map.db is merged into it
+        index.flush()?;
+        index.finish_merge(true)?;
+        assert!(index.runs.is_empty() && runs(&dir)?.is_empty());
+        assert_eq!(index.base.as_ref().map(|base| base.map.keys), Some(100));
+        assert_eq!(index.map_size, fs::metadata(&index.map_path)?.len());
+
+        // Flushes write the memtable to a run, searched before map.db
+        index.delete(&5u32.to_be_bytes())?;
+        index.commit(index.lsn + 1)?;
+        put(&mut index, 6, el(1006))?;
+        put(&mut index, 1000, el(1000))?;
+        assert_eq!(index.memtable.len(), 3);
+        index.flush()?;
+        assert!(index.memtable.is_empty());
+        assert_eq!(runs(&dir)?, [1]);
+        assert_eq!((get(&index, 5)?, get(&index, 6)?, get(&index, 99)?), (None, Some(el(1006)), Some(el(99))));
+        let tombstone = Tombstone { lsn: index.lsn + 1, deleted_at: 1 };
+        put(&mut index, 7, IndexEntry::Remove(tombstone))?;
+        assert_eq!((index.len(), index.tombstone_count()), (99, 1));
+
+        // Runs smaller than map.db are merged into the newest once there are max_map_deltas
+        index.flush()?;
+        put(&mut index, 1001, el(1001))?;
+        index.flush()?;
+        index.finish_merge(true)?;
+        assert_eq!(runs(&dir)?, [3]);
+        let (entries, lsn) = LsmIndex::read_run(&FileSystem, &LsmIndex::run_path(dir.path(), 3))?;
+        assert_eq!((entries.len(), lsn), (5, index.lsn));
+        assert!(entries.contains(&(5u32.to_be_bytes().to_vec(), DELETED)));
+
+        // Scans merge the memtable, the runs and map.db from either end
+        put(&mut index, 3, el(1003))?;
+        let scan = |index: &LsmIndex| -> Result<Vec<(u32, IndexEntry)>> {
+            index.scan(Bound::Included(&2u32.to_be_bytes()[..]), Bound::Excluded(&9u32.to_be_bytes()[..]))
+                .map(|pair| pair.and_then(|(key, value)| Ok((u32::from_be_bytes(key.as_slice().try_into()?), IndexEntry::decode(&value)?))))
+                .collect()
+        };
+        assert_eq!(scan(&index)?, [(2, el(2)), (3, el(1003)), (4, el(4)), (6, el(1006)), (8, el(8))]);
+        let mut all = index.scan(Bound::Unbounded, Bound::Unbounded);
+        let (first, last) = (all.next().transpose()?, all.next_back().transpose()?);
+        assert_eq!((first.map(|(key, _)| key), last.map(|(key, _)| key)), (Some(0u32.to_be_bytes().to_vec()), Some(1001u32.to_be_bytes().to_vec())));
+        assert_eq!(all.rev().count(), 98);
+        assert_eq!(index.estimate_range_count(&UNBOUNDED), 100);
+
+        // A compaction merges everything into map.db, without the keys deleted outright
+        index.compact()?;
+        assert!(runs(&dir)?.is_empty() && index.memtable.is_empty());
+        assert_eq!(index.base.as_ref().map(|base| (base.map.keys, base.map.tombstones)), Some((100, 1)));
+        let lsn = index.lsn;
+        drop(index);
+        let index = open(&dir, &options)?;
+        assert_eq!((index.len(), index.tombstone_count(), index.flushed_lsn()), (100, 1, lsn));
+        assert_eq!((get(&index, 7)?, get(&index, 3)?), (Some(IndexEntry::Remove(tombstone)), Some(el(1003))));
+        assert_eq!(index.expired_tombstones(1), [7u32.to_be_bytes().to_vec()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_recovery() -> Result<()> {
+        let dir = TempDir::new()?;
+        let options = DbOptions::new();
+        let mut index = open(&dir, &options)?;
+        for i in 0..100 {
+            put(&mut index, i, el(i))?;
+        }
+        index.flush()?;
+        index.finish_merge(true)?;
+        put(&mut index, 1, el(1001))?;
+        index.flush()?;
+
+        // A run left behind by a crash before a merge into map.db removed it is covered by map.db
+        let run = fs::read(LsmIndex::run_path(dir.path(), 1))?;
+        index.compact()?;
+        fs::write(LsmIndex::run_path(dir.path(), 1), &run)?;
+        drop(index);
+        let mut recovery = RecoveryReport::default();
+        let mut index = LsmIndex::open(dir.path(), &options, &mut recovery)?;
+        assert_eq!(recovery.repaired_files, [LsmIndex::run_path(dir.path(), 1)]);
+        assert!(runs(&dir)?.is_empty());
+        assert_eq!(get(&index, 1)?, Some(el(1001)));
+
+        // A corrupt run is dropped with the ones after it by a repairing open
+        put(&mut index, 2, el(1002))?;
+        index.flush()?;
+        put(&mut index, 3, el(1003))?;
+        index.flush()?;
+        let path = LsmIndex::run_path(dir.path(), 1);
+        let mut bytes = fs::read(&path)?;
+        bytes[HEADER_SIZE + 2] ^= 0xff;
+        fs::write(&path, &bytes)?;
+        drop(index);
+        assert!(matches!(open(&dir, &options), Err(Error::Map(MapError::ChecksumMismatch{..}))));
+        let mut recovery = RecoveryReport::default();
+        let mut index = LsmIndex::open(dir.path(), &DbOptions::new().repair(true), &mut recovery)?;
+        // Their entries are left to the WAL, which is replayed from the start
+        assert!(recovery.map_rebuilt);
+        assert_eq!((index.flushed_lsn(), get(&index, 2)?, get(&index, 3)?), (0, Some(el(2)), Some(el(3))));
+        index.compact()?;
+        assert!(runs(&dir)?.is_empty());
+        Ok(())
+    }
+
+    // Deltas of Engine::Hash are read into the memtable and merged into map.db right away
+    #[test]
+    fn test_hash_deltas() -> Result<()> {
+        let dir = TempDir::new()?;
+        let options = DbOptions::new();
+        let mut hash = LookupTable::open_index(dir.path(), &options, false, &mut RecoveryReport::default())?;
+        for i in 0..100u32 {
+            hash.put(&i.to_be_bytes(), &el(i).encode())?;
+        }
+        hash.commit(100)?;
+        hash.compact()?;
+        hash.delete(&0u32.to_be_bytes())?;
+        hash.put(&1u32.to_be_bytes(), &el(1001).encode())?;
+        hash.commit(101)?;
+        hash.flush()?;
+        drop(hash);
+        assert_eq!(HashIndex::map_delta_files(&FileSystem, dir.path())?.len(), 1);
+
+        let index = open(&dir, &options)?;
+        assert!(HashIndex::map_delta_files(&FileSystem, dir.path())?.is_empty() && index.memtable.is_empty());
+        assert_eq!((index.len(), index.flushed_lsn()), (99, 101));
+        assert_eq!((get(&index, 0)?, get(&index, 1)?), (None, Some(el(1001))));
+        Ok(())
+    }
+}
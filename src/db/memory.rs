@@ -0,0 +1,272 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use crate::db::backend::{OpenMode, StorageBackend, StorageFile};
+use crate::db::database::Db;
+use crate::db::options::DbOptions;
+use crate::error::Result;
+
+/// Storage backend that keeps every file in RAM, see [`Db::open_in_memory`].
+///
+/// Clones share the same files, so a database can be closed and opened again through a clone
+/// for as long as one of them is alive. Syncing does nothing.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    files: HashMap<PathBuf, Arc<MemoryFile>>,
+    folders: BTreeSet<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryFile {
+    data: Mutex<Vec<u8>>,
+    // Advisory lock taken through the handles of the file
+    locks: Mutex<Lock>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Lock {
+    #[default]
+    Free,
+    Shared(usize),
+    Exclusive,
+}
+
+// Handle returned by open, releases its lock when dropped like a closed file would
+struct MemoryHandle {
+    file: Arc<MemoryFile>,
+    // Whether this handle holds a shared (true) or exclusive (false) lock
+    locked: Mutex<Option<bool>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", path.display()))
+}
+
+impl StorageBackend for MemoryBackend {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn StorageFile>> {
+        let mut state = self.state();
+        let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty());
+        if parent.is_some_and(|parent| !state.folders.contains(parent)) {
+            return Err(not_found(path));
+        }
+        let file = match (state.files.get(path), mode) {
+            (Some(_), OpenMode::CreateNew) => {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists", path.display())));
+            }
+            (Some(file), OpenMode::Truncate) => {
+                file.data().clear();
+                Arc::clone(file)
+            }
+            (Some(file), _) => Arc::clone(file),
+            (None, OpenMode::Read | OpenMode::Write) => return Err(not_found(path)),
+            (None, _) => {
+                let file = Arc::new(MemoryFile::default());
+                state.files.insert(path.to_path_buf(), Arc::clone(&file));
+                file
+            }
+        };
+        Ok(Arc::new(MemoryHandle { file, locked: Mutex::new(None) }))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state();
+        state.files.contains_key(path) || state.folders.contains(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.state().folders.contains(path)
+    }
+
+    fn list(&self, folder: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state();
+        if !state.folders.contains(folder) {
+            return Err(not_found(folder));
+        }
+        Ok(state.files.keys().chain(&state.folders)
+            .filter(|path| path.parent() == Some(folder))
+            .cloned()
+            .collect())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        for folder in path.ancestors().filter(|folder| !folder.as_os_str().is_empty()) {
+            state.folders.insert(folder.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.state().files.remove(path).map(|_| ()).ok_or_else(|| not_found(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        if !state.folders.contains(path) {
+            return Err(not_found(path));
+        }
+        state.files.retain(|file, _| !file.starts_with(path));
+        state.folders.retain(|folder| !folder.starts_with(path));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        if state.folders.contains(from) {
+            // Moves the folder with everything in it
+            let moved = |path: &Path| path.strip_prefix(from).ok().map(|rest| to.join(rest));
+            let folders: Vec<PathBuf> = state.folders.iter().filter_map(|folder| moved(folder)).collect();
+            state.folders.retain(|folder| !folder.starts_with(from));
+            state.folders.extend(folders);
+            let files: Vec<(PathBuf, Arc<MemoryFile>)> = state.files.iter()
+                .filter_map(|(path, file)| Some((moved(path)?, Arc::clone(file))))
+                .collect();
+            state.files.retain(|path, _| !path.starts_with(from));
+            state.files.extend(files);
+            return Ok(());
+        }
+        let file = state.files.remove(from).ok_or_else(|| not_found(from))?;
+        state.files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn sync_dir(&self, _folder: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl MemoryFile {
+    fn data(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn locks(&self) -> MutexGuard<'_, Lock> {
+        self.locks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl StorageFile for MemoryHandle {
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        let data = self.file.data();
+        let bytes = usize::try_from(offset).ok()
+            .and_then(|start| data.get(start..start.checked_add(buffer.len())?))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_at(&self, bytes: &[u8], offset: u64) -> io::Result<()> {
+        let mut data = self.file.data();
+        let start = usize::try_from(offset).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let end = start + bytes.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.data().len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.file.data().resize(len, 0);
+        Ok(())
+    }
+
+    fn try_lock(&self, shared: bool) -> io::Result<bool> {
+        let mut locked = self.locked.lock().unwrap_or_else(|e| e.into_inner());
+        if locked.is_some() {
+            return Ok(true);
+        }
+        let mut locks = self.file.locks();
+        *locks = match (*locks, shared) {
+            (Lock::Free, true) => Lock::Shared(1),
+            (Lock::Shared(holders), true) => Lock::Shared(holders + 1),
+            (Lock::Free, false) => Lock::Exclusive,
+            _ => return Ok(false),
+        };
+        *locked = Some(shared);
+        Ok(true)
+    }
+}
+
+impl Drop for MemoryHandle {
+    fn drop(&mut self) {
+        let locked = self.locked.get_mut().unwrap_or_else(|e| e.into_inner());
+        if locked.is_some() {
+            let mut locks = self.file.locks();
+            *locks = match *locks {
+                Lock::Shared(holders) if holders > 1 => Lock::Shared(holders - 1),
+                _ => Lock::Free,
+            };
+        }
+    }
+}
+
+impl Db {
+    /// Opens an empty database that keeps its map, WAL and values in RAM and never touches
+    /// the file system. It behaves like one on disk and is gone once the handle is dropped.
+    ///
+    /// Use [`DbOptions::backend`] with a [`MemoryBackend`] for other settings or to reopen it.
+    pub fn open_in_memory() -> Result<Self> {
+        DbOptions::new().path("memory").backend(MemoryBackend::new()).open()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn test_open_in_memory() -> Result<()> {
+        let db = Db::open_in_memory()?;
+        db.put(b"a", b"1")?;
+        db.cf("users")?.put(b"b", b"2")?;
+        db.put(b"c", b"3")?;
+        db.delete(b"c")?;
+        db.compact()?;
+        db.vacuum()?;
+        assert_eq!(db.iter().collect::<Result<Vec<_>>>()?, [(b"a".to_vec(), b"1".to_vec())]);
+        assert_eq!(db.cf("users")?.get(b"b")?, Some(b"2".to_vec()));
+        assert!(!Path::new("memory").exists());
+
+        // Reopening through a clone of the backend replays the WAL like a restart would
+        let backend = MemoryBackend::new();
+        let options = DbOptions::new().path("memory").backend(backend.clone());
+        let db = options.open()?;
+        db.put(b"logged", b"1")?;
+        assert!(matches!(options.open(), Err(Error::DatabaseLocked(_))));
+        db.crash();
+        let db = options.open()?;
+        assert_eq!(db.get(b"logged")?, Some(b"1".to_vec()));
+        assert_eq!(db.recovery_report().wal_records_replayed, 1);
+        db.destroy()?;
+        assert_eq!(backend.list(Path::new("memory"))?, Vec::<PathBuf>::new());
+        Ok(())
+    }
+}
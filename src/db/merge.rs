@@ -0,0 +1,25 @@
+use std::fmt;
+use std::sync::Arc;
+
+type MergeFn = dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync;
+
+// User function that combines the current value of a key with a merge operand,
+// called with (key, current value if any, operand) under the write lock
+#[derive(Clone)]
+pub(crate) struct MergeOperator(Arc<MergeFn>);
+
+impl MergeOperator {
+    pub fn new(function: impl Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(function))
+    }
+
+    pub fn merge(&self, key: &[u8], current: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        (self.0)(key, current, operand)
+    }
+}
+
+impl fmt::Debug for MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MergeOperator")
+    }
+}
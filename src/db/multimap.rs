@@ -0,0 +1,131 @@
+use crate::db::batch::WriteBatch;
+use crate::db::database::Db;
+use crate::db::index::Index;
+use crate::db::keys::{Element, Tuple};
+use crate::error::{Error, Result};
+
+/// Keyspace holding any number of distinct values per key, returned by [`Db::multi_map`].
+///
+/// Every pair of key and value is an entry of the column family of the multi-map, keyed by the
+/// [`Tuple`] of the key and the value. The values of a key are returned in ascending order, and
+/// as part of the keys they should stay small. The column family is best not written to directly.
+#[derive(Clone)]
+pub struct MultiMap {
+    db: Db,
+    name: String,
+}
+
+impl Db {
+    /// Returns the multi-map stored in the column family `name`, creating it if it does not exist yet.
+    pub fn multi_map(&self, name: &str) -> Result<MultiMap> {
+        self.cf(name)?;
+        Ok(MultiMap { db: self.clone(), name: name.to_string() })
+    }
+}
+
+impl MultiMap {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Adds `value` to the values of `key`, a value it already has is kept once.
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.db.index_mut().insert(Some(&self.name), &MultiMap::entry(key, value), b"")
+    }
+
+    /// Iterates over the values of `key` in ascending order.
+    pub fn get_all(&self, key: &[u8]) -> Result<impl Iterator<Item = Vec<u8>>> {
+        let entries = MultiMap::entries(&self.db.index(), &self.name, key)?;
+        Ok(entries.into_iter().map(|(_, value)| value))
+    }
+
+    pub fn contains(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        Ok(self.db.index().get(Some(&self.name), &MultiMap::entry(key, value))?.is_some())
+    }
+
+    /// Removes `value` from the values of `key`.
+    pub fn delete(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.db.index_mut().remove(Some(&self.name), &MultiMap::entry(key, value))
+    }
+
+    /// Removes every value of `key` atomically.
+    pub fn delete_all(&self, key: &[u8]) -> Result<()> {
+        // Under the write lock, so values added meanwhile are removed too
+        let mut index = self.db.index_mut();
+        let mut batch = WriteBatch::new();
+        for (entry, _) in MultiMap::entries(&index, &self.name, key)? {
+            batch.delete(&entry);
+        }
+        match batch.is_empty() {
+            true => Ok(()),
+            false => index.write_batch(Some(&self.name), batch),
+        }
+    }
+
+    fn entry(key: &[u8], value: &[u8]) -> Vec<u8> {
+        Tuple::new().push(key).push(value).encode()
+    }
+
+    // Entries of key with their values. The encoding of a key is also a prefix of the entries of
+    // longer keys that continue it with a 0 byte, so the decoded key is compared too.
+    fn entries(index: &Index, name: &str, key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        for (entry, _) in index.prefix_locations(Some(name), &Tuple::new().push(key).encode())? {
+            match Tuple::decode(&entry)?.elements() {
+                [Element::Bytes(entry_key), Element::Bytes(value)] if entry_key.as_slice() == key => {
+                    entries.push((entry, value.clone()));
+                }
+                [Element::Bytes(_), Element::Bytes(_)] => {}
+                _ => return Err(Error::Serialization(format!("{} is not a multi-map entry", entry.escape_ascii()))),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multi_map() -> Result<()> {
+        let db = Db::open_temp()?;
+        let tags = db.multi_map("tags")?;
+        tags.put(b"post1", b"rust")?;
+        tags.put(b"post1", b"db")?;
+        tags.put(b"post1", b"rust")?;
+        tags.put(b"post10", b"other")?;
+        tags.put(b"post2", b"db")?;
+        assert_eq!(tags.get_all(b"post1")?.collect::<Vec<_>>(), [b"db".to_vec(), b"rust".to_vec()]);
+        assert!(tags.contains(b"post2", b"db")?);
+        assert!(!tags.contains(b"post2", b"rust")?);
+
+        tags.delete(b"post1", b"db")?;
+        assert_eq!(tags.get_all(b"post1")?.collect::<Vec<_>>(), [b"rust".to_vec()]);
+        tags.put(b"post1", b"new")?;
+        tags.delete_all(b"post1")?;
+        assert_eq!(tags.get_all(b"post1")?.count(), 0);
+        assert_eq!(tags.get_all(b"post10")?.collect::<Vec<_>>(), [b"other".to_vec()]);
+        tags.delete_all(b"missing")?;
+        // Not visible in the default keyspace
+        assert_eq!(db.iter().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keys_with_zero_bytes() -> Result<()> {
+        let db = Db::open_temp()?;
+        let tags = db.multi_map("tags")?;
+        tags.put(b"a", b"x")?;
+        tags.put(b"a\0", b"y")?;
+        tags.put(b"a\0\0", b"z")?;
+        assert_eq!(tags.get_all(b"a")?.collect::<Vec<_>>(), [b"x".to_vec()]);
+        assert_eq!(tags.get_all(b"a\0")?.collect::<Vec<_>>(), [b"y".to_vec()]);
+        tags.delete_all(b"a")?;
+        assert_eq!(tags.get_all(b"a")?.count(), 0);
+        assert_eq!(tags.get_all(b"a\0")?.collect::<Vec<_>>(), [b"y".to_vec()]);
+        assert_eq!(tags.get_all(b"a\0\0")?.collect::<Vec<_>>(), [b"z".to_vec()]);
+        Ok(())
+    }
+}
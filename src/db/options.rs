@@ -0,0 +1,428 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::db::backend::{FileSystem, StorageBackend};
+use crate::db::btree::{valid_block_size, DEFAULT_BLOCK_SIZE, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+use crate::db::clock::{Clock, SystemClock};
+use crate::db::compression::Compression;
+use crate::db::database::Db;
+use crate::db::engine::StorageEngine;
+use crate::db::hash::KeyHasher;
+use crate::db::merge::MergeOperator;
+use crate::db::sync::SyncPolicy;
+use crate::error::{Error, Result};
+
+const DEFAULT_MAX_WAL_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_BLOCK_CACHE_SIZE: usize = 8 * 1024 * 1024;
+const DEFAULT_WAL_REWRITE_THRESHOLD: u64 = 32 * 1024 * 1024;
+const DEFAULT_MAX_MAP_DELTAS: usize = 8;
+const DEFAULT_TOMBSTONE_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 64;
+const DEFAULT_MAX_VALUE_SIZE: usize = 64 * 1024 * 1024;
+const MAX_SHARDS: usize = 256;
+
+/// What a write does when the operations logged since the last flush are over the limits of
+/// [`DbOptions::max_unflushed_wal_bytes`] or [`DbOptions::max_unflushed_writes`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    /// Flush before writing. Other reads and writes wait until the flush completes.
+    #[default]
+    Block,
+    /// Fail with [`Error::Backpressure`] until [`Db::flush`] is called.
+    Fail,
+}
+
+/// How the lookup tables keep and persist their keys, see [`DbOptions::engine`]. Either engine opens a
+/// database written by the other.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// Every key is held in memory. Flushes write the changed keys to a delta file, and every
+    /// [`DbOptions::max_map_deltas`] flushes the whole map is rewritten in their place.
+    #[default]
+    Hash,
+    /// Only the keys written since the last flush are held in memory, flushes write them to a
+    /// sorted run. Lookups search them, then the runs on disk from the newest, then the map on
+    /// disk. Runs are merged with each other and with the map in a background thread, no flush
+    /// rewrites the whole map.
+    Lsm,
+}
+
+/// Diagnostics a database reports, see [`DbOptions::log_level`]. Each level includes the ones
+/// before it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum LogLevel {
+    /// Nothing, not even recovery problems.
+    Off,
+    /// Recovery and repair of damaged files, and background work that failed.
+    #[default]
+    Warn,
+    /// Opening and closing, compactions, vacuums and recovery of interrupted ones.
+    Info,
+    /// Flushes, map merges and WAL rewrites, rotations and truncations.
+    Debug,
+    /// Every WAL append.
+    Trace,
+}
+
+// Named like the levels of tracing, so the event macros can compare against either
+impl LogLevel {
+    pub(crate) const WARN: LogLevel = LogLevel::Warn;
+    pub(crate) const INFO: LogLevel = LogLevel::Info;
+    pub(crate) const DEBUG: LogLevel = LogLevel::Debug;
+    pub(crate) const TRACE: LogLevel = LogLevel::Trace;
+}
+
+/// Builder for opening a [`Db`] with non-default settings.
+///
+/// ```no_run
+/// use cendb::{DbOptions, SyncPolicy};
+///
+/// let db = DbOptions::new()
+///     .path("data/my_db")
+///     .create_if_missing(true)
+///     .sync(SyncPolicy::Never)
+///     .open()?;
+/// # Ok::<(), cendb::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct DbOptions {
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) create_if_missing: bool,
+    pub(crate) read_only: bool,
+    pub(crate) low_memory: bool,
+    pub(crate) reset: bool,
+    pub(crate) repair: bool,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) max_wal_segment_size: u64,
+    pub(crate) wal_rewrite_threshold: u64,
+    pub(crate) tombstone_retention: Duration,
+    pub(crate) max_map_deltas: usize,
+    pub(crate) engine: Engine,
+    pub(crate) max_unflushed_wal_bytes: Option<u64>,
+    pub(crate) max_unflushed_writes: Option<usize>,
+    pub(crate) backpressure: Backpressure,
+    pub(crate) memory_limit: Option<u64>,
+    pub(crate) shards: usize,
+    pub(crate) recovery_threads: Option<usize>,
+    pub(crate) block_size: usize,
+    pub(crate) direct_io: bool,
+    pub(crate) bloom_false_positive_rate: Option<f64>,
+    pub(crate) block_cache_size: usize,
+    pub(crate) compression: Compression,
+    pub(crate) compression_threshold: usize,
+    pub(crate) max_value_size: usize,
+    pub(crate) merge_operator: Option<MergeOperator>,
+    pub(crate) hasher: KeyHasher,
+    pub(crate) backend: Arc<dyn StorageBackend>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) log_level: LogLevel,
+}
+
+impl Default for DbOptions {
+    fn default() -> Self {
+        Self {
+            path: None,
+            create_if_missing: true,
+            read_only: false,
+            low_memory: false,
+            reset: false,
+            repair: false,
+            sync_policy: SyncPolicy::default(),
+            max_wal_segment_size: DEFAULT_MAX_WAL_SEGMENT_SIZE,
+            wal_rewrite_threshold: DEFAULT_WAL_REWRITE_THRESHOLD,
+            tombstone_retention: DEFAULT_TOMBSTONE_RETENTION,
+            max_map_deltas: DEFAULT_MAX_MAP_DELTAS,
+            engine: Engine::Hash,
+            max_unflushed_wal_bytes: None,
+            max_unflushed_writes: None,
+            backpressure: Backpressure::Block,
+            memory_limit: None,
+            shards: 1,
+            recovery_threads: None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            direct_io: false,
+            bloom_false_positive_rate: None,
+            block_cache_size: DEFAULT_BLOCK_CACHE_SIZE,
+            compression: Compression::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            merge_operator: None,
+            hasher: KeyHasher::SipHash,
+            backend: Arc::new(FileSystem),
+            clock: Arc::new(SystemClock),
+            log_level: LogLevel::Warn,
+        }
+    }
+}
+
+impl DbOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Directory holding the database files. Required.
+    pub fn path(mut self, path: impl AsRef<Path>) -> Self {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Create the database if the directory holds none yet. Defaults to `true`.
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Open without write access, every mutating call fails. Defaults to `false`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Answer [`get`](crate::Db::get), [`get_ref`](crate::Db::get_ref) and
+    /// [`multi_get`](crate::Db::multi_get) by searching the sorted map on disk instead of loading
+    /// every key into memory on open. Anything else that reads keys, iteration or
+    /// [`len`](crate::Db::len) for example, loads them on first use. Only read-only handles of a
+    /// database that was closed cleanly open this way, others load their keys as usual, and so do
+    /// maps written before sorted maps were indexed. Defaults to `false`.
+    pub fn low_memory(mut self, low_memory: bool) -> Self {
+        self.low_memory = low_memory;
+        self
+    }
+
+    /// Delete any existing database files before opening. Defaults to `false`.
+    pub fn reset(mut self, reset: bool) -> Self {
+        self.reset = reset;
+        self
+    }
+
+    /// Rebuild a corrupt map.db from the entries that can still be read and the write-ahead log
+    /// instead of failing to open. Entries in the damaged part of the map are lost, the value
+    /// file holds no keys to recover them from. Defaults to `false`.
+    pub fn repair(mut self, repair: bool) -> Self {
+        self.repair = repair;
+        self
+    }
+
+    /// Durability tradeoff for writes, see [`SyncPolicy`]. Defaults to [`SyncPolicy::Always`].
+    pub fn sync(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Size in bytes after which the write-ahead log continues in a new segment file.
+    /// Defaults to 64 MiB.
+    pub fn max_wal_segment_size(mut self, bytes: u64) -> Self {
+        self.max_wal_segment_size = bytes;
+        self
+    }
+
+    /// Size in bytes of all write-ahead log segments after which the log is rewritten
+    /// without the records of keys that were written again since. Defaults to 32 MiB.
+    pub fn wal_rewrite_threshold(mut self, bytes: u64) -> Self {
+        self.wal_rewrite_threshold = bytes;
+        self
+    }
+
+    /// How long a removed key keeps its tombstone, the record of the remove with its sequence
+    /// number and time that the map holds in place of the key. Flushes drop older tombstones.
+    /// Defaults to one day, zero drops them with the first flush after the remove.
+    pub fn tombstone_retention(mut self, retention: Duration) -> Self {
+        self.tombstone_retention = retention;
+        self
+    }
+
+    /// Number of delta files a flush writes with only the changed keys before the next one rewrites
+    /// the whole map. Defaults to 8, 0 rewrites the map on every flush. With [`Engine::Lsm`] the
+    /// number of runs that are merged into one, at least 2.
+    pub fn max_map_deltas(mut self, count: usize) -> Self {
+        self.max_map_deltas = count;
+        self
+    }
+
+    /// How the lookup tables keep and persist the keys, see [`Engine`]. With [`Engine::Lsm`]
+    /// every [`max_map_deltas`](DbOptions::max_map_deltas) runs are merged into one, and runs
+    /// that take more space than the map are merged into it. Defaults to [`Engine::Hash`].
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    /// Bytes the write-ahead logs of all keyspaces may grow by between flushes before writes
+    /// are held back, see [`Backpressure`]. Not limited by default.
+    pub fn max_unflushed_wal_bytes(mut self, bytes: u64) -> Self {
+        self.max_unflushed_wal_bytes = Some(bytes);
+        self
+    }
+
+    /// Writes of keys that may be kept in memory between flushes before further ones are held
+    /// back, see [`Backpressure`]. Every key of a batch counts. Not limited by default.
+    pub fn max_unflushed_writes(mut self, count: usize) -> Self {
+        self.max_unflushed_writes = Some(count);
+        self
+    }
+
+    /// What a write does once a limit on unflushed writes is reached. Defaults to
+    /// [`Backpressure::Block`].
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Memory in bytes the database may hold, see [`MemoryUsage`](crate::MemoryUsage). Writes flush first once it is
+    /// exceeded, which releases the operations logged since the last flush. The keys, the block
+    /// cache and at least 64 KiB of logged operations are always kept. Not limited by default.
+    pub fn memory_limit(mut self, bytes: u64) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Number of shards a new keyspace spreads its keys over by their hash, each with its own map
+    /// and write-ahead log, flushed in parallel. Keyspaces keep the count they were created with.
+    /// Must lie between 1 and 256. Defaults to 1.
+    pub fn shards(mut self, count: usize) -> Self {
+        self.shards = count;
+        self
+    }
+
+    /// Threads that decode and apply the write-ahead log when opening a database that was not
+    /// closed cleanly. Logs too small to be worth splitting are replayed on the calling thread.
+    /// Must be at least 1. Defaults to the available parallelism.
+    pub fn recovery_threads(mut self, count: usize) -> Self {
+        self.recovery_threads = Some(count);
+        self
+    }
+
+    /// Size in bytes of the blocks values are packed into in the data file, a power of two between
+    /// 512 bytes and 32 KiB. Larger blocks suit storage with large pages and hold bigger values
+    /// without splitting them. Databases keep the size they were created with. Defaults to 4 KiB.
+    pub fn block_size(mut self, bytes: usize) -> Self {
+        self.block_size = bytes;
+        self
+    }
+
+    /// Open the data files with `O_DIRECT` on Linux, so their blocks are cached once, in the block
+    /// cache, rather than again in the page cache. Reads and writes not aligned to 4 KiB are
+    /// widened internally. Suits databases larger than memory, with a block cache sized up to
+    /// make up for the page cache. Other systems, file systems that reject `O_DIRECT` and
+    /// backends other than the local file system keep buffering. Defaults to `false`.
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /// Keep a bloom filter over the keys, so lookups of absent keys can be answered
+    /// without consulting the index. `false_positive_rate` must lie between 0 and 1,
+    /// lower rates cost more memory. Disabled by default.
+    pub fn bloom_filter(mut self, false_positive_rate: f64) -> Self {
+        self.bloom_false_positive_rate = Some(false_positive_rate);
+        self
+    }
+
+    /// Memory in bytes for caching recently read blocks, 0 disables the cache.
+    /// Defaults to 8 MiB.
+    pub fn block_cache_size(mut self, bytes: usize) -> Self {
+        self.block_cache_size = bytes;
+        self
+    }
+
+    /// Codec that values are compressed with in the data file, see [`Compression`].
+    /// Values written before the codec changed stay readable. Defaults to no compression.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Size in bytes below which values are stored uncompressed. Defaults to 64.
+    pub fn compression_threshold(mut self, bytes: usize) -> Self {
+        self.compression_threshold = bytes;
+        self
+    }
+
+    /// Largest value in bytes that can be written, values bigger than a block are split over
+    /// several. Defaults to 64 MiB.
+    pub fn max_value_size(mut self, bytes: usize) -> Self {
+        self.max_value_size = bytes;
+        self
+    }
+
+    /// Function used by [`Db::merge`] to combine the current value of a key with an operand.
+    /// It is called with the key, the current value if there is one and the operand,
+    /// and returns the new value. Not set by default, merges fail without one.
+    pub fn merge_operator(
+        mut self,
+        merge: impl Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        self.merge_operator = Some(MergeOperator::new(merge));
+        self
+    }
+
+    /// Hash function of the in-memory tables that map keys to their entries, see [`KeyHasher`].
+    /// Defaults to SipHash, which keys from untrusted clients can't slow down.
+    pub fn hasher(mut self, hasher: KeyHasher) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    /// Storage holding the database files, see [`StorageBackend`].
+    /// Defaults to the local file system.
+    pub fn backend(mut self, backend: impl StorageBackend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Time that keys written with a TTL expire by, see [`Clock`].
+    /// Defaults to the system time.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Most verbose diagnostics the database reports, the others are dropped. They go to the
+    /// subscriber with the `tracing` feature. Without it warnings are written to stderr and the
+    /// other levels are not reported, [`LogLevel::Off`] keeps a database from writing to stderr.
+    /// Defaults to [`LogLevel::Warn`].
+    pub fn log_level(mut self, level: LogLevel) -> Self {
+        self.log_level = level;
+        self
+    }
+
+    pub fn open(&self) -> Result<Db> {
+        Db::open_with(self)
+    }
+
+    /// Opens the [`StorageEngine`] selected by [`engine`](Self::engine).
+    pub fn open_engine(&self) -> Result<Box<dyn StorageEngine>> {
+        match self.engine {
+            // The lookup tables of the Db open their key index for the engine
+            Engine::Hash | Engine::Lsm => Ok(Box::new(Db::open_with(self)?)),
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let Some(rate) = self.bloom_false_positive_rate {
+            if !(rate > 0.0 && rate < 1.0) {
+                return Err(Error::Custom(format!("bloom filter false positive rate {rate} is not between 0 and 1")));
+            }
+        }
+        if !(1..=MAX_SHARDS).contains(&self.shards) {
+            return Err(Error::Custom(format!("shard count {} is not between 1 and {MAX_SHARDS}", self.shards)));
+        }
+        if self.recovery_threads == Some(0) {
+            return Err(Error::Custom("recovery thread count must be at least 1".to_string()));
+        }
+        if !valid_block_size(self.block_size) {
+            return Err(Error::Custom(format!(
+                "block size {} is not a power of two between {MIN_BLOCK_SIZE} and {MAX_BLOCK_SIZE}", self.block_size
+            )));
+        }
+        self.compression.validate()
+    }
+
+    pub(crate) fn recovery_thread_count(&self) -> usize {
+        self.recovery_threads.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, usize::from))
+    }
+
+    pub(crate) fn folder(&self) -> Result<&Path> {
+        self.path.as_deref().ok_or_else(|| Error::Custom("no database path configured".to_string()))
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+// Keeps the value file of a compaction epoch readable while it is alive, so locations read from
+// the lookup tables in that epoch stay valid. A compaction or vacuum that replaces the file of a
+// pinned epoch retires it instead of closing it, until the last pin of the epoch is dropped.
+pub(crate) struct Pin {
+    epoch: u64,
+    registry: PinRegistry,
+}
+
+impl Pin {
+    // Compaction count of the index when the pin was taken
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        self.registry.release(self.epoch);
+    }
+}
+
+// Reference counts of the epochs that live pins hold on to
+#[derive(Clone, Default)]
+pub(crate) struct PinRegistry {
+    live: Arc<Mutex<BTreeMap<u64, usize>>>,
+}
+
+impl PinRegistry {
+    pub fn acquire(&self, epoch: u64) -> Pin {
+        *self.lock().entry(epoch).or_insert(0) += 1;
+        Pin { epoch, registry: self.clone() }
+    }
+
+    fn release(&self, epoch: u64) {
+        let mut live = self.lock();
+        if let Some(count) = live.get_mut(&epoch) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&epoch);
+            }
+        }
+    }
+
+    pub fn is_pinned(&self, epoch: u64) -> bool {
+        self.lock().contains_key(&epoch)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, usize>> {
+        self.live.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,153 @@
+use std::ops::Bound;
+use crate::db::database::Db;
+use crate::db::keys::{Element, Tuple};
+use crate::error::{Error, Result};
+
+// Column family holding the items of every queue
+const QUEUE_COLUMN_FAMILY: &str = "queues";
+
+/// Durable first-in first-out queue of byte strings returned by [`Db::queue`].
+///
+/// Items are kept in the column family `queues`, keyed by the [`Tuple`] of the queue name and a
+/// sequence number. A pop reads the first item and logs its removal under the write lock of the
+/// database, so every item is popped once, also by concurrent handles and across restarts.
+#[derive(Clone)]
+pub struct Queue {
+    db: Db,
+    name: String,
+}
+
+impl Db {
+    /// Returns the queue `name`, which is created with its first push.
+    pub fn queue(&self, name: &str) -> Queue {
+        Queue { db: self.clone(), name: name.to_string() }
+    }
+}
+
+impl Queue {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Appends `item` to the end of the queue.
+    pub fn push(&self, item: &[u8]) -> Result<()> {
+        self.db.cf(QUEUE_COLUMN_FAMILY)?;
+        let mut index = self.db.index_mut();
+        let next = match index.seek_location(Some(QUEUE_COLUMN_FAMILY), self.bounds(), true)? {
+            Some((key, _)) => self.sequence(&key)? + 1,
+            None => 0,
+        };
+        index.insert(Some(QUEUE_COLUMN_FAMILY), &self.key(next), item)
+    }
+
+    /// Removes the item at the front of the queue and returns it, `None` if the queue is empty.
+    pub fn pop(&self) -> Result<Option<Vec<u8>>> {
+        let mut index = self.db.index_mut();
+        let Some((key, location)) = index.seek_location(Some(QUEUE_COLUMN_FAMILY), self.bounds(), false)? else {
+            return Ok(None);
+        };
+        let item = index.read_value(location)?;
+        index.remove(Some(QUEUE_COLUMN_FAMILY), &key)?;
+        Ok(Some(item))
+    }
+
+    /// Returns the item at the front of the queue without removing it.
+    pub fn peek(&self) -> Result<Option<Vec<u8>>> {
+        let index = self.db.index();
+        match index.seek_location(Some(QUEUE_COLUMN_FAMILY), self.bounds(), false)? {
+            Some((_, location)) => Ok(Some(index.read_value(location)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn key(&self, sequence: u64) -> Vec<u8> {
+        Tuple::new().push(self.name.as_str()).push(sequence).encode()
+    }
+
+    // Keys of the items of this queue only. The name prefix alone also matches the items of longer
+    // names that continue it with a 0 byte.
+    fn bounds(&self) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        (Bound::Included(self.key(0)), Bound::Included(self.key(u64::MAX)))
+    }
+
+    fn sequence(&self, key: &[u8]) -> Result<u64> {
+        match Tuple::decode(key)?.elements() {
+            [_, Element::U64(sequence)] => Ok(*sequence),
+            _ => Err(Error::Serialization(format!("{} is not the key of a queue item", key.escape_ascii()))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::temp::TempDir;
+
+    #[test]
+    fn test_queue() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        let jobs = db.queue("jobs");
+        assert_eq!(jobs.pop()?, None);
+        jobs.push(b"first")?;
+        jobs.push(b"second")?;
+        db.queue("jobs2").push(b"other")?;
+        assert_eq!(jobs.peek()?, Some(b"first".to_vec()));
+        assert_eq!(jobs.pop()?, Some(b"first".to_vec()));
+        jobs.push(b"third")?;
+        drop(jobs);
+        db.crash();
+
+        // Pops are logged like any other delete
+        let db = Db::open(&dir)?;
+        let jobs = db.queue("jobs");
+        assert_eq!(jobs.pop()?, Some(b"second".to_vec()));
+        assert_eq!(jobs.pop()?, Some(b"third".to_vec()));
+        assert_eq!(jobs.pop()?, None);
+        assert_eq!(db.queue("jobs2").peek()?, Some(b"other".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_names_with_zero_bytes() -> Result<()> {
+        let db = Db::open_temp()?;
+        db.queue("q\0").push(b"other")?;
+        db.queue("q\0").push(b"other2")?;
+        let queue = db.queue("q");
+        assert_eq!(queue.pop()?, None);
+        queue.push(b"first")?;
+        queue.push(b"second")?;
+        assert_eq!(queue.pop()?, Some(b"first".to_vec()));
+        assert_eq!(queue.pop()?, Some(b"second".to_vec()));
+        assert_eq!(queue.pop()?, None);
+        assert_eq!(db.queue("q\0").pop()?, Some(b"other".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_pops() -> Result<()> {
+        let db = Db::open_temp()?;
+        let queue = db.queue("jobs");
+        for i in 0..200u32 {
+            queue.push(&i.to_le_bytes())?;
+        }
+        let threads: Vec<_> = (0..4).map(|_| {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                let mut popped = Vec::new();
+                while let Some(item) = queue.pop()? {
+                    popped.push(item);
+                }
+                Ok::<_, Error>(popped)
+            })
+        }).collect();
+        let mut popped = Vec::new();
+        for thread in threads {
+            popped.extend(thread.join().map_err(|_| "pop thread panicked")??);
+        }
+        popped.sort_by_key(|item| u32::from_le_bytes(item[..].try_into().unwrap_or_default()));
+        assert_eq!(popped, (0..200u32).map(|i| i.to_le_bytes().to_vec()).collect::<Vec<_>>());
+        Ok(())
+    }
+}
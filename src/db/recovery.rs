@@ -0,0 +1,30 @@
+use std::path::PathBuf;
+
+/// What opening the database had to recover after an unclean shutdown, see [`Db::recovery_report`].
+///
+/// [`Db::recovery_report`]: crate::Db::recovery_report
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// WAL records logged after the last flush that were applied again. A batch counts as one record.
+    pub wal_records_replayed: usize,
+    /// Bytes of torn or corrupt WAL records that were dropped, including whole segments after them.
+    pub wal_bytes_discarded: u64,
+    /// Files that were truncated, rewritten, renamed into place or removed.
+    pub repaired_files: Vec<PathBuf>,
+    /// Whether a corrupt map.db was rebuilt, see [`DbOptions::repair`](crate::DbOptions::repair).
+    pub map_rebuilt: bool,
+}
+
+impl RecoveryReport {
+    /// Whether nothing was discarded or repaired. Replaying WAL records is part of a normal open.
+    pub fn is_clean(&self) -> bool {
+        self.wal_bytes_discarded == 0 && self.repaired_files.is_empty() && !self.map_rebuilt
+    }
+
+    pub(crate) fn merge(&mut self, other: RecoveryReport) {
+        self.wal_records_replayed += other.wal_records_replayed;
+        self.wal_bytes_discarded += other.wal_bytes_discarded;
+        self.repaired_files.extend(other.repaired_files);
+        self.map_rebuilt |= other.map_rebuilt;
+    }
+}
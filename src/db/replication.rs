@@ -0,0 +1,459 @@
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use crate::db::database::Db;
+use crate::db::trace::{event, warning};
+use crate::error::{Error, Result};
+
+// How often the leader looks for new writes while a follower is caught up
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Write of a replicated record, the leader ships values instead of locations in its data file
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ReplicatedWrite {
+    Put { key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64> },
+    // Result of a merge or increment, which keeps the expiry time of the key
+    Update { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+// Writes of one WAL record of the leader, made in one keyspace under one sequence number
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ReplicatedRecord {
+    pub sequence: u64,
+    pub column_family: Option<String>,
+    pub writes: Vec<ReplicatedWrite>,
+}
+
+// Sent by the leader, framed as [body length: u64][last sequence number of the leader: u64][kind: u8][payload]
+#[derive(Debug, PartialEq)]
+enum Message {
+    // Sent while the follower is caught up, so it still learns the lag
+    Heartbeat,
+    Record(ReplicatedRecord),
+    // Every key of every keyspace, for a follower that is further behind than the WAL of the leader
+    Snapshot(Vec<ReplicatedRecord>),
+}
+
+impl ReplicatedWrite {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            ReplicatedWrite::Put{key, ..} | ReplicatedWrite::Update{key, ..} | ReplicatedWrite::Delete{key} => key,
+        }
+    }
+
+    // New value of the key, None for a delete
+    pub fn value(&self) -> Option<&[u8]> {
+        match self {
+            ReplicatedWrite::Put{value, ..} | ReplicatedWrite::Update{value, ..} => Some(value),
+            ReplicatedWrite::Delete{..} => None,
+        }
+    }
+}
+
+/// Ships the writes of a leader database to the followers that connect to it, see [`ReplicationHandle`].
+pub struct ReplicationServer {
+    db: Db,
+    listener: TcpListener,
+}
+
+impl ReplicationServer {
+    /// Listens on `address`, use port 0 for any free port and [`ReplicationServer::local_addr`] to find it.
+    pub fn bind(db: Db, address: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        Ok(Self { db, listener })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serves every follower on its own thread for as long as the listener accepts connections.
+    pub fn run(self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let db = self.db.clone();
+            // Shipping ends with an error once the follower disconnects
+            thread::spawn(move || {
+                let _ = ship(&db, stream);
+                event!(db.lookup_index().log_level(), DEBUG, "follower disconnected");
+            });
+        }
+        Ok(())
+    }
+}
+
+// Streams the writes after the sequence number the follower starts with, until it disconnects
+fn ship(db: &Db, mut stream: TcpStream) -> Result<()> {
+    let mut start = [0; 8];
+    stream.read_exact(&mut start)?;
+    let mut shipped = u64::from_le_bytes(start);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        // Read under one lock, so the records or the snapshot end exactly at the leader's sequence
+        let (leader_sequence, messages) = {
+            let index = db.index();
+            let messages = match index.replication_records(shipped)? {
+                Some(records) => records.into_iter().map(Message::Record).collect(),
+                None => vec![Message::Snapshot(index.replication_snapshot()?)],
+            };
+            (index.last_sequence(), messages)
+        };
+        if messages.is_empty() {
+            write_message(&mut writer, leader_sequence, &Message::Heartbeat)?;
+            writer.flush()?;
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+        for message in &messages {
+            write_message(&mut writer, leader_sequence, message)?;
+        }
+        writer.flush()?;
+        shipped = leader_sequence;
+    }
+}
+
+/// Keeps a follower database in sync with a leader served by a [`ReplicationServer`].
+///
+/// The follower applies the writes of the leader in order under their sequence numbers, so
+/// [`Db::last_sequence`] of both match once it caught up and following again after a restart
+/// continues where it stopped. A follower further behind than the write-ahead log of the leader
+/// is sent all of its keys instead. Writes to the follower fail until it is promoted.
+pub struct ReplicationHandle {
+    db: Db,
+    stream: TcpStream,
+    // Last sequence number of the leader, as of its latest message
+    leader_sequence: Arc<AtomicU64>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ReplicationHandle {
+    /// Connects `db` to the leader at `address` and applies its writes on a background thread.
+    pub fn follow(db: Db, leader: impl ToSocketAddrs) -> Result<Self> {
+        let mut stream = TcpStream::connect(leader)?;
+        db.index_mut().set_following(true);
+        stream.write_all(&db.last_sequence().to_le_bytes())?;
+        let leader_sequence = Arc::new(AtomicU64::new(db.last_sequence()));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let reader = BufReader::new(stream.try_clone()?);
+        let thread = {
+            let (db, leader_sequence, stopped) = (db.clone(), Arc::clone(&leader_sequence), Arc::clone(&stopped));
+            thread::spawn(move || {
+                if let Err(error) = apply(&db, reader, &leader_sequence) {
+                    if !stopped.load(Ordering::SeqCst) {
+                        warning!(db.lookup_index().log_level(), error = %error; "stopped following the leader");
+                    }
+                }
+            })
+        };
+        Ok(Self { db, stream, leader_sequence, stopped, thread: Some(thread) })
+    }
+
+    /// Number of writes the follower is behind the leader, as of the last message from the leader.
+    pub fn lag(&self) -> u64 {
+        self.leader_sequence.load(Ordering::SeqCst).saturating_sub(self.db.last_sequence())
+    }
+
+    /// Whether the follower is still connected to the leader and applying its writes.
+    pub fn is_connected(&self) -> bool {
+        self.thread.as_ref().is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Stops following and lets the database accept writes, for example after the leader failed.
+    /// Dropping the handle does the same.
+    pub fn promote(mut self) -> Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes up the thread blocked on reading from the leader
+        let _ = self.stream.shutdown(Shutdown::Both);
+        if let Some(thread) = self.thread.take() {
+            thread.join().map_err(|_| "replication thread panicked")?;
+        }
+        self.db.index_mut().set_following(false);
+        Ok(())
+    }
+}
+
+impl Drop for ReplicationHandle {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+// Applies the messages of the leader until the connection ends
+fn apply(db: &Db, mut reader: impl Read, leader_sequence: &AtomicU64) -> Result<()> {
+    while let Some((sequence, message)) = read_message(&mut reader)? {
+        match message {
+            Message::Heartbeat => {}
+            Message::Record(record) => db.index_mut().apply_replicated(record)?,
+            Message::Snapshot(records) => db.index_mut().apply_snapshot(records)?,
+        }
+        leader_sequence.store(sequence, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+fn write_message(writer: &mut impl Write, leader_sequence: u64, message: &Message) -> Result<()> {
+    let mut body = leader_sequence.to_le_bytes().to_vec();
+    match message {
+        Message::Heartbeat => body.push(0),
+        Message::Record(record) => {
+            body.push(1);
+            encode_record(&mut body, record);
+        }
+        Message::Snapshot(records) => {
+            body.push(2);
+            body.extend_from_slice(&(records.len() as u32).to_le_bytes());
+            for record in records {
+                encode_record(&mut body, record);
+            }
+        }
+    }
+    writer.write_all(&(body.len() as u64).to_le_bytes())?;
+    writer.write_all(&body)?;
+    Ok(())
+}
+
+// None once the leader closed the connection between messages
+fn read_message(reader: &mut impl Read) -> Result<Option<(u64, Message)>> {
+    let mut length = [0; 8];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error.into()),
+    }
+    let length = u64::from_le_bytes(length);
+    let mut body = Vec::new();
+    reader.take(length).read_to_end(&mut body)?;
+    if body.len() as u64 != length {
+        return Err(malformed());
+    }
+    decode_message(&body).ok_or_else(malformed).map(Some)
+}
+
+fn malformed() -> Error {
+    Error::InvalidFormat("malformed replication message".to_string())
+}
+
+fn decode_message(body: &[u8]) -> Option<(u64, Message)> {
+    let mut fields = Fields(body);
+    let leader_sequence = fields.u64()?;
+    let message = match fields.u8()? {
+        0 => Message::Heartbeat,
+        1 => Message::Record(fields.record()?),
+        2 => {
+            let count = fields.u32()?;
+            Message::Snapshot((0..count).map(|_| fields.record()).collect::<Option<_>>()?)
+        }
+        _ => return None,
+    };
+    fields.0.is_empty().then_some((leader_sequence, message))
+}
+
+// [sequence: u64][column family: u8 flag, then the name if set][write count: u32][writes]
+// A write is [kind: u8][key] followed by the value for all but deletes and the expiry time for kind 1
+fn encode_record(body: &mut Vec<u8>, record: &ReplicatedRecord) {
+    body.extend_from_slice(&record.sequence.to_le_bytes());
+    match &record.column_family {
+        Some(name) => {
+            body.push(1);
+            encode_bytes(body, name.as_bytes());
+        }
+        None => body.push(0),
+    }
+    body.extend_from_slice(&(record.writes.len() as u32).to_le_bytes());
+    for write in &record.writes {
+        match write {
+            ReplicatedWrite::Put{expires_at: None, ..} => body.push(0),
+            ReplicatedWrite::Put{expires_at: Some(_), ..} => body.push(1),
+            ReplicatedWrite::Update{..} => body.push(2),
+            ReplicatedWrite::Delete{..} => body.push(3),
+        }
+        encode_bytes(body, write.key());
+        if let Some(value) = write.value() {
+            encode_bytes(body, value);
+        }
+        if let ReplicatedWrite::Put{expires_at: Some(expires_at), ..} = write {
+            body.extend_from_slice(&expires_at.to_le_bytes());
+        }
+    }
+}
+
+fn encode_bytes(body: &mut Vec<u8>, bytes: &[u8]) {
+    body.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    body.extend_from_slice(bytes);
+}
+
+// Fields read from the front of a message body, None once they run past its end
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        let field = self.0.get(..length)?;
+        self.0 = &self.0[length..];
+        Some(field)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn bytes(&mut self) -> Option<Vec<u8>> {
+        let length = self.u32()? as usize;
+        Some(self.take(length)?.to_vec())
+    }
+
+    fn record(&mut self) -> Option<ReplicatedRecord> {
+        let sequence = self.u64()?;
+        let column_family = match self.u8()? {
+            0 => None,
+            1 => Some(String::from_utf8(self.bytes()?).ok()?),
+            _ => return None,
+        };
+        let count = self.u32()?;
+        let writes = (0..count).map(|_| {
+            let kind = self.u8()?;
+            let key = self.bytes()?;
+            Some(match kind {
+                0 => ReplicatedWrite::Put { key, value: self.bytes()?, expires_at: None },
+                1 => ReplicatedWrite::Put { key, value: self.bytes()?, expires_at: Some(self.u64()?) },
+                2 => ReplicatedWrite::Update { key, value: self.bytes()? },
+                3 => ReplicatedWrite::Delete { key },
+                _ => return None,
+            })
+        }).collect::<Option<_>>()?;
+        Some(ReplicatedRecord { sequence, column_family, writes })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::batch::WriteBatch;
+    use crate::db::index::IndexError;
+    use crate::db::temp::TempDir;
+    use std::time::Instant;
+
+    fn entries(db: &Db, column_family: Option<&str>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match column_family {
+            Some(name) => db.cf(name)?.iter().collect(),
+            None => db.iter().collect(),
+        }
+    }
+
+    // Waits until the follower applied every write the leader made so far
+    fn catch_up(leader: &Db, follower: &Db, handle: &ReplicationHandle) -> Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while follower.last_sequence() != leader.last_sequence() || handle.lag() != 0 {
+            assert!(Instant::now() < deadline, "follower stuck at {} of {}", follower.last_sequence(), leader.last_sequence());
+            thread::sleep(Duration::from_millis(5));
+        }
+        for column_family in [None, Some("users")] {
+            assert_eq!(entries(follower, column_family)?, entries(leader, column_family)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_replication() -> Result<()> {
+        let leader = Db::open_temp()?;
+        let server = ReplicationServer::bind(leader.clone(), "127.0.0.1:0")?;
+        let address = server.local_addr()?;
+        thread::spawn(move || server.run());
+        let users = leader.cf("users")?;
+        leader.put(b"flushed", b"1")?;
+        users.put(b"ada", b"admin")?;
+        leader.flush()?;
+        leader.put(b"logged", b"2")?;
+
+        // Behind the WAL of the leader, the follower starts from a snapshot
+        let dir = TempDir::new()?;
+        let follower = Db::open(&dir)?;
+        let handle = ReplicationHandle::follow(follower.clone(), address)?;
+        catch_up(&leader, &follower, &handle)?;
+        assert!(matches!(follower.put(b"local", b"3"), Err(Error::Index(IndexError::Follower))));
+
+        leader.write(WriteBatch::new().put(b"batched", b"4").delete(b"flushed").clone())?;
+        leader.increment(b"count", 5)?;
+        leader.put_with_ttl(b"expiring", b"6", Duration::from_secs(60))?;
+        users.put(b"bob", b"guest")?;
+        catch_up(&leader, &follower, &handle)?;
+        assert_eq!(follower.get(b"count")?, Some(5u64.to_le_bytes().to_vec()));
+
+        // Following again after a restart continues from the WAL of the follower
+        drop(handle);
+        drop(follower);
+        leader.delete(b"logged")?;
+        users.delete(b"ada")?;
+        let follower = Db::open(&dir)?;
+        let handle = ReplicationHandle::follow(follower.clone(), address)?;
+        catch_up(&leader, &follower, &handle)?;
+        assert!(handle.is_connected());
+
+        // A snapshot removes the keys the leader deleted meanwhile
+        drop(handle);
+        leader.delete(b"batched")?;
+        leader.flush()?;
+        let handle = ReplicationHandle::follow(follower.clone(), address)?;
+        catch_up(&leader, &follower, &handle)?;
+        assert_eq!(follower.get(b"batched")?, None);
+
+        handle.promote()?;
+        follower.put(b"local", b"7")?;
+        assert_eq!(follower.last_sequence(), leader.last_sequence() + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_messages() -> Result<()> {
+        let record = |sequence, column_family: Option<&str>| ReplicatedRecord {
+            sequence,
+            column_family: column_family.map(str::to_string),
+            writes: vec![
+                ReplicatedWrite::Put { key: b"a".to_vec(), value: b"1".to_vec(), expires_at: None },
+                ReplicatedWrite::Put { key: b"b".to_vec(), value: Vec::new(), expires_at: Some(u64::MAX) },
+                ReplicatedWrite::Update { key: Vec::new(), value: b"3".to_vec() },
+                ReplicatedWrite::Delete { key: b"d".to_vec() },
+            ],
+        };
+        let messages = [
+            Message::Heartbeat,
+            Message::Record(record(7, Some("users"))),
+            Message::Snapshot(vec![record(9, None), record(9, Some("users"))]),
+        ];
+        let mut stream = Vec::new();
+        for message in &messages {
+            write_message(&mut stream, 9, message)?;
+        }
+        let mut reader = stream.as_slice();
+        for message in messages {
+            assert_eq!(read_message(&mut reader)?, Some((9, message)));
+        }
+        assert_eq!(read_message(&mut reader)?, None);
+
+        // Cut off messages and unknown kinds are rejected
+        for len in 0..stream.len() {
+            let _ = read_message(&mut &stream[..len]);
+        }
+        assert!(read_message(&mut &stream[17..40]).is_err());
+        stream[16] = 9;
+        assert!(read_message(&mut stream.as_slice()).is_err());
+        Ok(())
+    }
+}
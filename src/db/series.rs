@@ -0,0 +1,63 @@
+use std::ops::Range;
+use std::time::SystemTime;
+use crate::db::database::Db;
+use crate::db::keys::{Element, Tuple};
+use crate::error::{Error, Result};
+
+// Column family holding the points of every series
+const SERIES_COLUMN_FAMILY: &str = "series";
+
+impl Db {
+    /// Stores `value` as the point of series `series_id` at `timestamp`, replacing a point at the same time.
+    ///
+    /// Series are kept in the column family `series`, keyed by the [`Tuple`] of the series id and the
+    /// time of the point, so the points of a series sort by time. Points appended in time order are
+    /// also next to each other in the data file, a time window takes few blocks to read.
+    pub fn append_series(&self, series_id: &str, timestamp: SystemTime, value: &[u8]) -> Result<()> {
+        self.cf(SERIES_COLUMN_FAMILY)?.put(&Db::series_key(series_id, timestamp), value)
+    }
+
+    /// Reads the points of series `series_id` from `range.start` up to `range.end`, in time order.
+    ///
+    /// Every block of the data file holding one of them is read once.
+    pub fn query_series(&self, series_id: &str, range: Range<SystemTime>) -> Result<Vec<(SystemTime, Vec<u8>)>> {
+        let keys = Db::series_key(series_id, range.start)..Db::series_key(series_id, range.end);
+        self.index().range_values(Some(SERIES_COLUMN_FAMILY), keys)?.into_iter()
+            .map(|(key, value)| match Tuple::decode(&key)?.elements() {
+                [_, Element::Timestamp(timestamp)] => Ok((*timestamp, value)),
+                _ => Err(Error::Serialization(format!("{} is not the key of a series point", key.escape_ascii()))),
+            })
+            .collect()
+    }
+
+    fn series_key(series_id: &str, timestamp: SystemTime) -> Vec<u8> {
+        Tuple::new().push(series_id).push(timestamp).encode()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_series() -> Result<()> {
+        let db = Db::open_temp()?;
+        let at = |seconds: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + seconds);
+        for seconds in [30, 10, 20, 40] {
+            db.append_series("cpu", at(seconds), format!("{seconds}").as_bytes())?;
+        }
+        // A longer id that starts with the other one, and a point at the same time replaced
+        db.append_series("cpu2", at(20), b"other")?;
+        db.append_series("cpu", at(40), b"last")?;
+
+        let points = db.query_series("cpu", at(10)..at(40))?;
+        assert_eq!(points, [(at(10), b"10".to_vec()), (at(20), b"20".to_vec()), (at(30), b"30".to_vec())]);
+        assert_eq!(db.query_series("cpu", at(35)..at(60))?, [(at(40), b"last".to_vec())]);
+        assert_eq!(db.query_series("cpu2", at(0)..at(60))?, [(at(20), b"other".to_vec())]);
+        assert!(db.query_series("mem", at(0)..at(60))?.is_empty());
+        assert!(Db::open_temp()?.query_series("cpu", at(0)..at(60))?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,421 @@
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use crate::db::database::Db;
+use crate::error::{Error, Result};
+
+// Keys returned by SCAN without a COUNT option, the default of Redis
+const DEFAULT_SCAN_COUNT: usize = 10;
+// Longest bulk string accepted from a client
+const MAX_BULK_LENGTH: usize = 512 * 1024 * 1024;
+// Longest a metrics scrape may wait on its client
+const METRICS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TCP server speaking the subset of the Redis protocol (RESP) that simple key/value clients need.
+///
+/// Supports `GET`, `SET` with `EX` and `PX`, `DEL`, `SCAN` with `MATCH` and `COUNT`, `PING`,
+/// `QUIT` and `SHUTDOWN`. The `SCAN` cursor is the number of keys before the next batch in key
+/// order, so keys written or deleted between calls can shift it.
+///
+/// With [`Server::serve_metrics`] it also answers `GET /metrics` over HTTP on a port of its own,
+/// with [`Db::stats`] in the Prometheus text format.
+pub struct Server {
+    db: Db,
+    listener: TcpListener,
+    metrics: Option<TcpListener>,
+    shutdown: Arc<AtomicBool>,
+}
+
+// Reply to a command
+enum Reply {
+    Ok,
+    Pong,
+    Error(String),
+    Integer(u64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Server {
+    /// Listens on `address`, use port 0 for any free port and [`Server::local_addr`] to find it.
+    pub fn bind(db: Db, address: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        Ok(Self { db, listener, metrics: None, shutdown: Arc::new(AtomicBool::new(false)) })
+    }
+
+    /// Also listens for HTTP scrapes of `/metrics` on `address`, see
+    /// [`Stats::to_prometheus`](crate::Stats::to_prometheus).
+    pub fn serve_metrics(mut self, address: impl ToSocketAddrs) -> Result<Self> {
+        self.metrics = Some(TcpListener::bind(address)?);
+        Ok(self)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Address of the metrics endpoint, None without [`Server::serve_metrics`].
+    pub fn metrics_addr(&self) -> Result<Option<SocketAddr>> {
+        Ok(self.metrics.as_ref().map(TcpListener::local_addr).transpose()?)
+    }
+
+    /// Serves every connection on its own thread until a client sends `SHUTDOWN`,
+    /// then waits for the open connections to close.
+    pub fn run(self) -> Result<()> {
+        let address = self.local_addr()?;
+        let metrics_address = self.metrics_addr()?;
+        let metrics = self.metrics.map(|listener| {
+            let (db, shutdown) = (self.db.clone(), Arc::clone(&self.shutdown));
+            thread::spawn(move || serve_metrics(&db, listener, &shutdown))
+        });
+        let mut connections: Vec<JoinHandle<()>> = Vec::new();
+        for stream in self.listener.incoming() {
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let Ok(stream) = stream else { continue };
+            let (db, shutdown) = (self.db.clone(), Arc::clone(&self.shutdown));
+            connections.retain(|connection| !connection.is_finished());
+            connections.push(thread::spawn(move || {
+                if let Ok(true) = serve_connection(&db, stream) {
+                    shutdown.store(true, Ordering::SeqCst);
+                    // Wakes up the accept loop so it sees the flag
+                    let _ = TcpStream::connect_timeout(&address, Duration::from_secs(1));
+                }
+            }));
+        }
+        if let (Some(metrics), Some(metrics_address)) = (metrics, metrics_address) {
+            let _ = TcpStream::connect_timeout(&metrics_address, Duration::from_secs(1));
+            metrics.join().map_err(|_| "metrics thread panicked")?;
+        }
+        for connection in connections {
+            connection.join().map_err(|_| "connection thread panicked")?;
+        }
+        Ok(())
+    }
+}
+
+// Answers HTTP requests one at a time until the server shuts down. Scrapes are rare and small,
+// a client that stalls is cut off by the timeout.
+fn serve_metrics(db: &Db, listener: TcpListener, shutdown: &AtomicBool) {
+    for stream in listener.incoming() {
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        let Ok(stream) = stream else { continue };
+        let _ = stream.set_read_timeout(Some(METRICS_TIMEOUT));
+        let _ = stream.set_write_timeout(Some(METRICS_TIMEOUT));
+        let _ = serve_scrape(db, stream);
+    }
+}
+
+fn serve_scrape(db: &Db, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Headers are not needed, but are read so that closing does not reset the connection
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+    let (status, body) = match request.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", "/metrics", _] => match db.stats() {
+            Ok(stats) => ("200 OK", stats.to_prometheus()),
+            Err(e) => ("500 Internal Server Error", format!("{}\n", error_message(e))),
+        },
+        [_, "/metrics", _] => ("405 Method Not Allowed", String::new()),
+        _ => ("404 Not Found", String::new()),
+    };
+    let mut writer = BufWriter::new(stream);
+    write!(writer, "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\n")?;
+    write!(writer, "Content-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Answers the commands of one client until it disconnects, returns whether it asked for a shutdown
+fn serve_connection(db: &Db, stream: TcpStream) -> Result<bool> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let arguments = match read_command(&mut reader) {
+            Ok(Some(arguments)) => arguments,
+            Ok(None) => return Ok(false),
+            Err(e) => {
+                write_reply(&mut writer, &Reply::Error(format!("ERR Protocol error: {}", error_message(e))))?;
+                writer.flush()?;
+                return Ok(false);
+            }
+        };
+        if arguments.is_empty() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&arguments[0]).to_ascii_uppercase();
+        let reply = match name.as_str() {
+            "QUIT" | "SHUTDOWN" => Reply::Ok,
+            _ => execute(db, &name, &arguments[1..])
+                .unwrap_or_else(|e| Reply::Error(format!("ERR {}", error_message(e)))),
+        };
+        write_reply(&mut writer, &reply)?;
+        writer.flush()?;
+        match name.as_str() {
+            "QUIT" => return Ok(false),
+            "SHUTDOWN" => return Ok(true),
+            _ => {}
+        }
+    }
+}
+
+fn execute(db: &Db, name: &str, arguments: &[Vec<u8>]) -> Result<Reply> {
+    match (name, arguments) {
+        ("PING", []) => Ok(Reply::Pong),
+        ("PING", [message]) => Ok(Reply::Bulk(Some(message.clone()))),
+        ("GET", [key]) => Ok(Reply::Bulk(db.get(key)?)),
+        ("SET", [key, value, options @ ..]) => {
+            match options {
+                [] => db.put(key, value)?,
+                [unit, amount] => {
+                    let amount: u64 = parse_number(amount)?;
+                    let ttl = match String::from_utf8_lossy(unit).to_ascii_uppercase().as_str() {
+                        "EX" => Duration::from_secs(amount),
+                        "PX" => Duration::from_millis(amount),
+                        _ => return Err(Error::Custom("syntax error".to_string())),
+                    };
+                    db.put_with_ttl(key, value, ttl)?;
+                }
+                _ => return Err(Error::Custom("syntax error".to_string())),
+            }
+            Ok(Reply::Ok)
+        }
+        ("DEL", keys @ [_, ..]) => {
+            let mut deleted = 0;
+            for key in keys {
+                if db.get(key)?.is_some() {
+                    db.delete(key)?;
+                    deleted += 1;
+                }
+            }
+            Ok(Reply::Integer(deleted))
+        }
+        ("SCAN", [cursor, options @ ..]) => scan(db, parse_number(cursor)?, options),
+        // Sent by redis-cli when it connects
+        ("COMMAND", _) => Ok(Reply::Array(Vec::new())),
+        ("PING" | "GET" | "SET" | "DEL" | "SCAN", _) => {
+            Err(Error::Custom(format!("wrong number of arguments for '{}' command", name.to_lowercase())))
+        }
+        _ => Err(Error::Custom(format!("unknown command '{}'", name.to_lowercase()))),
+    }
+}
+
+// Command errors are sent as their message, database errors in their debug form
+fn error_message(error: Error) -> String {
+    match error {
+        Error::Custom(message) => message,
+        error => error.to_string(),
+    }
+}
+
+// Returns the keys matching the MATCH pattern among the next COUNT keys after the first `start`
+fn scan(db: &Db, start: u64, options: &[Vec<u8>]) -> Result<Reply> {
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in options.chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = Some(value.as_slice()),
+            [name, value] if name.eq_ignore_ascii_case(b"COUNT") => count = parse_number(value)?,
+            _ => return Err(Error::Custom("syntax error".to_string())),
+        }
+    }
+    let mut cursor = db.cursor();
+    let mut valid = cursor.seek_to_first();
+    for _ in 0..start {
+        valid = valid && cursor.next();
+    }
+    let mut keys = Vec::new();
+    let mut position = start;
+    while valid && position < start + count.max(1) as u64 {
+        let key = cursor.key().unwrap_or_default();
+        if pattern.is_none_or(|pattern| glob_match(pattern, key)) {
+            keys.push(Reply::Bulk(Some(key.to_vec())));
+        }
+        position += 1;
+        valid = cursor.next();
+    }
+    // Cursor 0 ends the iteration
+    let next = if valid { position } else { 0 };
+    Ok(Reply::Array(vec![Reply::Bulk(Some(next.to_string().into_bytes())), Reply::Array(keys)]))
+}
+
+// Redis glob patterns with `*`, `?` and `\` escapes, without character classes
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|skip| glob_match(rest, &key[skip..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_match(rest, &key[1..]),
+        Some((b'\\', [escaped, rest @ ..])) => key.first() == Some(escaped) && glob_match(rest, &key[1..]),
+        Some((byte, rest)) => key.first() == Some(byte) && glob_match(rest, &key[1..]),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(bytes: &[u8]) -> Result<T> {
+    std::str::from_utf8(bytes).ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(|| Error::Custom("value is not an integer or out of range".to_string()))
+}
+
+// Reads an array of bulk strings, or an inline command split at whitespace.
+// Returns None once the client closed the connection.
+fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else { return Ok(None) };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let arguments = line.split(|byte| byte.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(arguments));
+    };
+    let count: usize = parse_number(count)?;
+    let mut arguments = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or("connection closed inside a command")?;
+        let length: usize = parse_number(header.strip_prefix(b"$").ok_or("expected a bulk string")?)?;
+        if length > MAX_BULK_LENGTH {
+            return Err(Error::Custom("bulk string too long".to_string()));
+        }
+        let mut argument = vec![0; length + 2];
+        reader.read_exact(&mut argument)?;
+        if !argument.ends_with(b"\r\n") {
+            return Err(Error::Custom("bulk string without CRLF".to_string()));
+        }
+        argument.truncate(length);
+        arguments.push(argument);
+    }
+    Ok(Some(arguments))
+}
+
+// Line without its CRLF, None at the end of the stream
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    while line.last().is_some_and(|byte| *byte == b'\n' || *byte == b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn write_reply(writer: &mut impl Write, reply: &Reply) -> Result<()> {
+    match reply {
+        Reply::Ok => writer.write_all(b"+OK\r\n")?,
+        Reply::Pong => writer.write_all(b"+PONG\r\n")?,
+        Reply::Error(message) => write!(writer, "-{}\r\n", message.replace(['\r', '\n'], " "))?,
+        Reply::Integer(value) => write!(writer, ":{value}\r\n")?,
+        Reply::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+        Reply::Bulk(Some(bytes)) => {
+            write!(writer, "${}\r\n", bytes.len())?;
+            writer.write_all(bytes)?;
+            writer.write_all(b"\r\n")?;
+        }
+        Reply::Array(replies) => {
+            write!(writer, "*{}\r\n", replies.len())?;
+            for reply in replies {
+                write_reply(writer, reply)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    // Sends a command as an array of bulk strings and reads back the raw reply
+    fn send(stream: &mut TcpStream, arguments: &[&str], reply_length: usize) -> Result<String> {
+        let mut command = format!("*{}\r\n", arguments.len());
+        for argument in arguments {
+            command.push_str(&format!("${}\r\n{argument}\r\n", argument.len()));
+        }
+        stream.write_all(command.as_bytes())?;
+        let mut reply = vec![0; reply_length];
+        stream.read_exact(&mut reply)?;
+        Ok(String::from_utf8_lossy(&reply).into_owned())
+    }
+
+    #[test]
+    fn test_server() -> Result<()> {
+        let db = Db::open_temp()?;
+        let server = Server::bind(db.clone(), "127.0.0.1:0")?;
+        let address = server.local_addr()?;
+        let running = thread::spawn(move || server.run());
+
+        let mut stream = TcpStream::connect(address)?;
+        assert_eq!(send(&mut stream, &["PING"], 7)?, "+PONG\r\n");
+        assert_eq!(send(&mut stream, &["SET", "a", "1"], 5)?, "+OK\r\n");
+        assert_eq!(send(&mut stream, &["set", "b", "22", "EX", "60"], 5)?, "+OK\r\n");
+        assert_eq!(send(&mut stream, &["SET", "c", "3"], 5)?, "+OK\r\n");
+        assert_eq!(send(&mut stream, &["GET", "b"], 8)?, "$2\r\n22\r\n");
+        assert_eq!(send(&mut stream, &["GET", "x"], 5)?, "$-1\r\n");
+        assert_eq!(send(&mut stream, &["SCAN", "0", "COUNT", "2"], 29)?, "*2\r\n$1\r\n2\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert_eq!(send(&mut stream, &["SCAN", "2", "MATCH", "?"], 22)?, "*2\r\n$1\r\n0\r\n*1\r\n$1\r\nc\r\n");
+        assert_eq!(send(&mut stream, &["DEL", "a", "x", "c"], 4)?, ":2\r\n");
+        assert_eq!(send(&mut stream, &["GET"], 50)?, "-ERR wrong number of arguments for 'get' command\r\n");
+        stream.write_all(b"\r\nPING\r\n")?;
+        let mut pong = [0; 7];
+        stream.read_exact(&mut pong)?;
+        assert_eq!(&pong, b"+PONG\r\n");
+        assert_eq!(db.get(b"b")?, Some(b"22".to_vec()));
+        assert_eq!(db.get(b"a")?, None);
+
+        assert_eq!(send(&mut stream, &["SHUTDOWN"], 5)?, "+OK\r\n");
+        drop(stream);
+        running.join().map_err(|_| "server thread panicked")??;
+        db.destroy()
+    }
+
+    #[test]
+    fn test_metrics_endpoint() -> Result<()> {
+        let db = Db::open_temp()?;
+        db.put(b"a", b"1")?;
+        db.get(b"a")?;
+        let server = Server::bind(db.clone(), "127.0.0.1:0")?.serve_metrics("127.0.0.1:0")?;
+        let (address, metrics_address) = (server.local_addr()?, server.metrics_addr()?.ok_or("no metrics address")?);
+        let running = thread::spawn(move || server.run());
+
+        let scrape = |request: &str| -> Result<String> {
+            let mut stream = TcpStream::connect(metrics_address)?;
+            stream.write_all(request.as_bytes())?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        };
+        let response = scrape("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\n\r\n# TYPE cendb_puts_total counter\ncendb_puts_total 1\n"));
+        assert!(response.contains("cendb_get_latency_seconds_count 1\n"));
+        assert!(response.contains("cendb_wal_size_bytes "));
+        assert!(scrape("GET / HTTP/1.1\r\n\r\n")?.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let mut stream = TcpStream::connect(address)?;
+        assert_eq!(send(&mut stream, &["SHUTDOWN"], 5)?, "+OK\r\n");
+        drop(stream);
+        running.join().map_err(|_| "server thread panicked")??;
+        db.destroy()
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"user:*", b"user:1"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"a?c", b"abc"));
+        assert!(!glob_match(b"a?c", b"ac"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+    }
+}
@@ -0,0 +1,654 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use crate::db::backend::{OpenMode, StorageBackend};
+use crate::db::header::{FileHeader, HEADER_SIZE, SHARDS_MAGIC};
+use crate::db::lookup::{EntryLocation, LookupTable, Relocation, WalOperation};
+use crate::db::options::{DbOptions, LogLevel};
+use crate::db::recovery::RecoveryReport;
+use crate::db::snapshot::{Snapshot, SnapshotRegistry};
+use crate::db::sync::SyncPolicy;
+use crate::error::{Error, Result};
+
+// Keys of a keyspace spread over lookup tables by a hash of the key, each with its own map and WAL.
+// The first shard lives in the folder of the keyspace, so a keyspace with a single shard keeps the
+// layout it had before sharding existed, the others in shard-<index> subfolders. shards.db records
+// the count of a keyspace created with more than one.
+//
+// A batch whose keys fall to several shards is logged as one part per shard under a shared sequence
+// number, every part naming the shards that got one. Opening replays a part only if each of those
+// shards logged it, a crash in between leaves the shards that missed theirs without any later record.
+pub(crate) struct ShardedTable {
+    shards: Vec<LookupTable>,
+    folder: PathBuf,
+}
+
+const SHARDS_FILE_NAME: &str = "shards.db";
+const SHARD_COUNT_SIZE: usize = 4;
+
+impl ShardedTable {
+    pub fn open(folder: &Path, options: &DbOptions) -> Result<Self> {
+        let backend = options.backend.as_ref();
+        let existed = backend.exists(&folder.join("map.db"));
+        // Loading the first shard takes the directory lock, nothing is reset before that
+        let first = LookupTable::load(folder, options)?;
+        if options.reset {
+            ShardedTable::remove_shards(backend, folder)?;
+        }
+        let count = match ShardedTable::read_count(backend, folder)? {
+            Some(count) => count,
+            None if (existed && !options.reset) || options.read_only || options.shards == 1 => 1,
+            None => {
+                ShardedTable::write_count(backend, folder, options.shards, options.block_size)?;
+                options.shards
+            }
+        };
+        let mut loaded = vec![first];
+        for shard in 1..count {
+            loaded.push(LookupTable::load(&ShardedTable::shard_folder(folder, shard), options)?);
+        }
+        let last_lsns: Vec<u64> = loaded.iter().map(|(table, _)| table.applied_lsn()).collect();
+        let mut shards = Vec::with_capacity(count);
+        for (mut table, parts) in loaded {
+            let discarded: BTreeSet<u64> = parts.into_iter()
+                .filter(|(lsn, logged_by)| logged_by.iter().any(|shard| {
+                    last_lsns.get(*shard as usize).is_none_or(|last| last < lsn)
+                }))
+                .map(|(lsn, _)| lsn)
+                .collect();
+            table.replay(&discarded, options)?;
+            shards.push(table);
+        }
+        Ok(Self { shards, folder: folder.to_path_buf() })
+    }
+
+    fn shard_folder(folder: &Path, shard: usize) -> PathBuf {
+        folder.join(format!("shard-{shard:03}"))
+    }
+
+    // Folders of every shard but the first
+    fn shard_folders(backend: &dyn StorageBackend, folder: &Path) -> Result<Vec<PathBuf>> {
+        if !backend.exists(folder) {
+            return Ok(Vec::new());
+        }
+        Ok(backend.list(folder)?.into_iter()
+            .filter(|path| backend.is_dir(path))
+            .filter(|path| path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with("shard-")))
+            .collect())
+    }
+
+    fn read_count(backend: &dyn StorageBackend, folder: &Path) -> Result<Option<usize>> {
+        let path = folder.join(SHARDS_FILE_NAME);
+        if !backend.exists(&path) {
+            return Ok(None);
+        }
+        let bytes = backend.read(&path)?;
+        FileHeader::decode(&bytes, SHARDS_MAGIC)?;
+        let count = bytes.get(HEADER_SIZE..HEADER_SIZE + SHARD_COUNT_SIZE)
+            .ok_or_else(|| Error::InvalidFormat(format!("truncated {}", path.display())))?;
+        match u32::from_le_bytes(count.try_into()?) {
+            0 => Err(Error::InvalidFormat(format!("no shards in {}", path.display()))),
+            count => Ok(Some(count as usize)),
+        }
+    }
+
+    fn write_count(backend: &dyn StorageBackend, folder: &Path, count: usize, block_size: usize) -> Result<()> {
+        let path = folder.join(SHARDS_FILE_NAME);
+        let mut buffer = FileHeader::new(SHARDS_MAGIC, block_size).encode().to_vec();
+        buffer.extend_from_slice(&(count as u32).to_le_bytes());
+        let file = backend.open(&path, OpenMode::Truncate)?;
+        file.write_at(&buffer, 0)?;
+        file.sync()?;
+        backend.sync_dir(folder)?;
+        Ok(())
+    }
+
+    fn remove_shards(backend: &dyn StorageBackend, folder: &Path) -> Result<()> {
+        let path = folder.join(SHARDS_FILE_NAME);
+        if backend.exists(&path) {
+            backend.remove_file(&path)?;
+        }
+        for path in ShardedTable::shard_folders(backend, folder)? {
+            backend.remove_dir_all(&path)?;
+        }
+        Ok(())
+    }
+
+    // Utility function to delete every shard of the keyspace in folder
+    pub fn cleanup(backend: &dyn StorageBackend, folder: &Path, log_level: LogLevel) -> Result<()> {
+        LookupTable::cleanup(backend, folder, log_level)?;
+        ShardedTable::remove_shards(backend, folder)
+    }
+
+    // Keeps the WAL records of every shard in folder up to sequence number lsn, see LookupTable::truncate_wal
+    pub fn truncate_wal(backend: &dyn StorageBackend, folder: &Path, lsn: u64) -> Result<()> {
+        LookupTable::truncate_wal(backend, folder, lsn)?;
+        for path in ShardedTable::shard_folders(backend, folder)? {
+            LookupTable::truncate_wal(backend, &path, lsn)?;
+        }
+        Ok(())
+    }
+
+    // Stable across runs and platforms, the shard of a key must not change once it was written
+    fn shard_of(&self, key: &[u8]) -> usize {
+        crc32fast::hash(key) as usize % self.shards.len()
+    }
+
+    fn shard(&self, key: &[u8]) -> &LookupTable {
+        &self.shards[self.shard_of(key)]
+    }
+
+    fn shard_mut(&mut self, key: &[u8]) -> &mut LookupTable {
+        let shard = self.shard_of(key);
+        &mut self.shards[shard]
+    }
+
+    pub fn shards(&self) -> impl Iterator<Item = &LookupTable> {
+        self.shards.iter()
+    }
+
+    pub fn share(&mut self, lsn: &Arc<AtomicU64>, snapshots: &SnapshotRegistry) {
+        for table in &mut self.shards {
+            table.share(lsn, snapshots);
+        }
+    }
+
+    pub fn recovery(&self) -> RecoveryReport {
+        let mut recovery = RecoveryReport::default();
+        for table in &self.shards {
+            recovery.merge(table.recovery().clone());
+        }
+        recovery
+    }
+
+    pub fn now(&self) -> u64 {
+        self.shards[0].now()
+    }
+
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) {
+        for table in &mut self.shards {
+            table.set_sync_policy(sync_policy);
+        }
+    }
+
+    pub fn add(&mut self, key: &[u8], location: EntryLocation) -> Result<()> {
+        self.shard_mut(key).add(key, location)
+    }
+
+    pub fn add_expiring(&mut self, key: &[u8], location: EntryLocation, expires_at: u64) -> Result<()> {
+        self.shard_mut(key).add_expiring(key, location, expires_at)
+    }
+
+    pub fn merge(&mut self, key: &[u8], location: EntryLocation) -> Result<()> {
+        self.shard_mut(key).merge(key, location)
+    }
+
+    pub fn increment(&mut self, key: &[u8], location: EntryLocation) -> Result<()> {
+        self.shard_mut(key).increment(key, location)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.shard_mut(key).remove(key)
+    }
+
+    // Logs all operations so that after a crash either all or none are replayed
+    pub fn write_batch(&mut self, operations: Vec<WalOperation>) -> Result<()> {
+        let mut parts = self.split(operations);
+        match parts.len() {
+            0 => Ok(()),
+            1 => {
+                let (shard, operations) = parts.pop_first().ok_or("missing batch part")?;
+                self.shards[shard].write_batch(operations)
+            }
+            _ => {
+                let lsn = self.shards[0].next_lsn();
+                self.write_parts(lsn, parts)
+            }
+        }
+    }
+
+    // Logs operations under the given sequence number, see LookupTable::write_batch_at
+    pub fn write_batch_at(&mut self, lsn: u64, operations: Vec<WalOperation>) -> Result<()> {
+        let mut parts = self.split(operations);
+        match parts.len() {
+            0 | 1 => {
+                let (shard, operations) = parts.pop_first().unwrap_or_default();
+                self.shards[shard].write_batch_at(lsn, operations)
+            }
+            _ => self.write_parts(lsn, parts),
+        }
+    }
+
+    // Operations grouped by the shard of their key, in the order they were given
+    fn split(&self, operations: Vec<WalOperation>) -> BTreeMap<usize, Vec<WalOperation>> {
+        let mut parts: BTreeMap<usize, Vec<WalOperation>> = BTreeMap::new();
+        for operation in operations {
+            match operation {
+                WalOperation::Batch(operations) => {
+                    for (shard, operations) in self.split(operations) {
+                        parts.entry(shard).or_default().extend(operations);
+                    }
+                }
+                operation => {
+                    let shard = operation.key().map_or(0, |key| self.shard_of(key));
+                    parts.entry(shard).or_default().push(operation);
+                }
+            }
+        }
+        parts
+    }
+
+    // Every part is logged before any is applied. If one fails the ones logged before it are
+    // removed again, otherwise they would be replayed once the failed shard logs a later record.
+    fn write_parts(&mut self, lsn: u64, parts: BTreeMap<usize, Vec<WalOperation>>) -> Result<()> {
+        let logged_by: Vec<u32> = parts.keys().map(|shard| *shard as u32).collect();
+        let parts: Vec<(usize, WalOperation)> = parts.into_iter()
+            .map(|(shard, operations)| (shard, WalOperation::batched(operations)))
+            .collect();
+        let mut written = Vec::with_capacity(parts.len());
+        for (shard, operation) in &parts {
+            match self.shards[*shard].write_part(lsn, operation, &logged_by) {
+                Ok(offset) => written.push((*shard, offset)),
+                Err(error) => {
+                    for (shard, offset) in written {
+                        self.shards[shard].discard_part(offset)?;
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        for (shard, operation) in parts {
+            self.shards[shard].commit_part(lsn, operation)?;
+        }
+        Ok(())
+    }
+
+    // Flushes the shards in parallel, each writes its own map
+    pub fn flush(&mut self) -> Result<()> {
+        self.on_every_shard(LookupTable::flush)
+    }
+
+    // Flushes every shard with everything merged into its map.db, see LookupTable::compact
+    pub fn compact(&mut self) -> Result<()> {
+        self.on_every_shard(LookupTable::compact)
+    }
+
+    // Runs operation on the shards in parallel
+    fn on_every_shard(&mut self, operation: fn(&mut LookupTable) -> Result<()>) -> Result<()> {
+        if let [table] = self.shards.as_mut_slice() {
+            return operation(table);
+        }
+        std::thread::scope(|scope| {
+            let runs: Vec<_> = self.shards.iter_mut()
+                .map(|table| scope.spawn(move || operation(table)))
+                .collect();
+            runs.into_iter()
+                .try_for_each(|run| run.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+        })
+    }
+
+    // Waits for the background merges of the shards and swaps them in
+    pub fn finish_merges(&mut self) -> Result<()> {
+        self.shards.iter_mut().try_for_each(|table| table.finish_merge(true))
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<EntryLocation>> {
+        self.shard(key).get(key)
+    }
+
+    pub fn get_at(&self, key: &[u8], snapshot: &Snapshot) -> Result<Option<EntryLocation>> {
+        self.shard(key).get_at(key, snapshot)
+    }
+
+    pub fn written_since(&self, key: &[u8], lsn: u64) -> bool {
+        self.shard(key).written_since(key, lsn)
+    }
+
+    pub fn expiry(&self, key: &[u8]) -> Result<Option<u64>> {
+        self.shard(key).expiry(key)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.shards[0].snapshot()
+    }
+
+    // Keys within range in ascending order together with their locations, merged from every shard
+    pub fn range<'a, K, R>(&'a self, range: R) -> impl DoubleEndedIterator<Item = Result<(Vec<u8>, EntryLocation)>> + 'a
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let bounds: (Bound<Vec<u8>>, Bound<Vec<u8>>) = (
+            range.start_bound().map(|b| b.as_ref().to_vec()),
+            range.end_bound().map(|b| b.as_ref().to_vec()),
+        );
+        MergedRange::new(self.shards.iter().map(|table| table.range(bounds.clone())).collect())
+    }
+
+    pub fn scan_prefix<'a>(&'a self, prefix: &[u8]) -> impl DoubleEndedIterator<Item = Result<(Vec<u8>, EntryLocation)>> + 'a {
+        self.range(LookupTable::prefix_bounds(prefix))
+    }
+
+    pub fn range_at<K, R>(&self, range: R, snapshot: &Snapshot) -> Result<Vec<Vec<u8>>>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let bounds: (Bound<Vec<u8>>, Bound<Vec<u8>>) = (
+            range.start_bound().map(|b| b.as_ref().to_vec()),
+            range.end_bound().map(|b| b.as_ref().to_vec()),
+        );
+        let mut keys = Vec::new();
+        for table in &self.shards {
+            keys.extend(table.range_at(bounds.clone(), snapshot)?);
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    // Operations logged after sequence number lsn in the order of their sequence numbers, the parts
+    // of a batch next to each other. None if a flush already removed some of them from the WAL.
+    pub fn logged_since(&self, lsn: u64) -> Option<Vec<&(u64, WalOperation)>> {
+        let mut logged = Vec::new();
+        for table in &self.shards {
+            logged.extend(table.logged_since(lsn)?);
+        }
+        logged.sort_by_key(|(sequence, _)| *sequence);
+        Some(logged)
+    }
+
+    // Every key in no particular order, including expired ones
+    pub fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        for table in &self.shards {
+            keys.extend(table.keys()?);
+        }
+        Ok(keys)
+    }
+
+    pub fn live_locations(&self) -> Result<Vec<EntryLocation>> {
+        let mut locations = Vec::new();
+        for table in &self.shards {
+            locations.extend(table.live_locations()?);
+        }
+        Ok(locations)
+    }
+
+    pub fn prepare_relocation(&self, relocated: &HashMap<EntryLocation, EntryLocation>) -> Result<Vec<Relocation>> {
+        self.shards.iter().map(|table| table.prepare_relocation(relocated)).collect()
+    }
+
+    pub fn commit_relocation(&mut self, relocations: Vec<Relocation>) -> Result<()> {
+        for (table, relocation) in self.shards.iter_mut().zip(relocations) {
+            table.commit_relocation(relocation)?;
+        }
+        Ok(())
+    }
+
+    // Writes the map of every shard into folder, see LookupTable::write_vacuumed
+    pub fn write_vacuumed(
+        &self,
+        backend: &dyn StorageBackend,
+        folder: &Path,
+        relocated: &HashMap<EntryLocation, EntryLocation>,
+    ) -> Result<()> {
+        for (shard, table) in self.shards.iter().enumerate() {
+            match shard {
+                0 => table.write_vacuumed(folder, relocated)?,
+                shard => table.write_vacuumed(&ShardedTable::shard_folder(folder, shard), relocated)?,
+            }
+        }
+        if self.shards.len() > 1 {
+            ShardedTable::write_count(backend, folder, self.shards.len(), self.shards[0].block_size())?;
+        }
+        Ok(())
+    }
+
+    // Files holding the state of every shard, see LookupTable::files
+    pub fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for table in &self.shards {
+            files.extend(table.files()?);
+        }
+        if self.shards.len() > 1 {
+            files.push(self.folder.join(SHARDS_FILE_NAME));
+        }
+        Ok(files)
+    }
+
+    pub fn corrupt_files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for table in &self.shards {
+            files.extend(table.corrupt_files()?);
+        }
+        Ok(files)
+    }
+
+    pub fn disk_size(&self) -> Result<u64> {
+        let mut size = 0;
+        for table in &self.shards {
+            size += table.disk_size()?;
+        }
+        Ok(size)
+    }
+
+    pub fn wal_size(&self) -> Result<u64> {
+        let mut size = 0;
+        for table in &self.shards {
+            size += table.wal_size()?;
+        }
+        Ok(size)
+    }
+
+    pub fn wal_bytes_written(&self) -> u64 {
+        self.shards.iter().map(LookupTable::wal_bytes_written).sum()
+    }
+
+    pub fn memory_usage(&self) -> (usize, usize) {
+        self.shards.iter().map(LookupTable::memory_usage)
+            .fold((0, 0), |(index, wal), (shard_index, shard_wal)| (index + shard_index, wal + shard_wal))
+    }
+
+    pub fn unflushed(&self) -> (u64, usize) {
+        self.shards.iter().map(LookupTable::unflushed)
+            .fold((0, 0), |(bytes, operations), (shard_bytes, shard_operations)| (bytes + shard_bytes, operations + shard_operations))
+    }
+
+    pub fn tombstone_count(&self) -> usize {
+        self.shards.iter().map(LookupTable::tombstone_count).sum()
+    }
+
+    // Whether a shard still searches its map.db on disk, see LookupTable::load_base
+    pub fn is_lazy(&self) -> bool {
+        self.shards.iter().any(LookupTable::is_lazy)
+    }
+
+    pub fn load_base(&mut self) -> Result<()> {
+        self.shards.iter_mut().try_for_each(LookupTable::load_base)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(LookupTable::len).sum()
+    }
+
+    pub fn estimate_range_count<K, R>(&self, range: R) -> usize
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let bounds: (Bound<Vec<u8>>, Bound<Vec<u8>>) = (
+            range.start_bound().map(|b| b.as_ref().to_vec()),
+            range.end_bound().map(|b| b.as_ref().to_vec()),
+        );
+        self.shards.iter().map(|table| table.estimate_range_count(&bounds)).sum()
+    }
+
+    // Every shard scales its own samples by its own key count
+    pub fn approximate_size(&self, bounds: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Result<u64> {
+        let mut size = 0;
+        for table in &self.shards {
+            size += table.approximate_size(bounds)?;
+        }
+        Ok(size)
+    }
+
+    pub fn lock_path(&self) -> PathBuf {
+        self.shards[0].lock_path()
+    }
+}
+
+// Entries of several shards in ascending key order, merged lazily from either end.
+// No two shards hold the same key. An error of a shard is returned as soon as it is read.
+struct MergedRange<I> {
+    shards: Vec<I>,
+    // Entries taken from the front and the back of every shard that were not returned yet
+    fronts: Vec<Option<(Vec<u8>, EntryLocation)>>,
+    backs: Vec<Option<(Vec<u8>, EntryLocation)>>,
+}
+
+impl<I: DoubleEndedIterator<Item = Result<(Vec<u8>, EntryLocation)>>> MergedRange<I> {
+    fn new(shards: Vec<I>) -> Self {
+        let (fronts, backs) = shards.iter().map(|_| (None, None)).unzip();
+        Self { shards, fronts, backs }
+    }
+}
+
+impl<I: DoubleEndedIterator<Item = Result<(Vec<u8>, EntryLocation)>>> Iterator for MergedRange<I> {
+    type Item = Result<(Vec<u8>, EntryLocation)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (shard, entries) in self.shards.iter_mut().enumerate() {
+            if self.fronts[shard].is_none() {
+                // Once a shard runs out the entry taken from its back is the last one left
+                self.fronts[shard] = match entries.next() {
+                    Some(entry) => Some(match entry {
+                        Ok(entry) => entry,
+                        Err(e) => return Some(Err(e)),
+                    }),
+                    None => self.backs[shard].take(),
+                };
+            }
+        }
+        let (_, first) = self.fronts.iter().enumerate()
+            .filter_map(|(shard, entry)| Some((&entry.as_ref()?.0, shard)))
+            .min()?;
+        self.fronts[first].take().map(Ok)
+    }
+}
+
+impl<I: DoubleEndedIterator<Item = Result<(Vec<u8>, EntryLocation)>>> DoubleEndedIterator for MergedRange<I> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        for (shard, entries) in self.shards.iter_mut().enumerate() {
+            if self.backs[shard].is_none() {
+                self.backs[shard] = match entries.next_back() {
+                    Some(entry) => Some(match entry {
+                        Ok(entry) => entry,
+                        Err(e) => return Some(Err(e)),
+                    }),
+                    None => self.fronts[shard].take(),
+                };
+            }
+        }
+        let (_, last) = self.backs.iter().enumerate()
+            .filter_map(|(shard, entry)| Some((&entry.as_ref()?.0, shard)))
+            .max()?;
+        self.backs[last].take().map(Ok)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::backend::FileSystem;
+    use crate::db::batch::WriteBatch;
+    use crate::db::temp::TempDir;
+
+    fn shard_of(key: &[u8], count: usize) -> usize {
+        crc32fast::hash(key) as usize % count
+    }
+
+    #[test]
+    fn test_sharded_keyspace() -> Result<()> {
+        let dir = TempDir::new()?;
+        let options = DbOptions::new().path(&dir).shards(4);
+        let db = options.open()?;
+        let keys: Vec<Vec<u8>> = (0..200u32).map(|i| format!("key{i:03}").into_bytes()).collect();
+        for key in &keys {
+            db.put(key, key)?;
+        }
+        db.write(WriteBatch::new().delete(b"key000").put(b"key199", b"last").clone())?;
+        let items = db.cf("items")?;
+        items.put(b"book", b"1")?;
+        db.flush()?;
+        assert!(dir.path().join("shard-003").join("map.db").exists());
+        assert!(dir.path().join("cf").join("items").join("shard-001").exists());
+
+        // Merged from every shard in key order, from either end
+        let forward: Vec<Vec<u8>> = db.iter().map(|entry| Ok(entry?.0)).collect::<Result<_>>()?;
+        assert_eq!(forward, keys[1..].to_vec());
+        let backward: Vec<Vec<u8>> = db.iter().rev().map(|entry| Ok(entry?.0)).collect::<Result<_>>()?;
+        assert_eq!(backward, keys[1..].iter().rev().cloned().collect::<Vec<_>>());
+        let mut both = db.scan_prefix(b"key01");
+        assert_eq!(both.next().transpose()?.map(|(key, _)| key), Some(b"key010".to_vec()));
+        assert_eq!(both.next_back().transpose()?.map(|(key, _)| key), Some(b"key019".to_vec()));
+        assert_eq!(both.count(), 8);
+        drop((items, db));
+
+        // The count is kept from creation, the option only applies to new keyspaces
+        let db = options.clone().shards(1).open()?;
+        assert_eq!(db.get(b"key199")?, Some(b"last".to_vec()));
+        assert_eq!(db.get(b"key000")?, None);
+        assert_eq!(db.cf("items")?.get(b"book")?, Some(b"1".to_vec()));
+        assert_eq!(db.iter().count(), 199);
+        db.cf("users")?.put(b"ada", b"admin")?;
+        assert!(!dir.path().join("cf").join("users").join("shard-001").exists());
+        assert!(DbOptions::new().shards(0).validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_missing_from_a_shard() -> Result<()> {
+        let dir = TempDir::new()?;
+        let options = DbOptions::new().path(&dir).shards(4);
+        let keys: [&[u8]; 4] = [b"a", b"b", b"c", b"d"];
+        let spanned: BTreeSet<usize> = keys.iter().map(|key| shard_of(key, 4)).collect();
+        assert!(spanned.len() > 1);
+        let db = options.open()?;
+        db.put(b"before", b"1")?;
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            batch.put(key, b"batched");
+        }
+        db.write(batch)?;
+        let sequence = db.last_sequence();
+        db.crash();
+        // As if the process died before the last shard logged its part
+        let missing = *spanned.last().ok_or("no shard")?;
+        let folder = match missing {
+            0 => dir.path().to_path_buf(),
+            shard => ShardedTable::shard_folder(dir.path(), shard),
+        };
+        LookupTable::truncate_wal(&FileSystem, &folder, sequence - 1)?;
+
+        let db = options.open()?;
+        assert!(!db.recovery_report().is_clean());
+        for key in keys {
+            assert_eq!(db.get(key)?, None);
+        }
+        assert_eq!(db.get(b"before")?, Some(b"1".to_vec()));
+        // Later writes to the shard that missed its part don't bring the batch back
+        let later: Vec<Vec<u8>> = (0..20).map(|i| format!("later{i}").into_bytes()).collect();
+        assert!(later.iter().any(|key| shard_of(key, 4) == missing));
+        for key in &later {
+            db.put(key, b"2")?;
+        }
+        drop(db);
+        let db = options.open()?;
+        assert!(db.recovery_report().is_clean());
+        assert_eq!(db.iter().count(), 21);
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+// Deterministic simulation of long randomized workloads with crashes, FoundationDB style.
+//
+// Everything runs on one thread against a MemoryBackend behind a FaultyBackend, with a ManualClock
+// for expiries, so the workload and the faults injected into it replay from the seed alone.
+// A failure names its seed, which reruns with
+// CENDB_SIMULATION_SEED=<seed> cargo test simulation -- --nocapture
+use std::collections::BTreeMap;
+use std::time::Duration;
+use crate::db::batch::WriteBatch;
+use crate::db::clock::{Clock, ManualClock};
+use crate::db::database::Db;
+use crate::db::fault::FaultyBackend;
+use crate::db::memory::MemoryBackend;
+use crate::db::options::DbOptions;
+use crate::error::{Error, Result};
+
+const STEPS: usize = 1000;
+const SEEDS: u64 = 8;
+const KEYS: u64 = 48;
+
+// splitmix64, small and good enough to drive a workload
+struct SimRng(u64);
+
+impl SimRng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn key(&mut self) -> Vec<u8> {
+        format!("key{:02}", self.below(KEYS)).into_bytes()
+    }
+
+    fn value(&mut self) -> Vec<u8> {
+        let len = self.below(900) as usize;
+        let byte = self.next() as u8;
+        vec![byte; len]
+    }
+}
+
+// Value of a key and when it expires, in milliseconds of the simulated clock
+type Model = BTreeMap<Vec<u8>, (Vec<u8>, Option<u64>)>;
+
+struct Simulation {
+    seed: u64,
+    rng: SimRng,
+    backend: FaultyBackend,
+    clock: ManualClock,
+    options: DbOptions,
+    db: Option<Db>,
+    // What every acknowledged write adds up to
+    model: Model,
+}
+
+impl Simulation {
+    fn new(seed: u64) -> Result<Self> {
+        let backend = FaultyBackend::new(MemoryBackend::new());
+        let clock = ManualClock::new(1_000_000);
+        // Small segments and thresholds so rotation and rewrites of the WAL happen within a run.
+        // Every other seed spreads the keys over shards, so batches get logged in parts.
+        let options = DbOptions::new().path("sim").backend(backend.clone()).clock(clock.clone())
+            .max_wal_segment_size(16 * 1024).wal_rewrite_threshold(64 * 1024)
+            .shards(1 + 2 * (seed % 2) as usize);
+        let db = options.open()?;
+        Ok(Simulation { seed, rng: SimRng(seed), backend, clock, options, db: Some(db), model: Model::new() })
+    }
+
+    fn db(&self) -> &Db {
+        self.db.as_ref().expect("the database is open between steps")
+    }
+
+    fn step(&mut self, step: usize) -> Result<()> {
+        let mut applied = self.model.clone();
+        let written = match self.rng.below(100) {
+            0..=34 => {
+                let (key, value) = (self.rng.key(), self.rng.value());
+                applied.insert(key.clone(), (value.clone(), None));
+                self.db().put(&key, &value)
+            }
+            35..=44 => {
+                let (key, value) = (self.rng.key(), self.rng.value());
+                let ttl = 1 + self.rng.below(5000);
+                applied.insert(key.clone(), (value.clone(), Some(self.clock.now_millis() + ttl)));
+                self.db().put_with_ttl(&key, &value, Duration::from_millis(ttl))
+            }
+            45..=59 => {
+                let key = self.rng.key();
+                applied.remove(&key);
+                self.db().delete(&key)
+            }
+            60..=67 => {
+                let mut batch = WriteBatch::new();
+                for _ in 0..1 + self.rng.below(6) {
+                    let key = self.rng.key();
+                    match self.rng.below(3) {
+                        0 => {
+                            applied.remove(&key);
+                            batch.delete(&key);
+                        }
+                        _ => {
+                            let value = self.rng.value();
+                            applied.insert(key.clone(), (value.clone(), None));
+                            batch.put(&key, &value);
+                        }
+                    }
+                }
+                self.db().write(batch)
+            }
+            68..=79 => {
+                let key = self.rng.key();
+                let expected = visible(&self.model, self.clock.now_millis()).remove(&key);
+                assert_eq!(self.db().get(&key)?, expected, "get {} at step {step} of seed {}", key.escape_ascii(), self.seed);
+                Ok(())
+            }
+            80..=84 => self.db().flush(),
+            85..=87 => self.db().compact(),
+            88..=91 => {
+                self.clock.advance(Duration::from_millis(self.rng.below(2000)));
+                Ok(())
+            }
+            92..=94 => {
+                self.backend.tear_write_after(self.rng.below(8) as usize);
+                Ok(())
+            }
+            95..=97 => return self.restart(None, step),
+            _ => {
+                drop(self.db.take());
+                // Opening writes too, a tear armed before turns the clean restart into a crash
+                return match self.options.open() {
+                    Ok(db) => {
+                        self.db = Some(db);
+                        self.check(step)
+                    }
+                    Err(_) => self.restart(None, step),
+                };
+            }
+        };
+        match written {
+            Ok(()) => {
+                self.model = applied;
+                Ok(())
+            }
+            // A torn write leaves the process dead, it is up to recovery what the failed write left behind
+            Err(_) => self.restart(Some(applied), step),
+        }
+    }
+
+    // Crashes and reopens the database, which then holds the model or, if a write failed, possibly its outcome
+    fn restart(&mut self, failed: Option<Model>, step: usize) -> Result<()> {
+        if let Some(db) = self.db.take() {
+            db.crash();
+        }
+        self.backend.crash()?;
+        self.db = Some(self.options.open()?);
+        let now = self.clock.now_millis();
+        if let Some(applied) = failed {
+            if self.entries()? == visible(&applied, now) {
+                self.model = applied;
+            }
+        }
+        self.check(step)
+    }
+
+    fn entries(&self) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+        self.db().iter().collect()
+    }
+
+    fn check(&self, step: usize) -> Result<()> {
+        assert_eq!(self.entries()?, visible(&self.model, self.clock.now_millis()), "entries at step {step} of seed {}", self.seed);
+        Ok(())
+    }
+}
+
+fn visible(model: &Model, now: u64) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    model.iter()
+        .filter(|(_, (_, expires_at))| expires_at.is_none_or(|expires_at| expires_at > now))
+        .map(|(key, (value, _))| (key.clone(), value.clone()))
+        .collect()
+}
+
+fn simulate(seed: u64, steps: usize) -> Result<()> {
+    let run = || {
+        let mut simulation = Simulation::new(seed)?;
+        for step in 0..steps {
+            simulation.step(step)?;
+        }
+        simulation.restart(None, steps)
+    };
+    run().map_err(|e| Error::Custom(format!("seed {seed}: {e}")))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulation() -> Result<()> {
+        match std::env::var("CENDB_SIMULATION_SEED") {
+            Ok(seed) => simulate(seed.parse().map_err(|_| "CENDB_SIMULATION_SEED is not a number")?, STEPS * 10),
+            Err(_) => (0..SEEDS).try_for_each(|seed| simulate(seed, STEPS)),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// A consistent read view of the database as of the moment it was taken.
+///
+/// Values overwritten or deleted after the snapshot was taken stay readable through it
+/// until the snapshot is dropped.
+pub struct Snapshot {
+    seq: u64,
+    registry: SnapshotRegistry,
+}
+
+impl Snapshot {
+    /// Sequence number of the last operation visible to this snapshot.
+    pub fn sequence(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.registry.release(self.seq);
+    }
+}
+
+// Reference counts of the sequence numbers that live snapshots are reading at
+#[derive(Clone, Default)]
+pub(crate) struct SnapshotRegistry {
+    live: Arc<Mutex<BTreeMap<u64, usize>>>,
+}
+
+impl SnapshotRegistry {
+    pub fn acquire(&self, seq: u64) -> Snapshot {
+        *self.lock().entry(seq).or_insert(0) += 1;
+        Snapshot { seq, registry: self.clone() }
+    }
+
+    fn release(&self, seq: u64) {
+        let mut live = self.lock();
+        if let Some(count) = live.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&seq);
+            }
+        }
+    }
+
+    // Sequence number of the oldest live snapshot
+    pub fn oldest(&self) -> Option<u64> {
+        self.lock().keys().next().copied()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, usize>> {
+        self.live.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
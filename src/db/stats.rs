@@ -0,0 +1,290 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use crate::db::cache::CacheStats;
+
+// Sub-buckets of every power of two of a latency histogram, quantiles are at most 1/16 above
+// the latencies they stand for
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Counters and file sizes of an open database, see [`Db::stats`](crate::Db::stats).
+///
+/// Counters start at zero when the database is opened and are shared by all of its handles.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Values written by puts, merges, increments and batches, in every keyspace.
+    pub puts: u64,
+    /// Keys looked up by gets and multi-gets, iterators are not counted.
+    pub gets: u64,
+    /// Deletes, including those in batches.
+    pub deletes: u64,
+    /// Bytes appended to the WAL of every keyspace, including WAL rewrites.
+    pub wal_bytes_written: u64,
+    pub flushes: u64,
+    pub compactions: u64,
+    pub cache: CacheStats,
+    /// Bytes of the value file.
+    pub data_size: u64,
+    /// Bytes of the maps, WAL segments and bloom filters of every keyspace.
+    pub index_size: u64,
+    /// Bytes of the WAL segments of every keyspace, part of the index size.
+    pub wal_size: u64,
+    /// Removed keys whose tombstones are kept, see
+    /// [`DbOptions::tombstone_retention`](crate::DbOptions::tombstone_retention).
+    pub tombstones: u64,
+    pub memory: MemoryUsage,
+    /// Gets, get-refs, multi-gets and snapshot reads, a multi-get counts once.
+    pub get_latency: Latency,
+    /// Puts, merges, increments and batches, a batch counts once.
+    pub put_latency: Latency,
+    pub flush_latency: Latency,
+    /// Compactions of the value file, including the flush they start with.
+    pub compaction_latency: Latency,
+}
+
+/// How long the operations of one kind took since the database was opened, see [`Stats`].
+///
+/// Percentiles come from a histogram and are at most 1/16 above the exact ones. Only calls
+/// that succeeded are counted.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Latency {
+    pub count: u64,
+    /// Time all of them took together.
+    pub total: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+#[cfg(feature = "metrics")]
+impl Stats {
+    /// The stats in the Prometheus text exposition format, with metric names prefixed by `cendb_`.
+    /// Counters end in `_total`, latencies are summaries in seconds.
+    pub fn to_prometheus(&self) -> String {
+        use std::fmt::Write;
+
+        let mut text = String::new();
+        let counters = [
+            ("puts", self.puts), ("gets", self.gets), ("deletes", self.deletes),
+            ("wal_bytes_written", self.wal_bytes_written), ("flushes", self.flushes),
+            ("compactions", self.compactions), ("cache_hits", self.cache.hits), ("cache_misses", self.cache.misses),
+        ];
+        for (name, value) in counters {
+            let _ = write!(text, "# TYPE cendb_{name}_total counter\ncendb_{name}_total {value}\n");
+        }
+        let gauges = [
+            ("data_size_bytes", self.data_size), ("index_size_bytes", self.index_size),
+            ("wal_size_bytes", self.wal_size), ("tombstones", self.tombstones),
+            ("memory_bytes", self.memory.total()), ("cache_memory_bytes", self.memory.cache),
+        ];
+        for (name, value) in gauges {
+            let _ = write!(text, "# TYPE cendb_{name} gauge\ncendb_{name} {value}\n");
+        }
+        let latencies = [
+            ("get", &self.get_latency), ("put", &self.put_latency),
+            ("flush", &self.flush_latency), ("compaction", &self.compaction_latency),
+        ];
+        for (name, latency) in latencies {
+            let _ = writeln!(text, "# TYPE cendb_{name}_latency_seconds summary");
+            for (quantile, value) in [("0.5", latency.p50), ("0.95", latency.p95), ("0.99", latency.p99)] {
+                let _ = writeln!(text, "cendb_{name}_latency_seconds{{quantile=\"{quantile}\"}} {}", value.as_secs_f64());
+            }
+            let _ = writeln!(text, "cendb_{name}_latency_seconds_sum {}", latency.total.as_secs_f64());
+            let _ = writeln!(text, "cendb_{name}_latency_seconds_count {}", latency.count);
+        }
+        text
+    }
+}
+
+/// Approximate memory held by an open database, see [`Stats::memory`] and
+/// [`DbOptions::memory_limit`](crate::DbOptions::memory_limit).
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Bytes of the keys of every keyspace with their locations, expiry times, tombstones,
+    /// versions kept for snapshots and bloom filters.
+    pub index: u64,
+    /// Bytes of the operations logged since the last flush, which a flush releases.
+    pub wal: u64,
+    /// Bytes of the blocks in the block cache.
+    pub cache: u64,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.index + self.wal + self.cache
+    }
+}
+
+/// Space the database takes on disk, see [`Db::size_on_disk`](crate::Db::size_on_disk).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Bytes of the value file.
+    pub data_size: u64,
+    /// Bytes of the value file holding values that are still reachable, including the
+    /// previous versions that open snapshots read.
+    pub live_bytes: u64,
+    /// Bytes of the value file that a compaction would reclaim. Free space at the end of the
+    /// last block, where the next values go, is not counted.
+    pub dead_bytes: u64,
+    /// Usage of the default keyspace followed by every column family.
+    pub keyspaces: Vec<KeyspaceUsage>,
+}
+
+/// Space taken by one keyspace, see [`DiskUsage`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyspaceUsage {
+    /// Name of the column family, None for the default keyspace.
+    pub name: Option<String>,
+    /// Bytes of the value file holding the reachable values of the keyspace.
+    pub live_bytes: u64,
+    /// Bytes of the WAL segments.
+    pub wal_size: u64,
+    /// Bytes of the maps and bloom filters.
+    pub index_size: u64,
+}
+
+// Operation counts, atomic since reads only hold the shared lock of the index
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub puts: AtomicU64,
+    pub gets: AtomicU64,
+    pub deletes: AtomicU64,
+    pub flushes: AtomicU64,
+    pub get_latency: Histogram,
+    pub put_latency: Histogram,
+    pub flush_latency: Histogram,
+    pub compaction_latency: Histogram,
+}
+
+impl Counters {
+    pub fn add(counter: &AtomicU64, count: usize) {
+        counter.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    // Stats with the operation counts filled in
+    pub fn stats(&self) -> Stats {
+        Stats {
+            puts: self.puts.load(Ordering::Relaxed),
+            gets: self.gets.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            get_latency: self.get_latency.latency(),
+            put_latency: self.put_latency.latency(),
+            flush_latency: self.flush_latency.latency(),
+            compaction_latency: self.compaction_latency.latency(),
+            ..Stats::default()
+        }
+    }
+}
+
+// Counts of latencies in nanoseconds by bucket. Below 2 * SUB_BUCKETS every nanosecond has a
+// bucket of its own, above each power of two is split into SUB_BUCKETS buckets of equal width.
+#[derive(Debug)]
+pub(crate) struct Histogram {
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    total: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, latency: Duration) {
+        let nanos = latency.as_nanos().try_into().unwrap_or(u64::MAX);
+        self.buckets[Histogram::bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn bucket(nanos: u64) -> usize {
+        if nanos < SUB_BUCKETS as u64 {
+            return nanos as usize;
+        }
+        let shift = 63 - nanos.leading_zeros() - SUB_BUCKET_BITS;
+        (shift as usize + 1) * SUB_BUCKETS + (nanos >> shift) as usize - SUB_BUCKETS
+    }
+
+    // Largest latency that falls into the bucket
+    fn bucket_end(bucket: usize) -> u64 {
+        if bucket < 2 * SUB_BUCKETS {
+            return bucket as u64;
+        }
+        let shift = bucket / SUB_BUCKETS - 1;
+        let start = ((bucket % SUB_BUCKETS + SUB_BUCKETS) as u64) << shift;
+        start + ((1 << shift) - 1)
+    }
+
+    fn latency(&self) -> Latency {
+        // Recorded concurrently the counts may be off by a few, the buckets are authoritative
+        let counts: Vec<u64> = self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        let count: u64 = counts.iter().sum();
+        let max = self.max.load(Ordering::Relaxed);
+        let quantile = |quantile: f64| {
+            let rank = ((count as f64 * quantile).ceil() as u64).max(1);
+            let mut seen = 0;
+            let bucket = counts.iter().position(|&bucket| {
+                seen += bucket;
+                seen >= rank
+            });
+            Duration::from_nanos(bucket.map_or(0, |bucket| Histogram::bucket_end(bucket).min(max)))
+        };
+        Latency {
+            count,
+            total: Duration::from_nanos(self.total.load(Ordering::Relaxed)),
+            p50: quantile(0.5),
+            p95: quantile(0.95),
+            p99: quantile(0.99),
+            max: Duration::from_nanos(max),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.latency(), Latency::default());
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        let latency = histogram.latency();
+        assert_eq!((latency.count, latency.max), (1000, Duration::from_millis(1)));
+        assert_eq!(latency.total, Duration::from_micros(500_500));
+        for (quantile, exact) in [(latency.p50, 500), (latency.p95, 950), (latency.p99, 990)] {
+            let exact = Duration::from_micros(exact);
+            assert!(quantile >= exact && quantile <= exact + exact / 16, "{quantile:?} for {exact:?}");
+        }
+        for nanos in [0, 1, 15, 16, 31, 32, 1000, u64::MAX] {
+            assert!(Histogram::bucket_end(Histogram::bucket(nanos)) >= nanos);
+        }
+        assert_eq!(Histogram::bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_prometheus_text() {
+        let mut stats = Stats { puts: 3, ..Stats::default() };
+        stats.get_latency = Latency { count: 2, total: Duration::from_millis(3), p99: Duration::from_millis(2), ..Latency::default() };
+        let text = stats.to_prometheus();
+        assert!(text.contains("# TYPE cendb_puts_total counter\ncendb_puts_total 3\n"));
+        assert!(text.contains("cendb_get_latency_seconds{quantile=\"0.99\"} 0.002\n"));
+        assert!(text.contains("cendb_get_latency_seconds_sum 0.003\ncendb_get_latency_seconds_count 2\n"));
+    }
+}
@@ -0,0 +1,431 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::sync::Arc;
+use crate::db::backend::StorageFile;
+use crate::db::btree::{Node, Pager, ENTRY_LENGTH_SIZE, NODE_HEADER_SIZE};
+use crate::db::cache::BlockCache;
+use crate::db::compression::{Compression, RAW};
+use crate::db::lookup::EntryLocation;
+use crate::db::options::DbOptions;
+use crate::db::recovery::RecoveryReport;
+use crate::db::sync::SyncPolicy;
+use crate::error::{Error, Result};
+
+pub(crate) const DATA_FILE_NAME: &str = "data.db";
+// Compaction copies the live values here before the file replaces data.db
+pub(crate) const COMPACTED_DATA_FILE_NAME: &str = "data.db.compact";
+const OVERFLOW: u8 = 0x80;
+const OVERFLOW_ENTRY_SIZE: usize = 16;
+
+// Append-only value log stored in data.db.
+// Values are length-prefixed entries packed into blocks, a value never straddles two blocks.
+// Each entry is flagged with the codec it was compressed with. Values too big for a block are split
+// into chunks filling a run of blocks of their own, and the entry flagged OVERFLOW points to them.
+// Its bytes are the first block of the run and the length of the stored value, both u64.
+pub(crate) struct ValueLog {
+    pager: Pager,
+    // Last block of the log, new values are appended here until it is full
+    tail: Option<Node>,
+    sync_policy: SyncPolicy,
+    compression: Compression,
+    compression_threshold: usize,
+    max_value_size: usize,
+}
+
+/// Value returned by [`Db::get_ref`](crate::Db::get_ref), dereferences to its bytes.
+///
+/// Uncompressed values that fit into a block borrow the block as the cache holds it instead of
+/// being copied, and keep it alive after the cache evicts it. Other values are decoded into a
+/// buffer of their own.
+pub struct ValueRef<'a> {
+    bytes: ValueBytes,
+    _db: PhantomData<&'a ()>,
+}
+
+enum ValueBytes {
+    Block(Arc<Node>, Range<usize>),
+    Owned(Vec<u8>),
+}
+
+impl ValueRef<'_> {
+    /// Copies the bytes out, unless they are already a buffer of their own.
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.bytes {
+            ValueBytes::Block(node, range) => node.as_bytes()[range].to_vec(),
+            ValueBytes::Owned(value) => value,
+        }
+    }
+
+    fn new(bytes: ValueBytes) -> Self {
+        ValueRef { bytes, _db: PhantomData }
+    }
+}
+
+impl Deref for ValueRef<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.bytes {
+            ValueBytes::Block(node, range) => &node.as_bytes()[range.clone()],
+            ValueBytes::Owned(value) => value,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueRef<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for ValueRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ValueRef").field(&&**self).finish()
+    }
+}
+
+impl PartialEq<[u8]> for ValueRef<'_> {
+    fn eq(&self, other: &[u8]) -> bool {
+        **self == *other
+    }
+}
+
+impl ValueLog {
+    pub fn open(folder: &Path, options: &DbOptions, cache: Arc<BlockCache>) -> Result<Self> {
+        ValueLog::open_recovering(folder, options, cache, &mut RecoveryReport::default())
+    }
+
+    pub fn open_recovering(
+        folder: &Path,
+        options: &DbOptions,
+        cache: Arc<BlockCache>,
+        recovery: &mut RecoveryReport,
+    ) -> Result<Self> {
+        // Left behind by a compaction that never committed
+        let backend = options.backend.as_ref();
+        let compacted_path = folder.join(COMPACTED_DATA_FILE_NAME);
+        if backend.exists(&compacted_path) && !options.read_only {
+            backend.remove_file(&compacted_path)?;
+            recovery.repaired_files.push(compacted_path);
+        }
+        let pager = Pager::open(backend, &folder.join(DATA_FILE_NAME), options.block_size, options.read_only, options.direct_io, cache)?;
+        ValueLog::from_pager(pager, options, options.sync_policy)
+    }
+
+    // Empty log at path for copying values into, data.db.compact for a compaction. It is synced once
+    // by the caller after all values are copied and reopened afterwards, so its blocks are not cached.
+    // Copied values are compressed with the codec of options.
+    pub fn create(path: &Path, options: &DbOptions) -> Result<Self> {
+        let backend = options.backend.as_ref();
+        if backend.exists(path) {
+            backend.remove_file(path)?;
+        }
+        let pager = Pager::open(backend, path, options.block_size, false, options.direct_io, Arc::new(BlockCache::new(0)))?;
+        ValueLog::from_pager(pager, options, SyncPolicy::Never)
+    }
+
+    fn from_pager(pager: Pager, options: &DbOptions, sync_policy: SyncPolicy) -> Result<Self> {
+        let tail = match pager.block_count() {
+            0 => None,
+            count => Some(pager.read_node(count - 1)?),
+        };
+        Ok(Self {
+            pager,
+            tail,
+            sync_policy,
+            compression: options.compression,
+            compression_threshold: options.compression_threshold,
+            max_value_size: options.max_value_size,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.pager.path()
+    }
+
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) {
+        self.sync_policy = sync_policy;
+    }
+
+    pub fn append(&mut self, value: &[u8]) -> Result<EntryLocation> {
+        let (flag, stored) = self.encode(value)?;
+        let location = self.push(&stored, flag)?;
+        self.write_tail()?;
+        Ok(location)
+    }
+
+    // Appends all values with at most one sync
+    pub fn append_batch(&mut self, values: &[&[u8]]) -> Result<Vec<EntryLocation>> {
+        let encoded = values.iter()
+            .map(|value| self.encode(value))
+            .collect::<Result<Vec<_>>>()?;
+        let locations = encoded.iter()
+            .map(|(flag, stored)| self.push(stored, *flag))
+            .collect::<Result<Vec<_>>>()?;
+        self.write_tail()?;
+        Ok(locations)
+    }
+
+    // Compressed bytes of value with their flag
+    fn encode<'a>(&self, value: &'a [u8]) -> Result<(u8, Cow<'a, [u8]>)> {
+        if value.len() > self.max_value_size {
+            return Err(Error::Custom(format!(
+                "value of {} bytes exceeds the maximum of {}", value.len(), self.max_value_size
+            )));
+        }
+        self.compression.compress(value, self.compression_threshold)
+    }
+
+    // Adds the stored bytes to the tail node, only full nodes are written to disk here
+    fn push(&mut self, value: &[u8], flag: u8) -> Result<EntryLocation> {
+        if value.len() > self.max_entry_size() {
+            return self.push_overflow(value, flag);
+        }
+        let mut tail = match self.tail.take() {
+            Some(node) if node.fits(value.len()) => node,
+            Some(full) => {
+                self.pager.write_node(&full)?;
+                self.pager.allocate()?
+            }
+            None => self.pager.allocate()?,
+        };
+        let pointer = tail.push(value, flag).ok_or("value does not fit into a fresh block")?;
+        let size = (ENTRY_LENGTH_SIZE + value.len()) as u32;
+        let location = EntryLocation { block: tail.block(), pointer, size };
+        self.tail = Some(tail);
+        Ok(location)
+    }
+
+    // Writes the chunks of value to new blocks and adds the entry pointing to them.
+    // The tail is written out first, so it stays the last block of the file.
+    fn push_overflow(&mut self, value: &[u8], flag: u8) -> Result<EntryLocation> {
+        if let Some(tail) = self.tail.take() {
+            self.pager.write_node(&tail)?;
+        }
+        let mut first = None;
+        for chunk in value.chunks(self.max_entry_size()) {
+            let mut node = self.pager.allocate()?;
+            node.push(chunk, 0).ok_or("chunk does not fit into a fresh block")?;
+            self.pager.write_node(&node)?;
+            first.get_or_insert(node.block());
+        }
+        let mut entry = first.ok_or("overflow value is empty")?.to_le_bytes().to_vec();
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        let mut location = self.push(&entry, flag | OVERFLOW)?;
+        let chunks = value.len().div_ceil(self.max_entry_size()) as u64;
+        location.size = u32::try_from(location.size as u64 + chunks * self.block_payload()).unwrap_or(u32::MAX);
+        Ok(location)
+    }
+
+    fn write_tail(&mut self) -> Result<()> {
+        if let Some(tail) = &self.tail {
+            self.pager.write_node(tail)?;
+        }
+        if self.sync_policy.sync_each_write() {
+            self.pager.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, location: EntryLocation) -> Result<Vec<u8>> {
+        match &self.tail {
+            Some(tail) if tail.block() == location.block => self.decode(tail, location),
+            _ => self.decode(&self.pager.read_node(location.block)?, location),
+        }
+    }
+
+    // Like read, but borrows the bytes of uncompressed values from the block they are in. Values in
+    // the tail are copied, it changes with every append.
+    pub fn read_ref(&self, location: EntryLocation) -> Result<ValueRef<'static>> {
+        if self.tail.as_ref().is_some_and(|tail| tail.block() == location.block) {
+            return Ok(ValueRef::new(ValueBytes::Owned(self.read(location)?)));
+        }
+        let node = self.pager.read_shared_node(location.block)?;
+        match node.entry_range(location.pointer)? {
+            (RAW, range) => Ok(ValueRef::new(ValueBytes::Block(node, range))),
+            _ => Ok(ValueRef::new(ValueBytes::Owned(self.decode(&node, location)?))),
+        }
+    }
+
+    fn decode(&self, node: &Node, location: EntryLocation) -> Result<Vec<u8>> {
+        let (flag, stored) = node.read(location.pointer)?;
+        if flag & OVERFLOW == 0 {
+            return Compression::decompress(flag, stored);
+        }
+        let corrupt = || Error::Custom(format!("corrupt overflow entry at {location:?}"));
+        if stored.len() != OVERFLOW_ENTRY_SIZE {
+            return Err(corrupt());
+        }
+        let first = u64::from_le_bytes(stored[0..8].try_into()?);
+        let length = u64::from_le_bytes(stored[8..16].try_into()?);
+        let count = length.div_ceil(self.max_entry_size() as u64);
+        let end = first.checked_add(count).filter(|end| *end <= self.pager.block_count()).ok_or_else(corrupt)?;
+        let blocks: Vec<u64> = (first..end).collect();
+        let mut value = Vec::with_capacity(length as usize);
+        for node in self.pager.read_nodes(&blocks)? {
+            value.extend_from_slice(node.first_entry()?.1);
+        }
+        if value.len() as u64 != length {
+            return Err(corrupt());
+        }
+        Compression::decompress(flag & !OVERFLOW, &value)
+    }
+
+    // Reads the values in block order so every block is read once, the values are
+    // returned in the order of locations
+    pub fn read_many(&self, locations: &[EntryLocation]) -> Result<Vec<Vec<u8>>> {
+        let tail_block = self.tail.as_ref().map(|tail| tail.block());
+        let mut blocks: Vec<u64> = locations.iter()
+            .map(|location| location.block)
+            .filter(|block| Some(*block) != tail_block)
+            .collect();
+        blocks.sort_unstable();
+        blocks.dedup();
+        let nodes: HashMap<u64, Node> = blocks.iter().copied().zip(self.pager.read_nodes(&blocks)?).collect();
+        locations.iter()
+            .map(|location| {
+                let node = match &self.tail {
+                    Some(tail) if tail.block() == location.block => tail,
+                    _ => &nodes[&location.block],
+                };
+                self.decode(node, *location)
+            })
+            .collect()
+    }
+
+    // Bytes of the file taken by the values at locations, including their overflow blocks.
+    // Block headers are not counted. Reads every block holding one of them, a block at a time.
+    pub fn stored_size(&self, locations: &[EntryLocation]) -> Result<u64> {
+        let mut locations = locations.to_vec();
+        locations.sort_unstable_by_key(|location| (location.block, location.pointer));
+        locations.dedup();
+        let mut size = 0;
+        let mut current: Option<Node> = None;
+        for location in locations {
+            let node = match &self.tail {
+                Some(tail) if tail.block() == location.block => tail,
+                _ => {
+                    if current.as_ref().map(Node::block) != Some(location.block) {
+                        current = Some(self.pager.read_node(location.block)?);
+                    }
+                    current.as_ref().ok_or("block was not read")?
+                }
+            };
+            let (flag, stored) = node.read(location.pointer)?;
+            size += (ENTRY_LENGTH_SIZE + stored.len()) as u64;
+            if flag & OVERFLOW != 0 && stored.len() == OVERFLOW_ENTRY_SIZE {
+                let length = u64::from_le_bytes(stored[8..16].try_into()?);
+                size += length.div_ceil(self.max_entry_size() as u64) * self.block_payload();
+            }
+        }
+        Ok(size)
+    }
+
+    // Bytes of the file up to the end of the last value without block headers, the rest of
+    // the tail is free space
+    pub fn used_size(&self) -> u64 {
+        let free = self.tail.as_ref().map_or(0, Node::free_space);
+        self.pager.block_count() * self.block_payload() - free as u64
+    }
+
+    fn max_entry_size(&self) -> usize {
+        Node::max_entry_size(self.pager.block_size())
+    }
+
+    // Bytes of a block that hold entries
+    fn block_payload(&self) -> u64 {
+        (self.pager.block_size() - NODE_HEADER_SIZE) as u64
+    }
+
+    pub fn file_handle(&self) -> Arc<dyn StorageFile> {
+        self.pager.file_handle()
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.pager.sync()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::temp::TempDir;
+
+    #[test]
+    fn test_append_read() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut log = ValueLog::open(dir.path(), &DbOptions::new(), Arc::new(BlockCache::new(4)))?;
+        let small = log.append(b"small")?;
+        let big = log.append(&vec![1; 4070])?;
+        let after = log.append(b"after the block is full")?;
+        assert_eq!(small.block, 0);
+        assert_eq!(big.block, 0);
+        assert_eq!(after.block, 1);
+
+        let log = ValueLog::open(dir.path(), &DbOptions::new(), Arc::new(BlockCache::new(4)))?;
+        assert_eq!(log.read(small)?, b"small");
+        assert_eq!(log.read(big)?, vec![1; 4070]);
+        assert_eq!(log.read(after)?, b"after the block is full");
+        assert!(log.read(EntryLocation { block: 7, pointer: 4, size: 0 }).is_err());
+        assert_eq!(*log.read_ref(small)?, *b"small");
+        assert!(matches!(log.read_ref(big)?.bytes, ValueBytes::Block(..)));
+        // The tail is copied
+        assert!(matches!(log.read_ref(after)?.bytes, ValueBytes::Owned(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_overflow_values() -> Result<()> {
+        let dir = TempDir::new()?;
+        let options = DbOptions::new().max_value_size(100_000);
+        let mut log = ValueLog::open(dir.path(), &options, Arc::new(BlockCache::new(4)))?;
+        let before = log.append(b"before")?;
+        let exact = vec![1; log.max_entry_size() + 1];
+        let big: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let locations = log.append_batch(&[&exact, b"between", &big])?;
+        let after = log.append(b"after")?;
+        assert!(log.append(&vec![0; 100_001]).is_err());
+        assert_eq!(log.read(locations[0])?, exact);
+
+        let log = ValueLog::open(dir.path(), &options, Arc::new(BlockCache::new(4)))?;
+        assert_eq!(log.read(locations[2])?, big);
+        assert_eq!(log.read_ref(locations[2])?.into_vec(), big);
+        let values = log.read_many(&[after, locations[2], before, locations[1], locations[0]])?;
+        assert_eq!(values, vec![b"after".to_vec(), big, b"before".to_vec(), b"between".to_vec(), exact]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_values() -> Result<()> {
+        let dir = TempDir::new()?;
+        let codecs = [
+            Compression::None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(3),
+        ];
+        let repetitive = b"0123456789".repeat(100);
+        let mut written = Vec::new();
+        for codec in codecs {
+            let options = DbOptions::new().compression(codec);
+            let mut log = ValueLog::open(dir.path(), &options, Arc::new(BlockCache::new(4)))?;
+            let single = log.append(&repetitive)?;
+            let batch = log.append_batch(&[b"short", &repetitive])?;
+            written.push((single, repetitive.clone()));
+            written.push((batch[0], b"short".to_vec()));
+            written.push((batch[1], repetitive.clone()));
+        }
+        // Values of every codec stay readable whichever one the log is opened with
+        let log = ValueLog::open(dir.path(), &DbOptions::new(), Arc::new(BlockCache::new(4)))?;
+        let locations: Vec<_> = written.iter().map(|(location, _)| *location).collect();
+        let values: Vec<_> = written.into_iter().map(|(_, value)| value).collect();
+        assert_eq!(log.read_many(&locations)?, values);
+        Ok(())
+    }
+}
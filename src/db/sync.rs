@@ -0,0 +1,74 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use crate::db::backend::StorageFile;
+
+/// Controls when writes to the WAL and the value log are forced to stable storage.
+///
+/// `flush` always syncs regardless of the policy.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// fsync after every write. A write that returned `Ok` survives a power loss.
+    #[default]
+    Always,
+    /// fsync from a background thread every `Duration`.
+    /// A power loss can lose the writes of the last interval, a process crash loses nothing.
+    Interval(Duration),
+    /// Leave it to the OS to write back buffered data.
+    /// A process crash loses nothing, a power loss can lose everything since the last flush.
+    Never,
+}
+
+impl SyncPolicy {
+    pub(crate) fn sync_each_write(&self) -> bool {
+        matches!(self, SyncPolicy::Always)
+    }
+}
+
+/// Settings of a single write, see [`Db::put_with`](crate::Db::put_with).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct WriteOptions {
+    /// Whether the write is fsynced before it returns even if the [`SyncPolicy`] of the database
+    /// would not sync it. Without it the write is synced as the policy says, so a write option
+    /// never lowers the durability the database was opened with. A sync covers the writes
+    /// before it.
+    pub sync: bool,
+}
+
+// Periodically fsyncs a set of files until dropped
+pub(crate) struct BackgroundSync {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundSync {
+    pub fn start(files: Vec<Arc<dyn StorageFile>>, interval: Duration) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            let (lock, condvar) = &*thread_stop;
+            let mut stopped = lock.lock().unwrap_or_else(|e| e.into_inner());
+            while !*stopped {
+                stopped = condvar.wait_timeout(stopped, interval)
+                    .unwrap_or_else(|e| e.into_inner()).0;
+                for file in &files {
+                    // A failed background sync is retried on the next tick and by flush
+                    let _ = file.sync();
+                }
+            }
+        });
+        Self { stop, handle: Some(handle) }
+    }
+}
+
+impl Drop for BackgroundSync {
+    fn drop(&mut self) {
+        let (lock, condvar) = &*self.stop;
+        *lock.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        condvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
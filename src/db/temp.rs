@@ -0,0 +1,79 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::error::Result;
+
+// Distinguishes the folders created by one process
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Folder with a unique name in the system temp directory, removed with everything in it when dropped.
+///
+/// Tests that open databases in their own `TempDir` can run in parallel, see also [`Db::open_temp`](crate::Db::open_temp).
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Result<Self> {
+        let root = std::env::temp_dir();
+        // The time keeps names apart from folders left behind by an earlier process with the same id
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.subsec_nanos());
+        loop {
+            let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+            let path = root.join(format!("cendb-{}-{nanos:x}-{count}", std::process::id()));
+            match fs::create_dir(&path) {
+                Ok(()) => return Ok(TempDir { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::database::Db;
+
+    #[test]
+    fn test_temp_dir() -> Result<()> {
+        let dir = TempDir::new()?;
+        let other = TempDir::new()?;
+        assert_ne!(dir.path(), other.path());
+        fs::write(dir.path().join("file"), b"")?;
+        let path = dir.path().to_path_buf();
+        drop(dir);
+        assert!(!path.exists());
+
+        let db = Db::open_temp()?;
+        db.put(b"a", b"1")?;
+        let folder = db.index().folder().to_path_buf();
+        let iter = db.iter();
+        drop(db);
+        // Handles that outlive the Db keep the folder until the last one is gone
+        assert!(folder.exists());
+        assert_eq!(iter.count(), 1);
+        assert!(!folder.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,30 @@
+// Both take the LogLevel of the database first and drop what is more verbose than it.
+
+// Events of the tracing feature, they compile to nothing without it
+macro_rules! event {
+    ($log_level:expr, $level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        if $log_level >= crate::db::options::LogLevel::$level {
+            tracing::event!(tracing::Level::$level, $($arg)+);
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = $log_level >= crate::db::options::LogLevel::$level;
+    };
+}
+
+// Warnings go to the tracing subscriber with the tracing feature and to stderr without it, so
+// recovery problems stay visible. LogLevel::Off silences both.
+// On stderr the fields given before the message as `name = %value;` are displayed after it.
+macro_rules! warning {
+    ($log_level:expr, $($field:ident = %$value:expr),+; $message:literal) => {
+        if $log_level >= crate::db::options::LogLevel::WARN {
+            #[cfg(feature = "tracing")]
+            tracing::warn!($($field = %$value),+, $message);
+            #[cfg(not(feature = "tracing"))]
+            eprintln!(concat!("warning: ", $message $(, " ", stringify!($field), "={}")+), $($value),+);
+        }
+    };
+}
+
+pub(crate) use event;
+pub(crate) use warning;
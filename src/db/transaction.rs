@@ -0,0 +1,238 @@
+use std::collections::BTreeSet;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use crate::db::batch::WriteBatch;
+use crate::db::database::Db;
+use crate::db::iter::SnapshotIter;
+use crate::db::lookup::LookupTable;
+use crate::db::snapshot::Snapshot;
+use crate::error::{Error, Result};
+
+/// Buffers puts and deletes and applies them atomically on [`commit`](Transaction::commit).
+///
+/// All operations are logged as a single WAL record, so after a crash either all of them
+/// are visible or none. Dropping the transaction without committing discards it.
+///
+/// Transactions are optimistic: [`get`](Transaction::get) reads the database as of
+/// [`Db::begin`] and remembers the key, and the commit fails with [`Error::Conflict`] if another
+/// write changed one of the keys read in the meantime. No locks are held until then.
+pub struct Transaction<'a> {
+    db: &'a Db,
+    batch: WriteBatch,
+    snapshot: Snapshot,
+    // Keys read from the database, as opposed to the transaction's own writes
+    reads: BTreeSet<Vec<u8>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(db: &'a Db) -> Self {
+        let snapshot = db.index().snapshot();
+        Self { db, batch: WriteBatch::new(), snapshot, reads: BTreeSet::new() }
+    }
+
+    /// Reads `key`, seeing the transaction's own puts and deletes.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.batch.lookup(key) {
+            return Ok(value.map(<[u8]>::to_vec));
+        }
+        self.reads.insert(key.to_vec());
+        self.db.index().get_at(None, key, &self.snapshot)
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.batch.put(key, value);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.batch.delete(key);
+    }
+
+    /// Applies the operations unless a key read with [`get`](Transaction::get) was written since the
+    /// transaction began, in that case nothing is written and [`Error::Conflict`] names the key.
+    pub fn commit(self) -> Result<()> {
+        let mut index = self.db.index_mut();
+        if let Some(key) = index.first_written_since(None, &self.reads, &self.snapshot)? {
+            return Err(Error::Conflict(key.clone()));
+        }
+        index.write_batch(None, self.batch)
+    }
+
+    pub fn rollback(self) {}
+}
+
+/// A read-only view of every keyspace pinned to one sequence number, started with [`Db::read_txn`].
+///
+/// Reads ignore writes and compactions that happen after the transaction started. The versions
+/// it reads are kept until it is dropped, so a long-lived one holds on to space in the data file.
+pub struct ReadTransaction {
+    db: Db,
+    snapshot: Arc<Snapshot>,
+    column_family: Option<String>,
+}
+
+impl ReadTransaction {
+    pub(crate) fn new(db: Db) -> Self {
+        let snapshot = Arc::new(db.index().snapshot());
+        Self { db, snapshot, column_family: None }
+    }
+
+    /// Sequence number of the last write visible to the transaction, see [`Db::last_sequence`].
+    pub fn sequence(&self) -> u64 {
+        self.snapshot.sequence()
+    }
+
+    /// The same transaction reading the column family `name` instead.
+    pub fn cf(&self, name: &str) -> ReadTransaction {
+        Self { db: self.db.clone(), snapshot: Arc::clone(&self.snapshot), column_family: Some(name.to_string()) }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.index().get_at(self.column_family.as_deref(), key, &self.snapshot)
+    }
+
+    /// Iterates over the key/value pairs whose key lies within `range`, in ascending key order.
+    pub fn range<K, R>(&self, range: R) -> SnapshotIter
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let keys = self.db.index().range_at(self.column_family.as_deref(), range, &self.snapshot);
+        SnapshotIter::new(self.db.clone(), Arc::clone(&self.snapshot), self.column_family.clone(), keys)
+    }
+
+    /// Iterates over the key/value pairs whose key starts with `prefix`, in ascending key order.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> SnapshotIter {
+        self.range(LookupTable::prefix_bounds(prefix))
+    }
+
+    /// Iterates over all key/value pairs in ascending key order.
+    pub fn iter(&self) -> SnapshotIter {
+        self.range::<&[u8], _>(..)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::temp::TempDir;
+
+    fn entries(iter: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        iter.collect()
+    }
+
+    #[test]
+    fn test_conflicting_transactions() -> Result<()> {
+        let db = Db::open_temp()?;
+        db.put(b"balance", b"10")?;
+        db.put(b"other", b"1")?;
+
+        let mut first = db.begin();
+        let mut second = db.begin();
+        assert_eq!(first.get(b"balance")?, Some(b"10".to_vec()));
+        assert_eq!(second.get(b"balance")?, Some(b"10".to_vec()));
+        first.put(b"balance", b"5");
+        second.put(b"balance", b"7");
+        first.commit()?;
+        assert!(matches!(second.commit(), Err(Error::Conflict(key)) if key == b"balance"));
+        assert_eq!(db.get(b"balance")?, Some(b"5".to_vec()));
+
+        // Writes to keys that were not read and blind writes do not conflict
+        let mut reader = db.begin();
+        let mut blind = db.begin();
+        assert_eq!(reader.get(b"balance")?, Some(b"5".to_vec()));
+        reader.put(b"log", b"read balance");
+        blind.put(b"balance", b"0");
+        db.put(b"other", b"2")?;
+        db.flush()?;
+        reader.commit()?;
+        blind.commit()?;
+        assert_eq!(db.get(b"log")?, Some(b"read balance".to_vec()));
+
+        // A read key deleted concurrently conflicts too, even if it was deleted by a flushed write.
+        // Reads stay at the state the transaction began with.
+        let mut txn = db.begin();
+        assert_eq!(txn.get(b"other")?, Some(b"2".to_vec()));
+        db.delete(b"other")?;
+        db.flush()?;
+        assert_eq!(txn.get(b"other")?, Some(b"2".to_vec()));
+        txn.put(b"other", b"3");
+        assert!(matches!(txn.commit(), Err(Error::Conflict(_))));
+        assert_eq!(db.get(b"other")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_reads_own_writes() -> Result<()> {
+        let db = Db::open_temp()?;
+        db.put(b"a", b"1")?;
+        let mut txn = db.begin();
+        txn.put(b"a", b"2");
+        txn.delete(b"b");
+        assert_eq!(txn.get(b"a")?, Some(b"2".to_vec()));
+        assert_eq!(txn.get(b"b")?, None);
+        // Keys only written by the transaction are not part of its reads
+        db.put(b"a", b"3")?;
+        txn.commit()?;
+        assert_eq!(db.get(b"a")?, Some(b"2".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_txn() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        let items = db.cf("items")?;
+        db.put(b"a", b"1")?;
+        db.put(b"b", b"2")?;
+        items.put(b"book", b"3")?;
+        let txn = db.read_txn();
+        assert_eq!(txn.sequence(), db.last_sequence());
+
+        db.put(b"a", b"changed")?;
+        db.delete(b"b")?;
+        db.put(b"c", b"added")?;
+        items.delete(b"book")?;
+        db.flush()?;
+        db.compact()?;
+        items.put(b"bowl", b"4")?;
+
+        assert_eq!(txn.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(txn.get(b"b")?, Some(b"2".to_vec()));
+        assert_eq!(txn.get(b"c")?, None);
+        assert_eq!(entries(txn.iter())?, vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"2".to_vec())]);
+        assert_eq!(entries(txn.range(b"b".as_slice()..).rev())?, vec![(b"b".to_vec(), b"2".to_vec())]);
+        assert_eq!(entries(txn.cf("items").scan_prefix(b"bo"))?, vec![(b"book".to_vec(), b"3".to_vec())]);
+        assert!(txn.cf("missing").get(b"a").is_err());
+
+        // A transaction started later sees the writes in between
+        let later = db.read_txn();
+        assert_eq!(later.get(b"a")?, Some(b"changed".to_vec()));
+        assert_eq!(entries(later.cf("items").iter())?, vec![(b"bowl".to_vec(), b"4".to_vec())]);
+        drop(txn);
+        drop(later);
+        db.flush()?;
+        assert_eq!(db.get(b"b")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_txn_outlives_iterators() -> Result<()> {
+        let db = Db::open_temp()?;
+        for i in 0u8..10 {
+            db.put(&[i], &[i; 64])?;
+        }
+        let txn = db.read_txn();
+        let mut iter = txn.iter();
+        assert_eq!(iter.next().transpose()?, Some((vec![0], vec![0; 64])));
+        for i in 0u8..10 {
+            db.delete(&[i])?;
+        }
+        db.flush()?;
+        db.compact()?;
+        // A DbIter would fail here, the transaction still reads the removed values
+        assert_eq!(iter.count(), 9);
+        assert_eq!(db.iter().count(), 0);
+        Ok(())
+    }
+}
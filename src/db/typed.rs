@@ -0,0 +1,143 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::db::database::Db;
+use crate::db::typed_db::{KeyCodec, ValueCodec};
+use crate::error::{Error, Result};
+
+/// Serialization format of values stored with [`Db::put_ser_with`] and read with [`Db::get_de_with`].
+///
+/// A value has to be read with the codec it was written with.
+pub trait Codec {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+/// Compact binary encoding with bincode, the codec of [`Db::put_ser`] and [`Db::get_de`].
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Bincode;
+
+/// Varint based binary encoding with postcard, smaller than bincode for small integers.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Postcard;
+
+/// JSON encoding, larger but readable with other tools.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Json;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| Error::Serialization(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(|e| Error::Serialization(e.to_string()))
+    }
+}
+
+impl Codec for Postcard {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        postcard::to_stdvec(value).map_err(|e| Error::Serialization(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        postcard::from_bytes(bytes).map_err(|e| Error::Serialization(e.to_string()))
+    }
+}
+
+impl Codec for Json {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| Error::Serialization(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(|e| Error::Serialization(e.to_string()))
+    }
+}
+
+// Every serde codec encodes the keys and values of a TypedDb, keys sort by their encoding
+impl<C: Codec, T: Serialize + DeserializeOwned> KeyCodec<T> for C {
+    fn encode_key(&self, key: &T) -> Result<Vec<u8>> {
+        C::encode(key)
+    }
+
+    fn decode_key(&self, bytes: &[u8]) -> Result<T> {
+        C::decode(bytes)
+    }
+}
+
+impl<C: Codec, T: Serialize + DeserializeOwned> ValueCodec<T> for C {
+    fn encode_value(&self, value: &T) -> Result<Vec<u8>> {
+        C::encode(value)
+    }
+
+    fn decode_value(&self, bytes: &[u8]) -> Result<T> {
+        C::decode(bytes)
+    }
+}
+
+impl Db {
+    /// Serializes `value` with [`Bincode`] and stores it under `key`.
+    pub fn put_ser<T: Serialize + ?Sized>(&self, key: &[u8], value: &T) -> Result<()> {
+        self.put_ser_with::<Bincode, T>(key, value)
+    }
+
+    /// Reads the value of `key` and deserializes it with [`Bincode`].
+    pub fn get_de<T: DeserializeOwned>(&self, key: &[u8]) -> Result<Option<T>> {
+        self.get_de_with::<Bincode, T>(key)
+    }
+
+    /// Serializes `value` with codec `C` and stores it under `key`.
+    pub fn put_ser_with<C: Codec, T: Serialize + ?Sized>(&self, key: &[u8], value: &T) -> Result<()> {
+        self.put(key, &C::encode(value)?)
+    }
+
+    /// Reads the value of `key` and deserializes it with codec `C`.
+    pub fn get_de_with<C: Codec, T: DeserializeOwned>(&self, key: &[u8]) -> Result<Option<T>> {
+        self.get(key)?.map(|bytes| C::decode(&bytes)).transpose()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use crate::db::typed_db::BigEndian;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn test_typed_values() -> Result<()> {
+        let db = Db::open_temp()?;
+        let user = User { name: "ada".to_string(), age: 36, tags: vec!["admin".to_string()] };
+        db.put_ser(b"bincode", &user)?;
+        db.put_ser_with::<Postcard, _>(b"postcard", &user)?;
+        db.put_ser_with::<Json, _>(b"json", &user)?;
+
+        assert_eq!(db.get_de::<User>(b"bincode")?, Some(user));
+        assert!(db.get_de_with::<Postcard, User>(b"postcard")?.is_some());
+        assert_eq!(db.get(b"json")?, Some(br#"{"name":"ada","age":36,"tags":["admin"]}"#.to_vec()));
+        assert_eq!(db.get_de::<User>(b"missing")?, None);
+        assert!(matches!(db.get_de_with::<Json, User>(b"bincode"), Err(Error::Serialization(_))));
+        db.destroy()
+    }
+
+    #[test]
+    fn test_typed_db_with_serde() -> Result<()> {
+        let db = Db::open_temp()?;
+        let users = db.typed::<u64, User, _, _>(BigEndian, Json);
+        let user = User { name: "ada".to_string(), age: 36, tags: vec![] };
+        users.put(&1, &user)?;
+        assert_eq!(db.get(&1u64.to_be_bytes())?, Some(br#"{"name":"ada","age":36,"tags":[]}"#.to_vec()));
+        assert_eq!(users.get(&1)?, Some(user));
+        let tags = db.typed::<(String, u32), Vec<String>, _, _>(Postcard, Bincode);
+        tags.put(&("ada".to_string(), 2), &vec!["admin".to_string()])?;
+        assert_eq!(tags.get(&("ada".to_string(), 2))?, Some(vec!["admin".to_string()]));
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use crate::db::database::Db;
+use crate::db::iter::DbIter;
+use crate::error::{Error, Result};
+
+/// Turns keys of type `K` into the bytes stored in the database and back, see [`Db::typed`].
+///
+/// Keys are ordered by their encoding, so [`TypedDb::range`] and [`TypedDb::iter`] only follow
+/// the order of `K` with codecs that preserve it, such as [`BigEndian`] and [`Utf8`].
+pub trait KeyCodec<K> {
+    fn encode_key(&self, key: &K) -> Result<Vec<u8>>;
+    fn decode_key(&self, bytes: &[u8]) -> Result<K>;
+}
+
+/// Turns values of type `V` into the bytes stored in the database and back, see [`Db::typed`].
+pub trait ValueCodec<V> {
+    fn encode_value(&self, value: &V) -> Result<Vec<u8>>;
+    fn decode_value(&self, bytes: &[u8]) -> Result<V>;
+}
+
+/// Fixed-size big-endian encoding of integers. The sign bit of signed integers is flipped, so
+/// keys sort in numeric order.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BigEndian;
+
+/// UTF-8 bytes of strings, which sort like the strings.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Utf8;
+
+/// Byte vectors stored as they are.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Raw;
+
+macro_rules! big_endian {
+    ($($int:ty => $flip:expr),*) => {$(
+        impl KeyCodec<$int> for BigEndian {
+            fn encode_key(&self, key: &$int) -> Result<Vec<u8>> {
+                Ok((key ^ $flip).to_be_bytes().to_vec())
+            }
+
+            fn decode_key(&self, bytes: &[u8]) -> Result<$int> {
+                let bytes = bytes.try_into().map_err(|_| {
+                    Error::Serialization(format!("{} bytes are not a {}", bytes.len(), stringify!($int)))
+                })?;
+                Ok(<$int>::from_be_bytes(bytes) ^ $flip)
+            }
+        }
+
+        impl ValueCodec<$int> for BigEndian {
+            fn encode_value(&self, value: &$int) -> Result<Vec<u8>> {
+                self.encode_key(value)
+            }
+
+            fn decode_value(&self, bytes: &[u8]) -> Result<$int> {
+                self.decode_key(bytes)
+            }
+        }
+    )*};
+}
+
+big_endian!(u8 => 0, u16 => 0, u32 => 0, u64 => 0, u128 => 0,
+    i8 => i8::MIN, i16 => i16::MIN, i32 => i32::MIN, i64 => i64::MIN, i128 => i128::MIN);
+
+impl KeyCodec<String> for Utf8 {
+    fn encode_key(&self, key: &String) -> Result<Vec<u8>> {
+        Ok(key.as_bytes().to_vec())
+    }
+
+    fn decode_key(&self, bytes: &[u8]) -> Result<String> {
+        String::from_utf8(bytes.to_vec()).map_err(|e| Error::Serialization(e.to_string()))
+    }
+}
+
+impl ValueCodec<String> for Utf8 {
+    fn encode_value(&self, value: &String) -> Result<Vec<u8>> {
+        self.encode_key(value)
+    }
+
+    fn decode_value(&self, bytes: &[u8]) -> Result<String> {
+        self.decode_key(bytes)
+    }
+}
+
+impl KeyCodec<Vec<u8>> for Raw {
+    fn encode_key(&self, key: &Vec<u8>) -> Result<Vec<u8>> {
+        Ok(key.clone())
+    }
+
+    fn decode_key(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl ValueCodec<Vec<u8>> for Raw {
+    fn encode_value(&self, value: &Vec<u8>) -> Result<Vec<u8>> {
+        Ok(value.clone())
+    }
+
+    fn decode_value(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Handle that reads and writes keys of type `K` and values of type `V` through the codecs it
+/// was created with, returned by [`Db::typed`].
+///
+/// ```no_run
+/// use cendb::{BigEndian, Db, Utf8};
+///
+/// let db = Db::open("data/my_db")?;
+/// let names = db.typed::<u64, String, _, _>(BigEndian, Utf8);
+/// names.put(&7, &"ada".to_string())?;
+/// assert_eq!(names.get(&7)?, Some("ada".to_string()));
+/// # Ok::<(), cendb::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct TypedDb<K, V, KC, VC> {
+    db: Db,
+    key_codec: KC,
+    value_codec: VC,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl Db {
+    /// Returns a handle to this database that encodes keys with `key_codec` and values with
+    /// `value_codec`.
+    pub fn typed<K, V, KC: KeyCodec<K>, VC: ValueCodec<V>>(&self, key_codec: KC, value_codec: VC) -> TypedDb<K, V, KC, VC> {
+        TypedDb { db: self.clone(), key_codec, value_codec, types: PhantomData }
+    }
+}
+
+impl<K, V, KC: KeyCodec<K>, VC: ValueCodec<V>> TypedDb<K, V, KC, VC> {
+    /// The database the handle reads and writes.
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    pub fn put(&self, key: &K, value: &V) -> Result<()> {
+        self.db.put(&self.key_codec.encode_key(key)?, &self.value_codec.encode_value(value)?)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let bytes = self.db.get(&self.key_codec.encode_key(key)?)?;
+        bytes.map(|bytes| self.value_codec.decode_value(&bytes)).transpose()
+    }
+
+    pub fn delete(&self, key: &K) -> Result<()> {
+        self.db.delete(&self.key_codec.encode_key(key)?)
+    }
+
+    /// Iterates over the pairs whose encoded key lies within the encoded `range`, in the order
+    /// of the encoded keys.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<TypedIter<'_, K, V, KC, VC>> {
+        let encode = |bound: Bound<&K>| -> Result<Bound<Vec<u8>>> {
+            Ok(match bound {
+                Bound::Included(key) => Bound::Included(self.key_codec.encode_key(key)?),
+                Bound::Excluded(key) => Bound::Excluded(self.key_codec.encode_key(key)?),
+                Bound::Unbounded => Bound::Unbounded,
+            })
+        };
+        let bounds = (encode(range.start_bound())?, encode(range.end_bound())?);
+        Ok(TypedIter { typed: self, inner: self.db.range::<Vec<u8>, _>(bounds) })
+    }
+
+    /// Iterates over all pairs in the order of the encoded keys.
+    pub fn iter(&self) -> TypedIter<'_, K, V, KC, VC> {
+        TypedIter { typed: self, inner: self.db.iter() }
+    }
+}
+
+/// Iterator over the decoded pairs of a [`TypedDb`].
+pub struct TypedIter<'a, K, V, KC, VC> {
+    typed: &'a TypedDb<K, V, KC, VC>,
+    inner: DbIter,
+}
+
+impl<K, V, KC: KeyCodec<K>, VC: ValueCodec<V>> Iterator for TypedIter<'_, K, V, KC, VC> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pair = self.inner.next()?;
+        Some(pair.and_then(|(key, value)| {
+            Ok((self.typed.key_codec.decode_key(&key)?, self.typed.value_codec.decode_value(&value)?))
+        }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typed_db() -> Result<()> {
+        let db = Db::open_temp()?;
+        let balances = db.typed::<i64, u64, _, _>(BigEndian, BigEndian);
+        for key in [3, -20, 0, i64::MIN, 7] {
+            balances.put(&key, &(key.unsigned_abs() / 2))?;
+        }
+        assert_eq!(balances.get(&-20)?, Some(10));
+        balances.delete(&0)?;
+        assert_eq!(balances.get(&0)?, None);
+        // Signed keys sort in numeric order
+        let keys: Vec<_> = balances.iter().map(|pair| pair.map(|(key, _)| key)).collect::<Result<_>>()?;
+        assert_eq!(keys, [i64::MIN, -20, 3, 7]);
+        let keys: Vec<_> = balances.range(-20..=3)?.map(|pair| pair.map(|(key, _)| key)).collect::<Result<_>>()?;
+        assert_eq!(keys, [-20, 3]);
+
+        let names = db.typed::<String, Vec<u8>, _, _>(Utf8, Raw);
+        db.put(&[0xff], b"")?;
+        assert!(matches!(names.iter().last(), Some(Err(Error::Serialization(_)))));
+        names.put(&"ada".to_string(), &vec![1, 2])?;
+        assert_eq!(names.get(&"ada".to_string())?, Some(vec![1, 2]));
+        Ok(())
+    }
+}
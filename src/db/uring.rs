@@ -0,0 +1,317 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::db::backend::{FileSystem, OpenMode, StorageBackend, StorageFile};
+
+/// The local file system with file reads, writes and syncs submitted through io_uring on Linux,
+/// see [`DbOptions::backend`](crate::DbOptions::backend).
+///
+/// Every thread submits through a ring of its own, so readers don't wait on each other. A WAL
+/// append that is synced right away is submitted together with its sync as one request. Where
+/// io_uring is not available, on other systems or on kernels that lack or forbid it, files are
+/// read and written as [`FileSystem`] does.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct IoUringBackend;
+
+impl IoUringBackend {
+    /// Whether the calling thread can submit through io_uring, files fall back to `std::fs` if not.
+    pub fn is_supported() -> bool {
+        ring::available()
+    }
+}
+
+impl StorageBackend for IoUringBackend {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Arc<dyn StorageFile>> {
+        Ok(Arc::new(UringFile(FileSystem::open_file(path, mode)?)))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        FileSystem.exists(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        FileSystem.is_dir(path)
+    }
+
+    fn list(&self, folder: &Path) -> io::Result<Vec<PathBuf>> {
+        FileSystem.list(folder)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        FileSystem.create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        FileSystem.remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        FileSystem.remove_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        FileSystem.rename(from, to)
+    }
+
+    fn sync_dir(&self, folder: &Path) -> io::Result<()> {
+        FileSystem.sync_dir(folder)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        FileSystem.read(path)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        FileSystem.file_len(path)
+    }
+}
+
+// A file whose reads, writes and syncs go through the ring of the calling thread
+struct UringFile(File);
+
+#[cfg(target_os = "linux")]
+impl StorageFile for UringFile {
+    fn read_at(&self, mut buffer: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buffer.is_empty() {
+            let len = buffer.len().min(ring::MAX_IO) as u32;
+            let read = ring::read(&self.0, buffer.as_mut_ptr(), len, offset);
+            let Some(read) = read else { return self.0.read_at(buffer, offset) };
+            match read? {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                n => {
+                    buffer = &mut buffer[n..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+        while !data.is_empty() {
+            let len = data.len().min(ring::MAX_IO) as u32;
+            let Some(written) = ring::write(&self.0, data.as_ptr(), len, offset) else {
+                return self.0.write_at(data, offset);
+            };
+            match written? {
+                0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                n => {
+                    data = &data[n..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_and_sync_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        if data.len() > ring::MAX_IO {
+            self.write_at(data, offset)?;
+            return self.sync();
+        }
+        let Some(written) = ring::write_and_sync(&self.0, data.as_ptr(), data.len() as u32, offset) else {
+            self.0.write_at(data, offset)?;
+            return self.0.sync();
+        };
+        // A short write cancels the linked sync, the rest is written and synced on its own
+        match written? {
+            n if n == data.len() => Ok(()),
+            n => {
+                self.write_at(&data[n..], offset + n as u64)?;
+                self.sync()
+            }
+        }
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        match ring::sync(&self.0) {
+            Some(synced) => synced,
+            None => self.0.sync(),
+        }
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.0.len()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        StorageFile::set_len(&self.0, len)
+    }
+
+    fn try_lock(&self, shared: bool) -> io::Result<bool> {
+        StorageFile::try_lock(&self.0, shared)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl StorageFile for UringFile {
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        self.0.read_at(buffer, offset)
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        self.0.write_at(data, offset)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.0.sync()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.0.len()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        StorageFile::set_len(&self.0, len)
+    }
+
+    fn try_lock(&self, shared: bool) -> io::Result<bool> {
+        StorageFile::try_lock(&self.0, shared)
+    }
+}
+
+// The ring of each thread, created on its first use. Every call submits its entries and waits
+// for their completions before it returns, so the buffers they point to outlive the requests.
+// Calls return None if the thread has no ring.
+#[cfg(target_os = "linux")]
+mod ring {
+    use std::cell::RefCell;
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::AsRawFd;
+
+    use io_uring::{opcode, squeue, types, IoUring};
+
+    // Largest read or write of a single request, longer ones are split
+    pub(super) const MAX_IO: usize = 1 << 30;
+
+    // Requests in flight at once, a call never submits more than two
+    const RING_ENTRIES: u32 = 4;
+
+    thread_local! {
+        static RING: Option<RefCell<IoUring>> = IoUring::new(RING_ENTRIES).ok().map(RefCell::new);
+    }
+
+    pub(super) fn available() -> bool {
+        RING.with(|ring| ring.is_some())
+    }
+
+    pub(super) fn read(file: &File, buffer: *mut u8, len: u32, offset: u64) -> Option<io::Result<usize>> {
+        let entry = opcode::Read::new(types::Fd(file.as_raw_fd()), buffer, len).offset(offset).build();
+        submit(&[entry]).map(|results| results.and_then(|results| completed(results[0])))
+    }
+
+    pub(super) fn write(file: &File, data: *const u8, len: u32, offset: u64) -> Option<io::Result<usize>> {
+        let entry = opcode::Write::new(types::Fd(file.as_raw_fd()), data, len).offset(offset).build();
+        submit(&[entry]).map(|results| results.and_then(|results| completed(results[0])))
+    }
+
+    pub(super) fn sync(file: &File) -> Option<io::Result<()>> {
+        let entry = opcode::Fsync::new(types::Fd(file.as_raw_fd())).build();
+        submit(&[entry]).map(|results| results.and_then(|results| completed(results[0]).map(|_| ())))
+    }
+
+    // Writes and then syncs in a single submission. The sync only runs once the write completed
+    // in full, returns the bytes written.
+    pub(super) fn write_and_sync(file: &File, data: *const u8, len: u32, offset: u64) -> Option<io::Result<usize>> {
+        let fd = types::Fd(file.as_raw_fd());
+        let write = opcode::Write::new(fd, data, len).offset(offset).build().flags(squeue::Flags::IO_LINK);
+        let sync = opcode::Fsync::new(fd).build();
+        submit(&[write, sync]).map(|results| {
+            let results = results?;
+            let written = completed(results[0])?;
+            if written == len as usize {
+                completed(results[1])?;
+            }
+            Ok(written)
+        })
+    }
+
+    // Results of the entries in order, negative ones are errno values
+    fn submit(entries: &[squeue::Entry]) -> Option<io::Result<Vec<i32>>> {
+        RING.with(|ring| {
+            let mut ring = ring.as_ref()?.borrow_mut();
+            let entries: Vec<_> = entries.iter().enumerate().map(|(i, entry)| entry.clone().user_data(i as u64)).collect();
+            // Safety: the buffers the entries point to are borrowed by the caller until the
+            // completions of all entries were reaped below
+            let pushed = unsafe { ring.submission().push_multiple(&entries) };
+            if pushed.is_err() {
+                return Some(Err(io::Error::other("io_uring submission queue is full")));
+            }
+            let mut results = vec![None; entries.len()];
+            let mut waiting = entries.len();
+            while waiting > 0 {
+                match ring.submit_and_wait(waiting) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Some(Err(e)),
+                }
+                for entry in ring.completion() {
+                    results[entry.user_data() as usize] = Some(entry.result());
+                    waiting -= 1;
+                }
+            }
+            Some(Ok(results.into_iter().map(|result| result.unwrap_or(0)).collect()))
+        })
+    }
+
+    fn completed(result: i32) -> io::Result<usize> {
+        match result {
+            ..0 => Err(io::Error::from_raw_os_error(-result)),
+            n => Ok(n as usize),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod ring {
+    pub(super) fn available() -> bool {
+        false
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::options::DbOptions;
+    use crate::db::temp::TempDir;
+    use crate::error::Result;
+
+    #[test]
+    fn test_uring_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("file");
+        let file = IoUringBackend.open(&path, OpenMode::CreateNew)?;
+        file.write_at(b"hello", 0)?;
+        file.write_and_sync_at(b" world", 5)?;
+        let mut buffer = [0; 11];
+        file.read_at(&mut buffer, 0)?;
+        assert_eq!(&buffer, b"hello world");
+        assert_eq!(file.len()?, 11);
+        let error = file.read_at(&mut buffer, 4).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(std::fs::read(&path)?, b"hello world");
+        Ok(())
+    }
+
+    #[test]
+    fn test_uring_database() -> Result<()> {
+        let dir = TempDir::new()?;
+        let options = DbOptions::new().path(dir.path()).backend(IoUringBackend);
+        let db = options.open()?;
+        for i in 0..100u32 {
+            db.put(&i.to_be_bytes(), &i.to_le_bytes())?;
+        }
+        db.flush()?;
+        db.put(b"tail", b"1")?;
+        db.close()?;
+        // Files written through the ring are the same as those of std::fs
+        let db = DbOptions::new().path(dir.path()).open()?;
+        assert_eq!(db.get(&7u32.to_be_bytes())?, Some(7u32.to_le_bytes().to_vec()));
+        assert_eq!(db.get(b"tail")?, Some(b"1".to_vec()));
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+// Unsigned LEB128, seven bits per byte starting with the lowest, the high bit set on every byte but the last
+pub(crate) const MAX_VARINT_SIZE: usize = 10;
+
+pub(crate) fn encode_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+// Reads the varint at offset, returning it with the offset just past it. None if it runs past the
+// end of buffer or does not fit in a u64.
+pub(crate) fn decode_varint(buffer: &[u8], offset: usize) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, byte) in buffer.get(offset..)?.iter().take(MAX_VARINT_SIZE).enumerate() {
+        let bits = (*byte & 0x7f) as u64;
+        if i == MAX_VARINT_SIZE - 1 && bits > 1 {
+            return None;
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, offset + i + 1));
+        }
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint_roundtrip() {
+        for value in [0, 1, 127, 128, 300, 16_383, 16_384, u32::MAX as u64, u64::MAX - 1, u64::MAX] {
+            let mut buffer = vec![0xff];
+            encode_varint(&mut buffer, value);
+            assert_eq!(decode_varint(&buffer, 1), Some((value, buffer.len())));
+            // Torn
+            assert_eq!(decode_varint(&buffer[..buffer.len() - 1], 1), None);
+        }
+        let mut small = Vec::new();
+        encode_varint(&mut small, 100);
+        assert_eq!(small, [100]);
+        // Past u64::MAX, and longer than any u64
+        assert_eq!(decode_varint(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02], 0), None);
+        assert_eq!(decode_varint(&[0x80; 11], 0), None);
+    }
+}
@@ -0,0 +1,262 @@
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use crate::db::backend::FileSystem;
+use crate::db::header::{FileHeader, HEADER_SIZE, WAL_MAGIC};
+use crate::db::lookup::{
+    LookupTable, WalError, WalFormat, WalOperation, CHECKPOINT_RECORD, COUNT_SIZE, LSN_SIZE, PART_RECORD, SEQUENCED_RECORD,
+    SHARD_INDEX_SIZE,
+};
+use crate::error::Result;
+
+/// Operation logged by a WAL record, see [`dump`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalOpType {
+    Insert,
+    Remove,
+    Batch,
+    InsertExpiring,
+    Merge,
+    Increment,
+    /// Marks the records before it as covered by map.db, written by a flush.
+    Checkpoint,
+    /// A type byte this build does not know, or a record too short to have one.
+    Unknown,
+}
+
+impl WalOpType {
+    fn from_byte(byte: Option<&u8>) -> Self {
+        match byte {
+            Some(0) => WalOpType::Insert,
+            Some(1) => WalOpType::Remove,
+            Some(2) => WalOpType::Batch,
+            Some(3) => WalOpType::InsertExpiring,
+            Some(4) => WalOpType::Merge,
+            Some(5) => WalOpType::Increment,
+            _ => WalOpType::Unknown,
+        }
+    }
+
+    // Since format version 4 the operation follows the sequence number at the start of the body,
+    // in parts of a batch spanning several shards also the indexes of those shards
+    fn of_body(body: &[u8], format: WalFormat) -> Self {
+        let start = match body.first() {
+            Some(&SEQUENCED_RECORD) => format.read_int(body, 1, LSN_SIZE).map(|(_, start)| start),
+            Some(&PART_RECORD) => format.read_int(body, 1, LSN_SIZE)
+                .and_then(|(_, start)| format.read_int(body, start, COUNT_SIZE))
+                .and_then(|(count, start)| (0..count).try_fold(start, |start, _| {
+                    Some(format.read_int(body, start, SHARD_INDEX_SIZE)?.1)
+                })),
+            Some(&CHECKPOINT_RECORD) => return WalOpType::Checkpoint,
+            _ => Some(0),
+        };
+        WalOpType::from_byte(start.and_then(|start| body.get(start)))
+    }
+}
+
+/// A WAL record decoded by [`dump`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalRecordInfo {
+    /// Segment file holding the record.
+    pub path: PathBuf,
+    /// Byte offset of the record in its segment.
+    pub offset: u64,
+    /// Sequence number of the record, as used by [`Db::restore_to`](crate::Db::restore_to).
+    /// Records written before sequence numbers were logged are numbered by their position in the log.
+    /// `None` for records that opening the database discards, the first invalid one and all after it.
+    pub sequence: Option<u64>,
+    pub op_type: WalOpType,
+    /// Keys written or removed by the record in order, several for a batch.
+    /// Empty if the record could not be decoded.
+    pub keys: Vec<Vec<u8>>,
+    /// Length of the record body in bytes, as stored in its header.
+    pub length: u64,
+    /// Whether the body is complete and matches its checksum.
+    pub checksum_valid: bool,
+}
+
+/// Decodes every record of the WAL at `path` without opening the database.
+///
+/// `path` is a WAL segment or the folder of a database, column family or shard, whose segments are read
+/// in replay order. Records after a corrupt one are still decoded as long as the lengths in their
+/// headers stay within the file, a torn record ends its segment.
+pub fn dump(path: impl AsRef<Path>) -> Result<Vec<WalRecordInfo>> {
+    let path = path.as_ref();
+    let segments = match path.is_dir() {
+        true => LookupTable::wal_segments(&FileSystem, path)?.into_iter().map(|(_, path)| path).collect(),
+        false => vec![path.to_path_buf()],
+    };
+    let mut records = Vec::new();
+    let mut sequence = Some(0);
+    for segment in segments {
+        dump_segment(&segment, &mut sequence, &mut records)?;
+    }
+    Ok(records)
+}
+
+/// Decodes the records of every WAL of the database at `folder`: the default keyspace, then every
+/// column family, each followed by its shards. Every folder is numbered on its own like [`dump`] does.
+pub fn dump_database(folder: impl AsRef<Path>) -> Result<Vec<WalRecordInfo>> {
+    let folder = folder.as_ref();
+    let mut keyspaces = vec![folder.to_path_buf()];
+    let column_families = folder.join("cf");
+    if column_families.is_dir() {
+        keyspaces.extend(subfolders(&column_families, |_| true)?);
+    }
+    let mut records = Vec::new();
+    for keyspace in keyspaces {
+        records.extend(dump(&keyspace)?);
+        for shard in subfolders(&keyspace, |name| name.starts_with("shard-"))? {
+            records.extend(dump(&shard)?);
+        }
+    }
+    Ok(records)
+}
+
+// Subfolders of folder whose name matches, in name order
+fn subfolders(folder: &Path, matches: impl Fn(&str) -> bool) -> Result<Vec<PathBuf>> {
+    let mut folders = Vec::new();
+    for entry in fs::read_dir(folder).map_err(WalError::io(folder, "list"))? {
+        let path = entry.map_err(WalError::io(folder, "list"))?.path();
+        if path.is_dir() && path.file_name().and_then(|name| name.to_str()).is_some_and(&matches) {
+            folders.push(path);
+        }
+    }
+    folders.sort();
+    Ok(folders)
+}
+
+// Appends the records of one segment, sequence holds the last sequence number and turns None at the
+// first record replay would reject
+fn dump_segment(path: &Path, sequence: &mut Option<u64>, records: &mut Vec<WalRecordInfo>) -> Result<()> {
+    let buffer = fs::read(path).map_err(WalError::io(path, "read"))?;
+    let format = WalFormat::of_version(FileHeader::decode(&buffer, WAL_MAGIC)?.version());
+    let mut offset = HEADER_SIZE;
+    while offset < buffer.len() {
+        let frame = format.frame(&buffer, offset);
+        let (record, next) = match (LookupTable::read_wal_record(&buffer, offset, format), &frame) {
+            (Some((record, next)), Some((body, _, _))) => {
+                let mut keys = Vec::new();
+                if let Some(operation) = &record.operation {
+                    collect_keys(operation, &mut keys);
+                }
+                let op_type = WalOpType::of_body(&buffer[body.clone()], format);
+                *sequence = sequence.map(|sequence| record.lsn.unwrap_or(sequence + 1));
+                (WalRecordInfo {
+                    path: path.to_path_buf(), offset: offset as u64, sequence: *sequence, op_type, keys,
+                    length: body.len() as u64, checksum_valid: true,
+                }, Some(next))
+            }
+            _ => {
+                *sequence = None;
+                invalid_record(path, &buffer, offset, format, frame.clone())
+            }
+        };
+        records.push(record);
+        match next {
+            Some(next) => offset = next,
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+// Describes a record replay rejects, with the offset of the next one if its length is within the file
+fn invalid_record(
+    path: &Path,
+    buffer: &[u8],
+    offset: usize,
+    format: WalFormat,
+    frame: Option<(Range<usize>, bool, usize)>,
+) -> (WalRecordInfo, Option<usize>) {
+    let info = WalRecordInfo {
+        path: path.to_path_buf(),
+        offset: offset as u64,
+        sequence: None,
+        op_type: match &frame {
+            Some((body, _, _)) => WalOpType::of_body(&buffer[body.clone()], format),
+            None => WalOpType::Unknown,
+        },
+        keys: Vec::new(),
+        length: frame.as_ref().map_or(buffer.len() - offset, |(body, _, _)| body.len()) as u64,
+        checksum_valid: frame.as_ref().is_some_and(|(_, valid, _)| *valid),
+    };
+    (info, frame.map(|(_, _, next)| next))
+}
+
+fn collect_keys(operation: &WalOperation, keys: &mut Vec<Vec<u8>>) {
+    match operation {
+        WalOperation::Insert{key, ..}
+        | WalOperation::InsertExpiring{key, ..}
+        | WalOperation::Merge{key, ..}
+        | WalOperation::Increment{key, ..}
+        | WalOperation::Remove{key} => keys.push(key.clone()),
+        WalOperation::Batch(operations) => {
+            for operation in operations {
+                collect_keys(operation, keys);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::database::Db;
+    use crate::db::batch::WriteBatch;
+    use crate::db::options::DbOptions;
+    use crate::db::temp::TempDir;
+
+    #[test]
+    fn test_dump() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = Db::open(&dir)?;
+        db.put(b"a", b"1")?;
+        db.delete(b"a")?;
+        db.write(WriteBatch::new().put(b"b", b"2").put(b"c", b"3").clone())?;
+        db.put(b"d", b"4")?;
+
+        let records = dump(&dir)?;
+        let summary: Vec<_> = records.iter().map(|record| (record.sequence, record.op_type, record.keys.len())).collect();
+        assert_eq!(summary, [
+            (Some(1), WalOpType::Insert, 1),
+            (Some(2), WalOpType::Remove, 1),
+            (Some(3), WalOpType::Batch, 2),
+            (Some(4), WalOpType::Insert, 1),
+        ]);
+        assert_eq!(records[2].keys, [b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(records[0].offset, HEADER_SIZE as u64);
+
+        // A flipped byte in the key of the second record ends replay, the records after it are still listed
+        let path = records[1].path.clone();
+        let mut bytes = fs::read(&path)?;
+        bytes[(records[1].offset + records[1].length) as usize] ^= 0xff;
+        bytes.truncate(bytes.len() - 3);
+        fs::write(&path, &bytes)?;
+        let records = dump(&path)?;
+        let summary: Vec<_> = records.iter().map(|record| (record.sequence, record.checksum_valid)).collect();
+        assert_eq!(summary, [(Some(1), true), (None, false), (None, true), (None, false)]);
+        assert_eq!(records[1].op_type, WalOpType::Remove);
+        assert!(dump(dir.path().join("map.db")).is_err());
+        db.destroy()
+    }
+
+    #[test]
+    fn test_dump_database() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = DbOptions::new().path(&dir).shards(4).open()?;
+        for i in 0..20u32 {
+            db.put(&i.to_be_bytes(), b"v")?;
+        }
+        db.cf("users")?.put(b"ada", b"1")?;
+        db.cf("users")?.put(b"bob", b"2")?;
+
+        let records = dump_database(&dir)?;
+        let inserts = records.iter().filter(|record| record.op_type == WalOpType::Insert).count();
+        assert_eq!(inserts, 22);
+        assert!(records.iter().any(|record| record.path.starts_with(dir.path().join("shard-003"))));
+        assert!(records.iter().any(|record| record.path.starts_with(dir.path().join("cf").join("users"))));
+        db.destroy()
+    }
+}
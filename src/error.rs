@@ -1,5 +1,7 @@
-use std::{io, path::Display};
+use std::path::{Display, PathBuf};
 use derive_more::From;
+use crate::db::index::IndexError;
+use crate::db::lookup::{MapError, WalError};
 pub type Result<T> = core::result::Result<T, Error>;
 // pub type Error = Box<dyn std::error::Error>; // for development
 
@@ -15,6 +17,26 @@ pub enum Error {
     // or later we can declare the error in the module and do
     // Fs(crate::fs::Error)
 
+    // -- db
+    // Another handle or process holds the lock file of the database directory
+    DatabaseLocked(PathBuf),
+    // A file does not carry a valid header, it was not written by this database
+    InvalidFormat(String),
+    // A file was written by a newer format version than this build can read
+    UnsupportedVersion(u16),
+    // A typed value could not be encoded or decoded by its codec
+    Serialization(String),
+    // A key read by a transaction was written by someone else before the transaction committed
+    Conflict(Vec<u8>),
+    // Too many writes are waiting for a flush, see DbOptions::backpressure
+    Backpressure,
+    #[from]
+    Wal(WalError),
+    #[from]
+    Map(MapError),
+    #[from]
+    Index(IndexError),
+
     // -- Externals
     // #[from]
     // Io(std::io::Error), // create a new error type in the module
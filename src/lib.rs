@@ -0,0 +1,21 @@
+mod error;
+pub mod db;
+
+pub use self::error::{Error, Result};
+pub use self::db::{
+    Backpressure, BigEndian, CacheStats, ChangeEvent, CheckReport, Clock, ColumnFamily, Compression, Cursor, Db, DbIter, DbOptions, DiskUsage, Element, Engine, EngineIter, FaultyBackend, FaultyFile, FileSystem, Format,
+    IndexError, KeyCodec, KeyHasher, KeyspaceUsage, Latency, LogLevel, ManualClock, MapError, MemoryBackend, MemoryUsage, MultiMap, OpenMode, Queue, Raw, ReadTransaction, RecoveryReport, ReplicationHandle,
+    ReplicationServer, Snapshot, SnapshotIter, Stats, StorageBackend, StorageEngine, StorageFile, SyncPolicy, SystemClock, TempDir,
+    Transaction, Tuple, TypedDb, TypedIter, Utf8, ValueCodec, ValueRef, WalError, WalOpType, WalRecordInfo, WriteBatch, WriteOptions,
+};
+pub use self::db::wal;
+#[cfg(feature = "serde")]
+pub use self::db::{Bincode, Codec, Json, Postcard};
+#[cfg(feature = "tokio")]
+pub use self::db::AsyncDb;
+#[cfg(feature = "server")]
+pub use self::db::Server;
+#[cfg(feature = "cendb-grpc")]
+pub use self::db::grpc::GrpcService;
+#[cfg(feature = "io-uring")]
+pub use self::db::IoUringBackend;
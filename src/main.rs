@@ -1,9 +1,237 @@
+#[cfg(feature = "shell")]
+mod shell;
 
-mod error;
-pub mod db;
+use std::path::Path;
+use cendb::{wal, ColumnFamily, Db, DbIter, DbOptions, Error, Result};
 
+const USAGE: &str = "\
+usage: cendb <command> <dir> [arguments]
 
-pub use self::error::{Error, Result};
-fn main() -> Result<()> {
+commands:
+    get <dir> <key> [--cf <name>]            print the value of a key
+    put <dir> <key> <value> [--cf <name>]    store a value
+    delete <dir> <key> [--cf <name>]         delete a key
+    scan <dir> [prefix] [--cf <name>] [--limit <n>]
+                                             print the entries whose key starts with prefix
+    stats <dir>                              print entry counts and file sizes
+    check <dir> [--repair]                   verify every value and index file, --repair deletes
+                                             broken keys and rebuilds the database
+    dump-wal <dir>                           decode the WAL records of every keyspace
+    shell <dir>                              interactive prompt, needs the shell feature
+
+Keys and values are taken as UTF-8, bytes that are not printable ASCII are shown escaped.";
+
+// Default keyspace or a column family of an opened database
+enum Keyspace {
+    Default(Db),
+    ColumnFamily(ColumnFamily),
+}
+
+impl Keyspace {
+    fn open(dir: &str, column_family: Option<&str>, read_only: bool) -> Result<Self> {
+        let db = DbOptions::new().path(dir).create_if_missing(false).read_only(read_only).open()?;
+        match column_family {
+            Some(name) if read_only && !db.column_families().iter().any(|existing| existing == name) => {
+                Err(Error::Custom(format!("no column family {name}")))
+            }
+            Some(name) => Ok(Keyspace::ColumnFamily(db.cf(name)?)),
+            None => Ok(Keyspace::Default(db)),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self {
+            Keyspace::Default(db) => db.get(key),
+            Keyspace::ColumnFamily(cf) => cf.get(key),
+        }
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        match self {
+            Keyspace::Default(db) => db.put(key, value),
+            Keyspace::ColumnFamily(cf) => cf.put(key, value),
+        }
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        match self {
+            Keyspace::Default(db) => db.delete(key),
+            Keyspace::ColumnFamily(cf) => cf.delete(key),
+        }
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> DbIter {
+        match self {
+            Keyspace::Default(db) => db.scan_prefix(prefix),
+            Keyspace::ColumnFamily(cf) => cf.scan_prefix(prefix),
+        }
+    }
+}
+
+// Positional arguments and the values of the --cf, --limit and --repair options
+struct Args {
+    positional: Vec<String>,
+    column_family: Option<String>,
+    limit: Option<usize>,
+    repair: bool,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut parsed = Args { positional: Vec::new(), column_family: None, limit: None, repair: false };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--cf" => parsed.column_family = Some(args.next().ok_or("--cf needs a name")?),
+                "--limit" => {
+                    let limit = args.next().ok_or("--limit needs a number")?;
+                    parsed.limit = Some(limit.parse().map_err(|_| Error::Custom(format!("invalid limit {limit}")))?);
+                }
+                "--repair" => parsed.repair = true,
+                _ => parsed.positional.push(arg),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn main() {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => exit_with_usage(&e),
+    };
+    let positional: Vec<&str> = args.positional.iter().map(String::as_str).collect();
+    let column_family = args.column_family.as_deref();
+    let result = match positional.as_slice() {
+        ["get", dir, key] => get(dir, column_family, key),
+        ["put", dir, key, value] => put(dir, column_family, key, value),
+        ["delete", dir, key] => delete(dir, column_family, key),
+        ["scan", dir] => scan(dir, column_family, "", args.limit),
+        ["scan", dir, prefix] => scan(dir, column_family, prefix, args.limit),
+        ["stats", dir] => stats(dir),
+        ["check", dir] => check(dir, args.repair),
+        ["dump-wal", dir] => dump_wal(dir),
+        ["shell", dir] => shell(dir),
+        _ => exit_with_usage(&Error::Custom("invalid arguments".to_string())),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn exit_with_usage(error: &Error) -> ! {
+    eprintln!("error: {error}\n\n{USAGE}");
+    std::process::exit(2);
+}
+
+fn get(dir: &str, column_family: Option<&str>, key: &str) -> Result<()> {
+    match Keyspace::open(dir, column_family, true)?.get(key.as_bytes())? {
+        Some(value) => println!("{}", value.escape_ascii()),
+        None => return Err(Error::Custom(format!("key {key} not found"))),
+    }
+    Ok(())
+}
+
+fn put(dir: &str, column_family: Option<&str>, key: &str, value: &str) -> Result<()> {
+    Keyspace::open(dir, column_family, false)?.put(key.as_bytes(), value.as_bytes())
+}
+
+fn delete(dir: &str, column_family: Option<&str>, key: &str) -> Result<()> {
+    Keyspace::open(dir, column_family, false)?.delete(key.as_bytes())
+}
+
+fn scan(dir: &str, column_family: Option<&str>, prefix: &str, limit: Option<usize>) -> Result<()> {
+    let keyspace = Keyspace::open(dir, column_family, true)?;
+    for entry in keyspace.scan_prefix(prefix.as_bytes()).take(limit.unwrap_or(usize::MAX)) {
+        let (key, value) = entry?;
+        println!("{}\t{}", key.escape_ascii(), value.escape_ascii());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "shell")]
+fn shell(dir: &str) -> Result<()> {
+    shell::run(dir)
+}
+
+#[cfg(not(feature = "shell"))]
+fn shell(_dir: &str) -> Result<()> {
+    Err(Error::Custom("cendb was built without the shell feature".to_string()))
+}
+
+fn stats(dir: &str) -> Result<()> {
+    let db = DbOptions::new().path(dir).create_if_missing(false).read_only(true).open()?;
+    let stats = db.stats()?;
+    println!("entries: {}", db.iter().count());
+    for name in db.column_families() {
+        println!("column family {name}: {} entries", db.cf(&name)?.iter().count());
+    }
+    let recovery = db.recovery_report();
+    println!("wal records replayed: {}", recovery.wal_records_replayed);
+    if !recovery.is_clean() {
+        println!("recovery: {recovery:?}");
+    }
+    println!("data size: {} bytes", stats.data_size);
+    println!("index size: {} bytes", stats.index_size);
+    print_file_sizes(Path::new(dir))
+}
+
+fn check(dir: &str, repair: bool) -> Result<()> {
+    let db = DbOptions::new().path(dir).create_if_missing(false).read_only(!repair).open()?;
+    let report = db.check(repair)?;
+    println!("keys checked: {}", report.keys_checked);
+    for (column_family, key) in &report.broken_keys {
+        match column_family {
+            Some(name) => println!("broken key in column family {name}: {}", key.escape_ascii()),
+            None => println!("broken key: {}", key.escape_ascii()),
+        }
+    }
+    for path in &report.corrupt_files {
+        println!("corrupt file: {}", path.display());
+    }
+    println!("orphaned data: {} bytes", report.orphaned_bytes);
+    if report.repaired {
+        println!("repaired");
+    } else if !report.is_clean() {
+        return Err(Error::Custom("database is inconsistent, run with --repair to fix it".to_string()));
+    }
+    Ok(())
+}
+
+// Sizes of the files in dir and its subfolders, relative to the database folder
+fn print_file_sizes(dir: &Path) -> Result<()> {
+    let mut folders = vec![dir.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        let mut entries = std::fs::read_dir(&folder)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.path());
+        for entry in entries {
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                folders.push(path);
+            } else {
+                let name = path.strip_prefix(dir).unwrap_or(&path);
+                println!("{}: {} bytes", name.display(), entry.metadata()?.len());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn dump_wal(dir: &str) -> Result<()> {
+    let dir = Path::new(dir);
+    for record in wal::dump_database(dir)? {
+        let name = record.path.strip_prefix(dir).unwrap_or(&record.path);
+        let sequence = record.sequence.map_or("-".to_string(), |sequence| sequence.to_string());
+        let keys: Vec<String> = record.keys.iter().map(|key| key.escape_ascii().to_string()).collect();
+        let status = match record.checksum_valid {
+            true if record.sequence.is_some() => "ok",
+            true => "discarded",
+            false => "corrupt",
+        };
+        println!(
+            "{}@{}\tseq {sequence}\t{:?}\t{} bytes\t{status}\t{}",
+            name.display(), record.offset, record.op_type, record.length, keys.join(" "),
+        );
+    }
     Ok(())
-}
\ No newline at end of file
+}
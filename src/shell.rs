@@ -0,0 +1,145 @@
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use cendb::{Db, DbOptions, Error, Result};
+use crate::Keyspace;
+
+const HELP: &str = "\
+commands:
+    get <key>              print the value of a key
+    put <key> <value>      store a value
+    delete <key>           delete a key
+    scan [prefix] [limit]  print the entries whose key starts with prefix
+    use [column family]    switch to a column family, or back to the default keyspace
+    flush                  write the lookup tables and checkpoint the WAL
+    compact                reclaim the space of overwritten and deleted values
+    help                   show this list
+    exit                   leave the shell
+
+Arguments with spaces can be put in double quotes.";
+
+// Entries printed by scan without a limit
+const DEFAULT_SCAN_LIMIT: usize = 100;
+
+// Interactive prompt on the database in dir, until exit or end of input
+pub fn run(dir: &str) -> Result<()> {
+    let db = DbOptions::new().path(dir).create_if_missing(false).open()?;
+    let mut keyspace = Keyspace::Default(db.clone());
+    let mut editor = DefaultEditor::new().map_err(readline_error)?;
+    println!("cendb shell on {dir}, type help for the commands");
+    loop {
+        let prompt = match &keyspace {
+            Keyspace::Default(_) => "cendb> ".to_string(),
+            Keyspace::ColumnFamily(cf) => format!("cendb:{}> ", cf.name()),
+        };
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(readline_error(e)),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line.as_str()).map_err(readline_error)?;
+        let words = match split_words(&line) {
+            Some(words) => words,
+            None => {
+                eprintln!("error: unterminated quote");
+                continue;
+            }
+        };
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        match words.as_slice() {
+            ["exit" | "quit"] => return Ok(()),
+            ["use"] => keyspace = Keyspace::Default(db.clone()),
+            ["use", name] => match db.cf(name) {
+                Ok(cf) => keyspace = Keyspace::ColumnFamily(cf),
+                Err(e) => eprintln!("error: {e}"),
+            },
+            words => {
+                if let Err(e) = execute(&db, &keyspace, words) {
+                    eprintln!("error: {e}");
+                }
+            }
+        }
+    }
+}
+
+fn execute(db: &Db, keyspace: &Keyspace, words: &[&str]) -> Result<()> {
+    match words {
+        ["get", key] => match keyspace.get(key.as_bytes())? {
+            Some(value) => println!("{}", value.escape_ascii()),
+            None => println!("(not found)"),
+        },
+        ["put", key, value] => {
+            keyspace.put(key.as_bytes(), value.as_bytes())?;
+            println!("ok");
+        }
+        ["delete", key] => {
+            keyspace.delete(key.as_bytes())?;
+            println!("ok");
+        }
+        ["scan"] => scan(keyspace, "", DEFAULT_SCAN_LIMIT)?,
+        ["scan", prefix] => scan(keyspace, prefix, DEFAULT_SCAN_LIMIT)?,
+        ["scan", prefix, limit] => {
+            let limit = limit.parse().map_err(|_| Error::Custom(format!("invalid limit {limit}")))?;
+            scan(keyspace, prefix, limit)?
+        }
+        ["flush"] => {
+            db.flush()?;
+            println!("ok");
+        }
+        ["compact"] => {
+            db.compact()?;
+            println!("ok");
+        }
+        ["help"] => println!("{HELP}"),
+        _ => return Err(Error::Custom(format!("unknown command {}, type help for the commands", words.join(" ")))),
+    }
+    Ok(())
+}
+
+// Prints the matching entries as key => value with the keys aligned
+fn scan(keyspace: &Keyspace, prefix: &str, limit: usize) -> Result<()> {
+    let mut entries = Vec::new();
+    let mut matching = 0;
+    for entry in keyspace.scan_prefix(prefix.as_bytes()) {
+        let (key, value) = entry?;
+        if entries.len() < limit {
+            entries.push((key.escape_ascii().to_string(), value.escape_ascii().to_string()));
+        }
+        matching += 1;
+    }
+    let width = entries.iter().map(|(key, _)| key.chars().count()).max().unwrap_or(0);
+    for (key, value) in &entries {
+        println!("{key:<width$} => {value}");
+    }
+    match matching > entries.len() {
+        true => println!("({} of {matching} entries)", entries.len()),
+        false => println!("({matching} entries)"),
+    }
+    Ok(())
+}
+
+// Splits a line at whitespace outside of double quotes, None if a quote is not closed
+fn split_words(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    (!quoted).then_some(words)
+}
+
+fn readline_error(error: ReadlineError) -> Error {
+    Error::Custom(format!("line editor: {error}"))
+}